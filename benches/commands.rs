@@ -0,0 +1,149 @@
+// Drives commands straight through `Command`, against an in-memory database, so the numbers measure blueis and
+// sqlite rather than the network.  Run them with `cargo bench`.
+
+#[macro_use]
+extern crate criterion;
+extern crate blueis;
+extern crate resp;
+extern crate rusqlite;
+
+use blueis::checkout::Reservation;
+use blueis::commands::Command;
+use blueis::config::Config;
+use blueis::connection::Connectionable;
+use blueis::pubsub::PubSub;
+use blueis::push_waiters::PushWaiters;
+use blueis::read_cache::ReadCache;
+use blueis::reply;
+use blueis::stats::Stats;
+use blueis::storage::{SqliteStorage, Storage};
+use criterion::Criterion;
+use resp::Value;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+
+struct BenchConnection {
+    sqlite_connection_mutex: Arc<Mutex<rusqlite::Connection>>,
+    push_waiters: Arc<PushWaiters>,
+    config: Config,
+    read_cache: Arc<Mutex<ReadCache<Value>>>,
+    stats: Stats,
+    pubsub: PubSub,
+    storage: SqliteStorage,
+    reservation: Arc<Reservation>,
+    client_name: Mutex<Option<Vec<u8>>>,
+}
+
+impl Connectionable for BenchConnection {
+    fn get_config(&self) -> &Config { &self.config }
+    fn get_push_waiters(&self) -> Arc<PushWaiters> { self.push_waiters.clone() }
+    fn get_sqlite_connection_mutex(&self) -> &Arc<Mutex<rusqlite::Connection>> { &self.sqlite_connection_mutex }
+    fn get_read_cache(&self) -> &Arc<Mutex<ReadCache<Value>>> { &self.read_cache }
+    fn get_stats(&self) -> &Stats { &self.stats }
+    fn get_pubsub(&self) -> &PubSub { &self.pubsub }
+    fn get_storage(&self) -> &Storage { &self.storage }
+    fn get_reservation(&self) -> &Reservation { &self.reservation }
+    fn get_client_name(&self) -> &Mutex<Option<Vec<u8>>> { &self.client_name }
+    fn is_stream_alive(&self) -> bool { true }
+    fn is_authenticated(&self) -> bool { true }
+    fn send_to_command_log(&self, _command: String) {}
+    fn send_to_replication_log(&self, _command: Vec<Vec<u8>>) {}
+}
+
+impl BenchConnection {
+    fn new() -> BenchConnection {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        blueis::set_up_tables(&connection);
+
+        BenchConnection::sharing(Arc::new(Mutex::new(connection)), Arc::new(PushWaiters::default()), Arc::new(Reservation::default()))
+    }
+
+    // another client of the same server
+    fn sharing(sqlite_connection_mutex: Arc<Mutex<rusqlite::Connection>>, push_waiters: Arc<PushWaiters>, reservation: Arc<Reservation>) -> BenchConnection {
+        BenchConnection {
+            sqlite_connection_mutex: sqlite_connection_mutex,
+            push_waiters:            push_waiters,
+            config:                  Config::default(),
+            read_cache:              Arc::new(Mutex::new(ReadCache::new(0))),
+            stats:                   Stats::default(),
+            pubsub:                  PubSub::default(),
+            storage:                 SqliteStorage,
+            reservation:             reservation,
+            client_name:             Mutex::new(None),
+        }
+    }
+
+    fn run(&self, name: &str, arguments: &[&str]) -> Value {
+        let mut command = Command {
+            name:       name,
+            arguments:  arguments.iter().map(|argument| argument.as_bytes()).collect(),
+            connection: self as &Connectionable,
+        };
+
+        command.execute().0
+    }
+}
+
+fn lpush(c: &mut Criterion) {
+    let connection = BenchConnection::new();
+
+    c.bench_function("lpush", move |b| b.iter(|| connection.run("LPUSH", &["queue", "value"])));
+}
+
+fn lpop(c: &mut Criterion) {
+    let connection = BenchConnection::new();
+
+    c.bench_function("lpop", move |b| b.iter_with_setup(
+        || { connection.run("LPUSH", &["queue", "value"]); },
+        |_| connection.run("LPOP", &["queue"])
+    ));
+}
+
+fn lrange(c: &mut Criterion) {
+    let connection = BenchConnection::new();
+    let values: Vec<String> = (0..1000).map(|index| index.to_string()).collect();
+
+    let mut arguments = vec!["list"];
+    arguments.extend(values.iter().map(|value| value.as_str()));
+    connection.run("RPUSH", &arguments);
+
+    c.bench_function("lrange 100 of 1000", move |b| b.iter(|| connection.run("LRANGE", &["list", "450", "549"])));
+}
+
+// how long a client blocked in BLPOP takes to receive an element once another client pushes it
+fn blocking_pop_wakeup(c: &mut Criterion) {
+    let pusher = BenchConnection::new();
+    let popper = BenchConnection::sharing(pusher.sqlite_connection_mutex.clone(), pusher.push_waiters.clone(), pusher.reservation.clone());
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        loop {
+            let value = popper.run("BLPOP", &["wakeup", "0"]);
+            if sender.send(value).is_err() { break; }
+        }
+    });
+
+    c.bench_function("blocking pop wakeup", move |b| b.iter(|| {
+        pusher.run("LPUSH", &["wakeup", "value"]);
+        receiver.recv().unwrap()
+    }));
+}
+
+// an LRANGE-sized reply, encoded afresh each time as resp does, and into one buffer reused for every reply
+fn encode_reply(c: &mut Criterion) {
+    let value = Value::Array((0..100).map(|index| Value::BufBulk(index.to_string().into_bytes())).collect());
+    let reused_value = value.clone();
+    let mut buffer = Vec::new();
+
+    c.bench_function("encode reply with resp", move |b| b.iter(|| value.encode()));
+
+    c.bench_function("encode reply into a reused buffer", move |b| b.iter(|| {
+        buffer.clear();
+        reply::encode(&reused_value, &mut buffer);
+        buffer.len()
+    }));
+}
+
+criterion_group!(benches, lpush, lpop, lrange, blocking_pop_wakeup, encode_reply);
+criterion_main!(benches);
@@ -0,0 +1,196 @@
+extern crate rusqlite;
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, ThreadId};
+
+// The sqlite connection as held by one command.  A read-only command's checkout has sqlite refuse to write, so a
+// read that writes by mistake fails rather than changing data, and the refusal is lifted when the checkout ends so
+// whoever takes the connection next can write.
+pub struct Checkout<'a> {
+    connection: MutexGuard<'a, rusqlite::Connection>,
+    read_only: bool,
+}
+
+impl<'a> Checkout<'a> {
+    pub fn new(connection: MutexGuard<'a, rusqlite::Connection>, read_only: bool) -> Checkout<'a> {
+        if read_only {
+            connection.execute_batch("PRAGMA query_only = ON").unwrap();
+        }
+
+        Checkout { connection: connection, read_only: read_only }
+    }
+}
+
+// Locks the connection even after a command has panicked while holding it, which leaves the lock poisoned until the
+// next command recovers it.  Any rusqlite transaction the command had open was rolled back as it unwound, so the connection is still usable.
+pub fn lock(mutex: &Mutex<rusqlite::Connection>) -> MutexGuard<'_, rusqlite::Connection> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Keeps the connection for one thread across many locks, as EXEC does for the commands it runs, so no other client's
+// command can run in between.  Whether it's held is only checked with the connection locked, so a command that locked
+// the connection before it was held has finished before its holder's first command runs, and none after can start.
+#[derive(Default)]
+pub struct Reservation {
+    holder: Mutex<Option<ThreadId>>,
+    released: Condvar,
+}
+
+pub struct Held<'a> {
+    reservation: &'a Reservation,
+}
+
+impl Reservation {
+    // locks the connection once no other thread holds the reservation
+    pub fn lock<'a>(&self, mutex: &'a Mutex<rusqlite::Connection>) -> MutexGuard<'a, rusqlite::Connection> {
+        self.lock_and_then(mutex, |_| ())
+    }
+
+    // holds the reservation for this thread until what's returned is dropped
+    pub fn hold<'a>(&'a self, mutex: &Mutex<rusqlite::Connection>) -> Held<'a> {
+        // the connection is only locked while the reservation is taken
+        drop(self.lock_and_then(mutex, |holder| *holder = Some(thread::current().id())));
+        Held { reservation: self }
+    }
+
+    // whether this thread holds the reservation
+    pub fn is_held(&self) -> bool {
+        *self.holder.lock().unwrap() == Some(thread::current().id())
+    }
+
+    fn lock_and_then<'a, F>(&self, mutex: &'a Mutex<rusqlite::Connection>, then: F) -> MutexGuard<'a, rusqlite::Connection>
+        where F: Fn(&mut Option<ThreadId>)
+    {
+        let current = thread::current().id();
+
+        loop {
+            let connection = lock(mutex);
+            let mut holder = self.holder.lock().unwrap();
+
+            if holder.map(|holder| holder == current).unwrap_or(true) {
+                then(&mut holder);
+                return connection;
+            }
+
+            // the connection is let go first, so the holder can go on using it while this waits
+            drop(connection);
+
+            while holder.is_some() {
+                holder = self.released.wait(holder).unwrap();
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Held<'a> {
+    fn drop(&mut self) {
+        *self.reservation.holder.lock().unwrap() = None;
+        self.reservation.released.notify_all();
+    }
+}
+
+impl<'a> Deref for Checkout<'a> {
+    type Target = rusqlite::Connection;
+
+    fn deref(&self) -> &rusqlite::Connection { &self.connection }
+}
+
+impl<'a> DerefMut for Checkout<'a> {
+    fn deref_mut(&mut self) -> &mut rusqlite::Connection { &mut self.connection }
+}
+
+// This can run while a panic unwinds, when a second panic would abort the process, so a failure is ignored.
+// Lifting the refusal only fails if sqlite itself is failing, when the next write would fail anyway.
+impl<'a> Drop for Checkout<'a> {
+    fn drop(&mut self) {
+        if self.read_only {
+            self.connection.execute_batch("PRAGMA query_only = OFF").ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lock, Checkout, Reservation, rusqlite};
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn a_read_only_checkout_refuses_writes_until_it_ends() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        connection.execute("CREATE TABLE items (value integer)", &[]).unwrap();
+        let mutex = Mutex::new(connection);
+
+        {
+            let checkout = Checkout::new(mutex.lock().unwrap(), true);
+            assert!(checkout.execute("INSERT INTO items (value) VALUES (1)", &[]).is_err());
+
+            let count: i64 = checkout.query_row("SELECT COUNT(*) FROM items", &[], |row| row.get(0)).unwrap();
+            assert_eq!(count, 0);
+        }
+
+        let checkout = Checkout::new(mutex.lock().unwrap(), false);
+        assert!(checkout.execute("INSERT INTO items (value) VALUES (1)", &[]).is_ok());
+    }
+
+    #[test]
+    fn a_read_only_checkout_dropped_by_a_panic_leaves_the_connection_writable() {
+        let mutex = Arc::new(Mutex::new(rusqlite::Connection::open_in_memory().unwrap()));
+
+        let reader = mutex.clone();
+        assert!(thread::spawn(move || {
+            let _checkout = Checkout::new(lock(&reader), true);
+            panic!("failing partway through a read");
+        }).join().is_err());
+
+        assert!(lock(&mutex).execute_batch("CREATE TABLE items (value integer)").is_ok());
+    }
+
+    #[test]
+    fn a_poisoned_connection_can_still_be_locked() {
+        let mutex = Arc::new(Mutex::new(rusqlite::Connection::open_in_memory().unwrap()));
+
+        let poisoner = mutex.clone();
+        assert!(thread::spawn(move || {
+            let _connection = poisoner.lock().unwrap();
+            panic!("poisoning the lock");
+        }).join().is_err());
+
+        assert!(mutex.is_poisoned());
+        assert!(lock(&mutex).execute_batch("CREATE TABLE items (value integer)").is_ok());
+    }
+
+    #[test]
+    fn a_held_reservation_keeps_other_threads_waiting_between_its_locks() {
+        let mutex = Arc::new(Mutex::new(rusqlite::Connection::open_in_memory().unwrap()));
+        let reservation = Arc::new(Reservation::default());
+        lock(&mutex).execute_batch("CREATE TABLE items (value integer)").unwrap();
+
+        let held = reservation.hold(&mutex);
+        assert!(reservation.is_held());
+
+        let (sender, receiver) = mpsc::channel();
+        let (other_mutex, other_reservation) = (mutex.clone(), reservation.clone());
+
+        let handle = thread::spawn(move || {
+            assert!(!other_reservation.is_held());
+            other_reservation.lock(&other_mutex).execute_batch("INSERT INTO items (value) VALUES (2)").unwrap();
+            sender.send(()).unwrap();
+        });
+
+        reservation.lock(&mutex).execute_batch("INSERT INTO items (value) VALUES (1)").unwrap();
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        reservation.lock(&mutex).execute_batch("INSERT INTO items (value) VALUES (1)").unwrap();
+
+        drop(held);
+        handle.join().unwrap();
+
+        let connection = lock(&mutex);
+        let mut statement = connection.prepare("SELECT value FROM items ORDER BY rowid").unwrap();
+        let values = statement.query_map(&[], |row| row.get(0)).unwrap().collect::<Result<Vec<i64>, _>>().unwrap();
+        assert_eq!(values, vec![1, 1, 2]);
+    }
+}
@@ -2,25 +2,81 @@ extern crate resp;
 extern crate rusqlite;
 extern crate time;
 
+use checkout::{self, Checkout};
+use config::EvictionPolicy;
 use connection::Connectionable;
+use serialization;
+use stats::Stats;
+use DATABASE_VERSION;
+use set_up_tables;
+use storage::{self, Direction};
 use self::resp::Value;
-use std::sync::MutexGuard;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
 use std::time::{Instant, Duration};
 use std::str;
 use std::cmp;
 
-type CommandResult = Result<Value, String>;
+type CommandResult = Result<Value, CommandError>;
+
+#[derive(PartialEq, Debug)]
+pub enum CommandError {
+    // replied to with the usual ERR prefix
+    Generic(String),
+    // replied to with its own prefix in place of ERR, such as WRONGTYPE or BUSYKEY
+    Prefixed(&'static str, String),
+}
+
+impl CommandError {
+    pub fn to_value(&self) -> Value {
+        match *self {
+            CommandError::Generic(ref message)          => Value::Error(format!("ERR {}", message)),
+            CommandError::Prefixed(prefix, ref message) => Value::Error(format!("{} {}", prefix, message))
+        }
+    }
+}
+
+impl From<String> for CommandError {
+    fn from(message: String) -> CommandError { CommandError::Generic(message) }
+}
+
+impl<'a> From<&'a str> for CommandError {
+    fn from(message: &'a str) -> CommandError { CommandError::Generic(message.to_string()) }
+}
 
 pub struct Command<'a> {
+    // uppercased, as the connection does once for each command it reads
     pub name: &'a str,
     pub arguments: Vec<&'a [u8]>,
     pub connection: &'a Connectionable,
 }
 
+// a subcommand's argument count doesn't include the subcommand itself
+struct SubcommandSettings {
+    name: &'static str,
+    argument_count: i32,
+    handler: fn(&Command) -> CommandResult,
+}
+
 struct CommandSettings {
     name: &'static str,
     argument_count: i32,
-    handler: fn(&Command) -> CommandResult
+    handler: fn(&Command) -> CommandResult,
+    keys: KeySpec,
+    flags: &'static [&'static str],
+}
+
+// Which arguments name keys, counted from 1 for the first argument as redis does: the first key, the last key and
+// the step between keys.  A negative last counts back from the end of the arguments, so -1 is the last argument.
+// For a command whose keys are counted by one of its arguments, numkeys is that argument, and last is ignored.
+struct KeySpec {
+    first: i32,
+    last: i32,
+    step: i32,
+    numkeys: i32,
 }
 
 #[derive(PartialEq, Debug)]
@@ -28,64 +84,289 @@ pub enum Action {
     Continue,
     HangUp,
     StartMonitor,
+    StartSync,
+    StartSubscribe,
 }
 
-enum Direction {
-    Left,
-    Right
+enum Payload<'a> {
+    List(Vec<&'a [u8]>),
+    String(&'a [u8]),
 }
 
-const COMMAND_SETTINGS: [CommandSettings; 14] = [
-    CommandSettings { name: "LLEN",      argument_count: 1,  handler: Command::llen },
-    CommandSettings { name: "LPOP",      argument_count: 1,  handler: Command::lpop },
-    CommandSettings { name: "RPOP",      argument_count: 1,  handler: Command::rpop },
-    CommandSettings { name: "LPUSH",     argument_count: -2, handler: Command::lpush },
-    CommandSettings { name: "LPUSHX",    argument_count: -2, handler: Command::lpushx },
-    CommandSettings { name: "RPUSH",     argument_count: -2, handler: Command::rpush },
-    CommandSettings { name: "RPUSHX",    argument_count: -2, handler: Command::rpushx },
-    CommandSettings { name: "LRANGE",    argument_count: 3,  handler: Command::lrange },
-    CommandSettings { name: "LTRIM",     argument_count: 3,  handler: Command::ltrim },
-    CommandSettings { name: "RPOPLPUSH", argument_count: 2,  handler: Command::rpoplpush },
-    CommandSettings { name: "LINDEX",    argument_count: 2,  handler: Command::lindex },
-    CommandSettings { name: "LSET",      argument_count: 3,  handler: Command::lset },
-    CommandSettings { name: "BLPOP",     argument_count: -2, handler: Command::blpop },
-    CommandSettings { name: "BRPOP",     argument_count: -2, handler: Command::brpop },
+const DUMP_TYPE_LIST: u8 = b'l';
+const DUMP_TYPE_STRING: u8 = b's';
+
+const NO_KEYS: KeySpec = KeySpec { first: 0, last: 0, step: 0, numkeys: 0 };
+const FIRST_KEY: KeySpec = KeySpec { first: 1, last: 1, step: 1, numkeys: 0 };
+
+// accepts any number of arguments, including none, leaving the handler to check them
+const ANY_ARGUMENT_COUNT: i32 = i32::MIN;
+
+// the tables FLUSHDB empties, with which of their rows it deletes.  blueis holds strings alongside the version row
+// and any other of blueis's own, which must survive, and are told apart as their keys are text rather than blobs.
+const FLUSHED_TABLES: [(&'static str, &'static str); 5] = [
+    ("list_items", "1"),
+    ("zset_items", "1"),
+    ("hash_items", "1"),
+    ("key_meta",   "1"),
+    ("blueis",     "typeof(key) = 'blob'"),
+];
+
+// the tables holding each type's data by key; a key exists while any of them has a row for it.  Strings live in
+// blueis, whose version row has a text key that never equals a key given by a client, as those are always blobs.
+const KEY_TABLES: [&'static str; 4] = ["list_items", "zset_items", "hash_items", "blueis"];
+
+// the table holding each type other than lists, which are found through the storage, named as TYPE names them
+const KEY_TYPES: [(&'static str, &'static str); 3] = [("zset", "zset_items"), ("hash", "hash_items"), ("string", "blueis")];
+
+// OBJECT ENCODING reports sorted sets and hashes with up to this many members as listpacks, as redis stores them by
+// default, though nothing about how they're stored changes
+const ZSET_MAX_LISTPACK_ENTRIES: i64 = 128;
+const HASH_MAX_LISTPACK_ENTRIES: i64 = 128;
+
+// the longest string redis stores in the same allocation as its object
+const EMBSTR_MAX_LENGTH: usize = 44;
+
+// every key, once each, from all of KEY_TABLES
+const ALL_KEYS_SQL: &'static str = "SELECT key FROM list_items UNION SELECT key FROM zset_items UNION SELECT key FROM hash_items UNION SELECT key FROM blueis WHERE typeof(key) = 'blob'";
+
+// the keys of each type, named as TYPE names them, once each
+const TYPED_KEYS_SQL: [(&'static str, &'static str); 4] = [
+    ("list",   "SELECT DISTINCT key FROM list_items"),
+    ("zset",   "SELECT DISTINCT key FROM zset_items"),
+    ("hash",   "SELECT DISTINCT key FROM hash_items"),
+    ("string", "SELECT key FROM blueis WHERE typeof(key) = 'blob'"),
+];
+
+// The keys write commands write without reading what they held, by command and position among the command's keys,
+// which COMMAND GETKEYSANDFLAGS reports as OW.  Every other key of a write command is RW, and of any other command RO.
+const OVERWRITTEN_KEYS: [(&'static str, usize); 8] = [
+    ("SET",        0),
+    ("SETEX",      0),
+    ("PSETEX",     0),
+    ("RESTORE",    0),
+    ("RPOPLPUSH",  1),
+    ("BRPOPLPUSH", 1),
+    ("LMOVE",      1),
+    ("BLMOVE",     1),
+];
+
+// EXISTS checks this many keys in each query, well within sqlite's limit on the parameters a statement can have
+const EXISTS_BATCH_SIZE: usize = 500;
+
+// SCAN returns this many keys a call unless given a COUNT
+const DEFAULT_SCAN_COUNT: i64 = 10;
+
+// FLUSHDB ASYNC releases the lock after deleting this many rows from a flushed table, so other clients can run
+const FLUSH_BATCH_SIZE: i64 = 1000;
+
+// the start of the names FLUSHDB ASYNC moves the tables it's flushing to, until it has dropped them
+const FLUSHING_TABLE_PREFIX: &'static str = "flushing_";
+
+const CLIENT_SUBCOMMANDS: [SubcommandSettings; 2] = [
+    SubcommandSettings { name: "GETNAME", argument_count: 0, handler: Command::client_getname },
+    SubcommandSettings { name: "SETNAME", argument_count: 1, handler: Command::client_setname },
+];
+
+const COMMAND_SUBCOMMANDS: [SubcommandSettings; 3] = [
+    SubcommandSettings { name: "GETKEYS",         argument_count: -1, handler: Command::command_getkeys },
+    SubcommandSettings { name: "GETKEYSANDFLAGS", argument_count: -1, handler: Command::command_getkeysandflags },
+    SubcommandSettings { name: "INFO",            argument_count: ANY_ARGUMENT_COUNT, handler: Command::command_info },
+];
+
+const CONFIG_SUBCOMMANDS: [SubcommandSettings; 3] = [
+    SubcommandSettings { name: "GET",       argument_count: 1, handler: Command::config_get },
+    SubcommandSettings { name: "RESETSTAT", argument_count: 0, handler: Command::config_resetstat },
+    SubcommandSettings { name: "SET",       argument_count: 2, handler: Command::config_set },
+];
+
+// JMAP, CHANGE-REPL-ID and SET-ACTIVE-EXPIRE are called by tools during setup, and are safe to acknowledge without
+// doing anything
+const DEBUG_SUBCOMMANDS: [SubcommandSettings; 8] = [
+    SubcommandSettings { name: "CHANGE-REPL-ID",    argument_count: ANY_ARGUMENT_COUNT, handler: Command::debug_no_op },
+    SubcommandSettings { name: "FLUSHALL",          argument_count: ANY_ARGUMENT_COUNT, handler: Command::debug_flushall },
+    SubcommandSettings { name: "JMAP",              argument_count: ANY_ARGUMENT_COUNT, handler: Command::debug_no_op },
+    SubcommandSettings { name: "LISTPACK",          argument_count: 1, handler: Command::debug_list_info },
+    SubcommandSettings { name: "LOADLIST",          argument_count: 2, handler: Command::debug_loadlist },
+    SubcommandSettings { name: "QUICKLIST",         argument_count: 1, handler: Command::debug_list_info },
+    SubcommandSettings { name: "REINDEX",           argument_count: 0, handler: Command::debug_reindex },
+    SubcommandSettings { name: "SET-ACTIVE-EXPIRE", argument_count: ANY_ARGUMENT_COUNT, handler: Command::debug_no_op },
+];
+
+const OBJECT_SUBCOMMANDS: [SubcommandSettings; 1] = [
+    SubcommandSettings { name: "ENCODING", argument_count: 1, handler: Command::object_encoding },
+];
+
+const COMMAND_SETTINGS: [CommandSettings; 76] = [
+    CommandSettings { name: "LLEN",      argument_count: 1,  handler: Command::llen, keys: FIRST_KEY, flags: &["readonly", "fast"] },
+    CommandSettings { name: "LPOP",      argument_count: -1, handler: Command::lpop, keys: FIRST_KEY, flags: &["write", "fast"] },
+    CommandSettings { name: "RPOP",      argument_count: -1, handler: Command::rpop, keys: FIRST_KEY, flags: &["write", "fast"] },
+    CommandSettings { name: "LPUSH",     argument_count: -2, handler: Command::lpush, keys: FIRST_KEY, flags: &["write", "denyoom", "fast"] },
+    CommandSettings { name: "LPUSHX",    argument_count: -2, handler: Command::lpushx, keys: FIRST_KEY, flags: &["write", "fast"] },
+    CommandSettings { name: "RPUSH",     argument_count: -2, handler: Command::rpush, keys: FIRST_KEY, flags: &["write", "denyoom", "fast"] },
+    CommandSettings { name: "RPUSHX",    argument_count: -2, handler: Command::rpushx, keys: FIRST_KEY, flags: &["write", "fast"] },
+    CommandSettings { name: "LEXPORT",   argument_count: -1, handler: Command::lexport, keys: FIRST_KEY, flags: &["readonly"] },
+    CommandSettings { name: "LRANGE",    argument_count: 3,  handler: Command::lrange, keys: FIRST_KEY, flags: &["readonly"] },
+    CommandSettings { name: "LROTATE",   argument_count: 2,  handler: Command::lrotate, keys: FIRST_KEY, flags: &["write", "fast"] },
+    CommandSettings { name: "LTRIM",     argument_count: 3,  handler: Command::ltrim, keys: FIRST_KEY, flags: &["write"] },
+    CommandSettings { name: "RPOPLPUSH", argument_count: 2,  handler: Command::rpoplpush, keys: KeySpec { first: 1, last: 2, step: 1, numkeys: 0 }, flags: &["write", "denyoom"] },
+    CommandSettings { name: "LMOVE",     argument_count: 4,  handler: Command::lmove, keys: KeySpec { first: 1, last: 2, step: 1, numkeys: 0 }, flags: &["write", "denyoom"] },
+    CommandSettings { name: "LINDEX",    argument_count: 2,  handler: Command::lindex, keys: FIRST_KEY, flags: &["readonly"] },
+    CommandSettings { name: "LPOS",      argument_count: -2, handler: Command::lpos, keys: FIRST_KEY, flags: &["readonly"] },
+    CommandSettings { name: "LREM",      argument_count: 3,  handler: Command::lrem, keys: FIRST_KEY, flags: &["write"] },
+    CommandSettings { name: "LSET",      argument_count: 3,  handler: Command::lset, keys: FIRST_KEY, flags: &["write"] },
+    CommandSettings { name: "LSWAP",     argument_count: 3,  handler: Command::lswap, keys: FIRST_KEY, flags: &["write"] },
+    CommandSettings { name: "LINSERT",   argument_count: 4,  handler: Command::linsert, keys: FIRST_KEY, flags: &["write"] },
+    CommandSettings { name: "BLPOP",     argument_count: -2, handler: Command::blpop, keys: KeySpec { first: 1, last: -2, step: 1, numkeys: 0 }, flags: &["write"] },
+    CommandSettings { name: "BRPOP",     argument_count: -2, handler: Command::brpop, keys: KeySpec { first: 1, last: -2, step: 1, numkeys: 0 }, flags: &["write"] },
+    CommandSettings { name: "LMPOP",     argument_count: -3, handler: Command::lmpop, keys: KeySpec { first: 2, last: 0, step: 1, numkeys: 1 }, flags: &["write", "movablekeys"] },
+    CommandSettings { name: "BRPOPLPUSH", argument_count: 3, handler: Command::brpoplpush, keys: KeySpec { first: 1, last: 2, step: 1, numkeys: 0 }, flags: &["write", "denyoom"] },
+    CommandSettings { name: "BLMOVE",    argument_count: 5,  handler: Command::blmove, keys: KeySpec { first: 1, last: 2, step: 1, numkeys: 0 }, flags: &["write", "denyoom"] },
+    CommandSettings { name: "BLMPOP",    argument_count: -4, handler: Command::blmpop, keys: KeySpec { first: 3, last: 0, step: 1, numkeys: 2 }, flags: &["write", "movablekeys"] },
+    CommandSettings { name: "MPOP",      argument_count: -3, handler: Command::mpop, keys: KeySpec { first: 2, last: -2, step: 1, numkeys: 0 }, flags: &["write"] },
+    CommandSettings { name: "ZADD",      argument_count: -3, handler: Command::zadd, keys: FIRST_KEY, flags: &["write", "denyoom", "fast"] },
+    CommandSettings { name: "ZCARD",     argument_count: 1,  handler: Command::zcard, keys: FIRST_KEY, flags: &["readonly", "fast"] },
+    CommandSettings { name: "ZINCRBY",   argument_count: 3,  handler: Command::zincrby, keys: FIRST_KEY, flags: &["write", "denyoom", "fast"] },
+    CommandSettings { name: "ZRANGE",    argument_count: -3, handler: Command::zrange, keys: FIRST_KEY, flags: &["readonly"] },
+    CommandSettings { name: "ZRANGEBYSCORE", argument_count: -3, handler: Command::zrangebyscore, keys: FIRST_KEY, flags: &["readonly"] },
+    CommandSettings { name: "ZRANK",     argument_count: 2,  handler: Command::zrank, keys: FIRST_KEY, flags: &["readonly", "fast"] },
+    CommandSettings { name: "ZREM",      argument_count: -2, handler: Command::zrem, keys: FIRST_KEY, flags: &["write", "fast"] },
+    CommandSettings { name: "ZREVRANK",  argument_count: 2,  handler: Command::zrevrank, keys: FIRST_KEY, flags: &["readonly", "fast"] },
+    CommandSettings { name: "ZSCORE",    argument_count: 2,  handler: Command::zscore, keys: FIRST_KEY, flags: &["readonly", "fast"] },
+    CommandSettings { name: "HEXISTS",   argument_count: 2,  handler: Command::hexists, keys: FIRST_KEY, flags: &["readonly", "fast"] },
+    CommandSettings { name: "HGET",      argument_count: 2,  handler: Command::hget, keys: FIRST_KEY, flags: &["readonly", "fast"] },
+    CommandSettings { name: "HGETALL",   argument_count: 1,  handler: Command::hgetall, keys: FIRST_KEY, flags: &["readonly"] },
+    CommandSettings { name: "HINCRBY",   argument_count: 3,  handler: Command::hincrby, keys: FIRST_KEY, flags: &["write", "denyoom", "fast"] },
+    CommandSettings { name: "HINCRBYFLOAT", argument_count: 3, handler: Command::hincrbyfloat, keys: FIRST_KEY, flags: &["write", "denyoom", "fast"] },
+    CommandSettings { name: "HKEYS",     argument_count: 1,  handler: Command::hkeys, keys: FIRST_KEY, flags: &["readonly"] },
+    CommandSettings { name: "HMGET",     argument_count: -2, handler: Command::hmget, keys: FIRST_KEY, flags: &["readonly", "fast"] },
+    CommandSettings { name: "HSET",      argument_count: -3, handler: Command::hset, keys: FIRST_KEY, flags: &["write", "denyoom", "fast"] },
+    CommandSettings { name: "HVALS",     argument_count: 1,  handler: Command::hvals, keys: FIRST_KEY, flags: &["readonly"] },
+    CommandSettings { name: "GET",       argument_count: 1,  handler: Command::get, keys: FIRST_KEY, flags: &["readonly", "fast"] },
+    CommandSettings { name: "SET",       argument_count: 2,  handler: Command::set, keys: FIRST_KEY, flags: &["write", "denyoom"] },
+    CommandSettings { name: "PSETEX",    argument_count: 3,  handler: Command::psetex, keys: FIRST_KEY, flags: &["write", "denyoom"] },
+    CommandSettings { name: "SETEX",     argument_count: 3,  handler: Command::setex, keys: FIRST_KEY, flags: &["write", "denyoom"] },
+    CommandSettings { name: "APPEND",    argument_count: 2,  handler: Command::append, keys: FIRST_KEY, flags: &["write", "denyoom", "fast"] },
+    CommandSettings { name: "DBSIZE",    argument_count: 0,  handler: Command::dbsize, keys: NO_KEYS, flags: &["readonly", "fast"] },
+    CommandSettings { name: "DEL",       argument_count: -1, handler: Command::del, keys: KeySpec { first: 1, last: -1, step: 1, numkeys: 0 }, flags: &["write"] },
+    CommandSettings { name: "DUMP",      argument_count: 1,  handler: Command::dump, keys: FIRST_KEY, flags: &["readonly"] },
+    CommandSettings { name: "EXPIRE",    argument_count: 2,  handler: Command::expire, keys: FIRST_KEY, flags: &["write", "fast"] },
+    CommandSettings { name: "EXISTS",    argument_count: -1, handler: Command::exists, keys: KeySpec { first: 1, last: -1, step: 1, numkeys: 0 }, flags: &["readonly", "fast"] },
+    CommandSettings { name: "EXPIREAT",  argument_count: 2,  handler: Command::expireat, keys: FIRST_KEY, flags: &["write", "fast"] },
+    CommandSettings { name: "OBJECT",    argument_count: -1, handler: Command::object, keys: KeySpec { first: 2, last: 2, step: 1, numkeys: 0 }, flags: &["readonly"] },
+    CommandSettings { name: "PERSIST",   argument_count: 1,  handler: Command::persist, keys: FIRST_KEY, flags: &["write", "fast"] },
+    CommandSettings { name: "PEXPIRE",   argument_count: 2,  handler: Command::pexpire, keys: FIRST_KEY, flags: &["write", "fast"] },
+    CommandSettings { name: "PEXPIREAT", argument_count: 2,  handler: Command::pexpireat, keys: FIRST_KEY, flags: &["write", "fast"] },
+    CommandSettings { name: "PTTL",      argument_count: 1,  handler: Command::pttl, keys: FIRST_KEY, flags: &["readonly", "fast"] },
+    CommandSettings { name: "TTL",       argument_count: 1,  handler: Command::ttl, keys: FIRST_KEY, flags: &["readonly", "fast"] },
+    CommandSettings { name: "TYPE",      argument_count: 1,  handler: Command::type_, keys: FIRST_KEY, flags: &["readonly", "fast"] },
+    CommandSettings { name: "RANDOMKEY", argument_count: 0,  handler: Command::randomkey, keys: NO_KEYS, flags: &["readonly", "random"] },
+    CommandSettings { name: "SCAN",      argument_count: -1, handler: Command::scan, keys: NO_KEYS, flags: &["readonly"] },
+    CommandSettings { name: "RESTORE",   argument_count: -3, handler: Command::restore, keys: FIRST_KEY, flags: &["write", "denyoom"] },
+    CommandSettings { name: "PUBLISH",   argument_count: 2,  handler: Command::publish, keys: NO_KEYS, flags: &["pubsub", "fast"] },
+    CommandSettings { name: "CLIENT",    argument_count: -1, handler: Command::client, keys: NO_KEYS, flags: &[] },
+    CommandSettings { name: "COMMAND",   argument_count: -1, handler: Command::command, keys: NO_KEYS, flags: &[] },
+    CommandSettings { name: "CONFIG",    argument_count: -1, handler: Command::config, keys: NO_KEYS, flags: &["admin"] },
+    CommandSettings { name: "DEBUG",     argument_count: -1, handler: Command::debug, keys: NO_KEYS, flags: &["admin"] },
+    CommandSettings { name: "INFO",      argument_count: ANY_ARGUMENT_COUNT, handler: Command::info, keys: NO_KEYS, flags: &[] },
+    CommandSettings { name: "ECHO",      argument_count: 1,  handler: Command::echo, keys: NO_KEYS, flags: &["fast"] },
+    CommandSettings { name: "SELECT",    argument_count: 1,  handler: Command::select, keys: NO_KEYS, flags: &["fast"] },
+    CommandSettings { name: "WAIT",      argument_count: 2,  handler: Command::wait, keys: NO_KEYS, flags: &[] },
+    CommandSettings { name: "FLUSHDB",   argument_count: ANY_ARGUMENT_COUNT, handler: Command::flushdb, keys: NO_KEYS, flags: &["write"] },
+    CommandSettings { name: "FLUSHALL",  argument_count: ANY_ARGUMENT_COUNT, handler: Command::flushdb, keys: NO_KEYS, flags: &["write"] },
 ];
 
 impl<'a> Command<'a> {
     pub fn execute(&mut self) -> (Value, Action) {
-        match self.name.to_string().to_uppercase().as_str() {
+        match self.name {
             "QUIT"    => (Value::String("OK".to_string()), Action::HangUp),
+            "PING"    => (self.ping(), Action::Continue),
             "MONITOR" => (Value::String("OK".to_string()), Action::StartMonitor),
+            "SYNC"    => (Value::String("OK".to_string()), Action::StartSync),
+
+            "SUBSCRIBE" if self.arguments.is_empty() => (Value::Error("ERR wrong number of arguments".to_string()), Action::Continue),
+
+            // the connection replies itself, once for each channel
+            "SUBSCRIBE" | "UNSUBSCRIBE" => (Value::Null, Action::StartSubscribe),
+
             _         => (self.handle_nonterminal_command(), Action::Continue)
         }
     }
 
-    fn valid_argument_count(&self, command: &CommandSettings) -> bool {
-        (command.argument_count < 0 && self.arguments.len() as i32 >= -command.argument_count) ||
-            (command.argument_count >= 0 && self.arguments.len() as i32 == command.argument_count)
+    // answered without touching the database, so it works as a health check even while the database is loading
+    fn ping(&self) -> Value {
+        match self.arguments.len() {
+            0 => Value::String("PONG".to_string()),
+            1 => Value::BufBulk(self.arguments[0].to_vec()),
+            _ => Value::Error("ERR wrong number of arguments".to_string())
+        }
     }
 
-    fn handle_nonterminal_command(&mut self) -> Value {
-        let all = COMMAND_SETTINGS;
-        let upper = self.name.to_string().to_uppercase();
-        let name = upper.as_str();
-        let settings = all.iter().find(|settings| settings.name == name);
+    fn valid_argument_count(argument_count: i32, count: usize) -> bool {
+        argument_count == ANY_ARGUMENT_COUNT ||
+            (argument_count < 0 && count as i32 >= -argument_count) ||
+            (argument_count >= 0 && count as i32 == argument_count)
+    }
+
+    // Panics if two commands share a name, as only the first would ever be found.  Called at startup, so a rename
+    // that collides with another command is caught straight away rather than silently shadowing it.
+    pub fn check_settings() {
+        if let Some(name) = Command::duplicate_name(&COMMAND_SETTINGS) {
+            panic!("the {} command is registered more than once", name);
+        }
+    }
+
+    fn duplicate_name(settings: &[CommandSettings]) -> Option<&'static str> {
+        settings.iter().enumerate()
+            .find(|&(index, setting)| settings[..index].iter().any(|earlier| earlier.name.eq_ignore_ascii_case(setting.name)))
+            .map(|(_, setting)| setting.name)
+    }
 
-        match settings {
-            None => Value::Error("ERR unsupported".to_string()),
+    fn find_settings(name: &str) -> Option<&'static CommandSettings> {
+        COMMAND_SETTINGS.iter().find(|settings| settings.name.eq_ignore_ascii_case(name))
+    }
+
+    // Whether MULTI can queue a command, given its uppercased name, or the error it replies with instead.  Commands
+    // that take over the connection can't wait for EXEC.
+    pub fn check_queueable(name: &str, argument_count: usize) -> Result<(), Value> {
+        match name {
+            "MONITOR" | "SYNC" | "SUBSCRIBE" | "UNSUBSCRIBE" => Err(Value::Error("ERR Command not allowed inside a transaction".to_string())),
+            "PING" if argument_count <= 1                    => Ok(()),
+            _                                                => Command::find_valid_settings(name, argument_count).map(|_| ())
+        }
+    }
+
+    fn find_valid_settings(name: &str, argument_count: usize) -> Result<&'static CommandSettings, Value> {
+        match Command::find_settings(name) {
+            None => Err(Value::Error("ERR unsupported".to_string())),
 
             Some(settings) => {
-                if !self.valid_argument_count(settings) {
-                    Value::Error("ERR wrong number of arguments".to_string())
+                if Command::valid_argument_count(settings.argument_count, argument_count) {
+                    Ok(settings)
                 }
                 else {
-                    self.write_to_log();
+                    Err(Value::Error("ERR wrong number of arguments".to_string()))
+                }
+            }
+        }
+    }
 
-                    match (settings.handler)(self) {
-                        Ok(value)  => value,
-                        Err(error) => Value::Error(format!("ERR {}", error))
-                    }
+    fn handle_nonterminal_command(&mut self) -> Value {
+        match Command::find_valid_settings(self.name, self.arguments.len()) {
+            Err(error) => error,
+
+            Ok(settings) => {
+                if let Err(error) = self.recover_connection() {
+                    return error.to_value();
+                }
+
+                self.write_to_log();
+                self.expire_keys();
+                self.count_keyspace_lookups(settings);
+
+                match self.check_key_sizes(&self.keys(settings)).and_then(|_| (settings.handler)(self)) {
+                    Ok(value)  => value,
+                    Err(error) => error.to_value()
                 }
             }
         }
@@ -103,71 +384,45 @@ impl<'a> Command<'a> {
     }
 
     fn lpop(command: &Command) -> CommandResult {
-        let connection = command.lock_connection();
-
-        match Command::pop(&*connection, command.arguments[0], &Direction::Left) {
-            Some(data) => Ok(Value::BufBulk(data)),
-            None       => Ok(Value::Null)
-        }
+        command.pop_and_notify(Direction::Left, "lpop")
     }
 
     fn rpop(command: &Command) -> CommandResult {
-        let connection = command.lock_connection();
-
-        match Command::pop(&*connection, command.arguments[0], &Direction::Right) {
-            Some(data) => Ok(Value::BufBulk(data)),
-            None       => Ok(Value::Null)
-        }
+        command.pop_and_notify(Direction::Right, "rpop")
     }
 
     fn lpush(command: &Command) -> CommandResult {
         let key = command.arguments[0];
-        let mut connection = command.lock_connection();
+        command.check_value_sizes(&command.arguments[1..])?;
 
-        Command::push(&mut *connection, key, Direction::Left, command.arguments.iter().skip(1));
-        command.notify_push();
+        let mut connection = command.lock_connection_making_room()?;
+
+        command.push_in_transaction(&mut *connection, key, Direction::Left);
+        command.replicate();
+        command.notify_push(key);
 
         command.count_list_items_value(&*connection, key)
     }
 
     fn lpushx(command: &Command) -> CommandResult {
-        let key = command.arguments[0];
-        let mut connection = command.lock_connection();
-
-        if Command::count_list_items(&*connection, key) == 0 {
-            Ok(Value::Integer(0))
-        }
-        else {
-            Command::push(&mut *connection, key, Direction::Left, command.arguments.iter().skip(1));
-            command.notify_push();
-
-            command.count_list_items_value(&*connection, key)
-        }
+        command.push_if_exists(Direction::Left)
     }
 
     fn rpush(command: &Command) -> CommandResult {
         let key = command.arguments[0];
-        let mut connection = command.lock_connection();
+        command.check_value_sizes(&command.arguments[1..])?;
+
+        let mut connection = command.lock_connection_making_room()?;
 
-        Command::push(&mut *connection, key, Direction::Right, command.arguments.iter().skip(1));
-        command.notify_push();
+        command.push_in_transaction(&mut *connection, key, Direction::Right);
+        command.replicate();
+        command.notify_push(key);
 
         command.count_list_items_value(&*connection, key)
     }
 
     fn rpushx(command: &Command) -> CommandResult {
-        let key = command.arguments[0];
-        let mut connection = command.lock_connection();
-
-        if Command::count_list_items(&*connection, key) == 0 {
-            Ok(Value::Integer(0))
-        }
-        else {
-            Command::push(&mut *connection, key, Direction::Right, command.arguments.iter().skip(1));
-            command.notify_push();
-
-            command.count_list_items_value(&*connection, key)
-        }
+        command.push_if_exists(Direction::Right)
     }
 
     fn lrange(command: &Command) -> CommandResult {
@@ -176,37 +431,37 @@ impl<'a> Command<'a> {
         let stop: i64 = command.parse_argument_integer(2)?;
 
         let connection = command.lock_connection();
+        let mut cache = command.connection.get_read_cache().lock().unwrap();
 
-        let result: Result<Vec<Vec<u8>>, _> = match (start, stop) {
-            (0, -1) => {
-                let mut statement = connection.prepare("SELECT value FROM list_items WHERE key = ?1 ORDER BY position").unwrap();
-                let rows = statement.query_map(&[&key], |row| row.get(0)).unwrap();
-                rows.collect()
-            }
-
-            (0, s) if s >= 0 => {
-                let mut statement = connection.prepare("SELECT value FROM list_items WHERE key = ?1 ORDER BY position LIMIT ?2").unwrap();
-                let rows = statement.query_map(&[&key, &(stop + 1)], |row| row.get(0)).unwrap();
-                rows.collect()
-            }
+        if let Some(value) = cache.get(key, start, stop) {
+            return Ok(value);
+        }
 
-            _ => {
-                let boundaries = Command::find_position_boundaries(&*connection, key);
-                let (start_position, stop_position) = Command::parse_indexes(boundaries, (start, stop));
+        let values = command.connection.get_storage().range(&*connection, key, start, stop);
+        let value = Value::Array(values.into_iter().map(Value::BufBulk).collect());
+        cache.insert(key, start, stop, value.clone());
 
-                if start_position > stop_position {
-                    return Ok(Value::Array(vec![]));
-                }
+        Ok(value)
+    }
 
-                let mut statement = connection.prepare("SELECT value FROM list_items WHERE key = ?1 AND position BETWEEN ?2 AND ?3 ORDER BY position").unwrap();
-                let rows = statement.query_map(&[&key, &start_position, &stop_position], |row| row.get(0)).unwrap();
-                rows.collect()
-            }
+    // The whole list as one string, each element followed by a newline, for piping into line-based tools.  An element
+    // with a newline of its own reads as two, so NUL separates them instead for lists that may hold those.
+    fn lexport(command: &Command) -> CommandResult {
+        let separator = match command.arguments.len() {
+            1                                       => b'\n',
+            2 if command.argument_matches(1, "NUL") => 0,
+            _                                       => return Err("syntax error".into())
         };
 
-        let values = result.unwrap().iter().map(|value| Value::BufBulk(value.clone())).collect();
+        let connection = command.lock_connection();
+        let mut export = vec![];
 
-        Ok(Value::Array(values))
+        for value in command.connection.get_storage().range(&*connection, command.arguments[0], 0, -1) {
+            export.extend_from_slice(&value);
+            export.push(separator);
+        }
+
+        Ok(Value::BufBulk(export))
     }
 
     fn ltrim(command: &Command) -> CommandResult {
@@ -216,26 +471,50 @@ impl<'a> Command<'a> {
 
         if start != 0 || stop != -1 {
             let connection = command.lock_connection();
-
-            let boundaries = Command::find_position_boundaries(&*connection, key);
-            let (start_position, stop_position) = Command::parse_indexes(boundaries, (start, stop));
-
-            connection.execute("DELETE FROM list_items WHERE key = ?1 AND (position < ?2 OR position > ?3)", &[&key, &start_position, &stop_position]).unwrap();
+            command.connection.get_storage().trim(&*connection, key, start, stop);
+            command.replicate();
         }
 
         Ok(Value::String("OK".to_string()))
     }
 
     fn rpoplpush(command: &Command) -> CommandResult {
-        let source = command.arguments[0];
-        let destination = command.arguments[1];
+        let mut connection = command.lock_connection();
+        Ok(command.move_element(&mut connection, &Direction::Right, &Direction::Left)?.map(Value::BufBulk).unwrap_or(Value::Null))
+    }
+
+    fn lmove(command: &Command) -> CommandResult {
+        let from = command.parse_direction(2)?;
+        let to = command.parse_direction(3)?;
+
+        let mut connection = command.lock_connection();
+        Ok(command.move_element(&mut connection, &from, &to)?.map(Value::BufBulk).unwrap_or(Value::Null))
+    }
+
+    // RIGHT moves the last element to the front and LEFT moves the first element to the back
+    fn lrotate(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+
+        let (from, to) = if command.argument_matches(1, "LEFT") {
+            (Direction::Left, Direction::Right)
+        }
+        else if command.argument_matches(1, "RIGHT") {
+            (Direction::Right, Direction::Left)
+        }
+        else {
+            return Err("syntax error".into());
+        };
 
         let mut connection = command.lock_connection();
+        let tx = connection.transaction().unwrap();
 
-        match Command::pop(&*connection, source, &Direction::Right) {
+        let storage = command.connection.get_storage();
+
+        match storage.pop(&tx, key, &from) {
             Some(data) => {
-                Command::push(&mut *connection, destination, Direction::Left, [data.as_slice()].iter());
-                command.notify_push();
+                storage.push(&tx, key, &to, &[&data]);
+                tx.commit().unwrap();
+                command.replicate();
                 Ok(Value::BufBulk(data))
             }
 
@@ -249,464 +528,4534 @@ impl<'a> Command<'a> {
 
         let connection = command.lock_connection();
 
-        let boundaries = Command::find_position_boundaries(&*connection, key);
-        let position = Command::parse_index(boundaries, index);
-
-        let mut statement = connection.prepare("SELECT value FROM list_items WHERE key = ?1 AND position = ?2 LIMIT 1").unwrap();
-
-        match statement.query_row(&[&key, &position], |row| row.get(0)) {
-            Ok(data)                                  => Ok(Value::BufBulk(data)),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(Value::Null),
-            Err(e)                                    => panic!(e)
+        match command.connection.get_storage().range(&*connection, key, index, index).pop() {
+            Some(data) => Ok(Value::BufBulk(data)),
+            None       => Ok(Value::Null)
         }
     }
 
-    fn lset(command: &Command) -> CommandResult {
+    // A negative RANK searches from the tail, and every match is found in that order, so with COUNT the indexes come
+    // back in the order they were found rather than sorted.  Indexes always count from the head.
+    fn lpos(command: &Command) -> CommandResult {
         let key = command.arguments[0];
-        let index: i64 = command.parse_argument_integer(1)?;
-        let data = command.arguments[2];
+        let element = command.arguments[1];
 
-        let connection = command.lock_connection();
+        let mut rank = 1;
+        let mut count = None;
+        let mut max_length = 0;
 
-        let (first_position, last_position) = Command::find_position_boundaries(&*connection, key);
-        let position = Command::parse_index((first_position, last_position), index);
+        for index in (2..command.arguments.len()).step_by(2) {
+            if index + 1 == command.arguments.len() {
+                return Err("syntax error".into());
+            }
 
-        if position < first_position || position > last_position {
-            Err("index out of range".to_string())
-        }
-        else {
-            connection.execute("UPDATE list_items SET value = ?1 WHERE key = ?2 AND position = ?3", &[&data, &key, &position]).unwrap();
-            Ok(Value::String("OK".to_string()))
-        }
-    }
+            let value = command.parse_argument_integer(index + 1)?;
 
-    fn blpop(command: &Command) -> CommandResult {
-        command.blocking_pop(Direction::Left)
-    }
+            if command.argument_matches(index, "RANK") {
+                if value == 0 {
+                    return Err("RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list".into());
+                }
 
-    fn brpop(command: &Command) -> CommandResult {
-        command.blocking_pop(Direction::Right)
-    }
+                rank = value;
+            }
+            else if command.argument_matches(index, "COUNT") {
+                if value < 0 {
+                    return Err("COUNT can't be negative".into());
+                }
 
-    /*
-     * support methods
-     */
+                count = Some(value);
+            }
+            else if command.argument_matches(index, "MAXLEN") {
+                if value < 0 {
+                    return Err("MAXLEN can't be negative".into());
+                }
 
-    fn write_to_log(&self) {
-        let now = time::now_utc().to_timespec();
-        let args = self.arguments.iter().map(|argument| Command::quote_string(argument)).collect::<Vec<String>>().join(" ");
-        let log = format!("{}.{:09} {} {}", now.sec, now.nsec, Command::quote_string(self.name.as_bytes()), args);
+                max_length = value;
+            }
+            else {
+                return Err("syntax error".into());
+            }
+        }
 
-        self.connection.send_to_command_log(log);
-    }
+        let skipped = rank.checked_abs().ok_or("value is out of range")? - 1;
 
-    fn quote_string(input: &[u8]) -> String {
-        let mut output = String::from("\"");
+        let connection = command.lock_connection();
+        let storage = command.connection.get_storage();
+        let length = storage.count(&*connection, key);
+
+        // with MAXLEN, only that many elements from the end the search starts at are compared
+        let mut searched = match (max_length, rank < 0) {
+            (0, _)     => storage.range(&*connection, key, 0, -1),
+            (_, false) => storage.range(&*connection, key, 0, max_length - 1),
+            (_, true)  => storage.range(&*connection, key, -max_length, -1)
+        };
 
-        for c in input {
-            match *c {
-                b'\\'       => output.push_str("\\\\"),
-                b'"'        => output.push_str("\\\""),
-                b' '...b'~' => output.push(*c as char),
-                _           => output.push_str(format!("\\x{:02x}", c).as_ref())
-            }
+        if rank < 0 {
+            searched.reverse();
         }
 
-        output.push('"');
-        output
-    }
+        // values are compared byte for byte
+        let mut indexes = searched.into_iter().enumerate()
+            .filter(|(_, value)| value.as_slice() == element)
+            .map(|(offset, _)| if rank < 0 { length - 1 - offset as i64 } else { offset as i64 })
+            .skip(skipped as usize);
 
-    fn parse_argument_integer(&self, index: usize) -> Result<i64, &str> {
-        str::from_utf8(self.arguments[index])
-            .map_err(|_| "")
-            .and_then(|value| String::from(value).parse::<i64>().map_err(|_| ""))
-            .map_err(|_| "argument must be an integer")
+        match count {
+            None        => Ok(indexes.next().map(Value::Integer).unwrap_or(Value::Null)),
+            Some(0)     => Ok(Value::Array(indexes.map(Value::Integer).collect())),
+            Some(count) => Ok(Value::Array(indexes.take(count as usize).map(Value::Integer).collect()))
+        }
     }
 
-    fn blocking_pop(&self, direction: Direction) -> CommandResult {
-        let timeout = self.parse_argument_integer(self.arguments.len() - 1)?;
-        let (_, keys) = self.arguments.split_last().unwrap();
+    // a positive count removes that many matches from the head, a negative one from the tail, and zero removes them all
+    fn lrem(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let count = command.parse_argument_integer(1)?;
+        let element = command.arguments[2];
 
-        if timeout < 0 {
-            return Err("timeout is negative".to_owned());
-        }
+        let direction = if count < 0 { Direction::Right } else { Direction::Left };
+        let limit = if count == 0 { None } else { Some(count.checked_abs().ok_or("value is out of range")?) };
 
-        let start_instant = Instant::now();
-        let duration = Duration::new(timeout as u64, 0);
+        let connection = command.lock_connection();
+        let removed = command.connection.get_storage().remove(&*connection, key, element, &direction, limit);
 
-        while self.connection.is_stream_alive() && (timeout == 0 || start_instant.elapsed() < duration) {
-            {
-                let connection = self.lock_connection();
+        if removed > 0 {
+            command.replicate();
+        }
 
-                for key in keys {
-                    if let Some(data) = Command::pop(&*connection, key, &direction) {
-                        return Ok(Value::Array(vec![Value::BufBulk(key.to_vec()), Value::BufBulk(data)]));
-                    }
-                }
-            }
+        Ok(Value::Integer(removed))
+    }
 
-            let &(ref lock, ref cvar) = &*self.connection.get_push_notification();
-            let guard = lock.lock().unwrap();
+    // the index is resolved and the element written in one transaction, so a trim can't move the list in between
+    fn lset(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let index: i64 = command.parse_argument_integer(1)?;
+        let data = command.arguments[2];
+        command.check_value_sizes(&[data])?;
 
-            let wait = if timeout == 0 {
-                Duration::new(1, 0)
-            } else {
-                let elapsed = start_instant.elapsed();
-                if elapsed < duration {
-                    cmp::min(Duration::new(1, 0), duration - elapsed)
-                }
-                else {
-                    Duration::new(0, 0)
-                }
-            };
+        let mut connection = command.lock_connection();
+        let tx = connection.transaction().unwrap();
+        let storage = command.connection.get_storage();
+        let length = storage.count(&tx, key);
 
-            cvar.wait_timeout(guard, wait).unwrap();
+        if length == 0 {
+            return Err("no such key".into());
         }
 
-        Ok(Value::NullArray)
-    }
-
-    fn lock_connection(&self) -> MutexGuard<rusqlite::Connection> {
-        (*self.connection.get_sqlite_connection_mutex()).lock().unwrap()
-    }
+        match Command::parse_index(length, index) {
+            Some(offset) => {
+                storage.set(&tx, key, offset, data);
+                tx.commit().unwrap();
+                command.replicate();
+                Ok(Value::String("OK".to_string()))
+            }
 
-    fn count_list_items_value(&self, connection: &rusqlite::Connection, key: &[u8]) -> CommandResult {
-        Ok(Value::Integer(Command::count_list_items(connection, key)))
+            None => Err("index out of range".into())
+        }
     }
 
-    fn notify_push(&self) {
-        let &(ref lock, ref cvar) = &*self.connection.get_push_notification();
-        let _guard = lock.lock().unwrap();
-        cvar.notify_all();
-    }
+    // both elements are read and written back in one transaction, so no other write can land in between
+    fn lswap(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let first_index: i64 = command.parse_argument_integer(1)?;
+        let second_index: i64 = command.parse_argument_integer(2)?;
 
-    /*
-     * support functions
-     */
+        let mut connection = command.lock_connection();
+        let tx = connection.transaction().unwrap();
+        let storage = command.connection.get_storage();
+        let length = storage.count(&tx, key);
 
-    fn pop(connection: &rusqlite::Connection, key: &[u8], direction: &Direction) -> Option<Vec<u8>> {
-        let order = match direction { &Direction::Left => "ASC", &Direction::Right => "DESC" };
-        let mut statement = connection.prepare(&format!("SELECT id, value FROM list_items WHERE key = ?1 ORDER BY position {} LIMIT 1", order)).unwrap();
+        if length == 0 {
+            return Err("no such key".into());
+        }
 
-        match statement.query_row(&[&key], |row| (row.get(0), row.get(1))) {
-            Ok(result) => {
-                let (id, value): (i64, Vec<u8>) = result;
+        let (first_offset, second_offset) = match (Command::parse_index(length, first_index), Command::parse_index(length, second_index)) {
+            (Some(first), Some(second)) => (first, second),
+            _                           => return Err("index out of range".into())
+        };
 
-                connection.execute("DELETE FROM list_items WHERE id = ?1", &[&id]).unwrap();
-                Some(value)
-            }
+        let first_value = storage.range(&tx, key, first_offset, first_offset).pop().unwrap();
+        let second_value = storage.range(&tx, key, second_offset, second_offset).pop().unwrap();
 
-            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+        storage.set(&tx, key, first_offset, &second_value);
+        storage.set(&tx, key, second_offset, &first_value);
+        tx.commit().unwrap();
+        command.replicate();
 
-            Err(e) => Err(e).unwrap()
-        }
+        Ok(Value::String("OK".to_string()))
     }
 
-    fn push<'b, I>(connection: &mut rusqlite::Connection, key: &[u8], direction: Direction, iterator: I) -> ()
-        where I: Iterator<Item=&'b &'b [u8]>
-    {
-        let tx = connection.transaction().unwrap();
+    fn linsert(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let pivot = command.arguments[2];
+        let data = command.arguments[3];
+        command.check_value_sizes(&[data])?;
 
-        let next_position_sql = match direction {
-            Direction::Left  => "coalesce(MIN(position), 0) - 1",
-            Direction::Right => "coalesce(MAX(position), 0) + 1"
+        let direction = if command.argument_matches(1, "BEFORE") {
+            Direction::Left
+        }
+        else if command.argument_matches(1, "AFTER") {
+            Direction::Right
+        }
+        else {
+            return Err("syntax error".into());
         };
 
-        let sql = format!("INSERT INTO list_items (key, value, position) SELECT ?1, ?2, {} FROM list_items WHERE key = ?1", next_position_sql);
+        let mut connection = command.lock_connection();
+        let tx = connection.transaction().unwrap();
+        let storage = command.connection.get_storage();
+
+        if storage.count(&tx, key) == 0 {
+            return Ok(Value::Integer(0));
+        }
 
-        iterator.map(|value| tx.execute(&sql, &[&key, value])).collect::<Result<Vec<_>, _>>().unwrap();
+        if !storage.insert(&tx, key, pivot, &direction, data) {
+            return Ok(Value::Integer(-1));
+        }
 
+        let length = storage.count(&tx, key);
         tx.commit().unwrap();
-    }
+        command.replicate();
 
-    fn find_position_boundaries(connection: &rusqlite::Connection, key: &[u8]) -> (i64, i64) {
-        let mut statement = connection.prepare("SELECT MIN(position), MAX(position) AS c FROM list_items WHERE key = ?1").unwrap();
-        statement.query_row(&[&key], |row| (row.get(0), row.get(1))).unwrap()
+        Ok(Value::Integer(length))
     }
 
-    fn parse_indexes(boundaries: (i64, i64), (start, stop): (i64, i64)) -> (i64, i64) {
-        (Command::parse_index(boundaries, start), Command::parse_index(boundaries, stop))
-    }
+    fn mpop(command: &Command) -> CommandResult {
+        let key_count = command.parse_argument_integer(0)?;
 
-    fn parse_index((first_position, last_position): (i64, i64), index: i64) -> i64 {
-        if index < 0 { index + last_position + 1 } else { index + first_position }
-    }
+        if key_count <= 0 {
+            return Err("numkeys should be greater than 0".into());
+        }
 
-    fn count_list_items(connection: &rusqlite::Connection, key: &[u8]) -> i64 {
-        let mut statement = connection.prepare("SELECT COUNT(*) AS c FROM list_items WHERE key = ?1").unwrap();
-        statement.query_row(&[&key], |row| row.get(0)).unwrap()
-    }
-}
+        // compared this way round so a huge numkeys can't overflow
+        if key_count != (command.arguments.len() - 2) as i64 {
+            return Err("syntax error".into());
+        }
 
-#[cfg(test)]
-mod tests {
+        let (direction_argument, keys) = command.arguments[1..].split_last().unwrap();
+
+        let direction = match direction_argument.to_ascii_uppercase().as_slice() {
+            b"LEFT"  => Direction::Left,
+            b"RIGHT" => Direction::Right,
+            _        => return Err("syntax error".into())
+        };
+
+        let connection = command.lock_connection();
+
+        match command.pop_from_first_list(&*connection, keys, &direction, 1) {
+            Some(popped) => Ok(Command::single_pop_reply(popped)),
+            None         => Ok(Value::NullArray)
+        }
+    }
+
+    fn lmpop(command: &Command) -> CommandResult {
+        let (keys, direction, count) = command.parse_multiple_pop(0)?;
+        let connection = command.lock_connection();
+
+        match command.pop_from_first_list(&*connection, &keys, &direction, count) {
+            Some(popped) => Ok(Command::multiple_pop_reply(popped)),
+            None         => Ok(Value::NullArray)
+        }
+    }
+
+    fn blmpop(command: &Command) -> CommandResult {
+        let timeout = command.parse_argument_integer(0)?;
+        let (keys, direction, count) = command.parse_multiple_pop(1)?;
+
+        command.block_until_popped(&keys, timeout, Value::NullArray, |connection| {
+            Ok(command.pop_from_first_list(connection, &keys, &direction, count).map(Command::multiple_pop_reply))
+        })
+    }
+
+    fn brpoplpush(command: &Command) -> CommandResult {
+        let timeout = command.parse_argument_integer(2)?;
+
+        command.block_until_popped(&command.arguments[..1], timeout, Value::Null, |connection| {
+            Ok(command.move_element(connection, &Direction::Right, &Direction::Left)?.map(Value::BufBulk))
+        })
+    }
+
+    fn blmove(command: &Command) -> CommandResult {
+        let from = command.parse_direction(2)?;
+        let to = command.parse_direction(3)?;
+        let timeout = command.parse_argument_integer(4)?;
+
+        command.block_until_popped(&command.arguments[..1], timeout, Value::Null, |connection| {
+            Ok(command.move_element(connection, &from, &to)?.map(Value::BufBulk))
+        })
+    }
+
+    fn blpop(command: &Command) -> CommandResult {
+        command.blocking_pop(Direction::Left)
+    }
+
+    fn brpop(command: &Command) -> CommandResult {
+        command.blocking_pop(Direction::Right)
+    }
+
+    fn zadd(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+
+        if command.arguments.len().is_multiple_of(2) {
+            return Err("syntax error".into());
+        }
+
+        let scores = (1..command.arguments.len()).step_by(2)
+            .map(|index| command.parse_argument_float(index))
+            .collect::<Result<Vec<f64>, _>>()?;
+
+        let mut connection = command.lock_connection_making_room()?;
+        let tx = connection.transaction().unwrap();
+        let mut added = 0;
+
+        for (score, member) in scores.iter().zip(command.arguments.iter().skip(2).step_by(2)) {
+            if Command::find_zset_score(&tx, key, member).is_none() {
+                added += 1;
+            }
+
+            tx.execute("INSERT OR REPLACE INTO zset_items (key, member, score) VALUES (?1, ?2, ?3)", &[&key, member, score]).unwrap();
+        }
+
+        tx.commit().unwrap();
+        command.replicate();
+
+        Ok(Value::Integer(added))
+    }
+
+    fn zcard(command: &Command) -> CommandResult {
+        let connection = command.lock_connection();
+        Ok(Value::Integer(Command::count_zset_items(&*connection, command.arguments[0])))
+    }
+
+    fn zincrby(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let increment = command.parse_argument_float(1)?;
+        let member = command.arguments[2];
+
+        let mut connection = command.lock_connection_making_room()?;
+        let tx = connection.transaction().unwrap();
+
+        let score = Command::find_zset_score(&tx, key, member).unwrap_or(0.0) + increment;
+
+        if score.is_nan() {
+            return Err("resulting score is not a number (NaN)".into());
+        }
+
+        tx.execute("INSERT OR REPLACE INTO zset_items (key, member, score) VALUES (?1, ?2, ?3)", &[&key, &member, &score]).unwrap();
+        tx.commit().unwrap();
+        command.replicate();
+
+        Ok(Value::BufBulk(Command::format_score(score).into_bytes()))
+    }
+
+    fn zrank(command: &Command) -> CommandResult {
+        command.zset_rank("score < ?3 OR (score = ?3 AND member < ?2)")
+    }
+
+    fn zrevrank(command: &Command) -> CommandResult {
+        command.zset_rank("score > ?3 OR (score = ?3 AND member > ?2)")
+    }
+
+    fn zrange(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let start: i64 = command.parse_argument_integer(1)?;
+        let stop: i64 = command.parse_argument_integer(2)?;
+
+        let with_scores = match command.arguments.len() {
+            3                                              => false,
+            4 if command.argument_matches(3, "WITHSCORES") => true,
+            _                                              => return Err("syntax error".into())
+        };
+
+        let connection = command.lock_connection();
+        let length = Command::count_zset_items(&*connection, key);
+
+        let (offset, limit) = match storage::parse_range(length, (start, stop)) {
+            Some(range) => range,
+            None        => return Ok(Value::Array(vec![]))
+        };
+
+        let mut statement = connection.prepare("SELECT member, score FROM zset_items WHERE key = ?1 ORDER BY score, member LIMIT ?2 OFFSET ?3").unwrap();
+        let rows = statement.query_map(&[&key, &limit, &offset], |row| (row.get(0), row.get(1))).unwrap();
+        let items = rows.collect::<Result<Vec<(Vec<u8>, f64)>, _>>().unwrap();
+
+        Ok(Command::zset_values(items, with_scores))
+    }
+
+    fn zrangebyscore(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let (min, min_exclusive) = command.parse_score_bound(1)?;
+        let (max, max_exclusive) = command.parse_score_bound(2)?;
+
+        let mut with_scores = false;
+        let mut offset = 0;
+        let mut limit = -1;
+        let mut index = 3;
+
+        while index < command.arguments.len() {
+            if command.argument_matches(index, "WITHSCORES") {
+                with_scores = true;
+                index += 1;
+            }
+            else if command.argument_matches(index, "LIMIT") && index + 2 < command.arguments.len() {
+                offset = command.parse_argument_integer(index + 1)?;
+                limit = command.parse_argument_integer(index + 2)?;
+                index += 3;
+            }
+            else {
+                return Err("syntax error".into());
+            }
+        }
+
+        if offset < 0 {
+            return Ok(Value::Array(vec![]));
+        }
+
+        let sql = format!(
+            "SELECT member, score FROM zset_items WHERE key = ?1 AND score {} ?2 AND score {} ?3 ORDER BY score, member LIMIT ?4 OFFSET ?5",
+            if min_exclusive { ">" } else { ">=" },
+            if max_exclusive { "<" } else { "<=" }
+        );
+
+        let connection = command.lock_connection();
+        let mut statement = connection.prepare(&sql).unwrap();
+        let rows = statement.query_map(&[&key, &min, &max, &limit, &offset], |row| (row.get(0), row.get(1))).unwrap();
+        let items = rows.collect::<Result<Vec<(Vec<u8>, f64)>, _>>().unwrap();
+
+        Ok(Command::zset_values(items, with_scores))
+    }
+
+    fn zrem(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+
+        let mut connection = command.lock_connection();
+        let tx = connection.transaction().unwrap();
+        let mut removed = 0;
+
+        for member in &command.arguments[1..] {
+            removed += tx.execute("DELETE FROM zset_items WHERE key = ?1 AND member = ?2", &[&key, member]).unwrap() as i64;
+        }
+
+        tx.commit().unwrap();
+
+        if removed > 0 {
+            command.replicate();
+        }
+
+        Ok(Value::Integer(removed))
+    }
+
+    fn zscore(command: &Command) -> CommandResult {
+        let connection = command.lock_connection();
+
+        match Command::find_zset_score(&*connection, command.arguments[0], command.arguments[1]) {
+            Some(score) => Ok(Value::BufBulk(Command::format_score(score).into_bytes())),
+            None        => Ok(Value::Null)
+        }
+    }
+
+    fn hexists(command: &Command) -> CommandResult {
+        let connection = command.lock_connection();
+        let exists = Command::find_hash_value(&*connection, command.arguments[0], command.arguments[1]).is_some();
+
+        Ok(Value::Integer(exists as i64))
+    }
+
+    fn hget(command: &Command) -> CommandResult {
+        let connection = command.lock_connection();
+
+        match Command::find_hash_value(&*connection, command.arguments[0], command.arguments[1]) {
+            Some(value) => Ok(Value::BufBulk(value)),
+            None        => Ok(Value::Null)
+        }
+    }
+
+    fn hincrby(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let field = command.arguments[1];
+        let increment = command.parse_argument_integer(2)?;
+
+        let mut connection = command.lock_connection_making_room()?;
+        let tx = connection.transaction().unwrap();
+
+        let current = match Command::find_hash_value(&tx, key, field) {
+            Some(value) => str::from_utf8(&value).ok().and_then(|value| value.parse::<i64>().ok()).ok_or("hash value is not an integer")?,
+            None        => 0
+        };
+
+        let result = current.checked_add(increment).ok_or("increment or decrement would overflow")?;
+
+        Command::set_hash_value(&tx, key, field, result.to_string().as_bytes());
+        tx.commit().unwrap();
+        command.replicate();
+
+        Ok(Value::Integer(result))
+    }
+
+    fn hincrbyfloat(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let field = command.arguments[1];
+        let increment = command.parse_argument_float(2)?;
+
+        let mut connection = command.lock_connection_making_room()?;
+        let tx = connection.transaction().unwrap();
+
+        let current = match Command::find_hash_value(&tx, key, field) {
+            Some(value) => str::from_utf8(&value).ok().and_then(|value| value.parse::<f64>().ok()).ok_or("hash value is not a float")?,
+            None        => 0.0
+        };
+
+        let result = current + increment;
+
+        if !result.is_finite() {
+            return Err("increment would produce NaN or Infinity".into());
+        }
+
+        let result = Command::format_score(result).into_bytes();
+
+        Command::set_hash_value(&tx, key, field, &result);
+        tx.commit().unwrap();
+
+        // replicated as the value it produced, so a replica can't end up with a differently rounded result
+        command.replicate_as("HSET", &[key, field, &result]);
+
+        Ok(Value::BufBulk(result))
+    }
+
+    // fields and values alternate, in the order HKEYS and HVALS list them
+    fn hgetall(command: &Command) -> CommandResult {
+        let connection = command.lock_connection();
+
+        let mut statement = connection.prepare("SELECT field, value FROM hash_items WHERE key = ?1 ORDER BY field").unwrap();
+        let rows = statement.query_map(&[&command.arguments[0]], |row| (row.get(0), row.get(1))).unwrap();
+
+        let mut values = vec![];
+
+        for row in rows {
+            let (field, value) = row.unwrap();
+            values.push(Value::BufBulk(field));
+            values.push(Value::BufBulk(value));
+        }
+
+        Ok(Value::Array(values))
+    }
+
+    fn hkeys(command: &Command) -> CommandResult {
+        let connection = command.lock_connection();
+        Ok(Command::hash_column(&*connection, command.arguments[0], "field"))
+    }
+
+    fn hmget(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let connection = command.lock_connection();
+
+        let values = command.arguments[1..].iter().map(|field| {
+            match Command::find_hash_value(&*connection, key, field) {
+                Some(value) => Value::BufBulk(value),
+                None        => Value::Null
+            }
+        }).collect();
+
+        Ok(Value::Array(values))
+    }
+
+    fn hset(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+
+        if command.arguments.len().is_multiple_of(2) {
+            return Err("wrong number of arguments".into());
+        }
+
+        let mut connection = command.lock_connection_making_room()?;
+        let tx = connection.transaction().unwrap();
+        let mut added = 0;
+
+        for pair in command.arguments[1..].chunks(2) {
+            if Command::find_hash_value(&tx, key, pair[0]).is_none() {
+                added += 1;
+            }
+
+            Command::set_hash_value(&tx, key, pair[0], pair[1]);
+        }
+
+        tx.commit().unwrap();
+        command.replicate();
+
+        Ok(Value::Integer(added))
+    }
+
+    fn hvals(command: &Command) -> CommandResult {
+        let connection = command.lock_connection();
+        Ok(Command::hash_column(&*connection, command.arguments[0], "value"))
+    }
+
+    fn get(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let connection = command.lock_connection();
+
+        match command.key_type(&*connection, key) {
+            Some("string") => Ok(Value::BufBulk(Command::find_string(&*connection, key).unwrap())),
+            Some(_)        => Err(CommandError::Prefixed("WRONGTYPE", "Operation against a key holding the wrong kind of value".to_string())),
+            None           => Ok(Value::Null)
+        }
+    }
+
+    // replaces the key whatever its type, along with any expiry it had
+    fn set(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let value = command.arguments[1];
+
+        command.check_value_sizes(&[value])?;
+
+        let mut connection = command.lock_connection_making_room()?;
+        let tx = connection.transaction().unwrap();
+
+        command.delete_key(&tx, key);
+        Command::set_string(&tx, key, value);
+
+        tx.commit().unwrap();
+        command.replicate();
+
+        Ok(Value::String("OK".to_string()))
+    }
+
+    // a missing key is appended to as an empty string, but a key of another type is refused rather than treated as one
+    fn append(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+
+        let mut connection = command.lock_connection_making_room()?;
+        let tx = connection.transaction().unwrap();
+
+        let mut value = match command.key_type(&tx, key) {
+            Some("string") => Command::find_string(&tx, key).unwrap(),
+            Some(_)        => return Err(CommandError::Prefixed("WRONGTYPE", "Operation against a key holding the wrong kind of value".to_string())),
+            None           => vec![]
+        };
+
+        value.extend_from_slice(command.arguments[1]);
+        command.check_value_sizes(&[&value])?;
+
+        Command::set_string(&tx, key, &value);
+
+        tx.commit().unwrap();
+        command.replicate();
+
+        Ok(Value::Integer(value.len() as i64))
+    }
+
+    fn psetex(command: &Command) -> CommandResult {
+        command.set_with_expiry(1)
+    }
+
+    fn setex(command: &Command) -> CommandResult {
+        command.set_with_expiry(1000)
+    }
+
+    // whatever its type, a key is deleted from every table along with its expiry
+    fn del(command: &Command) -> CommandResult {
+        let mut connection = command.lock_connection();
+        let tx = connection.transaction().unwrap();
+        let mut deleted = 0;
+
+        for key in &command.arguments {
+            if command.key_exists(&tx, key) {
+                command.delete_key(&tx, key);
+                deleted += 1;
+            }
+        }
+
+        tx.commit().unwrap();
+
+        if deleted > 0 {
+            command.replicate();
+        }
+
+        Ok(Value::Integer(deleted))
+    }
+
+    fn dump(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let connection = command.lock_connection();
+
+        let values = command.connection.get_storage().range(&*connection, key, 0, -1);
+
+        match Command::dump_list(values).or_else(|| Command::dump_string(&*connection, key)) {
+            Some(payload) => Ok(Value::BufBulk(payload)),
+            None          => Ok(Value::Null)
+        }
+    }
+
+    // The cursor is how many keys, in key order, have been returned so far.  A key deleted during a scan can make
+    // another be skipped, and one added can make another be returned twice.  An unknown TYPE matches no keys.
+    fn scan(command: &Command) -> CommandResult {
+        let cursor = command.parse_argument_integer(0).map_err(|_| "invalid cursor")?;
+
+        if cursor < 0 {
+            return Err("invalid cursor".into());
+        }
+
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut keys_sql = Some(ALL_KEYS_SQL);
+
+        for index in (1..command.arguments.len()).step_by(2) {
+            if index + 1 == command.arguments.len() {
+                return Err("syntax error".into());
+            }
+
+            if command.argument_matches(index, "COUNT") {
+                count = command.parse_argument_integer(index + 1)?;
+
+                if count < 1 {
+                    return Err("syntax error".into());
+                }
+            }
+            else if command.argument_matches(index, "TYPE") {
+                keys_sql = TYPED_KEYS_SQL.iter()
+                    .find(|&&(name, _)| command.argument_matches(index + 1, name))
+                    .map(|&(_, sql)| sql);
+            }
+            else {
+                return Err("syntax error".into());
+            }
+        }
+
+        let keys: Vec<Vec<u8>> = match keys_sql {
+            Some(keys_sql) => {
+                let connection = command.lock_connection();
+
+                // one more than is returned, to tell whether this is the last page
+                let sql = format!("SELECT key FROM ({}) ORDER BY key LIMIT ?1 OFFSET ?2", keys_sql);
+                let mut statement = connection.prepare(&sql).unwrap();
+                let rows = statement.query_map(&[&(count + 1), &cursor], |row| row.get(0)).unwrap();
+                rows.collect::<Result<_, _>>().unwrap()
+            }
+
+            None => vec![]
+        };
+
+        let next_cursor = if keys.len() as i64 > count { cursor + count } else { 0 };
+
+        Ok(Value::Array(vec![
+            Value::BufBulk(next_cursor.to_string().into_bytes()),
+            Value::Array(keys.into_iter().take(count as usize).map(Value::BufBulk).collect()),
+        ]))
+    }
+
+    fn restore(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let ttl = command.parse_argument_integer(1)?;
+
+        let replace = match command.arguments.len() {
+            3                                           => false,
+            4 if command.argument_matches(3, "REPLACE") => true,
+            _                                           => return Err("syntax error".into())
+        };
+
+        if ttl < 0 {
+            return Err("Invalid TTL value, must be >= 0".into());
+        }
+
+        let payload = Command::decode_payload(command.arguments[2]).map_err(|_| "DUMP payload version or checksum are wrong")?;
+
+        match payload {
+            Payload::List(ref values)  => command.check_value_sizes(values)?,
+            Payload::String(value)     => command.check_value_sizes(&[value])?
+        }
+
+        let mut connection = command.lock_connection_making_room()?;
+        let tx = connection.transaction().unwrap();
+
+        if command.key_exists(&tx, key) {
+            if !replace {
+                return Err(CommandError::Prefixed("BUSYKEY", "Target key name already exists.".to_string()));
+            }
+
+            command.delete_key(&tx, key);
+        }
+
+        match payload {
+            Payload::List(values)  => command.connection.get_storage().push(&tx, key, &Direction::Right, &values),
+            Payload::String(value) => Command::set_string(&tx, key, value)
+        }
+
+        if ttl > 0 {
+            tx.execute("INSERT INTO key_meta (key, expires_at) VALUES (?1, ?2)", &[&key, &(Command::now_in_milliseconds() + ttl)]).unwrap();
+        }
+
+        tx.commit().unwrap();
+        command.replicate();
+
+        command.notify_push(key);
+
+        Ok(Value::String("OK".to_string()))
+    }
+
+    fn expire(command: &Command) -> CommandResult {
+        let seconds = command.parse_argument_integer(1)?;
+        command.set_expiry(seconds.checked_mul(1000).and_then(|milliseconds| milliseconds.checked_add(Command::now_in_milliseconds())))
+    }
+
+    // A key given more than once is counted each time.  The keys are checked against every table in one query,
+    // rather than one query a key a table.
+    fn exists(command: &Command) -> CommandResult {
+        let connection = command.lock_connection();
+
+        let in_any_table = KEY_TABLES.iter()
+            .map(|table| format!("EXISTS (SELECT 1 FROM {} WHERE key = given.key)", table))
+            .collect::<Vec<_>>()
+            .join(" OR ");
+
+        let mut count = 0;
+
+        for keys in command.arguments.chunks(EXISTS_BATCH_SIZE) {
+            let values = (1..keys.len() + 1).map(|index| format!("(?{})", index)).collect::<Vec<_>>().join(", ");
+            let sql = format!("WITH given(key) AS (VALUES {}) SELECT COUNT(*) FROM given WHERE {}", values, in_any_table);
+
+            let parameters: Vec<&rusqlite::types::ToSql> = keys.iter().map(|key| key as &rusqlite::types::ToSql).collect();
+            let found: i64 = connection.query_row(&sql, &parameters, |row| row.get(0)).unwrap();
+
+            count += found;
+        }
+
+        Ok(Value::Integer(count))
+    }
+
+    fn expireat(command: &Command) -> CommandResult {
+        let timestamp = command.parse_argument_integer(1)?;
+        command.set_expiry(timestamp.checked_mul(1000))
+    }
+
+    fn pexpire(command: &Command) -> CommandResult {
+        let milliseconds = command.parse_argument_integer(1)?;
+        command.set_expiry(milliseconds.checked_add(Command::now_in_milliseconds()))
+    }
+
+    fn pexpireat(command: &Command) -> CommandResult {
+        let timestamp = command.parse_argument_integer(1)?;
+        command.set_expiry(Some(timestamp))
+    }
+
+    fn object(command: &Command) -> CommandResult {
+        command.run_subcommand(&OBJECT_SUBCOMMANDS)
+    }
+
+    fn persist(command: &Command) -> CommandResult {
+        let key = command.arguments[0];
+        let connection = command.lock_connection();
+
+        let removed = connection.execute("DELETE FROM key_meta WHERE key = ?1", &[&key]).unwrap();
+
+        if removed > 0 {
+            command.replicate();
+        }
+
+        Ok(Value::Integer(removed as i64))
+    }
+
+    fn type_(command: &Command) -> CommandResult {
+        let connection = command.lock_connection();
+        let key_type = command.key_type(&*connection, command.arguments[0]).unwrap_or("none");
+
+        Ok(Value::String(key_type.to_string()))
+    }
+
+    fn ttl(command: &Command) -> CommandResult {
+        command.time_to_live(1000)
+    }
+
+    fn pttl(command: &Command) -> CommandResult {
+        command.time_to_live(1)
+    }
+
+    // keys that have expired but haven't been swept yet aren't counted
+    fn dbsize(command: &Command) -> CommandResult {
+        let connection = command.lock_connection();
+
+        let sql = format!("SELECT COUNT(*) FROM ({}) WHERE key NOT IN (SELECT key FROM key_meta WHERE expires_at <= ?1)", ALL_KEYS_SQL);
+        let count: i64 = connection.query_row(&sql, &[&Command::now_in_milliseconds()], |row| row.get(0)).unwrap();
+
+        Ok(Value::Integer(count))
+    }
+
+    // An expired key that's picked is swept, as expire_keys would have, and another is picked in its place, so an
+    // expired key is never returned.  Sweeping writes, so this takes the connection for writing despite being a read.
+    fn randomkey(command: &Command) -> CommandResult {
+        let connection = command.lock_connection_for_writing();
+        let sql = format!("SELECT key FROM ({}) ORDER BY RANDOM() LIMIT 1", ALL_KEYS_SQL);
+        let now = Command::now_in_milliseconds();
+
+        loop {
+            let key: Vec<u8> = match connection.query_row(&sql, &[], |row| row.get(0)) {
+                Ok(key)                                   => key,
+                Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(Value::Null),
+                Err(e)                                    => panic!("{}", e)
+            };
+
+            match Command::find_expiry(&*connection, &key) {
+                Some(expires_at) if expires_at <= now => {
+                    command.delete_key(&*connection, &key);
+                    command.connection.get_read_cache().lock().unwrap().invalidate(&key);
+                }
+
+                _ => return Ok(Value::BufBulk(key))
+            }
+        }
+    }
+
+    fn publish(command: &Command) -> CommandResult {
+        let receivers = command.connection.get_pubsub().publish(command.arguments[0], command.arguments[1]);
+        Ok(Value::Integer(receivers as i64))
+    }
+
+    fn client(command: &Command) -> CommandResult {
+        command.run_subcommand(&CLIENT_SUBCOMMANDS)
+    }
+
+    // GETKEYS and INFO are both answered from the command settings, so proxies and clients can work out how to
+    // route and send a command
+    fn command(command: &Command) -> CommandResult {
+        command.run_subcommand(&COMMAND_SUBCOMMANDS)
+    }
+
+    fn config(command: &Command) -> CommandResult {
+        command.run_subcommand(&CONFIG_SUBCOMMANDS)
+    }
+
+    fn debug(command: &Command) -> CommandResult {
+        command.run_subcommand(&DEBUG_SUBCOMMANDS)
+    }
+
+    // with only one database, FLUSHALL and FLUSHDB are the same
+    fn flushdb(command: &Command) -> CommandResult {
+        let asynchronous = match command.arguments.len() {
+            0                                        => false,
+            1 if command.argument_matches(0, "ASYNC") => true,
+            1 if command.argument_matches(0, "SYNC")  => false,
+            _                                        => return Err("syntax error".into())
+        };
+
+        let mut connection = command.lock_connection();
+        let tx = connection.transaction().unwrap();
+
+        if asynchronous {
+            let flushing = Command::swap_in_empty_tables(&tx);
+            let sqlite_connection_mutex = command.connection.get_sqlite_connection_mutex().clone();
+
+            thread::spawn(move || Command::drop_in_batches(&sqlite_connection_mutex, &flushing));
+        }
+        else {
+            for &(table, kept) in FLUSHED_TABLES.iter() {
+                tx.execute(&format!("DELETE FROM {} WHERE {}", table, kept), &[]).unwrap();
+            }
+        }
+
+        tx.commit().unwrap();
+        command.connection.get_read_cache().lock().unwrap().clear();
+
+        command.replicate();
+
+        Ok(Value::String("OK".to_string()))
+    }
+
+    // replicas following SYNC never acknowledge writes, so no replica can be counted and there is nothing to wait for
+    fn wait(command: &Command) -> CommandResult {
+        command.parse_argument_integer(0)?;
+
+        if command.parse_argument_integer(1)? < 0 {
+            return Err("timeout is negative".into());
+        }
+
+        Ok(Value::Integer(0))
+    }
+
+    fn echo(command: &Command) -> CommandResult {
+        Ok(Value::BufBulk(command.arguments[0].to_vec()))
+    }
+
+    // Every key lives in database 0, the only one there is, so any other index is out of range, as it is for a redis
+    // configured with a single database.  Clients that select database 0 on connecting still can.
+    fn select(command: &Command) -> CommandResult {
+        if command.parse_argument_integer(0)? != 0 {
+            return Err("DB index is out of range".into());
+        }
+
+        Ok(Value::String("OK".to_string()))
+    }
+
+    // With a section name, only that section is reported, and with none, or all, default or everything, every
+    // section.  An unknown section gives an empty reply, as redis does.
+    fn info(command: &Command) -> CommandResult {
+        let section = match command.arguments.len() {
+            0 => "all".to_string(),
+            1 => String::from_utf8_lossy(command.arguments[0]).to_lowercase(),
+            _ => return Err("syntax error".into())
+        };
+
+        let every_section = ["all", "default", "everything"].contains(&section.as_str());
+        let included = |name: &str| every_section || section == name;
+
+        let stats = command.connection.get_stats();
+        let mut sections = vec![];
+
+        // redis_version is blueis's own, as HELLO reports it, and blueis_version is that of the database's layout
+        if included("server") {
+            sections.push(format!(
+                "# Server\r\nredis_version:{}\r\nblueis_version:{}\r\nredis_mode:standalone\r\n",
+                env!("CARGO_PKG_VERSION"),
+                DATABASE_VERSION
+            ));
+        }
+
+        // --max-threads is the nearest thing to maxclients, though further clients wait rather than being rejected
+        if included("clients") {
+            sections.push(format!(
+                "# Clients\r\nconnected_clients:{}\r\npeak_connected_clients:{}\r\nmaxclients:{}\r\n",
+                Stats::get(&stats.connected_clients),
+                Stats::get(&stats.peak_connected_clients),
+                command.connection.get_config().max_threads
+            ));
+        }
+
+        if included("stats") {
+            sections.push(format!(
+                "# Stats\r\ntotal_reads_processed:{}\r\ntotal_error_replies:{}\r\ndatabase_syncs:{}\r\nkeyspace_hits:{}\r\nkeyspace_misses:{}\r\n",
+                Stats::get(&stats.total_reads_processed),
+                Stats::get(&stats.total_error_replies),
+                Stats::get(&stats.database_syncs),
+                Stats::get(&stats.keyspace_hits),
+                Stats::get(&stats.keyspace_misses)
+            ));
+        }
+
+        if included("keyspace") {
+            sections.push(format!("# Keyspace\r\n{}", command.keyspace_line()));
+        }
+
+        Ok(Value::BufBulk(sections.join("\r\n").into_bytes()))
+    }
+
+    /*
+     * support methods
+     */
+
+    fn write_to_log(&self) {
+        let now = time::now_utc().to_timespec();
+        let args = self.arguments.iter().map(|argument| Command::quote_string(argument)).collect::<Vec<String>>().join(" ");
+        let log = format!("{}.{:09} {} {}", now.sec, now.nsec, Command::quote_string(self.name.as_bytes()), args);
+
+        self.connection.send_to_command_log(log);
+    }
+
+    fn quote_string(input: &[u8]) -> String {
+        let mut output = String::from("\"");
+
+        for c in input {
+            match *c {
+                b'\\'       => output.push_str("\\\\"),
+                b'"'        => output.push_str("\\\""),
+                b' '...b'~' => output.push(*c as char),
+                _           => output.push_str(format!("\\x{:02x}", c).as_ref())
+            }
+        }
+
+        output.push('"');
+        output
+    }
+
+    // the first argument names the subcommand, and the rest are checked against that subcommand's argument count
+    fn run_subcommand(&self, subcommands: &[SubcommandSettings]) -> CommandResult {
+        let subcommand = String::from_utf8_lossy(self.arguments[0]);
+        let upper = subcommand.to_uppercase();
+
+        let settings = subcommands.iter().find(|settings| settings.name == upper)
+            .ok_or_else(|| format!("unknown subcommand '{}'. Try {} HELP.", subcommand, self.name))?;
+
+        if !Command::valid_argument_count(settings.argument_count, self.arguments.len() - 1) {
+            return Err(format!("wrong number of arguments for '{}|{}' command", self.name.to_lowercase(), upper.to_lowercase()).into());
+        }
+
+        (settings.handler)(self)
+    }
+
+    fn argument_matches(&self, index: usize, keyword: &str) -> bool {
+        self.arguments[index].eq_ignore_ascii_case(keyword.as_bytes())
+    }
+
+    // the keys a command is given, as its key spec finds them
+    fn keys(&self, settings: &CommandSettings) -> Vec<&[u8]> {
+        Command::key_indexes(&settings.keys, &self.arguments).into_iter().map(|index| self.arguments[index]).collect()
+    }
+
+    // every key a command is given is held to --max-key-size
+    fn check_key_sizes(&self, keys: &[&[u8]]) -> Result<(), CommandError> {
+        let max_key_size = self.connection.get_config().max_key_size;
+
+        if keys.iter().any(|key| key.len() > max_key_size) {
+            Err("key too long".into())
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    fn check_value_sizes(&self, values: &[&[u8]]) -> Result<(), CommandError> {
+        let max_value_size = self.connection.get_config().max_value_size;
+
+        if values.iter().any(|value| value.len() > max_value_size) {
+            Err("value too large".into())
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    // the rank is the number of members ordered before this one, so `preceding` selects those members
+    fn zset_rank(&self, preceding: &str) -> CommandResult {
+        let key = self.arguments[0];
+        let member = self.arguments[1];
+
+        let connection = self.lock_connection();
+
+        let score = match Command::find_zset_score(&*connection, key, member) {
+            Some(score) => score,
+            None        => return Ok(Value::Null)
+        };
+
+        let sql = format!("SELECT COUNT(*) FROM zset_items WHERE key = ?1 AND ({})", preceding);
+        let mut statement = connection.prepare(&sql).unwrap();
+        let rank: i64 = statement.query_row(&[&key, &member, &score], |row| row.get(0)).unwrap();
+
+        Ok(Value::Integer(rank))
+    }
+
+    fn command_getkeys(command: &Command) -> CommandResult {
+        let (_, keys) = command.keys_of_given_command()?;
+        Ok(Value::Array(keys.into_iter().map(|key| Value::BufBulk(key.to_vec())).collect()))
+    }
+
+    // each key is paired with how the command uses it: RO if it's only read, OW if it's only written, otherwise RW
+    fn command_getkeysandflags(command: &Command) -> CommandResult {
+        let (settings, keys) = command.keys_of_given_command()?;
+
+        Ok(Value::Array(keys.into_iter().enumerate().map(|(position, key)| {
+            let flag = if !settings.flags.contains(&"write") {
+                "RO"
+            }
+            else if OVERWRITTEN_KEYS.contains(&(settings.name, position)) {
+                "OW"
+            }
+            else {
+                "RW"
+            };
+
+            Value::Array(vec![Value::BufBulk(key.to_vec()), Value::Array(vec![Value::String(flag.to_string())])])
+        }).collect()))
+    }
+
+    // the settings of the command COMMAND GETKEYS and GETKEYSANDFLAGS are given, and the keys among its arguments
+    fn keys_of_given_command(&self) -> Result<(&'static CommandSettings, Vec<&[u8]>), CommandError> {
+        let settings = Command::find_settings(&String::from_utf8_lossy(self.arguments[1])).ok_or("Invalid command specified")?;
+        let arguments = &self.arguments[2..];
+
+        if !Command::valid_argument_count(settings.argument_count, arguments.len()) {
+            return Err("Invalid number of arguments specified for command".into());
+        }
+
+        let indexes = Command::key_indexes(&settings.keys, arguments);
+
+        if indexes.is_empty() {
+            return Err("The command has no key arguments".into());
+        }
+
+        Ok((settings, indexes.into_iter().map(|index| arguments[index]).collect()))
+    }
+
+    // Each command is described as redis does: its name, its arity counting the name itself, its flags, then where
+    // its keys are.  Unknown commands are described as nil.  With no names, every command is described.
+    fn command_info(command: &Command) -> CommandResult {
+        let settings: Vec<Option<&CommandSettings>> = if command.arguments.len() == 1 {
+            COMMAND_SETTINGS.iter().map(Some).collect()
+        }
+        else {
+            command.arguments[1..].iter().map(|name| Command::find_settings(&String::from_utf8_lossy(name))).collect()
+        };
+
+        Ok(Value::Array(settings.into_iter().map(|settings| match settings {
+            None           => Value::Null,
+            Some(settings) => {
+                let arity = match settings.argument_count {
+                    ANY_ARGUMENT_COUNT => -1,
+                    count if count < 0 => count - 1,
+                    count              => count + 1
+                };
+
+                // keys counted by an argument can't be described by position, so redis gives none and flags them
+                let key_positions = match settings.keys.numkeys {
+                    0 => (settings.keys.first, settings.keys.last, settings.keys.step),
+                    _ => (0, 0, 0)
+                };
+
+                Value::Array(vec![
+                    Value::BufBulk(settings.name.to_lowercase().into_bytes()),
+                    Value::Integer(arity as i64),
+                    Value::Array(settings.flags.iter().map(|flag| Value::String(flag.to_string())).collect()),
+                    Value::Integer(key_positions.0 as i64),
+                    Value::Integer(key_positions.1 as i64),
+                    Value::Integer(key_positions.2 as i64),
+                ])
+            }
+        }).collect()))
+    }
+
+    fn client_getname(command: &Command) -> CommandResult {
+        match *command.connection.get_client_name().lock().unwrap() {
+            Some(ref name) => Ok(Value::BufBulk(name.clone())),
+            None           => Ok(Value::Null)
+        }
+    }
+
+    // an empty name clears it, as in redis
+    fn client_setname(command: &Command) -> CommandResult {
+        let name = command.arguments[1];
+
+        if name.iter().any(|c| !(b'!'..=b'~').contains(c)) {
+            return Err("Client names cannot contain spaces, newlines or special characters.".into());
+        }
+
+        *command.connection.get_client_name().lock().unwrap() = if name.is_empty() { None } else { Some(name.to_vec()) };
+
+        Ok(Value::String("OK".to_string()))
+    }
+
+    // returns the parameter and its value, or nothing when there's no such parameter; * returns them all
+    fn config_get(command: &Command) -> CommandResult {
+        let name = String::from_utf8_lossy(command.arguments[1]).to_lowercase();
+
+        let values = command.connection.get_config().parameters().into_iter()
+            .filter(|&(parameter, _)| name == "*" || name == parameter)
+            .flat_map(|(parameter, value)| vec![Value::BufBulk(parameter.as_bytes().to_vec()), Value::BufBulk(value.into_bytes())])
+            .collect();
+
+        Ok(Value::Array(values))
+    }
+
+    fn config_resetstat(command: &Command) -> CommandResult {
+        command.connection.get_stats().reset();
+        Ok(Value::String("OK".to_string()))
+    }
+
+    // every parameter is fixed once blueis has started
+    fn config_set(command: &Command) -> CommandResult {
+        let name = String::from_utf8_lossy(command.arguments[1]).to_lowercase();
+
+        if command.connection.get_config().parameters().iter().any(|&(parameter, _)| parameter == name) {
+            Err(format!("CONFIG SET failed (possibly related to argument '{}') - can't set immutable config", name).into())
+        }
+        else {
+            Err(format!("Unknown option or number of arguments for CONFIG SET - '{}'", name).into())
+        }
+    }
+
+    fn debug_no_op(_command: &Command) -> CommandResult {
+        Ok(Value::String("OK".to_string()))
+    }
+
+    // the same as FLUSHALL, so the two spellings can't leave different data behind
+    fn debug_flushall(command: &Command) -> CommandResult {
+        Command::flushdb(&Command { name: "FLUSHALL", arguments: command.arguments[1..].to_vec(), connection: command.connection })
+    }
+
+    // Lists report the encoding redis would use for their length; blueis stores every type the same way, so hashes
+    // and sorted sets report redis' encodings for large ones.
+    fn object_encoding(command: &Command) -> CommandResult {
+        let key = command.arguments[1];
+        let connection = command.lock_connection();
+
+        let encoding = match command.key_type(&*connection, key) {
+            Some("list") if command.connection.get_storage().count(&*connection, key) > command.connection.get_config().list_max_listpack_size => "quicklist",
+            Some("zset") if Command::count_zset_items(&*connection, key) > ZSET_MAX_LISTPACK_ENTRIES => "skiplist",
+            Some("hash") if Command::count_hash_items(&*connection, key) > HASH_MAX_LISTPACK_ENTRIES => "hashtable",
+            Some("string") => Command::string_encoding(&Command::find_string(&*connection, key).unwrap_or_default()),
+            Some(_)        => "listpack",
+            None           => return Ok(Value::Null)
+        };
+
+        Ok(Value::BufBulk(encoding.as_bytes().to_vec()))
+    }
+
+    // a string that reads back as the same integer is held as one by redis
+    fn string_encoding(value: &[u8]) -> &'static str {
+        let is_integer = str::from_utf8(value).ok()
+            .and_then(|string| string.parse::<i64>().ok().map(|integer| integer.to_string() == string))
+            .unwrap_or(false);
+
+        if is_integer {
+            "int"
+        }
+        else if value.len() <= EMBSTR_MAX_LENGTH {
+            "embstr"
+        }
+        else {
+            "raw"
+        }
+    }
+
+    // reports the sparse positions behind a list; a renumber is pending when two neighbours have no gap left between
+    // them, so the next LINSERT there has to shift one side
+    fn debug_list_info(command: &Command) -> CommandResult {
+        let connection = command.lock_connection();
+
+        let mut statement = connection.prepare("SELECT position FROM list_items WHERE key = ?1 ORDER BY position").unwrap();
+        let positions = statement.query_map(&[&command.arguments[1]], |row| row.get(0)).unwrap().collect::<Result<Vec<i64>, _>>().unwrap();
+
+        if positions.is_empty() {
+            return Err("no such key".into());
+        }
+
+        let renumber_pending = positions.windows(2).any(|pair| pair[1] - pair[0] <= 1);
+
+        let info = format!(
+            "rows:{}\nmin_position:{}\nmax_position:{}\nrenumber_pending:{}\n",
+            positions.len(), positions[0], positions[positions.len() - 1], renumber_pending as u8
+        );
+
+        Ok(Value::BufBulk(info.into_bytes()))
+    }
+
+    // Pushes each line of a file on the server onto the end of a list in one transaction, which seeds a database far
+    // faster than an RPUSH per element.  Replicas may not have the file, so they're sent the elements as an RPUSH.
+    fn debug_loadlist(command: &Command) -> CommandResult {
+        let key = command.arguments[1];
+
+        // without --requirepass every client is let in, so none can be told apart as one allowed to load files
+        if command.connection.get_config().requirepass.is_none() || !command.connection.is_authenticated() {
+            return Err("loading files is only allowed for a client authenticated with --requirepass".into());
+        }
+
+        command.check_key_sizes(&[key])?;
+
+        let path = command.loadable_path(command.arguments[2])?;
+
+        let mut contents = vec![];
+        File::open(&path).and_then(|mut file| file.read_to_end(&mut contents))
+            .map_err(|error| format!("unable to read {}: {}", path.display(), error))?;
+
+        // a newline ends a line rather than starting another, so a file with one at the end has no empty last line
+        let lines: Vec<&[u8]> = match contents.split_last() {
+            None                  => vec![],
+            Some((&b'\n', rest)) => rest.split(|&byte| byte == b'\n').collect(),
+            Some(_)               => contents.split(|&byte| byte == b'\n').collect()
+        };
+
+        command.check_value_sizes(&lines)?;
+
+        if lines.is_empty() {
+            return Ok(Value::Integer(0));
+        }
+
+        let mut connection = command.lock_connection();
+        command.make_room(&mut connection, &[key])?;
+
+        let tx = connection.transaction().unwrap();
+        command.connection.get_storage().push(&tx, key, &Direction::Right, &lines);
+        tx.commit().unwrap();
+
+        let mut arguments = vec![key];
+        arguments.extend(lines.iter());
+        command.replicate_as("RPUSH", &arguments);
+        command.notify_push(key);
+
+        Ok(Value::Integer(lines.len() as i64))
+    }
+
+    // Only a file within --load-dir can be loaded, and without it none can.  A relative path is taken from there, and
+    // any path is resolved, symlinks and all, before it's checked, so neither .. nor a link can lead outside.
+    fn loadable_path(&self, path: &[u8]) -> Result<PathBuf, CommandError> {
+        let load_dir = match self.connection.get_config().load_dir {
+            Some(ref load_dir) => Path::new(load_dir).canonicalize().map_err(|error| format!("unable to use --load-dir {}: {}", load_dir, error))?,
+            None               => return Err("loading files is disabled, as blueis was started without --load-dir".into())
+        };
+
+        let path = String::from_utf8_lossy(path).into_owned();
+        let resolved = load_dir.join(&path).canonicalize().map_err(|error| format!("unable to read {}: {}", path, error))?;
+
+        if resolved.starts_with(&load_dir) {
+            Ok(resolved)
+        }
+        else {
+            Err(format!("{} is outside --load-dir", path).into())
+        }
+    }
+
+    // rebuilds the list index, which churn can leave bloated, and refreshes the statistics the query planner uses
+    fn debug_reindex(command: &Command) -> CommandResult {
+        let connection = command.lock_connection();
+
+        connection.execute_batch("REINDEX list_items; ANALYZE;").unwrap();
+
+        Ok(Value::String("OK".to_string()))
+    }
+
+    fn parse_argument_float(&self, index: usize) -> Result<f64, &str> {
+        str::from_utf8(self.arguments[index]).ok()
+            .and_then(|value| value.parse::<f64>().ok())
+            .and_then(|value| if value.is_nan() { None } else { Some(value) })
+            .ok_or("value is not a valid float")
+    }
+
+    // a leading ( makes the bound exclusive, as in redis
+    fn parse_score_bound(&self, index: usize) -> Result<(f64, bool), &str> {
+        let argument = self.arguments[index];
+
+        let (value, exclusive) = match argument.first() {
+            Some(&b'(') => (&argument[1..], true),
+            _           => (argument, false)
+        };
+
+        str::from_utf8(value).ok()
+            .and_then(|value| value.parse::<f64>().ok())
+            .and_then(|value| if value.is_nan() { None } else { Some((value, exclusive)) })
+            .ok_or("min or max is not a float")
+    }
+
+    fn parse_argument_integer(&self, index: usize) -> Result<i64, &str> {
+        str::from_utf8(self.arguments[index])
+            .map_err(|_| "")
+            .and_then(|value| String::from(value).parse::<i64>().map_err(|_| ""))
+            .map_err(|_| "argument must be an integer")
+    }
+
+    fn blocking_pop(&self, direction: Direction) -> CommandResult {
+        let timeout = self.parse_argument_integer(self.arguments.len() - 1)?;
+        let (_, keys) = self.arguments.split_last().unwrap();
+
+        self.block_until_popped(keys, timeout, Value::NullArray, |connection| {
+            Ok(self.pop_from_first_list(connection, keys, &direction, 1).map(Command::single_pop_reply))
+        })
+    }
+
+    // Tries `pop` each time a push onto one of the keys is notified, and at least every second, until it returns a
+    // reply, the timeout in seconds runs out, or the client goes away, when `timed_out` is the reply instead.  A
+    // timeout of zero waits forever.  Inside EXEC, which keeps every other client from running a command until it's
+    // done, no push could arrive, so `pop` is tried just once, as redis does.
+    fn block_until_popped<F>(&self, keys: &[&[u8]], timeout: i64, timed_out: Value, pop: F) -> CommandResult
+        where F: Fn(&mut rusqlite::Connection) -> Result<Option<Value>, CommandError>
+    {
+        if timeout < 0 {
+            return Err("timeout is negative".into());
+        }
+
+        let start_instant = Instant::now();
+        let duration = Duration::new(timeout as u64, 0);
+
+        let push_waiters = self.connection.get_push_waiters();
+
+        while self.connection.is_stream_alive() && (timeout == 0 || start_instant.elapsed() < duration) {
+            // Pushers notify while they still hold the connection, so taking the waiters' lock before letting go of
+            // the connection means a push can't land and notify between the pop finding nothing and the wait
+            // starting.  Otherwise that push would go unnoticed until the next periodic retry.
+            let guard = {
+                let mut connection = self.lock_connection();
+
+                if let Some(value) = pop(&mut connection)? {
+                    return Ok(value);
+                }
+
+                push_waiters.lock()
+            };
+
+            if self.connection.get_reservation().is_held() {
+                break;
+            }
+
+            let wait = if timeout == 0 {
+                Duration::new(1, 0)
+            } else {
+                let elapsed = start_instant.elapsed();
+                if elapsed < duration {
+                    cmp::min(Duration::new(1, 0), duration - elapsed)
+                }
+                else {
+                    Duration::new(0, 0)
+                }
+            };
+
+            push_waiters.wait(guard, keys, wait);
+        }
+
+        Ok(timed_out)
+    }
+
+    fn push_if_exists(&self, direction: Direction) -> CommandResult {
+        let key = self.arguments[0];
+        self.check_value_sizes(&self.arguments[1..])?;
+
+        let mut connection = self.lock_connection();
+
+        // the existence check and the push share a transaction so a concurrent pop can't empty the list in between
+        let tx = connection.transaction().unwrap();
+
+        let storage = self.connection.get_storage();
+
+        if storage.count(&tx, key) == 0 {
+            return Ok(Value::Integer(0));
+        }
+
+        storage.push(&tx, key, &direction, &self.arguments[1..]);
+        let result = self.count_list_items_value(&tx, key);
+        tx.commit().unwrap();
+        self.replicate();
+
+        self.notify_push(key);
+
+        result
+    }
+
+    // the number of keys, the keys, LEFT or RIGHT, then an optional COUNT, as LMPOP and BLMPOP take them starting
+    // at the argument giving the number of keys
+    fn parse_multiple_pop(&self, first: usize) -> Result<(Vec<&[u8]>, Direction, i64), CommandError> {
+        let key_count = self.parse_argument_integer(first)?;
+
+        if key_count <= 0 {
+            return Err("numkeys should be greater than 0".into());
+        }
+
+        if key_count >= (self.arguments.len() - first - 1) as i64 {
+            return Err("syntax error".into());
+        }
+
+        let direction_index = first + 1 + key_count as usize;
+
+        let direction = if self.argument_matches(direction_index, "LEFT") {
+            Direction::Left
+        }
+        else if self.argument_matches(direction_index, "RIGHT") {
+            Direction::Right
+        }
+        else {
+            return Err("syntax error".into());
+        };
+
+        let count = match self.arguments.len() - direction_index - 1 {
+            0                                                        => 1,
+            2 if self.argument_matches(direction_index + 1, "COUNT") => self.parse_argument_integer(direction_index + 2)?,
+            _                                                        => return Err("syntax error".into())
+        };
+
+        if count <= 0 {
+            return Err("count should be greater than 0".into());
+        }
+
+        Ok((self.arguments[first + 1..direction_index].to_vec(), direction, count))
+    }
+
+    // Pops up to count elements from the first of the keys holding a list, returning that key and the elements.
+    // Every pop, blocking or not and whatever its reply looks like, goes through here.
+    fn pop_from_first_list(&self, connection: &rusqlite::Connection, keys: &[&[u8]], direction: &Direction, count: i64) -> Option<(Vec<u8>, Vec<Vec<u8>>)> {
+        let storage = self.connection.get_storage();
+
+        for key in keys {
+            let mut values = vec![];
+
+            while (values.len() as i64) < count {
+                match storage.pop(connection, key, direction) {
+                    Some(data) => values.push(data),
+                    None       => break
+                }
+            }
+
+            if !values.is_empty() {
+                // replicas replay the pops that actually happened rather than repeating the search themselves
+                let name = match *direction { Direction::Left => "LPOP", Direction::Right => "RPOP" };
+
+                for _ in 0..values.len() {
+                    self.replicate_as(name, &[*key]);
+                }
+
+                return Some((key.to_vec(), values));
+            }
+        }
+
+        None
+    }
+
+    // Pops from one end of the source and pushes onto one end of the destination, in one transaction so the element is
+    // never missing from both.  The source and destination can be the same list.  Only a move from a list that isn't
+    // empty can add the destination, so that's when room is made for it.
+    fn move_element(&self, connection: &mut rusqlite::Connection, from: &Direction, to: &Direction) -> Result<Option<Vec<u8>>, CommandError> {
+        let source = self.arguments[0];
+        let destination = self.arguments[1];
+
+        let storage = self.connection.get_storage();
+
+        if storage.count(connection, source) == 0 {
+            return Ok(None);
+        }
+
+        self.make_room(connection, &[destination])?;
+
+        let tx = connection.transaction().unwrap();
+
+        match storage.pop(&tx, source, from) {
+            Some(data) => {
+                storage.push(&tx, destination, to, &[&data]);
+                tx.commit().unwrap();
+
+                // replicas replay the move that happened, so a blocking one never blocks them
+                self.replicate_as("LMOVE", &[source, destination, Command::direction_name(from), Command::direction_name(to)]);
+                self.notify_push(destination);
+                Ok(Some(data))
+            }
+
+            None => Ok(None)
+        }
+    }
+
+    fn direction_name(direction: &Direction) -> &'static [u8] {
+        match *direction {
+            Direction::Left  => b"LEFT",
+            Direction::Right => b"RIGHT"
+        }
+    }
+
+    fn parse_direction(&self, index: usize) -> Result<Direction, CommandError> {
+        if self.argument_matches(index, "LEFT") {
+            Ok(Direction::Left)
+        }
+        else if self.argument_matches(index, "RIGHT") {
+            Ok(Direction::Right)
+        }
+        else {
+            Err("syntax error".into())
+        }
+    }
+
+    // the key and the one element popped from it, as BLPOP, BRPOP and MPOP reply
+    fn single_pop_reply((key, mut values): (Vec<u8>, Vec<Vec<u8>>)) -> Value {
+        Value::Array(vec![Value::BufBulk(key), Value::BufBulk(values.remove(0))])
+    }
+
+    // the key and an array of the elements popped from it, as LMPOP and BLMPOP reply
+    fn multiple_pop_reply((key, values): (Vec<u8>, Vec<Vec<u8>>)) -> Value {
+        Value::Array(vec![Value::BufBulk(key), Value::Array(values.into_iter().map(Value::BufBulk).collect())])
+    }
+
+    // called while the connection lock is held, so replicas receive writes in the order they were committed
+    fn replicate(&self) {
+        self.replicate_as(self.name, &self.arguments);
+    }
+
+    // every write passes through here, so this is where cached reads are invalidated.  Any argument could be a key,
+    // and invalidating a few that aren't only costs a cache miss.
+    fn replicate_as(&self, name: &str, arguments: &[&[u8]]) {
+        {
+            let mut cache = self.connection.get_read_cache().lock().unwrap();
+            for argument in arguments { cache.invalidate(argument); }
+        }
+
+        let mut command = vec![name.as_bytes().to_vec()];
+        command.extend(arguments.iter().map(|argument| argument.to_vec()));
+
+        self.connection.send_to_replication_log(command);
+    }
+
+    // Moves every flushed table aside and sets up empty ones in their place, so the flushed keys are gone as soon as
+    // this commits, however long they take to delete.  Index names are shared by the whole database, so the moved
+    // tables' indexes are dropped for the new ones to be created.  blueis's own rows, which sort before every key as
+    // their keys are text, are copied across.  Returns the names the tables were moved to.
+    fn swap_in_empty_tables(connection: &rusqlite::Connection) -> Vec<String> {
+        let suffix = time::precise_time_ns();
+        let flushing: Vec<String> = FLUSHED_TABLES.iter().map(|&(table, _)| format!("{}{}_{}", FLUSHING_TABLE_PREFIX, table, suffix)).collect();
+
+        for (&(table, _), moved) in FLUSHED_TABLES.iter().zip(flushing.iter()) {
+            connection.execute_batch(&format!("ALTER TABLE {} RENAME TO {}", table, moved)).unwrap();
+        }
+
+        let moved_blueis = &flushing[FLUSHED_TABLES.iter().position(|&(table, _)| table == "blueis").unwrap()];
+        connection.execute_batch(&format!("CREATE TEMP TABLE kept_rows AS SELECT key, value FROM {} WHERE key < x''", moved_blueis)).unwrap();
+
+        for moved in &flushing {
+            let indexes = {
+                let mut statement = connection.prepare("SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = ?1 AND sql IS NOT NULL").unwrap();
+                let names = statement.query_map(&[moved], |row| row.get(0)).unwrap().collect::<Result<Vec<String>, _>>().unwrap();
+                names
+            };
+
+            for index in indexes {
+                connection.execute_batch(&format!("DROP INDEX {}", index)).unwrap();
+            }
+        }
+
+        set_up_tables(connection);
+        connection.execute_batch("INSERT OR REPLACE INTO blueis (key, value) SELECT key, value FROM temp.kept_rows; DROP TABLE temp.kept_rows;").unwrap();
+
+        flushing
+    }
+
+    // empties each table a batch at a time, letting go of the connection in between, then drops it
+    fn drop_in_batches(sqlite_connection_mutex: &Mutex<rusqlite::Connection>, tables: &[String]) {
+        for table in tables {
+            loop {
+                let connection = checkout::lock(sqlite_connection_mutex);
+                let deleted = connection.execute(&format!("DELETE FROM {0} WHERE rowid IN (SELECT rowid FROM {0} LIMIT ?1)", table), &[&FLUSH_BATCH_SIZE]).unwrap();
+
+                if deleted == 0 {
+                    connection.execute_batch(&format!("DROP TABLE {}", table)).unwrap();
+                    break;
+                }
+            }
+        }
+    }
+
+    // Tables left behind by a FLUSHDB ASYNC that was still deleting when blueis stopped, dropped before it serves
+    // clients.  Each is dropped in one go, as there are no clients yet to wait.
+    pub fn drop_abandoned_flushes(connection: &rusqlite::Connection) {
+        let tables = {
+            let mut statement = connection.prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND substr(name, 1, ?1) = ?2").unwrap();
+            let names = statement.query_map(&[&(FLUSHING_TABLE_PREFIX.len() as i64), &FLUSHING_TABLE_PREFIX], |row| row.get(0)).unwrap().collect::<Result<Vec<String>, _>>().unwrap();
+            names
+        };
+
+        for table in tables {
+            connection.execute_batch(&format!("DROP TABLE {}", table)).unwrap();
+        }
+    }
+
+    // Expiry is applied before every command to whichever of its arguments name keys with an expiry, so no command
+    // sees an expired key.  Any argument could be a key, and expiring one that's due is right whatever the command.
+    // An expiry left behind by a key that has since been emptied is dropped here too, so a new key of the same name
+    // can't inherit it.
+    fn expire_keys(&self) {
+        let mut connection = self.lock_connection_for_writing();
+
+        let expiries: i64 = connection.query_row("SELECT COUNT(*) FROM key_meta", &[], |row| row.get(0)).unwrap();
+
+        if expiries == 0 {
+            return;
+        }
+
+        let now = Command::now_in_milliseconds();
+        let tx = connection.transaction().unwrap();
+
+        for key in &self.arguments {
+            if let Some(expires_at) = Command::find_expiry(&tx, key) {
+                if expires_at <= now || !self.key_exists(&tx, key) {
+                    self.delete_key(&tx, key);
+                    self.connection.get_read_cache().lock().unwrap().invalidate(key);
+                }
+            }
+        }
+
+        tx.commit().unwrap();
+    }
+
+    // Once a command has panicked holding the connection, its lock is poisoned.  The command's rusqlite transaction
+    // rolled back as it unwound, but were a transaction somehow still open, it's rolled back now and the command that
+    // finds it refused, as it would otherwise see, and commit, changes that were only half made.  The poison is then
+    // cleared so that later commands don't go through this again.
+    fn recover_connection(&self) -> Result<(), CommandError> {
+        let mutex = self.connection.get_sqlite_connection_mutex();
+
+        if !mutex.is_poisoned() {
+            return Ok(());
+        }
+
+        let connection = self.connection.get_reservation().lock(mutex);
+
+        // another command may have recovered it while this one waited for the lock
+        if !mutex.is_poisoned() {
+            return Ok(());
+        }
+
+        let rolled_back = connection.execute_batch("ROLLBACK").is_ok();
+        mutex.clear_poison();
+
+        if rolled_back {
+            Err(CommandError::Prefixed("TRYAGAIN", "a command failed partway through, and what it had changed has been rolled back".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Every key a readonly command is given counts as a hit if it exists and a miss if it doesn't, whatever the
+    // command goes on to do with it, so each read command is counted the same way.
+    fn count_keyspace_lookups(&self, settings: &CommandSettings) {
+        if !settings.flags.contains(&"readonly") {
+            return;
+        }
+
+        let stats = self.connection.get_stats();
+        let connection = self.lock_connection();
+
+        for index in Command::key_indexes(&settings.keys, &self.arguments) {
+            if self.key_type(&*connection, self.arguments[index]).is_some() {
+                Stats::increment(&stats.keyspace_hits);
+            }
+            else {
+                Stats::increment(&stats.keyspace_misses);
+            }
+        }
+    }
+
+    // The connection for a command that can add keys.  With --max-keys, room is first made for those of its keys that
+    // don't exist yet, under the same lock the command goes on to write with, so no other client can take the room.
+    fn lock_connection_making_room(&self) -> Result<Checkout<'_>, CommandError> {
+        let mut connection = self.lock_connection();
+
+        match Command::find_settings(self.name) {
+            Some(settings) if settings.flags.contains(&"denyoom") => {
+                let keys = self.keys(settings);
+                self.make_room(&mut connection, &keys)?;
+            }
+
+            _ => {}
+        }
+
+        Ok(connection)
+    }
+
+    // Evicts keys as --maxmemory-policy chooses until the keys given that don't exist yet would fit within --max-keys.
+    // Keys that have already expired go first whatever the policy.  If nothing more can be evicted, the command is
+    // refused as redis refuses it over maxmemory.
+    fn make_room(&self, connection: &mut rusqlite::Connection, keys: &[&[u8]]) -> Result<(), CommandError> {
+        let config = self.connection.get_config();
+
+        if config.max_keys == 0 {
+            return Ok(());
+        }
+
+        let new_keys = keys.iter().filter(|key| !self.key_exists(connection, key)).count() as i64;
+
+        if new_keys == 0 {
+            return Ok(());
+        }
+
+        let victim_sql = match config.maxmemory_policy {
+            EvictionPolicy::NoEviction     => format!("SELECT key FROM key_meta WHERE expires_at <= ?1 AND key IN ({}) LIMIT 1", ALL_KEYS_SQL),
+            EvictionPolicy::VolatileTtl    => format!("SELECT key FROM key_meta WHERE key IN ({}) ORDER BY expires_at > ?1, expires_at LIMIT 1", ALL_KEYS_SQL),
+            EvictionPolicy::VolatileRandom => format!("SELECT key FROM key_meta WHERE key IN ({}) ORDER BY expires_at > ?1, RANDOM() LIMIT 1", ALL_KEYS_SQL),
+        };
+
+        let now = Command::now_in_milliseconds();
+        let tx = connection.transaction().unwrap();
+
+        let mut key_count: i64 = tx.query_row(&format!("SELECT COUNT(*) FROM ({})", ALL_KEYS_SQL), &[], |row| row.get(0)).unwrap();
+
+        while key_count + new_keys > config.max_keys as i64 {
+            let victim: Option<Vec<u8>> = match tx.query_row(&victim_sql, &[&now], |row| row.get(0)) {
+                Ok(key)                                   => Some(key),
+                Err(rusqlite::Error::QueryReturnedNoRows) => None,
+                Err(error)                                => return Err(format!("unable to choose a key to evict: {}", error).into())
+            };
+
+            match victim {
+                None      => break,
+                Some(key) => {
+                    self.delete_key(&tx, &key);
+                    self.replicate_as("DEL", &[&key]);
+                    self.notify_keyspace_event("evicted", &key);
+                    key_count -= 1;
+                }
+            }
+        }
+
+        tx.commit().unwrap();
+
+        if key_count + new_keys > config.max_keys as i64 {
+            return Err(CommandError::Prefixed("OOM", "command not allowed when the number of keys would exceed 'max-keys'.".to_string()));
+        }
+
+        Ok(())
+    }
+
+    // replicated as an absolute time, so the key expires at the same moment on a replica however late it applies it
+    fn set_expiry(&self, expires_at: Option<i64>) -> CommandResult {
+        let key = self.arguments[0];
+        let expires_at = expires_at.ok_or_else(|| format!("invalid expire time in '{}' command", self.name.to_lowercase()))?;
+
+        let mut connection = self.lock_connection();
+
+        if !self.key_exists(&*connection, key) {
+            return Ok(Value::Integer(0));
+        }
+
+        if expires_at <= Command::now_in_milliseconds() {
+            let tx = connection.transaction().unwrap();
+            self.delete_key(&tx, key);
+            tx.commit().unwrap();
+        }
+        else {
+            connection.execute("INSERT OR REPLACE INTO key_meta (key, expires_at) VALUES (?1, ?2)", &[&key, &expires_at]).unwrap();
+        }
+
+        self.replicate_as("PEXPIREAT", &[key, expires_at.to_string().as_bytes()]);
+
+        Ok(Value::Integer(1))
+    }
+
+    // The value and its expiry are written in one transaction, so the key is never seen without its expiry.  Replicas
+    // are sent the command as given followed by the absolute expiry, so the key expires at the same moment on both.
+    fn set_with_expiry(&self, unit: i64) -> CommandResult {
+        let key = self.arguments[0];
+        let value = self.arguments[2];
+        let time = self.parse_argument_integer(1)?;
+
+        let expires_at = if time > 0 { time.checked_mul(unit).and_then(|milliseconds| milliseconds.checked_add(Command::now_in_milliseconds())) } else { None };
+        let expires_at = expires_at.ok_or_else(|| format!("invalid expire time in '{}' command", self.name.to_lowercase()))?;
+
+        self.check_value_sizes(&[value])?;
+
+        let mut connection = self.lock_connection_making_room()?;
+        let tx = connection.transaction().unwrap();
+
+        self.delete_key(&tx, key);
+        Command::set_string(&tx, key, value);
+        tx.execute("INSERT INTO key_meta (key, expires_at) VALUES (?1, ?2)", &[&key, &expires_at]).unwrap();
+
+        tx.commit().unwrap();
+        self.replicate();
+        self.replicate_as("PEXPIREAT", &[key, expires_at.to_string().as_bytes()]);
+
+        Ok(Value::String("OK".to_string()))
+    }
+
+    // rounded to the nearest unit, as redis does
+    fn time_to_live(&self, unit: i64) -> CommandResult {
+        let key = self.arguments[0];
+        let connection = self.lock_connection();
+
+        if !self.key_exists(&*connection, key) {
+            return Ok(Value::Integer(-2));
+        }
+
+        match Command::find_expiry(&*connection, key) {
+            Some(expires_at) => Ok(Value::Integer((expires_at - Command::now_in_milliseconds() + unit / 2) / unit)),
+            None             => Ok(Value::Integer(-1))
+        }
+    }
+
+    // There's only the one database, which like redis is left out when it's empty.  Keys that have expired but
+    // haven't been swept yet aren't counted, and avg_ttl is in milliseconds.
+    fn keyspace_line(&self) -> String {
+        let connection = self.lock_connection();
+        let now = Command::now_in_milliseconds();
+
+        let keys_sql = format!("SELECT COUNT(*) FROM ({}) WHERE key NOT IN (SELECT key FROM key_meta WHERE expires_at <= ?1)", ALL_KEYS_SQL);
+        let keys: i64 = connection.query_row(&keys_sql, &[&now], |row| row.get(0)).unwrap();
+
+        if keys == 0 {
+            return String::new();
+        }
+
+        let expires_sql = format!("SELECT COUNT(*), coalesce(AVG(expires_at - ?1), 0) FROM key_meta WHERE expires_at > ?1 AND key IN ({})", ALL_KEYS_SQL);
+        let (expires, average_ttl): (i64, f64) = connection.query_row(&expires_sql, &[&now], |row| (row.get(0), row.get(1))).unwrap();
+
+        format!("db0:keys={},expires={},avg_ttl={}\r\n", keys, expires, average_ttl as i64)
+    }
+
+    // commands flagged readonly get a checkout that can't write
+    fn lock_connection(&self) -> Checkout<'_> {
+        let read_only = Command::find_settings(self.name).map(|settings| settings.flags.contains(&"readonly")).unwrap_or(false);
+        Checkout::new(self.lock_connection_for_writing(), read_only)
+    }
+
+    fn lock_connection_for_writing(&self) -> MutexGuard<'_, rusqlite::Connection> {
+        self.connection.get_reservation().lock(self.connection.get_sqlite_connection_mutex())
+    }
+
+    fn count_list_items_value(&self, connection: &rusqlite::Connection, key: &[u8]) -> CommandResult {
+        Ok(Value::Integer(self.connection.get_storage().count(connection, key)))
+    }
+
+    fn push_in_transaction(&self, connection: &mut rusqlite::Connection, key: &[u8], direction: Direction) {
+        let tx = connection.transaction().unwrap();
+        self.connection.get_storage().push(&tx, key, &direction, &self.arguments[1..]);
+        tx.commit().unwrap();
+    }
+
+    // Without a count, one element is popped and replied with on its own; with one, up to that many are popped in a
+    // single transaction and replied with as an array.  An emptied list has no rows left, so popping its last element
+    // deletes the key, and a del event follows the pop's own.  Nothing is published when there was nothing to pop.
+    fn pop_and_notify(&self, direction: Direction, event: &str) -> CommandResult {
+        let key = self.arguments[0];
+
+        let count = match self.arguments.len() {
+            1 => None,
+            2 => Some(self.parse_argument_integer(1)?),
+            _ => return Err("syntax error".into())
+        };
+
+        if count.map(|count| count < 0).unwrap_or(false) {
+            return Err("value is out of range, must be positive".into());
+        }
+
+        let mut connection = self.lock_connection();
+        let tx = connection.transaction().unwrap();
+        let storage = self.connection.get_storage();
+
+        let values = match self.pop_from_first_list(&tx, &[key], &direction, count.unwrap_or(1)) {
+            Some((_, values)) => values,
+            None              => vec![]
+        };
+
+        let length = storage.count(&tx, key);
+        tx.commit().unwrap();
+
+        if !values.is_empty() {
+            self.notify_keyspace_event(event, key);
+
+            if length == 0 {
+                self.notify_keyspace_event("del", key);
+            }
+        }
+
+        match count {
+            None                                        => Ok(values.into_iter().next().map(Value::BufBulk).unwrap_or(Value::Null)),
+            Some(_) if values.is_empty() && length == 0 => Ok(Value::NullArray),
+            Some(_)                                     => Ok(Value::Array(values.into_iter().map(Value::BufBulk).collect()))
+        }
+    }
+
+    // published to the key's keyspace channel and the event's keyevent channel, as redis does, with 0 as the database
+    fn notify_keyspace_event(&self, event: &str, key: &[u8]) {
+        let pubsub = self.connection.get_pubsub();
+
+        let mut keyspace_channel = b"__keyspace@0__:".to_vec();
+        keyspace_channel.extend_from_slice(key);
+
+        pubsub.publish(&keyspace_channel, event.as_bytes());
+        pubsub.publish(format!("__keyevent@0__:{}", event).as_bytes(), key);
+    }
+
+    // wakes the clients blocked on the key pushed onto
+    fn notify_push(&self, key: &[u8]) {
+        self.connection.get_push_waiters().notify(key);
+    }
+
+    /*
+     * support functions
+     */
+
+    fn list_values(connection: &rusqlite::Connection, key: &[u8]) -> Vec<Vec<u8>> {
+        let mut statement = connection.prepare("SELECT value FROM list_items WHERE key = ?1 ORDER BY position").unwrap();
+        let rows = statement.query_map(&[&key], |row| row.get(0)).unwrap();
+        rows.collect::<Result<Vec<Vec<u8>>, _>>().unwrap()
+    }
+
+    fn dump_key(connection: &rusqlite::Connection, key: &[u8]) -> Option<Vec<u8>> {
+        Command::dump_list(Command::list_values(connection, key)).or_else(|| Command::dump_string(connection, key))
+    }
+
+    fn dump_string(connection: &rusqlite::Connection, key: &[u8]) -> Option<Vec<u8>> {
+        Command::find_string(connection, key).map(|value| {
+            let mut output = vec![DUMP_TYPE_STRING];
+            serialization::write_bytes(&mut output, &value);
+            output
+        })
+    }
+
+    fn dump_list(values: Vec<Vec<u8>>) -> Option<Vec<u8>> {
+        if values.is_empty() {
+            return None;
+        }
+
+        let mut output = vec![DUMP_TYPE_LIST];
+        serialization::write_u64(&mut output, values.len() as u64);
+
+        for value in values.iter() {
+            serialization::write_bytes(&mut output, value);
+        }
+
+        Some(output)
+    }
+
+    // the whole dataset as RESTORE commands, which a replica replays before following the replication log
+    pub fn replication_snapshot(connection: &rusqlite::Connection) -> Vec<Vec<Vec<u8>>> {
+        let mut statement = connection.prepare("SELECT DISTINCT key FROM list_items UNION SELECT key FROM blueis WHERE typeof(key) = 'blob'").unwrap();
+        let keys = statement.query_map(&[], |row| row.get(0)).unwrap().collect::<Result<Vec<Vec<u8>>, _>>().unwrap();
+
+        let mut commands: Vec<Vec<Vec<u8>>> = keys.into_iter().map(|key| {
+            let payload = Command::dump_key(connection, &key).unwrap();
+            vec![b"RESTORE".to_vec(), key, b"0".to_vec(), payload, b"REPLACE".to_vec()]
+        }).collect();
+
+        let mut statement = connection.prepare("SELECT key, member, score FROM zset_items ORDER BY key").unwrap();
+        let rows = statement.query_map(&[], |row| (row.get(0), row.get(1), row.get(2))).unwrap();
+
+        for row in rows {
+            let (key, member, score): (Vec<u8>, Vec<u8>, f64) = row.unwrap();
+            Command::add_to_snapshot(&mut commands, b"ZADD", key, vec![Command::format_score(score).into_bytes(), member]);
+        }
+
+        let mut statement = connection.prepare("SELECT key, field, value FROM hash_items ORDER BY key").unwrap();
+        let rows = statement.query_map(&[], |row| (row.get(0), row.get(1), row.get(2))).unwrap();
+
+        for row in rows {
+            let (key, field, value): (Vec<u8>, Vec<u8>, Vec<u8>) = row.unwrap();
+            Command::add_to_snapshot(&mut commands, b"HSET", key, vec![field, value]);
+        }
+
+        let mut statement = connection.prepare("SELECT key, expires_at FROM key_meta").unwrap();
+        let rows = statement.query_map(&[], |row| (row.get(0), row.get(1))).unwrap();
+
+        for row in rows {
+            let (key, expires_at): (Vec<u8>, i64) = row.unwrap();
+            commands.push(vec![b"PEXPIREAT".to_vec(), key, expires_at.to_string().into_bytes()]);
+        }
+
+        commands
+    }
+
+    // consecutive rows for the same key are gathered into a single command
+    fn add_to_snapshot(commands: &mut Vec<Vec<Vec<u8>>>, name: &[u8], key: Vec<u8>, arguments: Vec<Vec<u8>>) {
+        if commands.last().map(|command| command[0] != name || command[1] != key).unwrap_or(true) {
+            commands.push(vec![name.to_vec(), key]);
+        }
+
+        commands.last_mut().unwrap().extend(arguments);
+    }
+
+    fn decode_payload(payload: &[u8]) -> Result<Payload<'_>, &'static str> {
+        let mut reader = serialization::Reader::new(payload);
+
+        let decoded = match reader.read_u8()? {
+            DUMP_TYPE_LIST => {
+                let count = reader.read_u64()?;
+                Payload::List((0..count).map(|_| reader.read_bytes()).collect::<Result<Vec<&[u8]>, _>>()?)
+            }
+
+            DUMP_TYPE_STRING => Payload::String(reader.read_bytes()?),
+
+            _ => return Err("unknown type")
+        };
+
+        if !reader.is_finished() {
+            return Err("trailing data");
+        }
+
+        Ok(decoded)
+    }
+
+    fn parse_index(length: i64, index: i64) -> Option<i64> {
+        let offset = if index < 0 { index + length } else { index };
+
+        if offset >= 0 && offset < length { Some(offset) } else { None }
+    }
+
+    // the indexes of the arguments that are keys, for a command given this many arguments
+    fn key_indexes(keys: &KeySpec, arguments: &[&[u8]]) -> Vec<usize> {
+        if keys.step == 0 {
+            return vec![];
+        }
+
+        let argument_count = arguments.len() as i32;
+
+        let last = if keys.numkeys > 0 {
+            // none at all when numkeys isn't a number, which the command itself goes on to refuse
+            let key_count = arguments.get(keys.numkeys as usize - 1)
+                .and_then(|argument| str::from_utf8(argument).ok())
+                .and_then(|argument| argument.parse::<usize>().ok())
+                .unwrap_or(0);
+
+            keys.first + (cmp::min(key_count, arguments.len()) as i32 - 1) * keys.step
+        }
+        else if keys.last < 0 {
+            argument_count + keys.last + 1
+        }
+        else {
+            keys.last
+        };
+
+        let last = cmp::min(last, argument_count);
+
+        (keys.first..last + 1).step_by(keys.step as usize).map(|position| position as usize - 1).collect()
+    }
+
+    fn count_hash_items(connection: &rusqlite::Connection, key: &[u8]) -> i64 {
+        connection.query_row("SELECT COUNT(*) FROM hash_items WHERE key = ?1", &[&key], |row| row.get(0)).unwrap()
+    }
+
+    fn count_zset_items(connection: &rusqlite::Connection, key: &[u8]) -> i64 {
+        let mut statement = connection.prepare("SELECT COUNT(*) FROM zset_items WHERE key = ?1").unwrap();
+        statement.query_row(&[&key], |row| row.get(0)).unwrap()
+    }
+
+    // the type of a key, named as TYPE names it, which OBJECT ENCODING goes by too
+    fn key_type(&self, connection: &rusqlite::Connection, key: &[u8]) -> Option<&'static str> {
+        if self.connection.get_storage().count(connection, key) > 0 {
+            return Some("list");
+        }
+
+        KEY_TYPES.iter().find(|&&(_, table)| {
+            let exists: i64 = connection.query_row(&format!("SELECT EXISTS (SELECT 1 FROM {} WHERE key = ?1)", table), &[&key], |row| row.get(0)).unwrap();
+            exists != 0
+        }).map(|&(name, _)| name)
+    }
+
+    fn key_exists(&self, connection: &rusqlite::Connection, key: &[u8]) -> bool {
+        self.key_type(connection, key).is_some()
+    }
+
+    fn delete_key(&self, connection: &rusqlite::Connection, key: &[u8]) {
+        self.connection.get_storage().delete(connection, key);
+
+        for &(_, table) in KEY_TYPES.iter() {
+            connection.execute(&format!("DELETE FROM {} WHERE key = ?1", table), &[&key]).unwrap();
+        }
+
+        connection.execute("DELETE FROM key_meta WHERE key = ?1", &[&key]).unwrap();
+    }
+
+    fn find_expiry(connection: &rusqlite::Connection, key: &[u8]) -> Option<i64> {
+        let mut statement = connection.prepare("SELECT expires_at FROM key_meta WHERE key = ?1").unwrap();
+
+        match statement.query_row(&[&key], |row| row.get(0)) {
+            Ok(expires_at)                            => Some(expires_at),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e)                                    => panic!("{}", e)
+        }
+    }
+
+    fn now_in_milliseconds() -> i64 {
+        let now = time::get_time();
+        now.sec * 1000 + now.nsec as i64 / 1000000
+    }
+
+    fn find_string(connection: &rusqlite::Connection, key: &[u8]) -> Option<Vec<u8>> {
+        let mut statement = connection.prepare("SELECT value FROM blueis WHERE key = ?1").unwrap();
+
+        match statement.query_row(&[&key], |row| row.get(0)) {
+            Ok(value)                                 => Some(value),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e)                                    => panic!("{}", e)
+        }
+    }
+
+    fn set_string(connection: &rusqlite::Connection, key: &[u8], value: &[u8]) {
+        connection.execute("INSERT OR REPLACE INTO blueis (key, value) VALUES (?1, ?2)", &[&key, &value]).unwrap();
+    }
+
+    fn find_hash_value(connection: &rusqlite::Connection, key: &[u8], field: &[u8]) -> Option<Vec<u8>> {
+        let mut statement = connection.prepare("SELECT value FROM hash_items WHERE key = ?1 AND field = ?2").unwrap();
+
+        match statement.query_row(&[&key, &field], |row| row.get(0)) {
+            Ok(value)                                 => Some(value),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e)                                    => panic!("{}", e)
+        }
+    }
+
+    // fields are always listed in the same order, so HKEYS and HVALS line up with each other
+    fn hash_column(connection: &rusqlite::Connection, key: &[u8], column: &str) -> Value {
+        let mut statement = connection.prepare(&format!("SELECT {} FROM hash_items WHERE key = ?1 ORDER BY field", column)).unwrap();
+        let rows = statement.query_map(&[&key], |row| row.get(0)).unwrap();
+
+        Value::Array(rows.map(|row| Value::BufBulk(row.unwrap())).collect())
+    }
+
+    fn set_hash_value(connection: &rusqlite::Connection, key: &[u8], field: &[u8], value: &[u8]) {
+        connection.execute("INSERT OR REPLACE INTO hash_items (key, field, value) VALUES (?1, ?2, ?3)", &[&key, &field, &value]).unwrap();
+    }
+
+    fn find_zset_score(connection: &rusqlite::Connection, key: &[u8], member: &[u8]) -> Option<f64> {
+        let mut statement = connection.prepare("SELECT score FROM zset_items WHERE key = ?1 AND member = ?2").unwrap();
+
+        match statement.query_row(&[&key, &member], |row| row.get(0)) {
+            Ok(score)                                 => Some(score),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e)                                    => panic!("{}", e)
+        }
+    }
+
+    fn zset_values(items: Vec<(Vec<u8>, f64)>, with_scores: bool) -> Value {
+        let mut values = vec![];
+
+        for (member, score) in items {
+            values.push(Value::BufBulk(member));
+
+            if with_scores {
+                values.push(Value::BufBulk(Command::format_score(score).into_bytes()));
+            }
+        }
+
+        Value::Array(values)
+    }
+
+    fn format_score(score: f64) -> String {
+        format!("{}", score)
+    }
+}
+
+#[cfg(test)]
+mod tests {
     use super::Command;
+    use super::CommandError;
+    use super::KeySpec;
+    use super::{CommandSettings, COMMAND_SETTINGS, FIRST_KEY};
     use super::Action;
     use super::rusqlite;
     use super::resp::Value;
+    use checkout::Reservation;
+    use config::{Config, EvictionPolicy};
     use connection::Connectionable;
-    use std::sync::{Arc, Mutex, Condvar};
-    use std::time::Instant;
+    use pubsub::{Outbox, Overflow, PubSub, Subscription};
+    use push_waiters::PushWaiters;
+    use read_cache::ReadCache;
+    use stats::Stats;
+    use storage::{SqliteStorage, Storage};
+    use set_up_tables;
+    use std::env;
+    use std::fs;
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::time::{Instant, Duration};
     use std::str;
+    use std::thread;
+
+    struct FakeConnection {
+        sqlite_connection_mutex: Arc<Mutex<rusqlite::Connection>>,
+        push_waiters: Arc<PushWaiters>,
+        config: Config,
+        read_cache: Arc<Mutex<ReadCache<Value>>>,
+        stats: Stats,
+        pubsub: Arc<PubSub>,
+        client_name: Mutex<Option<Vec<u8>>>,
+        storage: Arc<Storage>,
+        reservation: Arc<Reservation>,
+        authenticated: bool,
+    }
+
+    impl Connectionable for FakeConnection {
+        fn get_config(&self) -> &Config { &self.config }
+        fn send_to_command_log(&self, _command: String) {}
+        fn send_to_replication_log(&self, _command: Vec<Vec<u8>>) {}
+        fn get_push_waiters(&self) -> Arc<PushWaiters> { self.push_waiters.clone() }
+        fn get_sqlite_connection_mutex(&self) -> &Arc<Mutex<rusqlite::Connection>> { &self.sqlite_connection_mutex }
+        fn get_read_cache(&self) -> &Arc<Mutex<ReadCache<Value>>> { &self.read_cache }
+        fn get_stats(&self) -> &Stats { &self.stats }
+        fn get_pubsub(&self) -> &PubSub { &self.pubsub }
+        fn get_client_name(&self) -> &Mutex<Option<Vec<u8>>> { &self.client_name }
+        fn get_storage(&self) -> &Storage { &*self.storage }
+        fn get_reservation(&self) -> &Reservation { &self.reservation }
+
+        fn is_stream_alive(&self) -> bool { true }
+        fn is_authenticated(&self) -> bool { self.authenticated }
+    }
+
+    impl FakeConnection {
+        pub fn new() -> FakeConnection {
+            let sqlite_connection_mutex = FakeConnection::make_sqlite_connection_mutex();
+            let push_waiters = Arc::new(PushWaiters::default());
+
+            FakeConnection {
+                sqlite_connection_mutex: sqlite_connection_mutex,
+                push_waiters:            push_waiters,
+                config:                  Config::default(),
+                read_cache:              Arc::new(Mutex::new(ReadCache::new(0))),
+                stats:                   Stats::default(),
+                pubsub:                  Arc::new(PubSub::default()),
+                client_name:             Mutex::new(None),
+                storage:                 Arc::new(SqliteStorage),
+                reservation:             Arc::new(Reservation::default()),
+                authenticated:           true,
+            }
+        }
+
+        // another client of the same server
+        fn sharing(&self) -> FakeConnection {
+            FakeConnection {
+                sqlite_connection_mutex: self.sqlite_connection_mutex.clone(),
+                push_waiters:            self.push_waiters.clone(),
+                config:                  Config::default(),
+                read_cache:              Arc::new(Mutex::new(ReadCache::new(0))),
+                stats:                   Stats::default(),
+                pubsub:                  Arc::new(PubSub::default()),
+                client_name:             Mutex::new(None),
+                storage:                 self.storage.clone(),
+                reservation:             self.reservation.clone(),
+                authenticated:           true,
+            }
+        }
+
+        fn make_sqlite_connection_mutex() -> Arc<Mutex<rusqlite::Connection>> {
+            let connection = rusqlite::Connection::open("test.sqlite3").unwrap();
+            connection.execute("DROP TABLE list_items", &[]).ok();
+            connection.execute("CREATE TABLE list_items (id integer primary key autoincrement, key string, value blob, position integer)", &[]).unwrap();
+            connection.execute("CREATE INDEX list_items_key ON list_items(key, position)", &[]).unwrap();
+            connection.execute("INSERT INTO list_items (key, value, position) VALUES (X'74657374', X'616263', -4), (X'74657374', X'646566', -5)", &[]).unwrap();
+
+            connection.execute("DROP TABLE zset_items", &[]).ok();
+            connection.execute("CREATE TABLE zset_items (id integer primary key autoincrement, key blob, member blob, score real)", &[]).unwrap();
+            connection.execute("CREATE UNIQUE INDEX zset_items_key_member ON zset_items(key, member)", &[]).unwrap();
+
+            connection.execute("DROP TABLE hash_items", &[]).ok();
+            connection.execute("CREATE TABLE hash_items (id integer primary key autoincrement, key blob, field blob, value blob)", &[]).unwrap();
+            connection.execute("CREATE UNIQUE INDEX hash_items_key_field ON hash_items(key, field)", &[]).unwrap();
+
+            connection.execute("DROP TABLE key_meta", &[]).ok();
+            connection.execute("CREATE TABLE key_meta (id integer primary key autoincrement, key blob, expires_at integer)", &[]).unwrap();
+            connection.execute("CREATE UNIQUE INDEX key_meta_key ON key_meta(key)", &[]).unwrap();
+
+            connection.execute("DROP TABLE blueis", &[]).ok();
+            connection.execute("CREATE TABLE blueis (id integer primary key autoincrement, key string, value blob)", &[]).unwrap();
+            connection.execute("CREATE UNIQUE INDEX blueis_key_index ON blueis(key)", &[]).unwrap();
+            connection.execute("INSERT INTO blueis (key, value) VALUES ('version', '1')", &[]).unwrap();
+
+            Arc::new(Mutex::new(connection))
+        }
+    }
+
+    fn make_connection() -> FakeConnection { FakeConnection::new() }
+
+    fn add_more_items(connection: &FakeConnection) {
+        let sqlite_connection = connection.get_sqlite_connection_mutex().lock().unwrap();
+
+        sqlite_connection.execute("INSERT INTO list_items (key, value, position) VALUES (X'74657374', X'676869', -6), (X'74657374', X'6A6B6C', -7), (X'74657374', X'6D6E6F', -8), (X'74657374', X'707172', -9), (X'74657375', X'616263', 1)", &[]).unwrap();
+    }
+
+    fn make_command<'a>(name: &'static str, arguments: &[&'a str], connection: &'a FakeConnection) -> Command<'a> {
+        Command {
+            name:       name,
+            arguments:  arguments.to_vec().iter().map(|arg| arg.as_bytes()).collect(),
+            connection: connection as &Connectionable
+        }
+    }
+
+    fn run_command<'a>(connection: &FakeConnection, name: &'static str, arguments: &[&'a str], expect_action: Action) -> Value {
+        let mut command = make_command(name, arguments, connection);
+        let (value, action) = command.execute();
+        assert_eq!(action, expect_action);
+        value
+    }
+
+    // for arguments that aren't UTF-8
+    fn run_binary_command(connection: &FakeConnection, name: &'static str, arguments: &[&[u8]]) -> Value {
+        let mut command = Command { name: name, arguments: arguments.to_vec(), connection: connection as &Connectionable };
+        command.execute().0
+    }
+
+    fn list_key(key: &'static str, connection: &FakeConnection) -> Vec<String> {
+        let sqlite_connection = connection.get_sqlite_connection_mutex().lock().unwrap();
+        let values = connection.storage.range(&sqlite_connection, key.as_bytes(), 0, -1);
+        values.into_iter().map(|value| String::from_utf8(value).unwrap()).collect()
+    }
+
+    #[test]
+    fn errors_with_their_own_prefix_are_not_prefixed_with_err() {
+        let error = CommandError::Prefixed("WRONGTYPE", "Operation against a key holding the wrong kind of value".to_string());
+        assert_eq!(error.to_value().encode(), b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_vec());
+
+        let error = CommandError::from("syntax error");
+        assert_eq!(error.to_value().encode(), b"-ERR syntax error\r\n".to_vec());
+    }
+
+    fn version_rows(connection: &FakeConnection) -> i64 {
+        let connection = connection.get_sqlite_connection_mutex().lock().unwrap();
+        connection.query_row("SELECT COUNT(*) FROM blueis WHERE key = 'version'", &[], |row| row.get(0)).unwrap()
+    }
+
+    fn keyspace_line(connection: &FakeConnection) -> Option<String> {
+        let info = match run_command(connection, "INFO", &[], Action::Continue) {
+            Value::BufBulk(info) => String::from_utf8(info).unwrap(),
+            _                    => panic!("expected a bulk string")
+        };
+
+        let keyspace = info.split("# Keyspace\r\n").nth(1).unwrap();
+        keyspace.split("\r\n").next().filter(|line| !line.is_empty()).map(|line| line.to_string())
+    }
+
+    fn info_section_names(connection: &FakeConnection, arguments: &[&str]) -> Vec<String> {
+        let info = match run_command(connection, "INFO", arguments, Action::Continue) {
+            Value::BufBulk(info) => String::from_utf8(info).unwrap(),
+            _                    => panic!("expected a bulk string")
+        };
+
+        info.split("\r\n").filter(|line| line.starts_with("# ")).map(|line| line[2..].to_string()).collect()
+    }
+
+    #[test]
+    fn info_reports_one_section_or_every_section() {
+        let c = make_connection();
+
+        assert_eq!(info_section_names(&c, &[]), vec!["Server", "Clients", "Stats", "Keyspace"]);
+        assert_eq!(info_section_names(&c, &["everything"]), vec!["Server", "Clients", "Stats", "Keyspace"]);
+        assert_eq!(info_section_names(&c, &["Keyspace"]), vec!["Keyspace"]);
+        assert_eq!(info_section_names(&c, &["nope"]), Vec::<String>::new());
+        assert_eq!(run_command(&c, "INFO", &["server", "clients"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+
+        match run_command(&c, "INFO", &["server"], Action::Continue) {
+            Value::BufBulk(info) => assert_eq!(String::from_utf8(info).unwrap(), format!("# Server\r\nredis_version:{}\r\nblueis_version:1\r\nredis_mode:standalone\r\n", env!("CARGO_PKG_VERSION"))),
+            _                    => panic!("expected a bulk string")
+        }
+    }
+
+    #[test]
+    fn info_keyspace_counts_keys_and_expiries() {
+        let c = make_connection();
+
+        assert_eq!(keyspace_line(&c), Some("db0:keys=1,expires=0,avg_ttl=0".to_string()));
+
+        run_command(&c, "HSET", &["hash", "a", "1"], Action::Continue);
+        run_command(&c, "SETEX", &["greeting", "100", "hello"], Action::Continue);
+        run_command(&c, "PEXPIRE", &["hash", "1"], Action::Continue);
+        thread::sleep(Duration::from_millis(20));
+
+        // the hash has expired without being swept, and the greeting's TTL has only just started to count down
+        let line = keyspace_line(&c).unwrap();
+        assert!(line.starts_with("db0:keys=2,expires=1,avg_ttl="));
+
+        let average_ttl: i64 = line.split("avg_ttl=").nth(1).unwrap().parse().unwrap();
+        assert!(average_ttl > 99000 && average_ttl <= 100000);
+
+        run_command(&c, "FLUSHDB", &[], Action::Continue);
+        assert_eq!(keyspace_line(&c), None);
+    }
+
+    #[test]
+    fn flushdb() {
+        let c = make_connection();
+
+        run_command(&c, "ZADD", &["zset", "1", "a"], Action::Continue);
+
+        assert_eq!(run_command(&c, "FLUSHDB", &[], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(list_key("test", &c), vec![] as Vec<String>);
+        assert_eq!(run_command(&c, "ZCARD", &["zset"], Action::Continue), Value::Integer(0));
+        assert_eq!(version_rows(&c), 1);
+
+        run_command(&c, "RPUSH", &["test", "abc"], Action::Continue);
+        assert_eq!(run_command(&c, "FLUSHALL", &["sync"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(list_key("test", &c), vec![] as Vec<String>);
+
+        assert_eq!(run_command(&c, "FLUSHDB", &["LATER"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(run_command(&c, "FLUSHDB", &["ASYNC", "SYNC"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+    }
+
+    // the next startup checks the version row, so it has to survive a flush with the value it had
+    #[test]
+    fn the_database_still_starts_after_a_flush() {
+        let c = make_connection();
+
+        run_command(&c, "SET", &["greeting", "hello"], Action::Continue);
+        assert_eq!(run_command(&c, "FLUSHDB", &[], Action::Continue), Value::String("OK".to_string()));
+
+        let connection = c.get_sqlite_connection_mutex().lock().unwrap();
+        set_up_tables(&connection);
+
+        let version: String = connection.query_row("SELECT value FROM blueis WHERE key = 'version'", &[], |row| row.get(0)).unwrap();
+        assert_eq!(version, "1");
+    }
+
+    #[test]
+    fn flushall_keeps_blueis_own_rows() {
+        let c = make_connection();
+
+        c.get_sqlite_connection_mutex().lock().unwrap().execute("INSERT INTO blueis (key, value) VALUES ('internal', 'kept')", &[]).unwrap();
+        run_command(&c, "SET", &["version", "a client's key of the same name"], Action::Continue);
+
+        assert_eq!(run_command(&c, "FLUSHALL", &[], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(run_command(&c, "DBSIZE", &[], Action::Continue), Value::Integer(0));
+
+        let connection = c.get_sqlite_connection_mutex().lock().unwrap();
+        set_up_tables(&connection);
+
+        let rows: Vec<(String, String)> = connection.prepare("SELECT key, value FROM blueis ORDER BY key").unwrap()
+            .query_map(&[], |row| (row.get(0), row.get(1))).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(rows, vec![("internal".to_string(), "kept".to_string()), ("version".to_string(), "1".to_string())]);
+    }
+
+    #[test]
+    fn flushdb_async_clears_existing_data_in_the_background() {
+        // a database of its own, as the background flush can outlive the test
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        set_up_tables(&connection);
+
+        let c = FakeConnection {
+            sqlite_connection_mutex: Arc::new(Mutex::new(connection)),
+            push_waiters:            Arc::new(PushWaiters::default()),
+            config:                  Config::default(),
+            read_cache:              Arc::new(Mutex::new(ReadCache::new(0))),
+            stats:                   Stats::default(),
+            pubsub:                  Arc::new(PubSub::default()),
+            client_name:             Mutex::new(None),
+            storage:                 Arc::new(SqliteStorage),
+            reservation:             Arc::new(Reservation::default()),
+            authenticated:           true,
+        };
+
+        let values: Vec<String> = (0..2500).map(|index| index.to_string()).collect();
+        let mut arguments = vec!["big"];
+        arguments.extend(values.iter().map(|value| value.as_str()));
+        run_command(&c, "RPUSH", &arguments, Action::Continue);
+
+        run_command(&c, "SET", &["greeting", "hello"], Action::Continue);
+
+        assert_eq!(run_command(&c, "FLUSHDB", &["ASYNC"], Action::Continue), Value::String("OK".to_string()));
+
+        // the flushed keys are gone straight away, though their rows are still being deleted
+        assert_eq!(run_command(&c, "RPUSH", &["big", "x"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "LRANGE", &["big", "0", "-1"], Action::Continue), bulk_strings(&["x"]));
+        assert_eq!(run_command(&c, "EXISTS", &["greeting"], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "DBSIZE", &[], Action::Continue), Value::Integer(1));
+        assert_eq!(version_rows(&c), 1);
+
+        let start = Instant::now();
+
+        while flushing_tables(&c) > 0 {
+            assert!(start.elapsed().as_secs() < 5, "the flush did not finish");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(run_command(&c, "LRANGE", &["big", "0", "-1"], Action::Continue), bulk_strings(&["x"]));
+        assert_eq!(run_command(&c, "RPUSH", &["big", "y"], Action::Continue), Value::Integer(2));
+
+        // and the new tables have the indexes a new database has
+        let index_names = |connection: &rusqlite::Connection| {
+            let mut statement = connection.prepare("SELECT tbl_name || '.' || name FROM sqlite_master WHERE type = 'index' ORDER BY 1").unwrap();
+            let names = statement.query_map(&[], |row| row.get(0)).unwrap().collect::<Result<Vec<String>, _>>().unwrap();
+            names
+        };
+
+        let fresh = rusqlite::Connection::open_in_memory().unwrap();
+        set_up_tables(&fresh);
+
+        assert_eq!(index_names(&c.get_sqlite_connection_mutex().lock().unwrap()), index_names(&fresh));
+    }
+
+    fn flushing_tables(connection: &FakeConnection) -> i64 {
+        let connection = connection.get_sqlite_connection_mutex().lock().unwrap();
+        connection.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name LIKE 'flushing%'", &[], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn tables_left_by_an_unfinished_async_flush_are_dropped() {
+        let c = make_connection();
+
+        {
+            let connection = c.get_sqlite_connection_mutex().lock().unwrap();
+            Command::swap_in_empty_tables(&connection);
+        }
+
+        assert_eq!(flushing_tables(&c), 5);
+        assert_eq!(run_command(&c, "LLEN", &["test"], Action::Continue), Value::Integer(0));
+
+        Command::drop_abandoned_flushes(&c.get_sqlite_connection_mutex().lock().unwrap());
+        assert_eq!(flushing_tables(&c), 0);
+        assert_eq!(version_rows(&c), 1);
+    }
+
+    #[test]
+    fn wait_returns_immediately() {
+        let c = make_connection();
+        let start = Instant::now();
+
+        assert_eq!(run_command(&c, "WAIT", &["1", "1000"], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "WAIT", &["0", "0"], Action::Continue), Value::Integer(0));
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        assert_eq!(run_command(&c, "WAIT", &["one", "1000"], Action::Continue), Value::Error("ERR argument must be an integer".to_string()));
+        assert_eq!(run_command(&c, "WAIT", &["1", "soon"], Action::Continue), Value::Error("ERR argument must be an integer".to_string()));
+        assert_eq!(run_command(&c, "WAIT", &["1", "-1"], Action::Continue), Value::Error("ERR timeout is negative".to_string()));
+    }
+
+    #[test]
+    fn command_getkeys() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYS", "LPUSH", "queue", "a", "b"], Action::Continue), bulk_strings(&["queue"]));
+        assert_eq!(run_command(&c, "COMMAND", &["getkeys", "rpoplpush", "source", "destination"], Action::Continue), bulk_strings(&["source", "destination"]));
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYS", "LMOVE", "source", "destination", "LEFT", "RIGHT"], Action::Continue), bulk_strings(&["source", "destination"]));
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYS", "BLPOP", "first", "second", "0"], Action::Continue), bulk_strings(&["first", "second"]));
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYS", "MPOP", "2", "first", "second", "LEFT"], Action::Continue), bulk_strings(&["first", "second"]));
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYS", "LMPOP", "2", "a", "b", "LEFT", "COUNT", "3"], Action::Continue), bulk_strings(&["a", "b"]));
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYS", "BLMPOP", "0", "1", "a", "RIGHT", "COUNT", "3"], Action::Continue), bulk_strings(&["a"]));
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYS", "LMPOP", "many", "a", "LEFT"], Action::Continue), Value::Error("ERR The command has no key arguments".to_string()));
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYS", "OBJECT", "ENCODING"], Action::Continue), Value::Error("ERR The command has no key arguments".to_string()));
+
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYS", "NOPE", "a"], Action::Continue), Value::Error("ERR Invalid command specified".to_string()));
+        // there's no MSET here to take every other argument as a key; key_indexes_follow_the_step covers that layout
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYS", "MSET", "a", "1", "b", "2"], Action::Continue), Value::Error("ERR Invalid command specified".to_string()));
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYS", "LPUSH", "queue"], Action::Continue), Value::Error("ERR Invalid number of arguments specified for command".to_string()));
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYS", "INFO"], Action::Continue), Value::Error("ERR The command has no key arguments".to_string()));
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYS"], Action::Continue), Value::Error("ERR wrong number of arguments for 'command|getkeys' command".to_string()));
+    }
+
+    #[test]
+    fn command_getkeysandflags() {
+        let c = make_connection();
+
+        let key_and_flag = |key: &str, flag: &str| Value::Array(vec![Value::BufBulk(key.as_bytes().to_vec()), Value::Array(vec![Value::String(flag.to_string())])]);
+
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYSANDFLAGS", "LMOVE", "source", "destination", "LEFT", "RIGHT"], Action::Continue), Value::Array(vec![key_and_flag("source", "RW"), key_and_flag("destination", "OW")]));
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYSANDFLAGS", "LRANGE", "queue", "0", "-1"], Action::Continue), Value::Array(vec![key_and_flag("queue", "RO")]));
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYSANDFLAGS", "SET", "name", "value"], Action::Continue), Value::Array(vec![key_and_flag("name", "OW")]));
+        assert_eq!(run_command(&c, "COMMAND", &["GETKEYSANDFLAGS", "INFO"], Action::Continue), Value::Error("ERR The command has no key arguments".to_string()));
+    }
+
+    #[test]
+    fn command_info() {
+        let c = make_connection();
+
+        let lpush = Value::Array(vec![
+            Value::BufBulk(b"lpush".to_vec()),
+            Value::Integer(-3),
+            Value::Array(vec![Value::String("write".to_string()), Value::String("denyoom".to_string()), Value::String("fast".to_string())]),
+            Value::Integer(1),
+            Value::Integer(1),
+            Value::Integer(1),
+        ]);
+
+        assert_eq!(run_command(&c, "COMMAND", &["INFO", "lpush", "nope"], Action::Continue), Value::Array(vec![lpush, Value::Null]));
+
+        // keys counted by numkeys have no positions to give
+        let lmpop = Value::Array(vec![
+            Value::BufBulk(b"lmpop".to_vec()),
+            Value::Integer(-4),
+            Value::Array(vec![Value::String("write".to_string()), Value::String("movablekeys".to_string())]),
+            Value::Integer(0),
+            Value::Integer(0),
+            Value::Integer(0),
+        ]);
+
+        assert_eq!(run_command(&c, "COMMAND", &["INFO", "lmpop"], Action::Continue), Value::Array(vec![lmpop]));
+
+        match run_command(&c, "COMMAND", &["INFO", "LRANGE", "BLPOP", "FLUSHDB"], Action::Continue) {
+            Value::Array(replies) => {
+                let arities: Vec<Value> = replies.into_iter().map(|reply| match reply { Value::Array(fields) => fields[1].clone(), _ => panic!() }).collect();
+                assert_eq!(arities, vec![Value::Integer(4), Value::Integer(-3), Value::Integer(-1)]);
+            }
+
+            _ => panic!("expected an array")
+        }
+
+        match run_command(&c, "COMMAND", &["INFO"], Action::Continue) {
+            Value::Array(replies) => assert!(replies.len() > 40),
+            _                     => panic!("expected an array")
+        }
+    }
+
+    #[test]
+    fn config_get_and_set() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "CONFIG", &["GET", "max-threads"], Action::Continue), bulk_strings(&["max-threads", "0"]));
+        assert_eq!(run_command(&c, "CONFIG", &["GET", "nope"], Action::Continue), Value::Array(vec![]));
+        assert_eq!(run_command(&c, "CONFIG", &["SET", "max-threads", "8"], Action::Continue), Value::Error("ERR CONFIG SET failed (possibly related to argument 'max-threads') - can't set immutable config".to_string()));
+        assert_eq!(run_command(&c, "CONFIG", &["SET", "nope", "8"], Action::Continue), Value::Error("ERR Unknown option or number of arguments for CONFIG SET - 'nope'".to_string()));
+    }
+
+    #[test]
+    fn config_set_without_a_value() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "CONFIG", &["SET", "max-threads"], Action::Continue), Value::Error("ERR wrong number of arguments for 'config|set' command".to_string()));
+        assert_eq!(run_command(&c, "CONFIG", &["set"], Action::Continue), Value::Error("ERR wrong number of arguments for 'config|set' command".to_string()));
+        assert_eq!(run_command(&c, "CONFIG", &["REWRITE"], Action::Continue), Value::Error("ERR unknown subcommand 'REWRITE'. Try CONFIG HELP.".to_string()));
+    }
+
+    #[test]
+    fn client_setname_and_getname() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "CLIENT", &["GETNAME"], Action::Continue), Value::Null);
+        assert_eq!(run_command(&c, "CLIENT", &["SETNAME", "worker-1"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(run_command(&c, "CLIENT", &["GETNAME"], Action::Continue), Value::BufBulk(b"worker-1".to_vec()));
+        assert_eq!(run_command(&c, "CLIENT", &["SETNAME", "worker 1"], Action::Continue), Value::Error("ERR Client names cannot contain spaces, newlines or special characters.".to_string()));
+        assert_eq!(run_command(&c, "CLIENT", &["SETNAME", ""], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(run_command(&c, "CLIENT", &["GETNAME"], Action::Continue), Value::Null);
+    }
+
+    #[test]
+    fn client_setname_without_a_name() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "CLIENT", &["SETNAME"], Action::Continue), Value::Error("ERR wrong number of arguments for 'client|setname' command".to_string()));
+        assert_eq!(run_command(&c, "CLIENT", &["GETNAME", "extra"], Action::Continue), Value::Error("ERR wrong number of arguments for 'client|getname' command".to_string()));
+    }
+
+    #[test]
+    fn no_command_is_registered_twice() {
+        assert_eq!(Command::duplicate_name(&COMMAND_SETTINGS), None);
+        Command::check_settings();
+    }
+
+    #[test]
+    fn a_command_registered_twice_is_found() {
+        let settings = [
+            CommandSettings { name: "LLEN", argument_count: 1, handler: Command::llen, keys: FIRST_KEY, flags: &[] },
+            CommandSettings { name: "LPOP", argument_count: 1, handler: Command::lpop, keys: FIRST_KEY, flags: &[] },
+            CommandSettings { name: "llen", argument_count: 1, handler: Command::llen, keys: FIRST_KEY, flags: &[] },
+        ];
+
+        assert_eq!(Command::duplicate_name(&settings), Some("llen"));
+        assert_eq!(Command::duplicate_name(&settings[..2]), None);
+    }
+
+    #[test]
+    fn reads_count_keyspace_hits_and_misses() {
+        let c = make_connection();
+        run_command(&c, "SET", &["present", "value"], Action::Continue);
+
+        run_command(&c, "GET", &["present"], Action::Continue);
+        run_command(&c, "GET", &["absent"], Action::Continue);
+        run_command(&c, "GET", &["absent"], Action::Continue);
+        run_command(&c, "LRANGE", &["test", "0", "-1"], Action::Continue);
+
+        // writes aren't lookups
+        run_command(&c, "RPUSH", &["absent", "a"], Action::Continue);
+
+        assert_eq!(Stats::get(&c.get_stats().keyspace_hits), 2);
+        assert_eq!(Stats::get(&c.get_stats().keyspace_misses), 2);
+
+        run_command(&c, "CONFIG", &["RESETSTAT"], Action::Continue);
+        assert_eq!(Stats::get(&c.get_stats().keyspace_hits), 0);
+        assert_eq!(Stats::get(&c.get_stats().keyspace_misses), 0);
+    }
+
+    // a client's thread panicking with the connection locked poisons the lock for every other client
+    fn panic_holding_the_connection<F>(c: &FakeConnection, before_panicking: F) where F: FnOnce(&rusqlite::Connection) + Send + 'static {
+        let mutex = c.get_sqlite_connection_mutex().clone();
+
+        assert!(thread::spawn(move || {
+            let connection = mutex.lock().unwrap();
+            before_panicking(&connection);
+            panic!("failing while holding the connection");
+        }).join().is_err());
+    }
+
+    #[test]
+    fn commands_still_run_once_a_command_has_panicked() {
+        let c = make_connection();
+        panic_holding_the_connection(&c, |_| {});
+
+        assert_eq!(run_command(&c, "RPUSH", &["test", "ghi"], Action::Continue), Value::Integer(3));
+        assert!(!c.get_sqlite_connection_mutex().is_poisoned());
+        assert_eq!(run_command(&c, "LINDEX", &["test", "-1"], Action::Continue), Value::BufBulk(b"ghi".to_vec()));
+    }
+
+    #[test]
+    fn a_transaction_left_open_by_a_panic_is_rolled_back() {
+        let c = make_connection();
+        panic_holding_the_connection(&c, |connection| {
+            connection.execute_batch("BEGIN; DELETE FROM list_items").unwrap();
+        });
+
+        assert_eq!(run_command(&c, "LLEN", &["test"], Action::Continue), Value::Error("TRYAGAIN a command failed partway through, and what it had changed has been rolled back".to_string()));
+        assert_eq!(run_command(&c, "LLEN", &["test"], Action::Continue), Value::Integer(2));
+        assert!(!c.get_sqlite_connection_mutex().is_poisoned());
+    }
+
+    #[test]
+    fn key_indexes_follow_the_step() {
+        // laid out like MSET, with every other argument a key
+        let keys = KeySpec { first: 1, last: -1, step: 2, numkeys: 0 };
+        let arguments: Vec<&[u8]> = vec![b"a", b"1", b"b", b"2", b"c", b"3"];
+        assert_eq!(Command::key_indexes(&keys, &arguments), vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn publish_counts_the_subscribers_reached() {
+        let c = make_connection();
+        assert_eq!(run_command(&c, "PUBLISH", &["news", "hello"], Action::Continue), Value::Integer(0));
+    }
+
+    #[test]
+    fn subscribe_hands_the_connection_over() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "SUBSCRIBE", &["news"], Action::StartSubscribe), Value::Null);
+        assert_eq!(run_command(&c, "UNSUBSCRIBE", &[], Action::StartSubscribe), Value::Null);
+        assert_eq!(run_command(&c, "SUBSCRIBE", &[], Action::Continue), Value::Error("ERR wrong number of arguments".to_string()));
+    }
+
+    #[test]
+    fn ping() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "PING", &[], Action::Continue), Value::String("PONG".to_string()));
+        assert_eq!(run_command(&c, "PING", &["hello"], Action::Continue), Value::BufBulk(b"hello".to_vec()));
+        assert_eq!(run_command(&c, "PING", &["hello", "there"], Action::Continue), Value::Error("ERR wrong number of arguments".to_string()));
+    }
+
+    #[test]
+    fn echo() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "ECHO", &["hello"], Action::Continue), Value::BufBulk(b"hello".to_vec()));
+        assert_eq!(run_command(&c, "ECHO", &[], Action::Continue), Value::Error("ERR wrong number of arguments".to_string()));
+        assert_eq!(run_command(&c, "ECHO", &["hello", "there"], Action::Continue), Value::Error("ERR wrong number of arguments".to_string()));
+    }
+
+    #[test]
+    fn select_accepts_only_database_0() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "SELECT", &["0"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(run_command(&c, "SELECT", &["1"], Action::Continue), Value::Error("ERR DB index is out of range".to_string()));
+        assert_eq!(run_command(&c, "CONFIG", &["GET", "databases"], Action::Continue), Value::Array(vec![Value::BufBulk(b"databases".to_vec()), Value::BufBulk(b"1".to_vec())]));
+        assert_eq!(run_command(&c, "SELECT", &["-1"], Action::Continue), Value::Error("ERR DB index is out of range".to_string()));
+    }
+
+    #[test]
+    fn quit() {
+        let c = make_connection();
+        assert_eq!(run_command(&c, "QUIT", &[], Action::HangUp), Value::String("OK".to_string()));
+    }
+
+    #[test]
+    fn llen() {
+        let c = make_connection();
+        assert_eq!(run_command(&c, "LLEN", &["test"], Action::Continue), Value::Integer(2));
+        assert_eq!(run_command(&c, "LLEN", &["other"], Action::Continue), Value::Integer(0));
+    }
+
+    #[test]
+    fn lpop() {
+        let c = make_connection();
+        assert_eq!(run_command(&c, "LPOP", &["test"], Action::Continue), Value::BufBulk("def".to_string().into_bytes()));
+        assert_eq!(run_command(&c, "LPOP", &["test"], Action::Continue), Value::BufBulk("abc".to_string().into_bytes()));
+        assert_eq!(run_command(&c, "LPOP", &["test"], Action::Continue), Value::Null);
+
+        assert_eq!(run_command(&c, "LPOP", &["other"], Action::Continue), Value::Null);
+    }
+
+    #[test]
+    fn lpop_and_rpop_with_a_count() {
+        let c = make_connection();
+        run_command(&c, "RPUSH", &["test", "ghi", "jkl", "mno"], Action::Continue);
+
+        assert_eq!(run_command(&c, "LPOP", &["test", "2"], Action::Continue), Value::Array(vec![Value::BufBulk(b"def".to_vec()), Value::BufBulk(b"abc".to_vec())]));
+        assert_eq!(run_command(&c, "RPOP", &["test", "1"], Action::Continue), Value::Array(vec![Value::BufBulk(b"mno".to_vec())]));
+        assert_eq!(run_command(&c, "RPOP", &["test", "0"], Action::Continue), Value::Array(vec![]));
+        assert_eq!(run_command(&c, "RPOP", &["test", "5"], Action::Continue), Value::Array(vec![Value::BufBulk(b"jkl".to_vec()), Value::BufBulk(b"ghi".to_vec())]));
+        assert_eq!(run_command(&c, "LPOP", &["test", "2"], Action::Continue), Value::NullArray);
+        assert_eq!(run_command(&c, "LPOP", &["test", "0"], Action::Continue), Value::NullArray);
+    }
+
+    #[test]
+    fn lpop_rejects_a_bad_count() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "LPOP", &["test", "-1"], Action::Continue), Value::Error("ERR value is out of range, must be positive".to_string()));
+        assert_eq!(run_command(&c, "LPOP", &["test", "one"], Action::Continue), Value::Error("ERR argument must be an integer".to_string()));
+        assert_eq!(run_command(&c, "LPOP", &["test", "1", "2"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(list_key("test", &c), vec!["def", "abc"]);
+    }
+
+    #[test]
+    fn popping_the_last_element_notifies_a_pop_and_a_del() {
+        let c = make_connection();
+        let outbox = Arc::new(Outbox::new(0, Overflow::Disconnect, Box::new(|| {})));
+        let mut subscription = Subscription::new(c.pubsub.clone(), outbox.clone());
+        let received = |count: usize| (0..count).map(|_| outbox.pop().unwrap()).collect::<Vec<_>>();
+
+        for channel in &["__keyevent@0__:lpop", "__keyevent@0__:rpop", "__keyevent@0__:del", "__keyspace@0__:test"] {
+            subscription.subscribe(channel.as_bytes());
+        }
+
+        let message = |channel: &str, payload: &str| Value::Array(vec![
+            Value::BufBulk(b"message".to_vec()),
+            Value::BufBulk(channel.as_bytes().to_vec()),
+            Value::BufBulk(payload.as_bytes().to_vec()),
+        ]);
+
+        run_command(&c, "LPOP", &["test"], Action::Continue);
+        assert_eq!(received(2), vec![
+            message("__keyspace@0__:test", "lpop"),
+            message("__keyevent@0__:lpop", "test"),
+        ]);
+
+        run_command(&c, "RPOP", &["test"], Action::Continue);
+        assert_eq!(received(4), vec![
+            message("__keyspace@0__:test", "rpop"),
+            message("__keyevent@0__:rpop", "test"),
+            message("__keyspace@0__:test", "del"),
+            message("__keyevent@0__:del", "test"),
+        ]);
+
+        assert_eq!(run_command(&c, "LPOP", &["test"], Action::Continue), Value::Null);
+
+        // nothing was published ahead of this
+        outbox.push(Value::NullArray);
+        assert_eq!(received(1), vec![Value::NullArray]);
+    }
+
+    #[test]
+    fn rpop() {
+        let c = make_connection();
+        assert_eq!(run_command(&c, "RPOP", &["test"], Action::Continue), Value::BufBulk("abc".to_string().into_bytes()));
+        assert_eq!(run_command(&c, "RPOP", &["test"], Action::Continue), Value::BufBulk("def".to_string().into_bytes()));
+        assert_eq!(run_command(&c, "RPOP", &["test"], Action::Continue), Value::Null);
+
+        assert_eq!(run_command(&c, "RPOP", &["other"], Action::Continue), Value::Null);
+    }
+
+    #[test]
+    fn lpush() {
+        let c = make_connection();
+        assert_eq!(run_command(&c, "LPUSH", &["test", "ghi"], Action::Continue), Value::Integer(3));
+        assert_eq!(run_command(&c, "LPUSH", &["test", "jkl"], Action::Continue), Value::Integer(4));
+        assert_eq!(list_key("test", &c), vec!["jkl", "ghi", "def", "abc"]);
+
+        assert_eq!(run_command(&c, "LPUSH", &["other", "pqr"], Action::Continue), Value::Integer(1));
+        assert_eq!(list_key("other", &c), vec!["pqr"]);
+    }
+
+    #[test]
+    fn lpushx() {
+        let c = make_connection();
+        assert_eq!(run_command(&c, "LPUSHX", &["test", "ghi"], Action::Continue), Value::Integer(3));
+        assert_eq!(run_command(&c, "LPUSHX", &["test", "jkl"], Action::Continue), Value::Integer(4));
+        assert_eq!(list_key("test", &c), vec!["jkl", "ghi", "def", "abc"]);
+
+        assert_eq!(run_command(&c, "LPUSHX", &["other", "pqr"], Action::Continue), Value::Integer(0));
+        assert_eq!(list_key("other", &c), vec![] as Vec<String>);
+    }
+
+    #[test]
+    fn rpush() {
+        let c = make_connection();
+        assert_eq!(run_command(&c, "RPUSH", &["test", "ghi"], Action::Continue), Value::Integer(3));
+        assert_eq!(run_command(&c, "RPUSH", &["test", "jkl"], Action::Continue), Value::Integer(4));
+        assert_eq!(list_key("test", &c), vec!["def", "abc", "ghi", "jkl"]);
+
+        assert_eq!(run_command(&c, "RPUSH", &["other", "pqr"], Action::Continue), Value::Integer(1));
+        assert_eq!(list_key("other", &c), vec!["pqr"]);
+    }
+
+    #[test]
+    fn rpushx() {
+        let c = make_connection();
+        assert_eq!(run_command(&c, "RPUSHX", &["test", "ghi"], Action::Continue), Value::Integer(3));
+        assert_eq!(run_command(&c, "RPUSHX", &["test", "jkl"], Action::Continue), Value::Integer(4));
+        assert_eq!(list_key("test", &c), vec!["def", "abc", "ghi", "jkl"]);
+
+        assert_eq!(run_command(&c, "RPUSHX", &["other", "pqr"], Action::Continue), Value::Integer(0));
+        assert_eq!(list_key("other", &c), vec![] as Vec<String>);
+    }
+
+    #[test]
+    fn lpushx_never_recreates_a_list_emptied_concurrently() {
+        let c = make_connection();
+        let popper = FakeConnection {
+            sqlite_connection_mutex: c.sqlite_connection_mutex.clone(),
+            push_waiters:            c.push_waiters.clone(),
+            config:                  Config::default(),
+            read_cache:              Arc::new(Mutex::new(ReadCache::new(0))),
+            stats:                   Stats::default(),
+            pubsub:                  Arc::new(PubSub::default()),
+            client_name:             Mutex::new(None),
+            storage:                 c.storage.clone(),
+            reservation:             c.reservation.clone(),
+            authenticated:           true,
+        };
+
+        // more pops than there could ever be elements, so the list must empty at some point
+        let handle = thread::spawn(move || {
+            for _ in 0..200 {
+                run_command(&popper, "LPOP", &["test"], Action::Continue);
+            }
+        });
+
+        let results: Vec<Value> = (0..100).map(|_| run_command(&c, "LPUSHX", &["test", "x"], Action::Continue)).collect();
+        handle.join().unwrap();
+
+        let first_miss = results.iter().position(|result| *result == Value::Integer(0)).unwrap_or(results.len());
+        assert!(results[first_miss..].iter().all(|result| *result == Value::Integer(0)));
+        assert_eq!(list_key("test", &c), vec![] as Vec<String>);
+    }
+
+    fn unpack(v: Value) -> Vec<String> {
+        match v {
+            Value::Array(array) => array.iter().map(|bufbulk|
+                match *bufbulk {
+                    Value::BufBulk(ref data) => String::from_utf8(data.clone()).unwrap(),
+                    _ => panic!("invalid")
+                }
+            ).collect(),
+            _ => panic!("invalid")
+        }
+    }
+
+    fn run_lrange<'a>(arguments: &[&'a str], connection: &FakeConnection) -> Vec<String> {
+        unpack(run_command(&connection, "LRANGE", arguments, Action::Continue))
+    }
+
+    struct LrangeCase<'a> {
+        arguments: &'a [&'a str],
+        expected: Vec<&'a str>
+    }
+
+    #[test]
+    fn lexport() {
+        let c = make_connection();
+        add_more_items(&c);
+
+        assert_eq!(run_command(&c, "LEXPORT", &["test"], Action::Continue), Value::BufBulk(b"pqr\nmno\njkl\nghi\ndef\nabc\n".to_vec()));
+        assert_eq!(run_command(&c, "LEXPORT", &["test", "nul"], Action::Continue), Value::BufBulk(b"pqr\0mno\0jkl\0ghi\0def\0abc\0".to_vec()));
+        assert_eq!(run_command(&c, "LEXPORT", &["other"], Action::Continue), Value::BufBulk(vec![]));
+        assert_eq!(run_command(&c, "LEXPORT", &["test", "TAB"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+    }
+
+    #[test]
+    fn lrange() {
+        let c = make_connection();
+        add_more_items(&c);
+
+        let cases = [
+            LrangeCase { arguments: &["test", "0", "-1"], expected: vec!["pqr", "mno", "jkl", "ghi", "def", "abc"] },
+            LrangeCase { arguments: &["test", "0", "2"], expected: vec!["pqr", "mno", "jkl"] },
+            LrangeCase { arguments: &["test", "3", "-1"], expected: vec!["ghi", "def", "abc"] },
+            LrangeCase { arguments: &["test", "9", "-1"], expected: vec![] as Vec<&str> },
+            LrangeCase { arguments: &["test", "3", "2"], expected: vec![] as Vec<&str> },
+            LrangeCase { arguments: &["test", "-100", "-80"], expected: vec![] as Vec<&str> },
+            LrangeCase { arguments: &["other", "0", "-1"], expected: vec![] as Vec<&str> },
+            LrangeCase { arguments: &["test", "3", "3"], expected: vec!["ghi"] },
+            LrangeCase { arguments: &["test", "3", "4"], expected: vec!["ghi", "def"] },
+            LrangeCase { arguments: &["test", "3", "5"], expected: vec!["ghi", "def", "abc"] },
+            LrangeCase { arguments: &["test", "3", "6"], expected: vec!["ghi", "def", "abc"] },
+            LrangeCase { arguments: &["test", "3", "-3"], expected: vec!["ghi"] },
+            LrangeCase { arguments: &["test", "3", "-2"], expected: vec!["ghi", "def"] },
+            LrangeCase { arguments: &["test", "-3", "3"], expected: vec!["ghi"] },
+            LrangeCase { arguments: &["test", "-3", "4"], expected: vec!["ghi", "def"] },
+            LrangeCase { arguments: &["test", "-3", "-3"], expected: vec!["ghi"] },
+            LrangeCase { arguments: &["test", "-3", "-2"], expected: vec!["ghi", "def"] },
+        ];
+
+        for case in cases.iter() {
+            assert_eq!(run_lrange(case.arguments, &c), case.expected);
+        }
+    }
+
+    #[test]
+    fn lrange_results_are_cached_until_the_list_changes() {
+        let mut c = make_connection();
+        c.read_cache = Arc::new(Mutex::new(ReadCache::new(10)));
+
+        assert_eq!(run_lrange(&["test", "0", "9"], &c), vec!["def", "abc"]);
+
+        // written behind the cache's back, so only a cache miss would see it
+        add_more_items(&c);
+        assert_eq!(run_lrange(&["test", "0", "9"], &c), vec!["def", "abc"]);
+        assert_eq!(run_lrange(&["test", "0", "-1"], &c), vec!["pqr", "mno", "jkl", "ghi", "def", "abc"]);
+
+        run_command(&c, "RPUSH", &["test", "xyz"], Action::Continue);
+        assert_eq!(run_lrange(&["test", "0", "9"], &c), vec!["pqr", "mno", "jkl", "ghi", "def", "abc", "xyz"]);
+
+        run_command(&c, "LPOP", &["test"], Action::Continue);
+        assert_eq!(run_lrange(&["test", "0", "9"], &c), vec!["mno", "jkl", "ghi", "def", "abc", "xyz"]);
+
+        run_command(&c, "RPOPLPUSH", &["other", "test"], Action::Continue);
+        run_command(&c, "RPOPLPUSH", &["test", "other"], Action::Continue);
+        assert_eq!(run_lrange(&["test", "0", "9"], &c), vec!["mno", "jkl", "ghi", "def", "abc"]);
+    }
+
+    #[test]
+    fn ltrim() {
+        let c = make_connection();
+        add_more_items(&c);
+
+        assert_eq!(run_command(&c, "LTRIM", &["test", "0", "-1"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(list_key("test", &c), vec!["pqr", "mno", "jkl", "ghi", "def", "abc"]);
+
+        assert_eq!(run_command(&c, "LTRIM", &["test", "1", "-2"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(list_key("test", &c), vec!["mno", "jkl", "ghi", "def"]);
+
+        assert_eq!(run_command(&c, "LTRIM", &["test", "-3", "2"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(list_key("test", &c), vec!["jkl", "ghi"]);
+
+        assert_eq!(run_command(&c, "LTRIM", &["test", "300", "200"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(list_key("test", &c), vec![] as Vec<String>);
+    }
+
+    #[test]
+    fn rpoplpush() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "RPOPLPUSH", &["test", "other"], Action::Continue), Value::BufBulk("abc".to_string().into_bytes()));
+        assert_eq!(list_key("test", &c), vec!["def"]);
+        assert_eq!(list_key("other", &c), vec!["abc"]);
+
+        assert_eq!(run_command(&c, "RPOPLPUSH", &["test", "other"], Action::Continue), Value::BufBulk("def".to_string().into_bytes()));
+        assert_eq!(list_key("test", &c), vec![] as Vec<String>);
+        assert_eq!(list_key("other", &c), vec!["def", "abc"]);
+
+        assert_eq!(run_command(&c, "RPOPLPUSH", &["test", "other"], Action::Continue), Value::Null);
+        assert_eq!(list_key("test", &c), vec![] as Vec<String>);
+        assert_eq!(list_key("other", &c), vec!["def", "abc"]);
+    }
+
+    #[test]
+    fn lmove() {
+        let c = make_connection();
+        assert_eq!(run_command(&c, "LMOVE", &["test", "other", "LEFT", "RIGHT"], Action::Continue), Value::BufBulk(b"def".to_vec()));
+        assert_eq!(run_command(&c, "LMOVE", &["other", "other", "left", "left"], Action::Continue), Value::BufBulk(b"def".to_vec()));
+        assert_eq!(run_command(&c, "LMOVE", &["test", "other", "RIGHT", "LEFT"], Action::Continue), Value::BufBulk(b"abc".to_vec()));
+        assert_eq!(list_key("test", &c), vec![] as Vec<String>);
+        assert_eq!(list_key("other", &c), vec!["abc", "def"]);
+
+        // moving within one list rotates it rather than losing the element
+        assert_eq!(run_command(&c, "LMOVE", &["other", "other", "LEFT", "RIGHT"], Action::Continue), Value::BufBulk(b"abc".to_vec()));
+        assert_eq!(list_key("other", &c), vec!["def", "abc"]);
+
+        assert_eq!(run_command(&c, "LMOVE", &["test", "other", "LEFT", "RIGHT"], Action::Continue), Value::Null);
+        assert_eq!(run_command(&c, "LMOVE", &["other", "test", "UP", "RIGHT"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(list_key("other", &c), vec!["def", "abc"]);
+    }
+
+    #[test]
+    fn lrotate() {
+        let c = make_connection();
+        add_more_items(&c);
+
+        assert_eq!(run_command(&c, "LROTATE", &["test", "RIGHT"], Action::Continue), Value::BufBulk(b"abc".to_vec()));
+        assert_eq!(list_key("test", &c), vec!["abc", "pqr", "mno", "jkl", "ghi", "def"]);
+
+        assert_eq!(run_command(&c, "LROTATE", &["test", "left"], Action::Continue), Value::BufBulk(b"abc".to_vec()));
+        assert_eq!(run_command(&c, "LROTATE", &["test", "LEFT"], Action::Continue), Value::BufBulk(b"pqr".to_vec()));
+        assert_eq!(list_key("test", &c), vec!["mno", "jkl", "ghi", "def", "abc", "pqr"]);
+
+        assert_eq!(run_command(&c, "LROTATE", &["test", "UP"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+    }
+
+    #[test]
+    fn lrotate_single_element_and_empty_lists() {
+        let c = make_connection();
+
+        run_command(&c, "RPUSH", &["single", "only"], Action::Continue);
+
+        assert_eq!(run_command(&c, "LROTATE", &["single", "RIGHT"], Action::Continue), Value::BufBulk(b"only".to_vec()));
+        assert_eq!(run_command(&c, "LROTATE", &["single", "LEFT"], Action::Continue), Value::BufBulk(b"only".to_vec()));
+        assert_eq!(list_key("single", &c), vec!["only"]);
+
+        assert_eq!(run_command(&c, "LROTATE", &["other", "RIGHT"], Action::Continue), Value::Null);
+        assert_eq!(list_key("other", &c), vec![] as Vec<String>);
+    }
+
+    #[test]
+    fn lindex() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "LINDEX", &["test", "0"], Action::Continue), Value::BufBulk("def".to_string().into_bytes()));
+        assert_eq!(run_command(&c, "LINDEX", &["test", "1"], Action::Continue), Value::BufBulk("abc".to_string().into_bytes()));
+        assert_eq!(run_command(&c, "LINDEX", &["test", "2"], Action::Continue), Value::Null);
+        assert_eq!(run_command(&c, "LINDEX", &["test", "-1"], Action::Continue), Value::BufBulk("abc".to_string().into_bytes()));
+        assert_eq!(run_command(&c, "LINDEX", &["test", "-2"], Action::Continue), Value::BufBulk("def".to_string().into_bytes()));
+        assert_eq!(run_command(&c, "LINDEX", &["test", "-3"], Action::Continue), Value::Null);
+    }
+
+    fn integers(values: &[i64]) -> Value {
+        Value::Array(values.iter().map(|value| Value::Integer(*value)).collect())
+    }
+
+    #[test]
+    fn lpos() {
+        let c = make_connection();
+        run_command(&c, "RPUSH", &["letters", "a", "x", "b", "x", "c", "x"], Action::Continue);
+
+        assert_eq!(run_command(&c, "LPOS", &["letters", "x"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "LPOS", &["letters", "x", "RANK", "2"], Action::Continue), Value::Integer(3));
+        assert_eq!(run_command(&c, "LPOS", &["letters", "x", "RANK", "-1"], Action::Continue), Value::Integer(5));
+        assert_eq!(run_command(&c, "LPOS", &["letters", "x", "RANK", "4"], Action::Continue), Value::Null);
+        assert_eq!(run_command(&c, "LPOS", &["letters", "z"], Action::Continue), Value::Null);
+        assert_eq!(run_command(&c, "LPOS", &["letters", "z", "COUNT", "0"], Action::Continue), integers(&[]));
+        assert_eq!(run_command(&c, "LPOS", &["letters", "x", "COUNT", "2"], Action::Continue), integers(&[1, 3]));
+        assert_eq!(run_command(&c, "LPOS", &["letters", "x", "MAXLEN", "2", "COUNT", "0"], Action::Continue), integers(&[1]));
+        assert_eq!(run_command(&c, "LPOS", &["missing", "x"], Action::Continue), Value::Null);
+    }
+
+    #[test]
+    fn lrem() {
+        let c = make_connection();
+        run_command(&c, "RPUSH", &["letters", "x", "a", "x", "b", "x", "c", "x"], Action::Continue);
+
+        assert_eq!(run_command(&c, "LREM", &["letters", "1", "x"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "LREM", &["letters", "-2", "x"], Action::Continue), Value::Integer(2));
+        assert_eq!(list_key("letters", &c), vec!["a", "x", "b", "c"]);
+
+        assert_eq!(run_command(&c, "LREM", &["letters", "0", "x"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "LREM", &["letters", "0", "z"], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "LREM", &["missing", "0", "x"], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "LREM", &["letters", "x", "a"], Action::Continue), Value::Error("ERR argument must be an integer".to_string()));
+        assert_eq!(list_key("letters", &c), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn lrem_leaves_the_positions_of_other_elements_alone() {
+        let c = make_connection();
+        run_command(&c, "RPUSH", &["letters", "x", "a", "x", "b", "x"], Action::Continue);
+
+        let positions = |value: &str| -> Vec<i64> {
+            let connection = c.get_sqlite_connection_mutex().lock().unwrap();
+            let mut statement = connection.prepare("SELECT position FROM list_items WHERE key = ?1 AND value = ?2 ORDER BY position").unwrap();
+            let rows = statement.query_map(&[&b"letters".to_vec(), &value.as_bytes().to_vec()], |row| row.get(0)).unwrap();
+            rows.map(|row| row.unwrap()).collect()
+        };
+
+        let (a, b) = (positions("a"), positions("b"));
+        assert_eq!(run_command(&c, "LREM", &["letters", "0", "x"], Action::Continue), Value::Integer(3));
+        assert_eq!((positions("a"), positions("b")), (a, b));
+    }
+
+    // U+FFFD, the replacement character, is what a lossy UTF-8 conversion turns 0xFF into
+    #[test]
+    fn lrem_and_lpos_compare_binary_values_byte_for_byte() {
+        let c = make_connection();
+        let replacement = "\u{FFFD}".as_bytes();
+        run_binary_command(&c, "RPUSH", &[b"binary", b"a\0b", b"\xff", replacement, b"a\0b", b"a"]);
+
+        assert_eq!(run_binary_command(&c, "LPOS", &[b"binary", b"\xff"]), Value::Integer(1));
+        assert_eq!(run_binary_command(&c, "LPOS", &[b"binary", replacement]), Value::Integer(2));
+        assert_eq!(run_binary_command(&c, "LPOS", &[b"binary", b"\xfe"]), Value::Null);
+        assert_eq!(run_binary_command(&c, "LPOS", &[b"binary", b"a\0b", b"COUNT", b"0"]), integers(&[0, 3]));
+
+        assert_eq!(run_binary_command(&c, "LREM", &[b"binary", b"0", b"\xff"]), Value::Integer(1));
+        assert_eq!(run_binary_command(&c, "LREM", &[b"binary", b"0", b"a"]), Value::Integer(1));
+        assert_eq!(run_binary_command(&c, "LREM", &[b"binary", b"-1", b"a\0b"]), Value::Integer(1));
+
+        assert_eq!(run_binary_command(&c, "LRANGE", &[b"binary", b"0", b"-1"]), Value::Array(vec![
+            Value::BufBulk(b"a\0b".to_vec()),
+            Value::BufBulk(replacement.to_vec()),
+        ]));
+    }
+
+    #[test]
+    fn lpos_count_zero_follows_the_rank_direction() {
+        let c = make_connection();
+        run_command(&c, "RPUSH", &["letters", "a", "x", "b", "x", "c", "x"], Action::Continue);
+
+        assert_eq!(run_command(&c, "LPOS", &["letters", "x", "RANK", "1", "COUNT", "0"], Action::Continue), integers(&[1, 3, 5]));
+        assert_eq!(run_command(&c, "LPOS", &["letters", "x", "RANK", "2", "COUNT", "0"], Action::Continue), integers(&[3, 5]));
+        assert_eq!(run_command(&c, "LPOS", &["letters", "x", "RANK", "-1", "COUNT", "0"], Action::Continue), integers(&[5, 3, 1]));
+        assert_eq!(run_command(&c, "LPOS", &["letters", "x", "RANK", "-2", "COUNT", "0"], Action::Continue), integers(&[3, 1]));
+        assert_eq!(run_command(&c, "LPOS", &["letters", "x", "COUNT", "0", "RANK", "-1", "MAXLEN", "2"], Action::Continue), integers(&[5]));
+    }
+
+    #[test]
+    fn lpos_counts_indexes_from_the_head_whatever_the_positions() {
+        let c = make_connection();
+        run_command(&c, "RPUSH", &["letters", "b", "x", "c"], Action::Continue);
+        run_command(&c, "LPUSH", &["letters", "x", "a"], Action::Continue);
+        run_command(&c, "LPOP", &["letters"], Action::Continue);
+        run_command(&c, "LREM", &["letters", "1", "b"], Action::Continue);
+
+        // x, x, c, with positions that no longer start at zero or run on without gaps
+        assert_eq!(run_command(&c, "LPOS", &["letters", "x", "COUNT", "0"], Action::Continue), integers(&[0, 1]));
+        assert_eq!(run_command(&c, "LPOS", &["letters", "c"], Action::Continue), Value::Integer(2));
+        assert_eq!(run_command(&c, "LPOS", &["letters", "x", "RANK", "-1"], Action::Continue), Value::Integer(1));
+    }
+
+    #[test]
+    fn lpos_rejects_invalid_options() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "LPOS", &["test", "abc", "RANK", "0"], Action::Continue), Value::Error("ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list".to_string()));
+        assert_eq!(run_command(&c, "LPOS", &["test", "abc", "RANK", "-9223372036854775808"], Action::Continue), Value::Error("ERR value is out of range".to_string()));
+        assert_eq!(run_command(&c, "LPOS", &["test", "abc", "COUNT", "-1"], Action::Continue), Value::Error("ERR COUNT can't be negative".to_string()));
+        assert_eq!(run_command(&c, "LPOS", &["test", "abc", "MAXLEN", "-1"], Action::Continue), Value::Error("ERR MAXLEN can't be negative".to_string()));
+        assert_eq!(run_command(&c, "LPOS", &["test", "abc", "COUNT"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(run_command(&c, "LPOS", &["test", "abc", "NOPE", "1"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+    }
+
+    #[test]
+    fn lset() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "LSET", &["test", "0", "first"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(list_key("test", &c), vec!["first", "abc"]);
+
+        assert_eq!(run_command(&c, "LSET", &["test", "1", "second"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(list_key("test", &c), vec!["first", "second"]);
+
+        assert_eq!(run_command(&c, "LSET", &["test", "-1", "apple"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(list_key("test", &c), vec!["first", "apple"]);
+
+        assert_eq!(run_command(&c, "LSET", &["test", "-2", "banana"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(list_key("test", &c), vec!["banana", "apple"]);
+
+        assert_eq!(run_command(&c, "LSET", &["test", "-3", "nope"], Action::Continue), Value::Error("ERR index out of range".to_string()));
+        assert_eq!(run_command(&c, "LSET", &["test", "2", "nope"], Action::Continue), Value::Error("ERR index out of range".to_string()));
+        assert_eq!(run_command(&c, "LSET", &["other", "0", "nope"], Action::Continue), Value::Error("ERR no such key".to_string()));
+    }
+
+    // each LSET replaces whatever is at the head when it runs, so a write at a position resolved before a trim would
+    // leave "set" further down the list, and every LINSERT lands beside its pivot, which is never trimmed
+    #[test]
+    fn lset_and_linsert_never_write_at_a_position_trimmed_away() {
+        let c = make_connection();
+        let values: Vec<String> = (0..200).map(|number| number.to_string()).collect();
+        let mut arguments = vec!["queue"];
+        arguments.extend(values.iter().map(|value| value.as_str()));
+        run_command(&c, "RPUSH", &arguments, Action::Continue);
+
+        let trimmer = c.sharing();
+        let handle = thread::spawn(move || {
+            for _ in 0..100 {
+                run_command(&trimmer, "LTRIM", &["queue", "1", "-1"], Action::Continue);
+            }
+        });
+
+        for _ in 0..100 {
+            run_command(&c, "LSET", &["queue", "0", "set"], Action::Continue);
+            run_command(&c, "LINSERT", &["queue", "BEFORE", "199", "inserted"], Action::Continue);
+        }
+
+        handle.join().unwrap();
+
+        let list = list_key("queue", &c);
+        assert!(list[1..].iter().all(|value| value != "set"), "{:?}", list);
+        assert_eq!(list.iter().filter(|value| *value == "inserted").count(), 100);
+        assert_eq!(list.last().unwrap(), "199");
+    }
+
+    #[test]
+    fn lswap() {
+        let c = make_connection();
+        add_more_items(&c);
+
+        assert_eq!(run_command(&c, "LSWAP", &["test", "0", "-1"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(list_key("test", &c), vec!["abc", "mno", "jkl", "ghi", "def", "pqr"]);
+
+        assert_eq!(run_command(&c, "LSWAP", &["test", "-2", "1"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(run_command(&c, "LSWAP", &["test", "2", "2"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(list_key("test", &c), vec!["abc", "def", "jkl", "ghi", "mno", "pqr"]);
+    }
 
-    struct FakeConnection {
-        sqlite_connection_mutex: Arc<Mutex<rusqlite::Connection>>,
-        push_notification: Arc<(Mutex<bool>, Condvar)>,
+    #[test]
+    fn lswap_rejects_an_index_out_of_range() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "LSWAP", &["test", "0", "2"], Action::Continue), Value::Error("ERR index out of range".to_string()));
+        assert_eq!(run_command(&c, "LSWAP", &["test", "-3", "0"], Action::Continue), Value::Error("ERR index out of range".to_string()));
+        assert_eq!(run_command(&c, "LSWAP", &["other", "0", "0"], Action::Continue), Value::Error("ERR no such key".to_string()));
+        assert_eq!(run_command(&c, "LSWAP", &["test", "first", "0"], Action::Continue), Value::Error("ERR argument must be an integer".to_string()));
+        assert_eq!(list_key("test", &c), vec!["def", "abc"]);
     }
 
-    impl Connectionable for FakeConnection {
-        fn send_to_command_log(&self, _command: String) {}
-        fn get_push_notification(&self) -> Arc<(Mutex<bool>, Condvar)> { self.push_notification.clone() }
-        fn get_sqlite_connection_mutex(&self) -> &Arc<Mutex<rusqlite::Connection>> { &self.sqlite_connection_mutex }
+    #[test]
+    fn linsert() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "LINSERT", &["test", "BEFORE", "abc", "ghi"], Action::Continue), Value::Integer(3));
+        assert_eq!(run_command(&c, "LINSERT", &["test", "after", "abc", "jkl"], Action::Continue), Value::Integer(4));
+        assert_eq!(run_command(&c, "LINSERT", &["test", "BEFORE", "def", "mno"], Action::Continue), Value::Integer(5));
+        assert_eq!(list_key("test", &c), vec!["mno", "def", "ghi", "abc", "jkl"]);
+
+        assert_eq!(run_command(&c, "LINSERT", &["test", "BEFORE", "nope", "pqr"], Action::Continue), Value::Integer(-1));
+        assert_eq!(run_command(&c, "LINSERT", &["other", "BEFORE", "abc", "pqr"], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "LINSERT", &["test", "BESIDE", "abc", "pqr"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(list_key("other", &c), vec![] as Vec<String>);
+    }
+
+    #[test]
+    fn linsert_uses_the_first_element_matching_the_pivot() {
+        let c = make_connection();
+
+        run_command(&c, "RPUSH", &["test", "def"], Action::Continue);
+        assert_eq!(list_key("test", &c), vec!["def", "abc", "def"]);
+
+        assert_eq!(run_command(&c, "LINSERT", &["test", "AFTER", "def", "ghi"], Action::Continue), Value::Integer(4));
+        assert_eq!(run_command(&c, "LINSERT", &["test", "BEFORE", "def", "jkl"], Action::Continue), Value::Integer(5));
+        assert_eq!(list_key("test", &c), vec!["jkl", "def", "ghi", "abc", "def"]);
+    }
+
+    #[test]
+    fn linsert_renumbers_when_the_gap_between_elements_runs_out() {
+        let c = make_connection();
+        let values: Vec<String> = (0..50).map(|index| index.to_string()).collect();
+
+        for value in values.iter() {
+            run_command(&c, "LINSERT", &["test", "BEFORE", "abc", value], Action::Continue);
+            run_command(&c, "LINSERT", &["test", "AFTER", "def", value], Action::Continue);
+        }
+
+        let mut expected = vec!["def".to_string()];
+        expected.extend(values.iter().rev().cloned());
+        expected.extend(values.iter().cloned());
+        expected.push("abc".to_string());
+
+        assert_eq!(list_key("test", &c), expected);
+        assert_eq!(run_command(&c, "LINDEX", &["test", "1"], Action::Continue), Value::BufBulk("49".to_string().into_bytes()));
+        assert_eq!(run_command(&c, "LINDEX", &["test", "-2"], Action::Continue), Value::BufBulk("49".to_string().into_bytes()));
+    }
+
+    fn bulk_strings(values: &[&str]) -> Value {
+        Value::Array(values.iter().map(|value| Value::BufBulk(value.as_bytes().to_vec())).collect())
+    }
+
+    #[test]
+    fn zadd_and_zscore() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "ZADD", &["zset", "1", "a", "2.5", "b"], Action::Continue), Value::Integer(2));
+        assert_eq!(run_command(&c, "ZADD", &["zset", "3", "a", "-inf", "c"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "ZCARD", &["zset"], Action::Continue), Value::Integer(3));
+        assert_eq!(run_command(&c, "ZCARD", &["other"], Action::Continue), Value::Integer(0));
+
+        assert_eq!(run_command(&c, "ZSCORE", &["zset", "a"], Action::Continue), Value::BufBulk(b"3".to_vec()));
+        assert_eq!(run_command(&c, "ZSCORE", &["zset", "b"], Action::Continue), Value::BufBulk(b"2.5".to_vec()));
+        assert_eq!(run_command(&c, "ZSCORE", &["zset", "c"], Action::Continue), Value::BufBulk(b"-inf".to_vec()));
+        assert_eq!(run_command(&c, "ZSCORE", &["zset", "d"], Action::Continue), Value::Null);
+
+        assert_eq!(run_command(&c, "ZADD", &["zset", "1", "a", "2"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(run_command(&c, "ZADD", &["zset", "one", "a"], Action::Continue), Value::Error("ERR value is not a valid float".to_string()));
+        assert_eq!(run_command(&c, "ZADD", &["zset", "nan", "a"], Action::Continue), Value::Error("ERR value is not a valid float".to_string()));
+    }
+
+    #[test]
+    fn zrange() {
+        let c = make_connection();
+
+        run_command(&c, "ZADD", &["zset", "2", "b", "1", "z", "2", "a", "3", "c"], Action::Continue);
+
+        assert_eq!(run_command(&c, "ZRANGE", &["zset", "0", "-1"], Action::Continue), bulk_strings(&["z", "a", "b", "c"]));
+        assert_eq!(run_command(&c, "ZRANGE", &["zset", "1", "2"], Action::Continue), bulk_strings(&["a", "b"]));
+        assert_eq!(run_command(&c, "ZRANGE", &["zset", "-1", "-1", "withscores"], Action::Continue), bulk_strings(&["c", "3"]));
+        assert_eq!(run_command(&c, "ZRANGE", &["zset", "0", "1", "WITHSCORES"], Action::Continue), bulk_strings(&["z", "1", "a", "2"]));
+        assert_eq!(run_command(&c, "ZRANGE", &["zset", "5", "10"], Action::Continue), bulk_strings(&[]));
+        assert_eq!(run_command(&c, "ZRANGE", &["other", "0", "-1"], Action::Continue), bulk_strings(&[]));
+        assert_eq!(run_command(&c, "ZRANGE", &["zset", "0", "-1", "NOPE"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+    }
+
+    #[test]
+    fn zrangebyscore() {
+        let c = make_connection();
+
+        run_command(&c, "ZADD", &["zset", "1", "a", "2", "b", "2", "c", "3", "d", "4", "e"], Action::Continue);
+
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "2", "3"], Action::Continue), bulk_strings(&["b", "c", "d"]));
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "(2", "4"], Action::Continue), bulk_strings(&["d", "e"]));
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "1", "(3"], Action::Continue), bulk_strings(&["a", "b", "c"]));
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "(1", "(2"], Action::Continue), bulk_strings(&[]));
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "(3", "+inf", "WITHSCORES"], Action::Continue), bulk_strings(&["e", "4"]));
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "-inf", "(2"], Action::Continue), bulk_strings(&["a"]));
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "-inf", "+inf"], Action::Continue), bulk_strings(&["a", "b", "c", "d", "e"]));
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "x", "1"], Action::Continue), Value::Error("ERR min or max is not a float".to_string()));
+    }
+
+    #[test]
+    fn zrangebyscore_with_limit() {
+        let c = make_connection();
+
+        run_command(&c, "ZADD", &["zset", "1", "a", "2", "b", "3", "c", "4", "d", "5", "e"], Action::Continue);
+
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "-inf", "+inf", "LIMIT", "0", "2"], Action::Continue), bulk_strings(&["a", "b"]));
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "-inf", "+inf", "LIMIT", "2", "2"], Action::Continue), bulk_strings(&["c", "d"]));
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "-inf", "+inf", "LIMIT", "4", "2"], Action::Continue), bulk_strings(&["e"]));
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "2", "5", "LIMIT", "1", "-1"], Action::Continue), bulk_strings(&["c", "d", "e"]));
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "2", "5", "WITHSCORES", "LIMIT", "1", "1"], Action::Continue), bulk_strings(&["c", "3"]));
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "2", "5", "LIMIT", "-1", "1"], Action::Continue), bulk_strings(&[]));
+        assert_eq!(run_command(&c, "ZRANGEBYSCORE", &["zset", "2", "5", "LIMIT", "1"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+    }
+
+    #[test]
+    fn zincrby() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "ZINCRBY", &["zset", "2.5", "a"], Action::Continue), Value::BufBulk(b"2.5".to_vec()));
+        assert_eq!(run_command(&c, "ZINCRBY", &["zset", "-1", "a"], Action::Continue), Value::BufBulk(b"1.5".to_vec()));
+        assert_eq!(run_command(&c, "ZSCORE", &["zset", "a"], Action::Continue), Value::BufBulk(b"1.5".to_vec()));
+        assert_eq!(run_command(&c, "ZCARD", &["zset"], Action::Continue), Value::Integer(1));
+
+        assert_eq!(run_command(&c, "ZINCRBY", &["zset", "x", "a"], Action::Continue), Value::Error("ERR value is not a valid float".to_string()));
+
+        run_command(&c, "ZADD", &["zset", "inf", "b"], Action::Continue);
+        assert_eq!(run_command(&c, "ZINCRBY", &["zset", "-inf", "b"], Action::Continue), Value::Error("ERR resulting score is not a number (NaN)".to_string()));
+        assert_eq!(run_command(&c, "ZSCORE", &["zset", "b"], Action::Continue), Value::BufBulk(b"inf".to_vec()));
+    }
+
+    #[test]
+    fn zrank_and_zrevrank() {
+        let c = make_connection();
+
+        run_command(&c, "ZADD", &["zset", "1", "a", "2", "c", "2", "b", "3", "d"], Action::Continue);
+
+        assert_eq!(run_command(&c, "ZRANK", &["zset", "a"], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "ZRANK", &["zset", "b"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "ZRANK", &["zset", "c"], Action::Continue), Value::Integer(2));
+        assert_eq!(run_command(&c, "ZRANK", &["zset", "d"], Action::Continue), Value::Integer(3));
+        assert_eq!(run_command(&c, "ZRANK", &["zset", "x"], Action::Continue), Value::Null);
+
+        assert_eq!(run_command(&c, "ZREVRANK", &["zset", "d"], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "ZREVRANK", &["zset", "c"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "ZREVRANK", &["zset", "a"], Action::Continue), Value::Integer(3));
+        assert_eq!(run_command(&c, "ZREVRANK", &["other", "a"], Action::Continue), Value::Null);
+    }
+
+    #[test]
+    fn zrem() {
+        let c = make_connection();
+
+        run_command(&c, "ZADD", &["zset", "1", "a", "2", "b", "3", "c"], Action::Continue);
+
+        assert_eq!(run_command(&c, "ZREM", &["zset", "a", "c", "x", "a"], Action::Continue), Value::Integer(2));
+        assert_eq!(run_command(&c, "ZRANGE", &["zset", "0", "-1"], Action::Continue), bulk_strings(&["b"]));
+        assert_eq!(run_command(&c, "ZREM", &["other", "a"], Action::Continue), Value::Integer(0));
+    }
+
+    #[test]
+    fn hset_and_hget() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "HSET", &["hash", "a", "1", "b", "2"], Action::Continue), Value::Integer(2));
+        assert_eq!(run_command(&c, "HSET", &["hash", "a", "3", "c", "4"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "HGET", &["hash", "a"], Action::Continue), Value::BufBulk(b"3".to_vec()));
+        assert_eq!(run_command(&c, "HGET", &["hash", "x"], Action::Continue), Value::Null);
+        assert_eq!(run_command(&c, "HSET", &["hash", "a", "1", "b"], Action::Continue), Value::Error("ERR wrong number of arguments".to_string()));
+    }
+
+    #[test]
+    fn hmget_and_hexists() {
+        let c = make_connection();
+
+        run_command(&c, "HSET", &["hash", "a", "1", "b", "2"], Action::Continue);
+
+        assert_eq!(run_command(&c, "HMGET", &["hash", "b", "x", "a"], Action::Continue), Value::Array(vec![Value::BufBulk(b"2".to_vec()), Value::Null, Value::BufBulk(b"1".to_vec())]));
+        assert_eq!(run_command(&c, "HMGET", &["other", "a"], Action::Continue), Value::Array(vec![Value::Null]));
+
+        assert_eq!(run_command(&c, "HEXISTS", &["hash", "a"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "HEXISTS", &["hash", "x"], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "HEXISTS", &["other", "a"], Action::Continue), Value::Integer(0));
+    }
+
+    #[test]
+    fn hgetall_alternates_fields_and_values() {
+        let c = make_connection();
+
+        run_command(&c, "HSET", &["hash", "b", "2", "a", "1"], Action::Continue);
+
+        assert_eq!(run_command(&c, "HGETALL", &["hash"], Action::Continue), bulk_strings(&["a", "1", "b", "2"]));
+        assert_eq!(run_command(&c, "HGETALL", &["other"], Action::Continue), bulk_strings(&[]));
+    }
+
+    #[test]
+    fn hkeys_and_hvals_line_up() {
+        let c = make_connection();
+
+        run_command(&c, "HSET", &["hash", "c", "3", "a", "1", "b", "2"], Action::Continue);
+        run_command(&c, "HSET", &["hash", "a", "4"], Action::Continue);
+
+        assert_eq!(run_command(&c, "HKEYS", &["hash"], Action::Continue), bulk_strings(&["a", "b", "c"]));
+        assert_eq!(run_command(&c, "HVALS", &["hash"], Action::Continue), bulk_strings(&["4", "2", "3"]));
+        assert_eq!(run_command(&c, "HKEYS", &["other"], Action::Continue), bulk_strings(&[]));
+        assert_eq!(run_command(&c, "HVALS", &["other"], Action::Continue), bulk_strings(&[]));
+    }
+
+    #[test]
+    fn hincrby() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "HINCRBY", &["hash", "count", "5"], Action::Continue), Value::Integer(5));
+        assert_eq!(run_command(&c, "HINCRBY", &["hash", "count", "-7"], Action::Continue), Value::Integer(-2));
+        assert_eq!(run_command(&c, "HGET", &["hash", "count"], Action::Continue), Value::BufBulk(b"-2".to_vec()));
+
+        run_command(&c, "HSET", &["hash", "name", "abc", "big", "9223372036854775807"], Action::Continue);
+        assert_eq!(run_command(&c, "HINCRBY", &["hash", "name", "1"], Action::Continue), Value::Error("ERR hash value is not an integer".to_string()));
+        assert_eq!(run_command(&c, "HINCRBY", &["hash", "big", "1"], Action::Continue), Value::Error("ERR increment or decrement would overflow".to_string()));
+        assert_eq!(run_command(&c, "HINCRBY", &["hash", "count", "x"], Action::Continue), Value::Error("ERR argument must be an integer".to_string()));
+    }
+
+    #[test]
+    fn hincrbyfloat() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "HINCRBYFLOAT", &["hash", "price", "10.5"], Action::Continue), Value::BufBulk(b"10.5".to_vec()));
+        assert_eq!(run_command(&c, "HINCRBYFLOAT", &["hash", "price", "0.5"], Action::Continue), Value::BufBulk(b"11".to_vec()));
+        assert_eq!(run_command(&c, "HINCRBY", &["hash", "price", "1"], Action::Continue), Value::Integer(12));
+        assert_eq!(run_command(&c, "HINCRBYFLOAT", &["hash", "price", "-0.25"], Action::Continue), Value::BufBulk(b"11.75".to_vec()));
+
+        run_command(&c, "HSET", &["hash", "name", "abc"], Action::Continue);
+        assert_eq!(run_command(&c, "HINCRBYFLOAT", &["hash", "name", "1"], Action::Continue), Value::Error("ERR hash value is not a float".to_string()));
+        assert_eq!(run_command(&c, "HINCRBYFLOAT", &["hash", "price", "inf"], Action::Continue), Value::Error("ERR increment would produce NaN or Infinity".to_string()));
+        assert_eq!(run_command(&c, "HGET", &["hash", "price"], Action::Continue), Value::BufBulk(b"11.75".to_vec()));
+    }
+
+    #[test]
+    fn object_encoding() {
+        let mut c = make_connection();
+        c.config.list_max_listpack_size = 3;
+
+        assert_eq!(run_command(&c, "OBJECT", &["ENCODING", "test"], Action::Continue), Value::BufBulk(b"listpack".to_vec()));
+        run_command(&c, "RPUSH", &["test", "ghi"], Action::Continue);
+        assert_eq!(run_command(&c, "OBJECT", &["encoding", "test"], Action::Continue), Value::BufBulk(b"listpack".to_vec()));
+        run_command(&c, "RPUSH", &["test", "jkl"], Action::Continue);
+        assert_eq!(run_command(&c, "OBJECT", &["ENCODING", "test"], Action::Continue), Value::BufBulk(b"quicklist".to_vec()));
+
+        run_command(&c, "ZADD", &["zset", "1", "a"], Action::Continue);
+        run_command(&c, "HSET", &["hash", "a", "1"], Action::Continue);
+        assert_eq!(run_command(&c, "OBJECT", &["ENCODING", "zset"], Action::Continue), Value::BufBulk(b"listpack".to_vec()));
+        assert_eq!(run_command(&c, "OBJECT", &["ENCODING", "hash"], Action::Continue), Value::BufBulk(b"listpack".to_vec()));
+
+        for index in 0..128 {
+            let member = index.to_string();
+            run_command(&c, "ZADD", &["zset", "1", &member], Action::Continue);
+            run_command(&c, "HSET", &["hash", &member, "1"], Action::Continue);
+        }
+
+        assert_eq!(run_command(&c, "OBJECT", &["ENCODING", "zset"], Action::Continue), Value::BufBulk(b"skiplist".to_vec()));
+        assert_eq!(run_command(&c, "OBJECT", &["ENCODING", "hash"], Action::Continue), Value::BufBulk(b"hashtable".to_vec()));
+
+        run_command(&c, "SETEX", &["number", "100", "-42"], Action::Continue);
+        run_command(&c, "SETEX", &["short", "100", "042"], Action::Continue);
+        run_command(&c, "SETEX", &["long", "100", &"x".repeat(45)], Action::Continue);
+        assert_eq!(run_command(&c, "OBJECT", &["ENCODING", "number"], Action::Continue), Value::BufBulk(b"int".to_vec()));
+        assert_eq!(run_command(&c, "OBJECT", &["ENCODING", "short"], Action::Continue), Value::BufBulk(b"embstr".to_vec()));
+        assert_eq!(run_command(&c, "OBJECT", &["ENCODING", "long"], Action::Continue), Value::BufBulk(b"raw".to_vec()));
+
+        assert_eq!(run_command(&c, "OBJECT", &["ENCODING", "missing"], Action::Continue), Value::Null);
+
+        assert_eq!(run_command(&c, "OBJECT", &["FREQ", "test"], Action::Continue), Value::Error("ERR unknown subcommand 'FREQ'. Try OBJECT HELP.".to_string()));
+        assert_eq!(run_command(&c, "OBJECT", &["ENCODING"], Action::Continue), Value::Error("ERR wrong number of arguments for 'object|encoding' command".to_string()));
+    }
+
+    #[test]
+    fn set_and_get() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "GET", &["greeting"], Action::Continue), Value::Null);
+        assert_eq!(run_command(&c, "SET", &["greeting", "hello"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(run_command(&c, "GET", &["greeting"], Action::Continue), Value::BufBulk(b"hello".to_vec()));
+
+        run_command(&c, "EXPIRE", &["greeting", "100"], Action::Continue);
+        run_command(&c, "SET", &["greeting", "hi"], Action::Continue);
+        assert_eq!(run_command(&c, "GET", &["greeting"], Action::Continue), Value::BufBulk(b"hi".to_vec()));
+        assert_eq!(run_command(&c, "TTL", &["greeting"], Action::Continue), Value::Integer(-1));
+    }
+
+    #[test]
+    fn get_refuses_keys_of_other_types_and_set_replaces_them() {
+        let c = make_connection();
+        let wrongtype = Value::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string());
+
+        run_command(&c, "HSET", &["hash", "a", "1"], Action::Continue);
+        assert_eq!(run_command(&c, "GET", &["test"], Action::Continue), wrongtype);
+        assert_eq!(run_command(&c, "GET", &["hash"], Action::Continue), wrongtype);
+
+        assert_eq!(run_command(&c, "SET", &["test", "hello"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(run_command(&c, "TYPE", &["test"], Action::Continue), Value::String("string".to_string()));
+        assert_eq!(run_command(&c, "LLEN", &["test"], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "GET", &["test"], Action::Continue), Value::BufBulk(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn append_extends_strings_and_refuses_keys_of_other_types() {
+        let c = make_connection();
+        let wrongtype = Value::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string());
+
+        assert_eq!(run_command(&c, "APPEND", &["greeting", "hello"], Action::Continue), Value::Integer(5));
+        assert_eq!(run_command(&c, "APPEND", &["greeting", " world"], Action::Continue), Value::Integer(11));
+        assert_eq!(run_command(&c, "GET", &["greeting"], Action::Continue), Value::BufBulk(b"hello world".to_vec()));
+
+        assert_eq!(run_command(&c, "APPEND", &["test", "x"], Action::Continue), wrongtype);
+        assert_eq!(run_command(&c, "GET", &["test"], Action::Continue), wrongtype);
+        assert_eq!(run_lrange(&["test", "0", "-1"], &c), vec!["def", "abc"]);
+    }
+
+    #[test]
+    fn append_keeps_the_expiry_and_the_maximum_value_size() {
+        let mut c = make_connection();
+        c.config.max_value_size = 8;
+
+        run_command(&c, "SETEX", &["greeting", "100", "hello"], Action::Continue);
+        assert_eq!(run_command(&c, "APPEND", &["greeting", "!"], Action::Continue), Value::Integer(6));
+        assert_eq!(run_command(&c, "TTL", &["greeting"], Action::Continue), Value::Integer(100));
+
+        assert_eq!(run_command(&c, "APPEND", &["greeting", "!!!"], Action::Continue), Value::Error("ERR value too large".to_string()));
+        assert_eq!(run_command(&c, "GET", &["greeting"], Action::Continue), Value::BufBulk(b"hello!".to_vec()));
+    }
+
+    #[test]
+    fn type_finds_lists_through_the_storage() {
+        let c = make_connection();
+        assert_eq!(run_command(&c, "TYPE", &["test"], Action::Continue), Value::String("list".to_string()));
+        assert_eq!(run_command(&c, "TYPE", &["other"], Action::Continue), Value::String("none".to_string()));
+    }
+
+    #[test]
+    fn type_names_the_type_of_each_kind_of_key() {
+        let c = make_connection();
+
+        run_command(&c, "ZADD", &["zset", "1", "a"], Action::Continue);
+        run_command(&c, "HSET", &["hash", "a", "1"], Action::Continue);
+        run_command(&c, "SETEX", &["greeting", "100", "hello"], Action::Continue);
+
+        assert_eq!(run_command(&c, "TYPE", &["test"], Action::Continue), Value::String("list".to_string()));
+        assert_eq!(run_command(&c, "TYPE", &["zset"], Action::Continue), Value::String("zset".to_string()));
+        assert_eq!(run_command(&c, "TYPE", &["hash"], Action::Continue), Value::String("hash".to_string()));
+        assert_eq!(run_command(&c, "TYPE", &["greeting"], Action::Continue), Value::String("string".to_string()));
+        assert_eq!(run_command(&c, "TYPE", &["missing"], Action::Continue), Value::String("none".to_string()));
+    }
+
+    #[test]
+    fn ttl_works_the_same_for_every_type() {
+        let c = make_connection();
+
+        run_command(&c, "RPUSH", &["list", "a"], Action::Continue);
+        run_command(&c, "HSET", &["hash", "a", "1"], Action::Continue);
+        run_command(&c, "ZADD", &["zset", "1", "a"], Action::Continue);
+
+        for key in &["list", "hash", "zset"] {
+            assert_eq!(run_command(&c, "TTL", &[key], Action::Continue), Value::Integer(-1));
+            assert_eq!(run_command(&c, "EXPIRE", &[key, "100"], Action::Continue), Value::Integer(1));
+            assert_eq!(run_command(&c, "TTL", &[key], Action::Continue), Value::Integer(100));
+
+            match run_command(&c, "PTTL", &[key], Action::Continue) {
+                Value::Integer(milliseconds) => assert!(milliseconds > 99000 && milliseconds <= 100000),
+                _                            => panic!("expected an integer")
+            }
+
+            assert_eq!(run_command(&c, "PERSIST", &[key], Action::Continue), Value::Integer(1));
+            assert_eq!(run_command(&c, "PERSIST", &[key], Action::Continue), Value::Integer(0));
+            assert_eq!(run_command(&c, "TTL", &[key], Action::Continue), Value::Integer(-1));
+        }
+
+        assert_eq!(run_command(&c, "TTL", &["missing"], Action::Continue), Value::Integer(-2));
+        assert_eq!(run_command(&c, "PTTL", &["missing"], Action::Continue), Value::Integer(-2));
+        assert_eq!(run_command(&c, "EXPIRE", &["missing", "100"], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "EXPIRE", &["list", "soon"], Action::Continue), Value::Error("ERR argument must be an integer".to_string()));
+        assert_eq!(run_command(&c, "EXPIRE", &["list", "9223372036854775807"], Action::Continue), Value::Error("ERR invalid expire time in 'expire' command".to_string()));
+    }
+
+    #[test]
+    fn expired_keys_disappear() {
+        let c = make_connection();
+
+        run_command(&c, "HSET", &["hash", "a", "1"], Action::Continue);
+        assert_eq!(run_command(&c, "PEXPIRE", &["test", "1"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "PEXPIREAT", &["hash", "1"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "HGET", &["hash", "a"], Action::Continue), Value::Null);
+
+        thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(run_command(&c, "LLEN", &["test"], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "TTL", &["test"], Action::Continue), Value::Integer(-2));
+
+        run_command(&c, "RPUSH", &["test", "new"], Action::Continue);
+        assert_eq!(list_key("test", &c), vec!["new"]);
+        assert_eq!(run_command(&c, "TTL", &["test"], Action::Continue), Value::Integer(-1));
+
+        assert_eq!(run_command(&c, "EXPIRE", &["test", "0"], Action::Continue), Value::Integer(1));
+        assert_eq!(list_key("test", &c), vec![] as Vec<String>);
+    }
+
+    #[test]
+    fn del_removes_keys_of_every_type() {
+        let c = make_connection();
+
+        run_command(&c, "SETEX", &["greeting", "100", "hello"], Action::Continue);
+        run_command(&c, "HSET", &["hash", "a", "1"], Action::Continue);
+        run_command(&c, "ZADD", &["zset", "1", "a"], Action::Continue);
+        assert_eq!(run_command(&c, "EXISTS", &["test", "greeting", "hash", "zset", "missing"], Action::Continue), Value::Integer(4));
+
+        assert_eq!(run_command(&c, "DEL", &["greeting", "missing"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "TTL", &["greeting"], Action::Continue), Value::Integer(-2));
+        assert_eq!(run_command(&c, "DEL", &["hash", "zset", "test", "test"], Action::Continue), Value::Integer(3));
+
+        assert_eq!(run_command(&c, "EXISTS", &["test", "greeting", "hash", "zset"], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "DBSIZE", &[], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "DEL", &["test"], Action::Continue), Value::Integer(0));
+    }
+
+    #[test]
+    fn del_counts_a_list_as_one_key_however_long() {
+        let c = make_connection();
+
+        run_command(&c, "RPUSH", &["queue", "a", "b", "c", "d", "e"], Action::Continue);
+        assert_eq!(run_command(&c, "DEL", &["queue", "missing"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "LLEN", &["queue"], Action::Continue), Value::Integer(0));
+    }
+
+    #[test]
+    fn exists_counts_a_repeated_key_each_time() {
+        let c = make_connection();
+        assert_eq!(run_command(&c, "EXISTS", &["test", "test"], Action::Continue), Value::Integer(2));
+    }
+
+    #[test]
+    fn exists() {
+        let c = make_connection();
+
+        run_command(&c, "SET", &["greeting", "hello"], Action::Continue);
+
+        assert_eq!(run_command(&c, "EXISTS", &["test"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "EXISTS", &["other"], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "EXISTS", &["test", "other", "greeting", "test"], Action::Continue), Value::Integer(3));
+    }
+
+    #[test]
+    fn exists_checks_more_keys_than_fit_in_one_query() {
+        let c = make_connection();
+
+        let mut keys: Vec<String> = (0..1200).map(|index| format!("missing{}", index)).collect();
+        keys.push("test".to_string());
+        keys.insert(0, "test".to_string());
+
+        let arguments: Vec<&str> = keys.iter().map(|key| key.as_str()).collect();
+        assert_eq!(run_command(&c, "EXISTS", &arguments, Action::Continue), Value::Integer(2));
+    }
+
+    fn scanned(c: &FakeConnection, arguments: &[&str]) -> (String, Vec<String>) {
+        match run_command(c, "SCAN", arguments, Action::Continue) {
+            Value::Array(mut reply) => {
+                let keys = match reply.pop() {
+                    Some(Value::Array(keys)) => keys.into_iter().map(|key| match key {
+                        Value::BufBulk(key) => String::from_utf8(key).unwrap(),
+                        _                   => panic!("expected a bulk string")
+                    }).collect(),
+                    _ => panic!("expected an array of keys")
+                };
+
+                match reply.pop() {
+                    Some(Value::BufBulk(cursor)) => (String::from_utf8(cursor).unwrap(), keys),
+                    _                            => panic!("expected a cursor")
+                }
+            }
+
+            other => panic!("expected an array, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn scan_pages_through_every_key_in_order() {
+        let c = make_connection();
+
+        run_command(&c, "RPUSH", &["another", "a"], Action::Continue);
+        run_command(&c, "SETEX", &["greeting", "100", "hello"], Action::Continue);
+        run_command(&c, "HSET", &["hash", "a", "1"], Action::Continue);
+
+        assert_eq!(scanned(&c, &["0", "COUNT", "3"]), ("3".to_string(), vec!["another".to_string(), "greeting".to_string(), "hash".to_string()]));
+        assert_eq!(scanned(&c, &["3", "COUNT", "3"]), ("0".to_string(), vec!["test".to_string()]));
+        assert_eq!(scanned(&c, &["0"]), ("0".to_string(), vec!["another".to_string(), "greeting".to_string(), "hash".to_string(), "test".to_string()]));
+    }
+
+    #[test]
+    fn scan_with_a_type_returns_only_keys_of_that_type() {
+        let c = make_connection();
+
+        run_command(&c, "RPUSH", &["another", "a", "b"], Action::Continue);
+        run_command(&c, "SETEX", &["greeting", "100", "hello"], Action::Continue);
+        run_command(&c, "HSET", &["hash", "a", "1"], Action::Continue);
+        run_command(&c, "ZADD", &["zset", "1", "a"], Action::Continue);
+
+        assert_eq!(scanned(&c, &["0", "TYPE", "list"]), ("0".to_string(), vec!["another".to_string(), "test".to_string()]));
+        assert_eq!(scanned(&c, &["0", "TYPE", "LIST", "COUNT", "1"]), ("1".to_string(), vec!["another".to_string()]));
+        assert_eq!(scanned(&c, &["0", "TYPE", "string"]), ("0".to_string(), vec!["greeting".to_string()]));
+        assert_eq!(scanned(&c, &["0", "TYPE", "hash"]), ("0".to_string(), vec!["hash".to_string()]));
+        assert_eq!(scanned(&c, &["0", "TYPE", "zset"]), ("0".to_string(), vec!["zset".to_string()]));
+        assert_eq!(scanned(&c, &["0", "TYPE", "stream"]), ("0".to_string(), vec![]));
+    }
+
+    #[test]
+    fn scan_rejects_invalid_arguments() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "SCAN", &["-1"], Action::Continue), Value::Error("ERR invalid cursor".to_string()));
+        assert_eq!(run_command(&c, "SCAN", &["x"], Action::Continue), Value::Error("ERR invalid cursor".to_string()));
+        assert_eq!(run_command(&c, "SCAN", &["0", "COUNT", "0"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(run_command(&c, "SCAN", &["0", "TYPE"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(run_command(&c, "SCAN", &["0", "MATCH", "*"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+    }
+
+    #[test]
+    fn dbsize_counts_each_key_once_and_never_the_version_row() {
+        let c = make_connection();
+
+        // the test list has two elements
+        assert_eq!(run_command(&c, "DBSIZE", &[], Action::Continue), Value::Integer(1));
+
+        run_command(&c, "SET", &["greeting", "hello"], Action::Continue);
+        assert_eq!(run_command(&c, "DBSIZE", &[], Action::Continue), Value::Integer(2));
+
+        run_command(&c, "FLUSHDB", &[], Action::Continue);
+        assert_eq!(run_command(&c, "DBSIZE", &[], Action::Continue), Value::Integer(0));
+        assert_eq!(version_rows(&c), 1);
+    }
+
+    #[test]
+    fn dbsize_does_not_count_expired_keys() {
+        let c = make_connection();
+
+        run_command(&c, "HSET", &["hash", "a", "1"], Action::Continue);
+        run_command(&c, "SETEX", &["greeting", "100", "hello"], Action::Continue);
+        run_command(&c, "ZADD", &["zset", "1", "a"], Action::Continue);
+        assert_eq!(run_command(&c, "DBSIZE", &[], Action::Continue), Value::Integer(4));
+
+        run_command(&c, "PEXPIRE", &["test", "1"], Action::Continue);
+        thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(run_command(&c, "DBSIZE", &[], Action::Continue), Value::Integer(3));
+    }
+
+    #[test]
+    fn volatile_ttl_evicts_the_key_nearest_to_expiring_first() {
+        let mut c = make_connection();
+        c.config.max_keys = 3;
+        c.config.maxmemory_policy = EvictionPolicy::VolatileTtl;
+
+        run_command(&c, "SETEX", &["later", "200", "a"], Action::Continue);
+        run_command(&c, "SETEX", &["sooner", "100", "b"], Action::Continue);
+
+        // adding to a key that exists makes no room
+        run_command(&c, "RPUSH", &["test", "more"], Action::Continue);
+        assert_eq!(run_command(&c, "DBSIZE", &[], Action::Continue), Value::Integer(3));
+
+        assert_eq!(run_command(&c, "RPUSH", &["queue", "a"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "EXISTS", &["sooner", "later"], Action::Continue), Value::Integer(1));
+
+        assert_eq!(run_command(&c, "HSET", &["hash", "a", "1"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "EXISTS", &["later"], Action::Continue), Value::Integer(0));
+
+        // with no key left that has an expiry, nothing can be evicted
+        let oom = Value::Error("OOM command not allowed when the number of keys would exceed 'max-keys'.".to_string());
+        assert_eq!(run_command(&c, "ZADD", &["zset", "1", "a"], Action::Continue), oom);
+        assert_eq!(run_command(&c, "DBSIZE", &[], Action::Continue), Value::Integer(3));
+    }
+
+    #[test]
+    fn noeviction_refuses_new_keys_but_not_other_writes() {
+        let mut c = make_connection();
+        c.config.max_keys = 2;
+
+        run_command(&c, "SETEX", &["greeting", "100", "hello"], Action::Continue);
+
+        let oom = Value::Error("OOM command not allowed when the number of keys would exceed 'max-keys'.".to_string());
+        assert_eq!(run_command(&c, "LPUSH", &["queue", "a"], Action::Continue), oom);
+        assert_eq!(run_command(&c, "EXISTS", &["greeting"], Action::Continue), Value::Integer(1));
+
+        assert_eq!(run_command(&c, "LPUSH", &["test", "a"], Action::Continue), run_command(&c, "LLEN", &["test"], Action::Continue));
+        assert_eq!(run_command(&c, "DEL", &["greeting"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "LPUSH", &["queue", "a"], Action::Continue), Value::Integer(1));
+    }
+
+    #[test]
+    fn moves_count_only_a_destination_they_would_add() {
+        let mut c = make_connection();
+        c.config.max_keys = 1;
+
+        // nothing to move, so nothing is added
+        assert_eq!(run_command(&c, "RPOPLPUSH", &["missing", "queue"], Action::Continue), Value::Null);
+        assert_eq!(run_command(&c, "LMOVE", &["missing", "queue", "LEFT", "RIGHT"], Action::Continue), Value::Null);
+        assert_eq!(run_command(&c, "BLMOVE", &["missing", "queue", "LEFT", "RIGHT", "1"], Action::Continue), Value::Null);
+        assert_eq!(run_command(&c, "BRPOPLPUSH", &["missing", "queue", "1"], Action::Continue), Value::Null);
+
+        // onto itself, or any list that's already there
+        assert_eq!(run_command(&c, "RPOPLPUSH", &["test", "test"], Action::Continue), Value::BufBulk(b"abc".to_vec()));
+
+        let oom = Value::Error("OOM command not allowed when the number of keys would exceed 'max-keys'.".to_string());
+        assert_eq!(run_command(&c, "LMOVE", &["test", "queue", "LEFT", "RIGHT"], Action::Continue), oom);
+        assert_eq!(run_command(&c, "BRPOPLPUSH", &["test", "queue", "1"], Action::Continue), oom);
+        assert_eq!(list_key("test", &c), vec!["abc", "def"]);
+    }
+
+    // each SET makes room and writes under one lock, so of clients racing for the only free key just one can take it
+    #[test]
+    fn max_keys_holds_with_clients_adding_keys_at_once() {
+        let c = make_connection();
 
-        fn is_stream_alive(&self) -> bool { true }
-    }
+        for round in 0..20 {
+            run_command(&c, "FLUSHDB", &[], Action::Continue);
+            let barrier = Arc::new(Barrier::new(8));
 
-    impl FakeConnection {
-        pub fn new() -> FakeConnection {
-            let sqlite_connection_mutex = FakeConnection::make_sqlite_connection_mutex();
-            let push_notification = Arc::new((Mutex::new(false), Condvar::new()));
+            let handles: Vec<_> = (0..8).map(|client| {
+                let mut other = c.sharing();
+                other.config.max_keys = 1;
+                let barrier = barrier.clone();
 
-            FakeConnection {
-                sqlite_connection_mutex: sqlite_connection_mutex,
-                push_notification:       push_notification,
-            }
-        }
+                thread::spawn(move || {
+                    barrier.wait();
+                    run_command(&other, "SET", &[&format!("key-{}-{}", round, client), "a"], Action::Continue);
+                })
+            }).collect();
 
-        fn make_sqlite_connection_mutex() -> Arc<Mutex<rusqlite::Connection>> {
-            let connection = rusqlite::Connection::open("test.sqlite3").unwrap();
-            connection.execute("DROP TABLE list_items", &[]).ok();
-            connection.execute("CREATE TABLE list_items (id integer primary key autoincrement, key string, value blob, position integer)", &[]).unwrap();
-            connection.execute("CREATE INDEX list_items_key ON list_items(key, position)", &[]).unwrap();
-            connection.execute("INSERT INTO list_items (key, value, position) VALUES (X'74657374', X'616263', -4), (X'74657374', X'646566', -5)", &[]).unwrap();
+            for handle in handles {
+                handle.join().unwrap();
+            }
 
-            Arc::new(Mutex::new(connection))
+            assert_eq!(run_command(&c, "DBSIZE", &[], Action::Continue), Value::Integer(1));
         }
     }
 
-    fn make_connection() -> FakeConnection { FakeConnection::new() }
+    #[test]
+    fn randomkey_never_returns_an_expired_key() {
+        let c = make_connection();
 
-    fn add_more_items(connection: &FakeConnection) {
-        let connection = connection.get_sqlite_connection_mutex().lock().unwrap();
-        connection.execute("INSERT INTO list_items (key, value, position) VALUES (X'74657374', X'676869', -6), (X'74657374', X'6A6B6C', -7), (X'74657374', X'6D6E6F', -8), (X'74657374', X'707172', -9), (X'74657375', X'616263', 1)", &[]).unwrap();
-    }
+        assert_eq!(run_command(&c, "RANDOMKEY", &[], Action::Continue), Value::BufBulk(b"test".to_vec()));
 
-    fn make_command<'a>(name: &'static str, arguments: &[&'a str], connection: &'a FakeConnection) -> Command<'a> {
-        Command {
-            name:       name,
-            arguments:  arguments.to_vec().iter().map(|arg| arg.as_bytes()).collect(),
-            connection: connection as &Connectionable
+        run_command(&c, "HSET", &["hash", "a", "1"], Action::Continue);
+        run_command(&c, "PEXPIRE", &["test", "1"], Action::Continue);
+        thread::sleep(Duration::from_millis(20));
+
+        for _ in 0..20 {
+            assert_eq!(run_command(&c, "RANDOMKEY", &[], Action::Continue), Value::BufBulk(b"hash".to_vec()));
         }
+
+        run_command(&c, "PEXPIRE", &["hash", "1"], Action::Continue);
+        thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(run_command(&c, "RANDOMKEY", &[], Action::Continue), Value::Null);
+
+        // with no other key to pick, both expired keys were picked and swept away
+        let sqlite_connection = c.get_sqlite_connection_mutex().lock().unwrap();
+        let rows: i64 = sqlite_connection.query_row("SELECT (SELECT COUNT(*) FROM key_meta) + (SELECT COUNT(*) FROM list_items) + (SELECT COUNT(*) FROM hash_items)", &[], |row| row.get(0)).unwrap();
+        assert_eq!(rows, 0);
     }
 
-    fn run_command<'a>(connection: &FakeConnection, name: &'static str, arguments: &[&'a str], expect_action: Action) -> Value {
-        let mut command = make_command(name, arguments, connection);
-        let (value, action) = command.execute();
-        assert_eq!(action, expect_action);
-        value
+    #[test]
+    fn an_emptied_key_does_not_pass_its_expiry_on() {
+        let c = make_connection();
+
+        run_command(&c, "EXPIRE", &["test", "100"], Action::Continue);
+        run_command(&c, "LTRIM", &["test", "1", "0"], Action::Continue);
+        run_command(&c, "RPUSH", &["test", "new"], Action::Continue);
+
+        assert_eq!(run_command(&c, "TTL", &["test"], Action::Continue), Value::Integer(-1));
     }
 
-    fn list_key(key: &'static str, connection: &FakeConnection) -> Vec<String> {
+    fn string_value(key: &'static str, connection: &FakeConnection) -> Option<String> {
         let connection = connection.get_sqlite_connection_mutex().lock().unwrap();
-        let mut statement = connection.prepare("SELECT value FROM list_items WHERE key = ?1 ORDER BY position").unwrap();
-        let rows = statement.query_map(&[&key.as_bytes()], |row| String::from_utf8(row.get(0)).unwrap()).unwrap();
-        let result: Result<Vec<String>, _> = rows.collect();
-        result.unwrap()
+        Command::find_string(&*connection, key.as_bytes()).map(|value| String::from_utf8(value).unwrap())
     }
 
     #[test]
-    fn quit() {
+    fn setex_and_psetex() {
         let c = make_connection();
-        assert_eq!(run_command(&c, "QUIT", &[], Action::HangUp), Value::String("OK".to_string()));
+
+        assert_eq!(run_command(&c, "SETEX", &["greeting", "100", "hello"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(string_value("greeting", &c), Some("hello".to_string()));
+        assert_eq!(run_command(&c, "TTL", &["greeting"], Action::Continue), Value::Integer(100));
+
+        assert_eq!(run_command(&c, "PSETEX", &["greeting", "5000", "hi"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(string_value("greeting", &c), Some("hi".to_string()));
+        assert_eq!(run_command(&c, "TTL", &["greeting"], Action::Continue), Value::Integer(5));
+
+        assert_eq!(run_command(&c, "SETEX", &["test", "100", "replaced"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(list_key("test", &c), vec![] as Vec<String>);
+        assert_eq!(string_value("test", &c), Some("replaced".to_string()));
+
+        assert_eq!(run_command(&c, "PSETEX", &["short", "1", "gone"], Action::Continue), Value::String("OK".to_string()));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(run_command(&c, "TTL", &["short"], Action::Continue), Value::Integer(-2));
+        assert_eq!(string_value("short", &c), None);
     }
 
     #[test]
-    fn llen() {
+    fn setex_rejects_a_time_that_is_not_positive() {
         let c = make_connection();
-        assert_eq!(run_command(&c, "LLEN", &["test"], Action::Continue), Value::Integer(2));
-        assert_eq!(run_command(&c, "LLEN", &["other"], Action::Continue), Value::Integer(0));
+
+        assert_eq!(run_command(&c, "SETEX", &["greeting", "0", "hello"], Action::Continue), Value::Error("ERR invalid expire time in 'setex' command".to_string()));
+        assert_eq!(run_command(&c, "PSETEX", &["greeting", "-5", "hello"], Action::Continue), Value::Error("ERR invalid expire time in 'psetex' command".to_string()));
+        assert_eq!(run_command(&c, "SETEX", &["greeting", "9223372036854775807", "hello"], Action::Continue), Value::Error("ERR invalid expire time in 'setex' command".to_string()));
+        assert_eq!(run_command(&c, "SETEX", &["greeting", "soon", "hello"], Action::Continue), Value::Error("ERR argument must be an integer".to_string()));
+        assert_eq!(string_value("greeting", &c), None);
     }
 
     #[test]
-    fn lpop() {
+    fn version_row_is_not_a_string_key() {
         let c = make_connection();
-        assert_eq!(run_command(&c, "LPOP", &["test"], Action::Continue), Value::BufBulk("def".to_string().into_bytes()));
-        assert_eq!(run_command(&c, "LPOP", &["test"], Action::Continue), Value::BufBulk("abc".to_string().into_bytes()));
-        assert_eq!(run_command(&c, "LPOP", &["test"], Action::Continue), Value::Null);
 
-        assert_eq!(run_command(&c, "LPOP", &["other"], Action::Continue), Value::Null);
+        assert_eq!(run_command(&c, "TTL", &["version"], Action::Continue), Value::Integer(-2));
+        run_command(&c, "SETEX", &["version", "100", "mine"], Action::Continue);
+        assert_eq!(string_value("version", &c), Some("mine".to_string()));
+        assert_eq!(version_rows(&c), 1);
     }
 
     #[test]
-    fn rpop() {
+    fn dump_and_restore_a_string() {
         let c = make_connection();
-        assert_eq!(run_command(&c, "RPOP", &["test"], Action::Continue), Value::BufBulk("abc".to_string().into_bytes()));
-        assert_eq!(run_command(&c, "RPOP", &["test"], Action::Continue), Value::BufBulk("def".to_string().into_bytes()));
-        assert_eq!(run_command(&c, "RPOP", &["test"], Action::Continue), Value::Null);
+        run_command(&c, "SETEX", &["greeting", "100", "hello"], Action::Continue);
 
-        assert_eq!(run_command(&c, "RPOP", &["other"], Action::Continue), Value::Null);
+        let payload = match run_command(&c, "DUMP", &["greeting"], Action::Continue) {
+            Value::BufBulk(payload) => payload,
+            _ => panic!("invalid")
+        };
+
+        let mut command = make_command("RESTORE", &["copy", "0"], &c);
+        command.arguments.push(&payload);
+        assert_eq!(command.execute().0, Value::String("OK".to_string()));
+        assert_eq!(string_value("copy", &c), Some("hello".to_string()));
+        assert_eq!(run_command(&c, "TTL", &["copy"], Action::Continue), Value::Integer(-1));
     }
 
     #[test]
-    fn lpush() {
+    fn dump_and_restore() {
         let c = make_connection();
-        assert_eq!(run_command(&c, "LPUSH", &["test", "ghi"], Action::Continue), Value::Integer(3));
-        assert_eq!(run_command(&c, "LPUSH", &["test", "jkl"], Action::Continue), Value::Integer(4));
-        assert_eq!(list_key("test", &c), vec!["jkl", "ghi", "def", "abc"]);
 
-        assert_eq!(run_command(&c, "LPUSH", &["other", "pqr"], Action::Continue), Value::Integer(1));
-        assert_eq!(list_key("other", &c), vec!["pqr"]);
+        let payload = match run_command(&c, "DUMP", &["test"], Action::Continue) {
+            Value::BufBulk(payload) => payload,
+            _ => panic!("invalid")
+        };
+
+        assert_eq!(run_command(&c, "DUMP", &["other"], Action::Continue), Value::Null);
+
+        let mut command = make_command("RESTORE", &["other", "0"], &c);
+        command.arguments.push(&payload);
+        assert_eq!(command.execute(), (Value::String("OK".to_string()), Action::Continue));
+        assert_eq!(list_key("other", &c), vec!["def", "abc"]);
+
+        let mut command = make_command("RESTORE", &["other", "0"], &c);
+        command.arguments.push(&payload);
+        assert_eq!(command.execute().0, Value::Error("BUSYKEY Target key name already exists.".to_string()));
+
+        run_command(&c, "RPUSH", &["other", "ghi"], Action::Continue);
+
+        let mut command = make_command("RESTORE", &["other", "0"], &c);
+        command.arguments.push(&payload);
+        command.arguments.push(b"replace");
+        assert_eq!(command.execute().0, Value::String("OK".to_string()));
+        assert_eq!(list_key("other", &c), vec!["def", "abc"]);
+        assert_eq!(run_command(&c, "TTL", &["other"], Action::Continue), Value::Integer(-1));
+
+        let mut command = make_command("RESTORE", &["other", "5000"], &c);
+        command.arguments.push(&payload);
+        command.arguments.push(b"REPLACE");
+        assert_eq!(command.execute().0, Value::String("OK".to_string()));
+        assert_eq!(run_command(&c, "TTL", &["other"], Action::Continue), Value::Integer(5));
     }
 
     #[test]
-    fn lpushx() {
+    fn restore_rejects_invalid_payloads() {
         let c = make_connection();
-        assert_eq!(run_command(&c, "LPUSHX", &["test", "ghi"], Action::Continue), Value::Integer(3));
-        assert_eq!(run_command(&c, "LPUSHX", &["test", "jkl"], Action::Continue), Value::Integer(4));
-        assert_eq!(list_key("test", &c), vec!["jkl", "ghi", "def", "abc"]);
 
-        assert_eq!(run_command(&c, "LPUSHX", &["other", "pqr"], Action::Continue), Value::Integer(0));
+        assert_eq!(run_command(&c, "RESTORE", &["other", "0", "garbage"], Action::Continue), Value::Error("ERR DUMP payload version or checksum are wrong".to_string()));
+        assert_eq!(run_command(&c, "RESTORE", &["other", "-1", "garbage"], Action::Continue), Value::Error("ERR Invalid TTL value, must be >= 0".to_string()));
+        assert_eq!(run_command(&c, "RESTORE", &["other", "0", "garbage", "NOPE"], Action::Continue), Value::Error("ERR syntax error".to_string()));
         assert_eq!(list_key("other", &c), vec![] as Vec<String>);
     }
 
     #[test]
-    fn rpush() {
-        let c = make_connection();
+    fn keys_longer_than_the_maximum_size_are_rejected() {
+        let mut c = make_connection();
+        c.config.max_key_size = 4;
+
+        assert_eq!(run_command(&c, "RPUSH", &["four", "a"], Action::Continue), Value::Integer(1));
+        assert_eq!(run_command(&c, "RPUSH", &["fives", "a"], Action::Continue), Value::Error("ERR key too long".to_string()));
+        assert_eq!(run_command(&c, "RPOPLPUSH", &["four", "fives"], Action::Continue), Value::Error("ERR key too long".to_string()));
+        assert_eq!(run_command(&c, "LLEN", &["fives"], Action::Continue), Value::Error("ERR key too long".to_string()));
+        assert_eq!(run_command(&c, "LLEN", &["four"], Action::Continue), Value::Integer(1));
+    }
+
+    #[test]
+    fn values_larger_than_the_maximum_size_are_rejected() {
+        let mut c = make_connection();
+        c.config.max_value_size = 3;
+
         assert_eq!(run_command(&c, "RPUSH", &["test", "ghi"], Action::Continue), Value::Integer(3));
-        assert_eq!(run_command(&c, "RPUSH", &["test", "jkl"], Action::Continue), Value::Integer(4));
-        assert_eq!(list_key("test", &c), vec!["def", "abc", "ghi", "jkl"]);
+        assert_eq!(run_command(&c, "RPUSH", &["test", "jkl", "mnop"], Action::Continue), Value::Error("ERR value too large".to_string()));
+        assert_eq!(run_command(&c, "LPUSHX", &["test", "mnop"], Action::Continue), Value::Error("ERR value too large".to_string()));
+        assert_eq!(run_command(&c, "LSET", &["test", "0", "mnop"], Action::Continue), Value::Error("ERR value too large".to_string()));
+        assert_eq!(run_command(&c, "LINSERT", &["test", "BEFORE", "abc", "mnop"], Action::Continue), Value::Error("ERR value too large".to_string()));
+        assert_eq!(list_key("test", &c), vec!["def", "abc", "ghi"]);
+    }
 
-        assert_eq!(run_command(&c, "RPUSH", &["other", "pqr"], Action::Continue), Value::Integer(1));
-        assert_eq!(list_key("other", &c), vec!["pqr"]);
+    #[test]
+    fn debug() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "DEBUG", &["jmap"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(run_command(&c, "DEBUG", &["CHANGE-REPL-ID"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(run_command(&c, "DEBUG", &["SEGFAULT"], Action::Continue), Value::Error("ERR unknown subcommand 'SEGFAULT'. Try DEBUG HELP.".to_string()));
     }
 
     #[test]
-    fn rpushx() {
+    fn debug_flushall_flushes_as_flushall_does() {
         let c = make_connection();
-        assert_eq!(run_command(&c, "RPUSHX", &["test", "ghi"], Action::Continue), Value::Integer(3));
-        assert_eq!(run_command(&c, "RPUSHX", &["test", "jkl"], Action::Continue), Value::Integer(4));
-        assert_eq!(list_key("test", &c), vec!["def", "abc", "ghi", "jkl"]);
 
-        assert_eq!(run_command(&c, "RPUSHX", &["other", "pqr"], Action::Continue), Value::Integer(0));
-        assert_eq!(list_key("other", &c), vec![] as Vec<String>);
+        assert_eq!(run_command(&c, "DEBUG", &["FLUSHALL", "NOPE"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(run_command(&c, "DBSIZE", &[], Action::Continue), Value::Integer(1));
+
+        assert_eq!(run_command(&c, "DEBUG", &["FLUSHALL"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(run_command(&c, "DBSIZE", &[], Action::Continue), Value::Integer(0));
     }
 
-    fn unpack(v: Value) -> Vec<String> {
-        match v {
-            Value::Array(array) => array.iter().map(|bufbulk|
-                match *bufbulk {
-                    Value::BufBulk(ref data) => String::from_utf8(data.clone()).unwrap(),
-                    _ => panic!("invalid")
-                }
-            ).collect(),
-            _ => panic!("invalid")
+    #[test]
+    fn debug_reindex() {
+        let c = make_connection();
+        add_more_items(&c);
+
+        assert_eq!(run_command(&c, "DEBUG", &["REINDEX"], Action::Continue), Value::String("OK".to_string()));
+        assert_eq!(run_lrange(&["test", "1", "3"], &c), vec!["mno", "jkl", "ghi"]);
+        assert_eq!(run_command(&c, "LINDEX", &["test", "-1"], Action::Continue), Value::BufBulk(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn debug_loadlist_pushes_each_line_of_a_file() {
+        let load_dir = env::temp_dir().join("blueis-loadlist-test");
+        fs::create_dir_all(&load_dir).unwrap();
+        fs::write(load_dir.join("seed.txt"), "first\nsecond\n\nfourth\n").unwrap();
+
+        let mut c = make_connection();
+        c.config.requirepass = Some("secret".to_string());
+
+        assert_eq!(run_command(&c, "DEBUG", &["LOADLIST", "seeded", "seed.txt"], Action::Continue), Value::Error("ERR loading files is disabled, as blueis was started without --load-dir".to_string()));
+
+        c.config.load_dir = Some(load_dir.to_string_lossy().into_owned());
+
+        // only a client authenticated with --requirepass can load a file
+        let refused = Value::Error("ERR loading files is only allowed for a client authenticated with --requirepass".to_string());
+
+        c.authenticated = false;
+        assert_eq!(run_command(&c, "DEBUG", &["LOADLIST", "seeded", "seed.txt"], Action::Continue), refused);
+
+        c.authenticated = true;
+        c.config.requirepass = None;
+        assert_eq!(run_command(&c, "DEBUG", &["LOADLIST", "seeded", "seed.txt"], Action::Continue), refused);
+
+        c.config.requirepass = Some("secret".to_string());
+        assert_eq!(run_command(&c, "DEBUG", &["LOADLIST", "seeded", "seed.txt"], Action::Continue), Value::Integer(4));
+        assert_eq!(run_lrange(&["seeded", "0", "-1"], &c), vec!["first", "second", "", "fourth"]);
+
+        // onto the end of a list that's already there
+        assert_eq!(run_command(&c, "DEBUG", &["LOADLIST", "test", "seed.txt"], Action::Continue), Value::Integer(4));
+        assert_eq!(run_lrange(&["test", "0", "-1"], &c), vec!["def", "abc", "first", "second", "", "fourth"]);
+
+        let outside = run_command(&c, "DEBUG", &["LOADLIST", "seeded", "../blueis-loadlist-test/../"], Action::Continue);
+        assert_eq!(outside, Value::Error("ERR ../blueis-loadlist-test/../ is outside --load-dir".to_string()));
+
+        match run_command(&c, "DEBUG", &["LOADLIST", "seeded", "missing.txt"], Action::Continue) {
+            Value::Error(message) => assert!(message.starts_with("ERR unable to read missing.txt: "), "{}", message),
+            other                 => panic!("{:?}", other)
         }
+
+        assert_eq!(run_lrange(&["seeded", "0", "-1"], &c), vec!["first", "second", "", "fourth"]);
+
+        // the key is held to --max-key-size and --max-keys as RPUSH's would be
+        c.config.max_key_size = 6;
+        assert_eq!(run_command(&c, "DEBUG", &["LOADLIST", "longer", "seed.txt"], Action::Continue), Value::Integer(4));
+        assert_eq!(run_command(&c, "DEBUG", &["LOADLIST", "longest", "seed.txt"], Action::Continue), Value::Error("ERR key too long".to_string()));
+
+        c.config.max_keys = match run_command(&c, "DBSIZE", &[], Action::Continue) {
+            Value::Integer(keys) => keys as usize,
+            other                => panic!("{:?}", other)
+        };
+        assert_eq!(run_command(&c, "DEBUG", &["LOADLIST", "more", "seed.txt"], Action::Continue), Value::Error("OOM command not allowed when the number of keys would exceed 'max-keys'.".to_string()));
+        assert_eq!(run_command(&c, "DEBUG", &["LOADLIST", "seeded", "seed.txt"], Action::Continue), Value::Integer(4));
+        assert_eq!(run_command(&c, "EXISTS", &["more"], Action::Continue), Value::Integer(0));
+
+        fs::remove_dir_all(&load_dir).ok();
     }
 
-    fn run_lrange<'a>(arguments: &[&'a str], connection: &FakeConnection) -> Vec<String> {
-        unpack(run_command(&connection, "LRANGE", arguments, Action::Continue))
+    #[test]
+    fn debug_list_info() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "DEBUG", &["QUICKLIST", "test"], Action::Continue), Value::BufBulk(b"rows:2\nmin_position:-5\nmax_position:-4\nrenumber_pending:1\n".to_vec()));
+
+        run_command(&c, "RPUSH", &["other", "a", "b", "c"], Action::Continue);
+        assert_eq!(run_command(&c, "DEBUG", &["listpack", "other"], Action::Continue), Value::BufBulk(b"rows:3\nmin_position:1000\nmax_position:3000\nrenumber_pending:0\n".to_vec()));
+
+        assert_eq!(run_command(&c, "DEBUG", &["QUICKLIST", "missing"], Action::Continue), Value::Error("ERR no such key".to_string()));
     }
 
-    struct LrangeCase<'a> {
-        arguments: &'a [&'a str],
-        expected: Vec<&'a str>
+    #[test]
+    fn mpop() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "MPOP", &["2", "other", "test", "LEFT"], Action::Continue), Value::Array(vec![Value::BufBulk("test".to_string().into_bytes()), Value::BufBulk("def".to_string().into_bytes())]));
+        assert_eq!(run_command(&c, "MPOP", &["2", "other", "test", "right"], Action::Continue), Value::Array(vec![Value::BufBulk("test".to_string().into_bytes()), Value::BufBulk("abc".to_string().into_bytes())]));
+        assert_eq!(run_command(&c, "MPOP", &["2", "other", "test", "LEFT"], Action::Continue), Value::NullArray);
+
+        assert_eq!(run_command(&c, "MPOP", &["0", "test", "LEFT"], Action::Continue), Value::Error("ERR numkeys should be greater than 0".to_string()));
+        assert_eq!(run_command(&c, "MPOP", &["9223372036854775807", "test", "LEFT"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(run_command(&c, "MPOP", &["2", "test", "LEFT"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(run_command(&c, "MPOP", &["1", "test", "UP"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+    }
+
+    fn popped(key: &str, values: &[&str]) -> Value {
+        Value::Array(vec![Value::BufBulk(key.as_bytes().to_vec()), bulk_strings(values)])
     }
 
     #[test]
-    fn lrange() {
+    fn lmpop() {
         let c = make_connection();
-        add_more_items(&c);
+        run_command(&c, "RPUSH", &["test", "ghi", "jkl"], Action::Continue);
 
-        let cases = [
-            LrangeCase { arguments: &["test", "0", "-1"], expected: vec!["pqr", "mno", "jkl", "ghi", "def", "abc"] },
-            LrangeCase { arguments: &["test", "0", "2"], expected: vec!["pqr", "mno", "jkl"] },
-            LrangeCase { arguments: &["test", "3", "-1"], expected: vec!["ghi", "def", "abc"] },
-            LrangeCase { arguments: &["test", "9", "-1"], expected: vec![] as Vec<&str> },
-            LrangeCase { arguments: &["test", "3", "2"], expected: vec![] as Vec<&str> },
-            LrangeCase { arguments: &["test", "-100", "-80"], expected: vec![] as Vec<&str> },
-            LrangeCase { arguments: &["other", "0", "-1"], expected: vec![] as Vec<&str> },
-            LrangeCase { arguments: &["test", "3", "3"], expected: vec!["ghi"] },
-            LrangeCase { arguments: &["test", "3", "4"], expected: vec!["ghi", "def"] },
-            LrangeCase { arguments: &["test", "3", "5"], expected: vec!["ghi", "def", "abc"] },
-            LrangeCase { arguments: &["test", "3", "6"], expected: vec!["ghi", "def", "abc"] },
-            LrangeCase { arguments: &["test", "3", "-3"], expected: vec!["ghi"] },
-            LrangeCase { arguments: &["test", "3", "-2"], expected: vec!["ghi", "def"] },
-            LrangeCase { arguments: &["test", "-3", "3"], expected: vec!["ghi"] },
-            LrangeCase { arguments: &["test", "-3", "4"], expected: vec!["ghi", "def"] },
-            LrangeCase { arguments: &["test", "-3", "-3"], expected: vec!["ghi"] },
-            LrangeCase { arguments: &["test", "-3", "-2"], expected: vec!["ghi", "def"] },
+        assert_eq!(run_command(&c, "LMPOP", &["2", "other", "test", "LEFT"], Action::Continue), popped("test", &["def"]));
+        assert_eq!(run_command(&c, "LMPOP", &["2", "other", "test", "right", "COUNT", "2"], Action::Continue), popped("test", &["jkl", "ghi"]));
+        assert_eq!(run_command(&c, "LMPOP", &["1", "test", "LEFT", "count", "10"], Action::Continue), popped("test", &["abc"]));
+        assert_eq!(run_command(&c, "LMPOP", &["2", "other", "test", "LEFT"], Action::Continue), Value::NullArray);
+    }
+
+    #[test]
+    fn lmpop_rejects_invalid_arguments() {
+        let c = make_connection();
+
+        assert_eq!(run_command(&c, "LMPOP", &["0", "test", "LEFT"], Action::Continue), Value::Error("ERR numkeys should be greater than 0".to_string()));
+        assert_eq!(run_command(&c, "LMPOP", &["2", "test", "LEFT"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(run_command(&c, "LMPOP", &["1", "test", "UP"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(run_command(&c, "LMPOP", &["1", "test", "LEFT", "COUNT"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(run_command(&c, "LMPOP", &["1", "test", "LEFT", "COUNT", "0"], Action::Continue), Value::Error("ERR count should be greater than 0".to_string()));
+        assert_eq!(run_command(&c, "LMPOP", &["1", "test", "LEFT", "LIMIT", "2"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(run_command(&c, "BLMPOP", &["-1", "1", "test", "LEFT"], Action::Continue), Value::Error("ERR timeout is negative".to_string()));
+        assert_eq!(run_command(&c, "BLMPOP", &["1", "1", "test", "LEFT", "COUNT", "-2"], Action::Continue), Value::Error("ERR count should be greater than 0".to_string()));
+        assert_eq!(list_key("test", &c), vec!["def", "abc"]);
+    }
+
+    #[test]
+    fn lmpop_and_blmpop_pop_the_same_elements() {
+        let pops: &[&[&str]] = &[
+            &["2", "other", "test", "RIGHT"],
+            &["1", "queue", "LEFT", "COUNT", "2"],
+            &["3", "missing", "queue", "test", "RIGHT", "COUNT", "5"],
+            &["2", "test", "queue", "LEFT", "COUNT", "5"],
         ];
 
-        for case in cases.iter() {
-            assert_eq!(run_lrange(&case.arguments, &c), case.expected);
-        }
+        let results = |name: &'static str, timeout: Option<&'static str>| -> Vec<Value> {
+            let c = make_connection();
+            run_command(&c, "RPUSH", &["queue", "a", "b", "c"], Action::Continue);
+
+            pops.iter().map(|arguments| {
+                let arguments: Vec<&str> = timeout.into_iter().chain(arguments.iter().cloned()).collect();
+                run_command(&c, name, &arguments, Action::Continue)
+            }).collect()
+        };
+
+        let lmpop = results("LMPOP", None);
+        assert_eq!(lmpop, vec![popped("test", &["abc"]), popped("queue", &["a", "b"]), popped("queue", &["c"]), popped("test", &["def"])]);
+        assert_eq!(results("BLMPOP", Some("1")), lmpop);
     }
 
     #[test]
-    fn ltrim() {
+    fn blmpop_waits_for_a_push() {
         let c = make_connection();
-        add_more_items(&c);
+        let pusher = c.sharing();
 
-        assert_eq!(run_command(&c, "LTRIM", &["test", "0", "-1"], Action::Continue), Value::String("OK".to_string()));
-        assert_eq!(list_key("test", &c), vec!["pqr", "mno", "jkl", "ghi", "def", "abc"]);
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            run_command(&pusher, "RPUSH", &["queue", "a", "b", "c"], Action::Continue);
+        });
 
-        assert_eq!(run_command(&c, "LTRIM", &["test", "1", "-2"], Action::Continue), Value::String("OK".to_string()));
-        assert_eq!(list_key("test", &c), vec!["mno", "jkl", "ghi", "def"]);
+        assert_eq!(run_command(&c, "BLMPOP", &["5", "1", "queue", "LEFT", "COUNT", "2"], Action::Continue), popped("queue", &["a", "b"]));
+        handle.join().unwrap();
 
-        assert_eq!(run_command(&c, "LTRIM", &["test", "-3", "2"], Action::Continue), Value::String("OK".to_string()));
-        assert_eq!(list_key("test", &c), vec!["jkl", "ghi"]);
+        let start = Instant::now();
+        assert_eq!(run_command(&c, "BLMPOP", &["1", "1", "missing", "LEFT"], Action::Continue), Value::NullArray);
+        assert_eq!(start.elapsed().as_secs(), 1);
+    }
 
-        assert_eq!(run_command(&c, "LTRIM", &["test", "300", "200"], Action::Continue), Value::String("OK".to_string()));
-        assert_eq!(list_key("test", &c), vec![] as Vec<String>);
+    #[test]
+    fn brpoplpush_and_blmove_wait_for_a_push() {
+        let c = make_connection();
+        let pusher = c.sharing();
+
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            run_command(&pusher, "RPUSH", &["queue", "a", "b"], Action::Continue);
+        });
+
+        assert_eq!(run_command(&c, "BRPOPLPUSH", &["queue", "done", "5"], Action::Continue), Value::BufBulk(b"b".to_vec()));
+        handle.join().unwrap();
+
+        assert_eq!(run_command(&c, "BLMOVE", &["queue", "done", "LEFT", "RIGHT", "5"], Action::Continue), Value::BufBulk(b"a".to_vec()));
+        assert_eq!(run_command(&c, "BLMOVE", &["done", "done", "LEFT", "RIGHT", "5"], Action::Continue), Value::BufBulk(b"b".to_vec()));
+        assert_eq!(list_key("done", &c), vec!["a", "b"]);
+
+        let start = Instant::now();
+        assert_eq!(run_command(&c, "BLMOVE", &["queue", "done", "RIGHT", "LEFT", "1"], Action::Continue), Value::Null);
+        assert_eq!(start.elapsed().as_secs(), 1);
+
+        assert_eq!(run_command(&c, "BLMOVE", &["queue", "done", "UP", "LEFT", "1"], Action::Continue), Value::Error("ERR syntax error".to_string()));
+        assert_eq!(run_command(&c, "BRPOPLPUSH", &["queue", "done", "-1"], Action::Continue), Value::Error("ERR timeout is negative".to_string()));
     }
 
     #[test]
-    fn rpoplpush() {
+    fn rpoplpush_wakes_a_client_blocked_on_the_destination() {
         let c = make_connection();
 
-        assert_eq!(run_command(&c, "RPOPLPUSH", &["test", "other"], Action::Continue), Value::BufBulk("abc".to_string().into_bytes()));
-        assert_eq!(list_key("test", &c), vec!["def"]);
-        assert_eq!(list_key("other", &c), vec!["abc"]);
+        let blocked = |key: &'static str| {
+            let waiter = c.sharing();
+            thread::spawn(move || {
+                let start = Instant::now();
+                let value = run_command(&waiter, "BLPOP", &[key, "1"], Action::Continue);
+                (value, start.elapsed())
+            })
+        };
 
-        assert_eq!(run_command(&c, "RPOPLPUSH", &["test", "other"], Action::Continue), Value::BufBulk("def".to_string().into_bytes()));
-        assert_eq!(list_key("test", &c), vec![] as Vec<String>);
-        assert_eq!(list_key("other", &c), vec!["def", "abc"]);
+        let destination = blocked("destination");
+        let unrelated = blocked("unrelated");
 
-        assert_eq!(run_command(&c, "RPOPLPUSH", &["test", "other"], Action::Continue), Value::Null);
-        assert_eq!(list_key("test", &c), vec![] as Vec<String>);
-        assert_eq!(list_key("other", &c), vec!["def", "abc"]);
+        thread::sleep(Duration::from_millis(100));
+        run_command(&c, "RPOPLPUSH", &["test", "destination"], Action::Continue);
+
+        let (value, elapsed) = destination.join().unwrap();
+        assert_eq!(value, Value::Array(vec![Value::BufBulk(b"destination".to_vec()), Value::BufBulk(b"abc".to_vec())]));
+        assert!(elapsed < Duration::from_millis(500), "BLPOP took {:?} to see the move", elapsed);
+
+        assert_eq!(unrelated.join().unwrap().0, Value::NullArray);
     }
 
+    // a move commits before it notifies, and both happen under the connection lock, so a waiter woken by one always
+    // finds the element there
     #[test]
-    fn lindex() {
+    fn every_element_moved_reaches_a_client_blocked_on_the_destination() {
         let c = make_connection();
+        let values: Vec<String> = (0..200).map(|number| number.to_string()).collect();
+        let mut arguments = vec!["source"];
+        arguments.extend(values.iter().map(|value| value.as_str()));
+        run_command(&c, "RPUSH", &arguments, Action::Continue);
+
+        let waiters: Vec<_> = (0..4).map(|_| {
+            let waiter = c.sharing();
+            thread::spawn(move || {
+                let mut popped = vec![];
+
+                while let Value::Array(mut reply) = run_command(&waiter, "BLPOP", &["destination", "1"], Action::Continue) {
+                    match reply.pop() {
+                        Some(Value::BufBulk(value)) => popped.push(String::from_utf8(value).unwrap()),
+                        other                       => panic!("unexpected reply {:?}", other)
+                    }
+                }
 
-        assert_eq!(run_command(&c, "LINDEX", &["test", "0"], Action::Continue), Value::BufBulk("def".to_string().into_bytes()));
-        assert_eq!(run_command(&c, "LINDEX", &["test", "1"], Action::Continue), Value::BufBulk("abc".to_string().into_bytes()));
-        assert_eq!(run_command(&c, "LINDEX", &["test", "2"], Action::Continue), Value::Null);
-        assert_eq!(run_command(&c, "LINDEX", &["test", "-1"], Action::Continue), Value::BufBulk("abc".to_string().into_bytes()));
-        assert_eq!(run_command(&c, "LINDEX", &["test", "-2"], Action::Continue), Value::BufBulk("def".to_string().into_bytes()));
-        assert_eq!(run_command(&c, "LINDEX", &["test", "-3"], Action::Continue), Value::Null);
+                popped
+            })
+        }).collect();
+
+        for _ in 0..values.len() {
+            run_command(&c, "LMOVE", &["source", "destination", "LEFT", "RIGHT"], Action::Continue);
+        }
+
+        let mut popped: Vec<String> = waiters.into_iter().flat_map(|waiter| waiter.join().unwrap()).collect();
+        popped.sort_by_key(|value| value.parse::<i64>().unwrap());
+
+        assert_eq!(popped, values);
+        assert_eq!(run_command(&c, "LLEN", &["destination"], Action::Continue), Value::Integer(0));
     }
 
+    // a wakeup missed between a waiter's pop and its wait would leave it until its next once-a-second retry
     #[test]
-    fn lset() {
+    fn blpop_is_woken_by_every_push_without_delay() {
         let c = make_connection();
 
-        assert_eq!(run_command(&c, "LSET", &["test", "0", "first"], Action::Continue), Value::String("OK".to_string()));
-        assert_eq!(list_key("test", &c), vec!["first", "abc"]);
+        for _ in 0..200 {
+            let waiter = c.sharing();
+            let handle = thread::spawn(move || {
+                let start = Instant::now();
+                let value = run_command(&waiter, "BLPOP", &["queue", "5"], Action::Continue);
+                (value, start.elapsed())
+            });
 
-        assert_eq!(run_command(&c, "LSET", &["test", "1", "second"], Action::Continue), Value::String("OK".to_string()));
-        assert_eq!(list_key("test", &c), vec!["first", "second"]);
+            run_command(&c, "RPUSH", &["queue", "a"], Action::Continue);
 
-        assert_eq!(run_command(&c, "LSET", &["test", "-1", "apple"], Action::Continue), Value::String("OK".to_string()));
-        assert_eq!(list_key("test", &c), vec!["first", "apple"]);
+            let (value, elapsed) = handle.join().unwrap();
+            assert_eq!(value, Value::Array(vec![Value::BufBulk(b"queue".to_vec()), Value::BufBulk(b"a".to_vec())]));
+            assert!(elapsed < Duration::from_millis(500), "BLPOP took {:?} to see a push", elapsed);
+        }
+    }
 
-        assert_eq!(run_command(&c, "LSET", &["test", "-2", "banana"], Action::Continue), Value::String("OK".to_string()));
-        assert_eq!(list_key("test", &c), vec!["banana", "apple"]);
+    // each blocked client takes only what it asked for, so one push of two elements serves both
+    #[test]
+    fn blmpop_serves_every_blocked_client() {
+        let c = make_connection();
 
-        assert_eq!(run_command(&c, "LSET", &["test", "-3", "nope"], Action::Continue), Value::Error("ERR index out of range".to_string()));
-        assert_eq!(run_command(&c, "LSET", &["test", "2", "nope"], Action::Continue), Value::Error("ERR index out of range".to_string()));
+        let handles: Vec<_> = (0..2).map(|_| {
+            let client = c.sharing();
+            thread::spawn(move || run_command(&client, "BLMPOP", &["5", "1", "queue", "LEFT"], Action::Continue))
+        }).collect();
+
+        thread::sleep(Duration::from_millis(100));
+        run_command(&c, "RPUSH", &["queue", "a", "b"], Action::Continue);
+
+        let mut results: Vec<Value> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        results.sort_by_key(|result| format!("{:?}", result));
+
+        assert_eq!(results, vec![popped("queue", &["a"]), popped("queue", &["b"])]);
     }
 
     #[test]
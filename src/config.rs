@@ -0,0 +1,520 @@
+extern crate libc;
+
+use std::ffi::CString;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use pubsub::Overflow;
+
+pub struct Config {
+    pub address: String,
+    pub database_path: String,
+    pub tcp_keepalive: u32,
+    pub tcp_nodelay: bool,
+    pub max_value_size: usize,
+    pub max_key_size: usize,
+    pub max_threads: usize,
+    pub read_cache_size: usize,
+    pub check_integrity: bool,
+    pub warmup: bool,
+    pub appendfsync: AppendFsync,
+    pub list_max_listpack_size: i64,
+    pub logfile: Option<String>,
+    pub dir: Option<String>,
+    pub load_dir: Option<String>,
+    pub requirepass: Option<String>,
+    pub pubsub_buffer_limit: usize,
+    pub pubsub_overflow: Overflow,
+    pub client_output_buffer_limit: usize,
+    pub client_command_rate: usize,
+    pub max_keys: usize,
+    pub maxmemory_policy: EvictionPolicy,
+}
+
+// how often the database is synced to disk, named after redis's appendfsync policies
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AppendFsync {
+    Always,
+    EverySec,
+    No,
+}
+
+// which key makes room once --max-keys is reached, named after redis's maxmemory policies
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionPolicy {
+    NoEviction,
+    VolatileTtl,
+    VolatileRandom,
+}
+
+type ConfigResult = Result<Config, String>;
+
+pub const ADDRESS_VARIABLE: &'static str = "BLUEIS_ADDR";
+pub const DATABASE_VARIABLE: &'static str = "BLUEIS_DATABASE";
+
+const DEFAULT_MAX_VALUE_SIZE: usize = 512 * 1024 * 1024;
+const DEFAULT_MAX_KEY_SIZE: usize = 64 * 1024;
+const DEFAULT_LIST_MAX_LISTPACK_SIZE: i64 = 128;
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            address:        String::new(),
+            database_path:  String::new(),
+            tcp_keepalive:  0,
+            tcp_nodelay:    true,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            max_key_size:   DEFAULT_MAX_KEY_SIZE,
+            max_threads:    0,
+            read_cache_size: 0,
+            check_integrity: false,
+            warmup: false,
+            appendfsync: AppendFsync::Always,
+            list_max_listpack_size: DEFAULT_LIST_MAX_LISTPACK_SIZE,
+            logfile: None,
+            dir: None,
+            load_dir: None,
+            requirepass: None,
+            pubsub_buffer_limit: 0,
+            pubsub_overflow: Overflow::Disconnect,
+            client_output_buffer_limit: 0,
+            client_command_rate: 0,
+            max_keys: 0,
+            maxmemory_policy: EvictionPolicy::NoEviction,
+        }
+    }
+}
+
+impl Config {
+    // positional arguments take precedence over the environment, which is consulted through `env`
+    pub fn parse(args: &[String], env: &Fn(&str) -> Option<String>) -> ConfigResult {
+        let mut config = Config::default();
+        let mut positional: Vec<String> = vec![];
+
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--tcp-keepalive"  => config.tcp_keepalive = Config::parse_integer(arg, iter.next())?,
+                "--tcp-nodelay"    => config.tcp_nodelay = Config::parse_boolean(arg, iter.next())?,
+                "--max-value-size" => config.max_value_size = Config::parse_integer(arg, iter.next())?,
+                "--max-key-size"   => config.max_key_size = Config::parse_integer(arg, iter.next())?,
+                "--max-threads"    => config.max_threads = Config::parse_integer(arg, iter.next())?,
+                "--read-cache-size" => config.read_cache_size = Config::parse_integer(arg, iter.next())?,
+                "--check-integrity" => config.check_integrity = true,
+                "--warmup"          => config.warmup = true,
+                "--appendfsync"     => config.appendfsync = Config::parse_appendfsync(arg, iter.next())?,
+                "--list-max-listpack-size" => config.list_max_listpack_size = Config::parse_integer(arg, iter.next())?,
+                "--logfile" => config.logfile = Some(iter.next().ok_or(format!("{} requires a value", arg))?.clone()),
+                "--dir"     => config.dir = Some(iter.next().ok_or(format!("{} requires a value", arg))?.clone()),
+                "--load-dir" => config.load_dir = Some(iter.next().ok_or(format!("{} requires a value", arg))?.clone()),
+                "--requirepass" => config.requirepass = Some(iter.next().ok_or(format!("{} requires a value", arg))?.clone()),
+                "--pubsub-buffer-limit" => config.pubsub_buffer_limit = Config::parse_integer(arg, iter.next())?,
+                "--client-output-buffer-limit" => config.client_output_buffer_limit = Config::parse_integer(arg, iter.next())?,
+                "--client-command-rate" => config.client_command_rate = Config::parse_integer(arg, iter.next())?,
+                "--pubsub-overflow" => config.pubsub_overflow = Config::parse_overflow(arg, iter.next())?,
+                "--max-keys"        => config.max_keys = Config::parse_integer(arg, iter.next())?,
+                "--maxmemory-policy" => config.maxmemory_policy = Config::parse_eviction_policy(arg, iter.next())?,
+
+                option if option.starts_with("--") => return Err(format!("unknown option {}", option)),
+
+                _ => positional.push(arg.clone())
+            }
+        }
+
+        if positional.len() > 2 {
+            return Err("too many arguments".to_string());
+        }
+
+        let mut positional = positional.into_iter();
+
+        config.address = positional.next().or_else(|| env(ADDRESS_VARIABLE))
+            .ok_or(format!("a listen address is required, either as an argument or in {}", ADDRESS_VARIABLE))?;
+
+        config.database_path = positional.next().or_else(|| env(DATABASE_VARIABLE))
+            .ok_or(format!("a database path is required, either as an argument or in {}", DATABASE_VARIABLE))?;
+
+        // an absolute database path is left as it is, as is :memory:, which names no file at all
+        if let Some(ref dir) = config.dir {
+            if config.database_path != ":memory:" && !Path::new(&config.database_path).is_absolute() {
+                config.database_path = Path::new(dir).join(&config.database_path).to_string_lossy().into_owned();
+            }
+        }
+
+        Ok(config)
+    }
+
+    // checked at startup rather than while parsing, so the files blueis writes don't end up somewhere unexpected
+    pub fn check_dir(&self) -> Result<(), String> {
+        let dir = match self.dir {
+            Some(ref dir) => dir,
+            None          => return Ok(())
+        };
+
+        match fs::metadata(dir) {
+            Ok(ref metadata) if metadata.is_dir() => {}
+            _ => return Err(format!("{} is not a directory", dir))
+        }
+
+        let path = CString::new(dir.as_str()).map_err(|_| format!("{} is not a valid path", dir))?;
+
+        if unsafe { libc::access(path.as_ptr(), libc::W_OK) } != 0 {
+            return Err(format!("{} is not writable", dir));
+        }
+
+        Ok(())
+    }
+
+    // the settings CONFIG GET reports, named after their options
+    pub fn parameters(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("tcp-keepalive",          self.tcp_keepalive.to_string()),
+            ("tcp-nodelay",            (if self.tcp_nodelay { "yes" } else { "no" }).to_string()),
+            ("max-value-size",         self.max_value_size.to_string()),
+            ("max-key-size",           self.max_key_size.to_string()),
+            ("max-threads",            self.max_threads.to_string()),
+            ("databases",              "1".to_string()),
+            ("read-cache-size",        self.read_cache_size.to_string()),
+            ("warmup",                 (if self.warmup { "yes" } else { "no" }).to_string()),
+            ("appendfsync",            self.appendfsync.name().to_string()),
+            ("list-max-listpack-size", self.list_max_listpack_size.to_string()),
+            ("logfile",                self.logfile.clone().unwrap_or_default()),
+            ("dir",                    self.dir.clone().unwrap_or_default()),
+            ("load-dir",               self.load_dir.clone().unwrap_or_default()),
+            ("pubsub-buffer-limit",    self.pubsub_buffer_limit.to_string()),
+            ("client-output-buffer-limit", self.client_output_buffer_limit.to_string()),
+            ("client-command-rate",    self.client_command_rate.to_string()),
+            ("pubsub-overflow",        (if self.pubsub_overflow == Overflow::Disconnect { "disconnect" } else { "drop-oldest" }).to_string()),
+            ("max-keys",               self.max_keys.to_string()),
+            ("maxmemory-policy",       self.maxmemory_policy.name().to_string()),
+        ]
+    }
+
+    fn parse_boolean(option: &str, value: Option<&String>) -> Result<bool, String> {
+        match value.map(|value| value.as_str()) {
+            Some("yes") => Ok(true),
+            Some("no")  => Ok(false),
+            Some(_)     => Err(format!("{} must be yes or no", option)),
+            None        => Err(format!("{} requires a value", option))
+        }
+    }
+
+    fn parse_overflow(option: &str, value: Option<&String>) -> Result<Overflow, String> {
+        match value.map(|value| value.as_str()) {
+            Some("disconnect")  => Ok(Overflow::Disconnect),
+            Some("drop-oldest") => Ok(Overflow::DropOldest),
+            Some(_)             => Err(format!("{} must be disconnect or drop-oldest", option)),
+            None                => Err(format!("{} requires a value", option))
+        }
+    }
+
+    fn parse_appendfsync(option: &str, value: Option<&String>) -> Result<AppendFsync, String> {
+        match value.map(|value| value.as_str()) {
+            Some("always")   => Ok(AppendFsync::Always),
+            Some("everysec") => Ok(AppendFsync::EverySec),
+            Some("no")       => Ok(AppendFsync::No),
+            Some(_)          => Err(format!("{} must be always, everysec or no", option)),
+            None             => Err(format!("{} requires a value", option))
+        }
+    }
+
+    fn parse_eviction_policy(option: &str, value: Option<&String>) -> Result<EvictionPolicy, String> {
+        match value.map(|value| value.as_str()) {
+            Some("noeviction")      => Ok(EvictionPolicy::NoEviction),
+            Some("volatile-ttl")    => Ok(EvictionPolicy::VolatileTtl),
+            Some("volatile-random") => Ok(EvictionPolicy::VolatileRandom),
+            Some(_)                 => Err(format!("{} must be noeviction, volatile-ttl or volatile-random", option)),
+            None                    => Err(format!("{} requires a value", option))
+        }
+    }
+
+    fn parse_integer<T: FromStr>(option: &str, value: Option<&String>) -> Result<T, String> {
+        match value {
+            Some(value) => value.parse::<T>().map_err(|_| format!("{} must be a non-negative integer", option)),
+            None        => Err(format!("{} requires a value", option))
+        }
+    }
+}
+
+impl AppendFsync {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AppendFsync::Always   => "always",
+            AppendFsync::EverySec => "everysec",
+            AppendFsync::No       => "no",
+        }
+    }
+}
+
+impl EvictionPolicy {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EvictionPolicy::NoEviction     => "noeviction",
+            EvictionPolicy::VolatileTtl    => "volatile-ttl",
+            EvictionPolicy::VolatileRandom => "volatile-random",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AppendFsync, Config, EvictionPolicy};
+    use pubsub::Overflow;
+    use std::env;
+
+    fn args(input: &[&str]) -> Vec<String> {
+        input.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn no_env(_name: &str) -> Option<String> { None }
+
+    fn full_env(name: &str) -> Option<String> {
+        match name {
+            "BLUEIS_ADDR"     => Some("0.0.0.0:7000".to_string()),
+            "BLUEIS_DATABASE" => Some("env.sqlite3".to_string()),
+            _                 => None
+        }
+    }
+
+    #[test]
+    fn parses_positional_arguments() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+
+        assert_eq!(config.address, "127.0.0.1:6379");
+        assert_eq!(config.database_path, "db.sqlite3");
+        assert_eq!(config.tcp_keepalive, 0);
+    }
+
+    #[test]
+    fn parses_tcp_keepalive() {
+        let config = Config::parse(&args(&["--tcp-keepalive", "60", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.tcp_keepalive, 60);
+    }
+
+    #[test]
+    fn parses_tcp_nodelay() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert!(config.tcp_nodelay);
+
+        let config = Config::parse(&args(&["--tcp-nodelay", "no", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert!(!config.tcp_nodelay);
+
+        let config = Config::parse(&args(&["--tcp-nodelay", "yes", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert!(config.tcp_nodelay);
+
+        assert!(Config::parse(&args(&["--tcp-nodelay", "off", "127.0.0.1:6379", "db.sqlite3"]), &no_env).is_err());
+    }
+
+    #[test]
+    fn parses_max_value_size() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.max_value_size, 512 * 1024 * 1024);
+
+        let config = Config::parse(&args(&["--max-value-size", "1024", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.max_value_size, 1024);
+    }
+
+    #[test]
+    fn parses_max_key_size() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.max_key_size, 64 * 1024);
+
+        let config = Config::parse(&args(&["--max-key-size", "128", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.max_key_size, 128);
+    }
+
+    #[test]
+    fn parses_max_threads() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.max_threads, 0);
+
+        let config = Config::parse(&args(&["--max-threads", "64", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.max_threads, 64);
+    }
+
+    #[test]
+    fn parses_read_cache_size() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.read_cache_size, 0);
+
+        let config = Config::parse(&args(&["--read-cache-size", "100", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.read_cache_size, 100);
+    }
+
+    #[test]
+    fn parses_check_integrity() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert!(!config.check_integrity);
+
+        let config = Config::parse(&args(&["--check-integrity", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert!(config.check_integrity);
+    }
+
+    #[test]
+    fn parses_warmup() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert!(!config.warmup);
+
+        let config = Config::parse(&args(&["--warmup", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert!(config.warmup);
+    }
+
+    #[test]
+    fn parses_appendfsync() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.appendfsync, AppendFsync::Always);
+
+        let config = Config::parse(&args(&["--appendfsync", "everysec", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.appendfsync, AppendFsync::EverySec);
+
+        assert!(Config::parse(&args(&["--appendfsync", "sometimes", "127.0.0.1:6379", "db.sqlite3"]), &no_env).is_err());
+    }
+
+    #[test]
+    fn parses_list_max_listpack_size() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.list_max_listpack_size, 128);
+
+        let config = Config::parse(&args(&["--list-max-listpack-size", "4", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.list_max_listpack_size, 4);
+    }
+
+    #[test]
+    fn parses_logfile() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.logfile, None);
+
+        let config = Config::parse(&args(&["--logfile", "/var/log/blueis.log", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.logfile, Some("/var/log/blueis.log".to_string()));
+
+        assert!(Config::parse(&args(&["--logfile"]), &no_env).is_err());
+    }
+
+    #[test]
+    fn parses_load_dir() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.load_dir, None);
+
+        let config = Config::parse(&args(&["--load-dir", "/srv/seeds", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.load_dir, Some("/srv/seeds".to_string()));
+    }
+
+    #[test]
+    fn parses_requirepass() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.requirepass, None);
+
+        let config = Config::parse(&args(&["--requirepass", "secret", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.requirepass, Some("secret".to_string()));
+
+        // and it isn't given away by CONFIG GET
+        assert!(config.parameters().iter().all(|&(name, _)| name != "requirepass"));
+    }
+
+    #[test]
+    fn resolves_the_database_path_relative_to_dir() {
+        let config = Config::parse(&args(&["--dir", "/var/lib/blueis", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.dir, Some("/var/lib/blueis".to_string()));
+        assert_eq!(config.database_path, "/var/lib/blueis/db.sqlite3");
+
+        let config = Config::parse(&args(&["--dir", "/var/lib/blueis", "127.0.0.1:6379", "/tmp/db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.database_path, "/tmp/db.sqlite3");
+
+        let config = Config::parse(&args(&["--dir", "/var/lib/blueis", "127.0.0.1:6379", ":memory:"]), &no_env).unwrap();
+        assert_eq!(config.database_path, ":memory:");
+
+        assert!(Config::parse(&args(&["--dir"]), &no_env).is_err());
+    }
+
+    #[test]
+    fn checks_dir_is_a_writable_directory() {
+        let temp_dir = env::temp_dir().to_string_lossy().into_owned();
+
+        let config = Config::parse(&args(&["--dir", &temp_dir, "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.check_dir(), Ok(()));
+
+        let config = Config::parse(&args(&["--dir", "/nonexistent/blueis", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.check_dir(), Err("/nonexistent/blueis is not a directory".to_string()));
+
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.check_dir(), Ok(()));
+    }
+
+    #[test]
+    fn parses_client_output_buffer_limit() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.client_output_buffer_limit, 0);
+
+        let config = Config::parse(&args(&["--client-output-buffer-limit", "1048576", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.client_output_buffer_limit, 1048576);
+    }
+
+    #[test]
+    fn parses_client_command_rate() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.client_command_rate, 0);
+
+        let config = Config::parse(&args(&["--client-command-rate", "500", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!(config.client_command_rate, 500);
+    }
+
+    #[test]
+    fn parses_pubsub_buffer_limit_and_overflow() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!((config.pubsub_buffer_limit, config.pubsub_overflow), (0, Overflow::Disconnect));
+
+        let config = Config::parse(&args(&["--pubsub-buffer-limit", "1000", "--pubsub-overflow", "drop-oldest", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!((config.pubsub_buffer_limit, config.pubsub_overflow), (1000, Overflow::DropOldest));
+
+        assert!(Config::parse(&args(&["--pubsub-overflow", "block", "127.0.0.1:6379", "db.sqlite3"]), &no_env).is_err());
+    }
+
+    #[test]
+    fn parses_max_keys_and_maxmemory_policy() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!((config.max_keys, config.maxmemory_policy), (0, EvictionPolicy::NoEviction));
+
+        let config = Config::parse(&args(&["--max-keys", "1000", "--maxmemory-policy", "volatile-ttl", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        assert_eq!((config.max_keys, config.maxmemory_policy), (1000, EvictionPolicy::VolatileTtl));
+        assert!(config.parameters().contains(&("maxmemory-policy", "volatile-ttl".to_string())));
+
+        assert!(Config::parse(&args(&["--maxmemory-policy", "allkeys-lru", "127.0.0.1:6379", "db.sqlite3"]), &no_env).is_err());
+    }
+
+    #[test]
+    fn parameters_are_named_after_their_options() {
+        let config = Config::parse(&args(&["--tcp-nodelay", "no", "--max-threads", "8", "127.0.0.1:6379", "db.sqlite3"]), &no_env).unwrap();
+        let parameters = config.parameters();
+
+        assert!(parameters.contains(&("tcp-nodelay", "no".to_string())));
+        assert!(parameters.contains(&("max-threads", "8".to_string())));
+        assert!(parameters.contains(&("logfile", "".to_string())));
+    }
+
+    #[test]
+    fn uses_environment_variables_when_arguments_are_absent() {
+        let config = Config::parse(&args(&[]), &full_env).unwrap();
+
+        assert_eq!(config.address, "0.0.0.0:7000");
+        assert_eq!(config.database_path, "env.sqlite3");
+    }
+
+    #[test]
+    fn arguments_take_precedence_over_environment_variables() {
+        let config = Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3"]), &full_env).unwrap();
+
+        assert_eq!(config.address, "127.0.0.1:6379");
+        assert_eq!(config.database_path, "db.sqlite3");
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        assert!(Config::parse(&args(&[]), &no_env).is_err());
+        assert!(Config::parse(&args(&["127.0.0.1:6379"]), &no_env).is_err());
+        assert!(Config::parse(&args(&["127.0.0.1:6379", "db.sqlite3", "extra"]), &no_env).is_err());
+        assert!(Config::parse(&args(&["--tcp-keepalive", "127.0.0.1:6379", "db.sqlite3"]), &no_env).is_err());
+        assert!(Config::parse(&args(&["--tcp-keepalive", "-1", "127.0.0.1:6379", "db.sqlite3"]), &no_env).is_err());
+        assert!(Config::parse(&args(&["--tcp-keepalive"]), &no_env).is_err());
+        assert!(Config::parse(&args(&["--nope", "127.0.0.1:6379", "db.sqlite3"]), &no_env).is_err());
+    }
+}
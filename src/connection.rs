@@ -3,33 +3,105 @@ extern crate rusqlite;
 extern crate time;
 extern crate libc;
 
-use std::io::{Write, BufReader, BufWriter};
-use std::net::{TcpStream};
-use std::sync::{Arc, Mutex, Condvar};
+use std::io::{self, Write, BufReader, BufWriter};
+use std::net::{Shutdown, TcpStream};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use std::os::unix::io::AsRawFd;
 use self::resp::{Decoder, Value};
 
+use checkout::{self, Reservation};
 use commands;
+use config;
 use parser;
 use monitor;
+use pubsub::{Outbox, PubSub, Subscription};
+use push_waiters::PushWaiters;
+use rate_limit::RateLimit;
+use read_cache::ReadCache;
+use reply;
+use stats::Stats;
+use storage::Storage;
+
+// Replies are encoded into a buffer each connection keeps, which starts this big.  One grown past the larger size by
+// a big reply is swapped for a fresh one afterwards, so a single LRANGE over a long list doesn't pin its memory.
+const OUTPUT_BUFFER_SIZE: usize = 16 * 1024;
+const MAX_KEPT_OUTPUT_BUFFER_SIZE: usize = 1024 * 1024;
+
+// with an output buffer limit, how long a write waits on a client that isn't reading before the limit is checked
+const STALLED_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Commands queued by MULTI.  One that couldn't be queued makes EXEC refuse to run any of them.
+#[derive(Default)]
+struct Transaction {
+    // each command with its name, uppercased when it was queued
+    commands: Vec<(String, Value)>,
+    failed: bool,
+}
+
+// What every connection shares, cloned for each one.
+#[derive(Clone)]
+pub struct Shared {
+    pub sqlite_connection_mutex: Arc<Mutex<rusqlite::Connection>>,
+    pub monitor: monitor::Monitor<String>,
+    pub replication: monitor::Monitor<Vec<Vec<u8>>>,
+    pub push_waiters: Arc<PushWaiters>,
+    pub config: Arc<config::Config>,
+    pub read_cache: Arc<Mutex<ReadCache<Value>>>,
+    pub stats: Arc<Stats>,
+    pub pubsub: Arc<PubSub>,
+    pub storage: Arc<Storage>,
+    pub reservation: Arc<Reservation>,
+}
 
 pub struct Connection {
     sqlite_connection_mutex: Arc<Mutex<rusqlite::Connection>>,
-    monitor: monitor::Monitor,
-    push_notification: Arc<(Mutex<bool>, Condvar)>,
+    monitor: monitor::Monitor<String>,
+    replication: monitor::Monitor<Vec<Vec<u8>>>,
+    push_waiters: Arc<PushWaiters>,
+    config: Arc<config::Config>,
+    read_cache: Arc<Mutex<ReadCache<Value>>>,
+    stats: Arc<Stats>,
+    pubsub: Arc<PubSub>,
+    storage: Arc<Storage>,
+    reservation: Arc<Reservation>,
+    client_name: Mutex<Option<Vec<u8>>>,
+    transaction: Mutex<Option<Transaction>>,
+    protocol: Mutex<i64>,
+    authenticated: Mutex<bool>,
+    rate_limit: Mutex<RateLimit>,
     stream: Option<TcpStream>,
 }
 
 pub trait Connectionable {
-    fn get_push_notification(&self) -> Arc<(Mutex<bool>, Condvar)>;
+    fn get_config(&self) -> &config::Config;
+    fn get_push_waiters(&self) -> Arc<PushWaiters>;
     fn get_sqlite_connection_mutex(&self) -> &Arc<Mutex<rusqlite::Connection>>;
+    fn get_read_cache(&self) -> &Arc<Mutex<ReadCache<Value>>>;
+    fn get_stats(&self) -> &Stats;
+    fn get_pubsub(&self) -> &PubSub;
+    fn get_storage(&self) -> &Storage;
+    fn get_reservation(&self) -> &Reservation;
+    fn get_client_name(&self) -> &Mutex<Option<Vec<u8>>>;
     fn is_stream_alive(&self) -> bool;
+    fn is_authenticated(&self) -> bool;
     fn send_to_command_log(&self, command: String);
+    fn send_to_replication_log(&self, command: Vec<Vec<u8>>);
 }
 
 impl Connectionable for Connection {
-    fn get_push_notification(&self) -> Arc<(Mutex<bool>, Condvar)> { self.push_notification.clone() }
+    fn get_config(&self) -> &config::Config { &self.config }
+    fn get_push_waiters(&self) -> Arc<PushWaiters> { self.push_waiters.clone() }
     fn get_sqlite_connection_mutex(&self) -> &Arc<Mutex<rusqlite::Connection>> { &self.sqlite_connection_mutex }
+    fn get_read_cache(&self) -> &Arc<Mutex<ReadCache<Value>>> { &self.read_cache }
+    fn get_stats(&self) -> &Stats { &self.stats }
+    fn get_pubsub(&self) -> &PubSub { &self.pubsub }
+    fn get_storage(&self) -> &Storage { &*self.storage }
+    fn get_reservation(&self) -> &Reservation { &self.reservation }
+    fn get_client_name(&self) -> &Mutex<Option<Vec<u8>>> { &self.client_name }
+    fn is_authenticated(&self) -> bool { *self.authenticated.lock().unwrap() }
 
     fn is_stream_alive(&self) -> bool {
         let fd = self.borrow_stream().as_raw_fd();
@@ -45,14 +117,33 @@ impl Connectionable for Connection {
     fn send_to_command_log(&self, command: String) {
         self.monitor.send(command);
     }
+
+    fn send_to_replication_log(&self, command: Vec<Vec<u8>>) {
+        self.replication.send(command);
+    }
 }
 
 impl Connection {
-    pub fn new(sqlite_connection_mutex: Arc<Mutex<rusqlite::Connection>>, monitor: monitor::Monitor, push_notification: Arc<(Mutex<bool>, Condvar)>) -> Connection {
+    pub fn new(shared: Shared) -> Connection {
+        let rate_limit = RateLimit::new(shared.config.client_command_rate);
+        let authenticated = shared.config.requirepass.is_none();
+
         Connection {
-            sqlite_connection_mutex: sqlite_connection_mutex,
-            monitor: monitor,
-            push_notification: push_notification,
+            sqlite_connection_mutex: shared.sqlite_connection_mutex,
+            monitor: shared.monitor,
+            replication: shared.replication,
+            push_waiters: shared.push_waiters,
+            config: shared.config,
+            read_cache: shared.read_cache,
+            stats: shared.stats,
+            pubsub: shared.pubsub,
+            storage: shared.storage,
+            reservation: shared.reservation,
+            rate_limit: Mutex::new(rate_limit),
+            client_name: Mutex::new(None),
+            transaction: Mutex::new(None),
+            protocol: Mutex::new(2),
+            authenticated: Mutex::new(authenticated),
             stream: None,
         }
     }
@@ -64,34 +155,142 @@ impl Connection {
         }
     }
 
-    pub fn run(&mut self, stream: TcpStream) {
+    // returns why, if blueis hung up on the client rather than the client going away
+    pub fn run(&mut self, stream: TcpStream) -> Result<(), String> {
+        self.stats.client_connected();
+        let result = self.serve(stream);
+        self.stats.client_disconnected();
+
+        result
+    }
+
+    fn serve(&mut self, stream: TcpStream) -> Result<(), String> {
         self.stream = Some(stream);
 
         let stream = self.borrow_stream();
+        let limit = self.config.client_output_buffer_limit;
+
+        if limit > 0 {
+            stream.set_write_timeout(Some(STALLED_WRITE_TIMEOUT)).unwrap();
+        }
+
         let reader = BufReader::new(stream);
-        let mut writer = BufWriter::new(stream);
         let mut decoder = Decoder::with_buf_bulk(reader);
+        let mut output = Vec::with_capacity(OUTPUT_BUFFER_SIZE);
 
         loop {
             match decoder.decode() {
                 Ok(value) => {
-                    let (result, action) = self.handle_input(value);
-                    if writer.write(&result.encode()).is_err() { break; }
-                    if writer.flush().is_err() { break; }
+                    Stats::increment(&self.stats.total_reads_processed);
+
+                    // the name is uppercased once, here, for everything that goes by it
+                    let (name, result, action) = match parser::parse_command(&value) {
+                        Ok((name, arguments)) => {
+                            let name = name.to_ascii_uppercase();
+                            let (result, action) = self.handle_input(&value, &name, arguments);
+                            (name, result, action)
+                        }
+
+                        Err(error) => (String::new(), Value::Error(format!("ERR {}", error)), commands::Action::Continue)
+                    };
+
+                    // the limit only applies to replies; what follows takes the connection over
+                    if action != commands::Action::Continue && action != commands::Action::HangUp {
+                        stream.set_write_timeout(None).unwrap();
+                    }
+
+                    if action == commands::Action::StartSubscribe {
+                        self.run_subscribed(decoder, &value);
+                        break;
+                    }
+
+                    if let Value::Error(_) = result {
+                        Stats::increment(&self.stats.total_error_replies);
+                    }
+
+                    output.clear();
+
+                    if self.replies_with_map(&name) {
+                        reply::encode_map(&result, &mut output);
+                    }
+                    else {
+                        reply::encode(&result, &mut output);
+                    }
+
+                    match Connection::write_reply(stream, &output, limit) {
+                        Ok(true)  => {}
+                        Ok(false) => return Err(format!("client output buffer limit of {} bytes exceeded by a {} byte reply it isn't reading", limit, output.len())),
+                        Err(_)    => break
+                    }
+
+                    if output.capacity() > MAX_KEPT_OUTPUT_BUFFER_SIZE {
+                        output = Vec::with_capacity(OUTPUT_BUFFER_SIZE);
+                    }
 
                     match action {
                         commands::Action::HangUp => break,
                         commands::Action::StartMonitor => {
-                            self.run_monitor(writer);
+                            self.run_monitor(BufWriter::new(stream));
+                            break;
+                        }
+                        commands::Action::StartSync => {
+                            self.run_sync(BufWriter::new(stream));
                             break;
                         }
                         _ => {}
                     };
                 }
 
+                // what follows a request that doesn't make sense can't be made sense of either, so the client is told
+                // why and hung up on
+                Err(ref error) if Connection::is_protocol_error(error) => {
+                    Stats::increment(&self.stats.total_error_replies);
+                    Connection::write_reply(stream, &Connection::protocol_error_reply(error).encode(), 0).ok();
+                    return Err(format!("protocol error: {}", error));
+                }
+
+                // the client has gone, possibly partway through sending a command, which is simply dropped
                 _ => break
             }
         }
+
+        Ok(())
+    }
+
+    // the decoder fails this way on malformed input rather than on the stream
+    fn is_protocol_error(error: &io::Error) -> bool {
+        error.kind() == io::ErrorKind::InvalidInput || error.kind() == io::ErrorKind::InvalidData
+    }
+
+    fn protocol_error_reply(error: &io::Error) -> Value {
+        Value::Error(format!("ERR Protocol error: {}", error))
+    }
+
+    // Returns false, without finishing, once a write has timed out without sending anything and with more than
+    // `limit` bytes of the reply still to go, as the client has stopped reading.  A write that sent some of the reply
+    // before timing out is from a client that's reading, however slowly, so it carries on, as does one without a limit
+    // or with less than that to go.
+    fn write_reply(mut stream: &TcpStream, reply: &[u8], limit: usize) -> io::Result<bool> {
+        let mut written = 0;
+
+        while written < reply.len() {
+            // a write to a blocking socket only stops short, or sends nothing, when it's timed out
+            match stream.write(&reply[written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(count) => written += count,
+
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock || error.kind() == io::ErrorKind::TimedOut => {
+                    if limit > 0 && reply.len() - written > limit {
+                        return Ok(false);
+                    }
+                }
+
+                Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error)
+            }
+        }
+
+        Ok(true)
     }
 
     fn run_monitor(&self, mut writer: BufWriter<&TcpStream>) {
@@ -110,19 +309,912 @@ impl Connection {
        }
     }
 
-    fn handle_input(&self, ref value: Value) -> (Value, commands::Action) {
-        match parser::parse_command(value) {
-            Ok((name, arguments)) => {
-                let mut command = commands::Command {
-                    name:       name,
-                    arguments:  arguments,
-                    connection: self as &Connectionable,
-                };
+    fn run_sync(&self, mut writer: BufWriter<&TcpStream>) {
+        // listening before the snapshot is taken, under the same lock writers hold while replicating, means every
+        // write is either in the snapshot or in the log but never both
+        let (listener, snapshot) = {
+            let connection = checkout::lock(&self.sqlite_connection_mutex);
+            (self.replication.listen(), commands::Command::replication_snapshot(&*connection))
+        };
+
+        for command in snapshot {
+            if writer.write(&Connection::encode_command(command)).is_err() { return; }
+        }
+
+        if writer.flush().is_err() { return; }
+
+        // a replica that falls too far behind is disconnected, as it can no longer be kept consistent
+        while let Some(command) = listener.recv_without_gaps() {
+            if writer.write(&Connection::encode_command(command)).is_err() { break; }
+            if writer.flush().is_err() { break; }
+        }
+    }
+
+    // A subscribed client is sent messages as they're published while it's still sending commands, so replies and
+    // messages are both queued for a thread of its own to write.  Only pub/sub commands, PING and QUIT are accepted
+    // while subscribed to any channel; once unsubscribed from them all, other commands run as usual.
+    fn run_subscribed(&self, mut decoder: Decoder<&TcpStream>, first: &Value) {
+        let (stream, overflow_stream) = match (self.borrow_stream().try_clone(), self.borrow_stream().try_clone()) {
+            (Ok(stream), Ok(overflow_stream)) => (stream, overflow_stream),
+            _                                 => return
+        };
+
+        // shutting the socket down unblocks both the writer and the decoder below, which then finish as for a hang up
+        let hang_up = Box::new(move || { overflow_stream.shutdown(Shutdown::Both).ok(); });
+        let outbox = Arc::new(Outbox::new(self.config.pubsub_buffer_limit, self.config.pubsub_overflow, hang_up));
+        let writer_outbox = outbox.clone();
+
+        let writer = thread::spawn(move || {
+            let mut writer = BufWriter::new(stream);
+
+            while let Some(value) = writer_outbox.pop() {
+                if writer.write(&value.encode()).is_err() { break; }
+                if writer.flush().is_err() { break; }
+            }
+        });
+
+        {
+            let mut subscription = Subscription::new(self.pubsub.clone(), outbox.clone());
+            let mut hang_up = self.handle_subscribed_input(first, &mut subscription, &outbox);
+
+            while !hang_up {
+                match decoder.decode() {
+                    Ok(value) => {
+                        Stats::increment(&self.stats.total_reads_processed);
+                        hang_up = self.handle_subscribed_input(&value, &mut subscription, &outbox);
+                    }
+
+                    Err(ref error) if Connection::is_protocol_error(error) => {
+                        Stats::increment(&self.stats.total_error_replies);
+                        outbox.push(Connection::protocol_error_reply(error));
+                        break;
+                    }
+
+                    _ => break
+                }
+            }
+        }
+
+        // with the subscription gone nothing more is published to the outbox, so the writer finishes once it's empty
+        outbox.close();
+        writer.join().ok();
+    }
+
+    // returns true once the client should be hung up on
+    fn handle_subscribed_input(&self, value: &Value, subscription: &mut Subscription, outbox: &Outbox) -> bool {
+        let reply = |value: Value| {
+            if let Value::Error(_) = value {
+                Stats::increment(&self.stats.total_error_replies);
+            }
+
+            outbox.push(value);
+        };
+
+        let (name, arguments) = match parser::parse_command(value) {
+            Ok(command) => command,
+            Err(error)  => {
+                reply(Value::Error(format!("ERR {}", error)));
+                return false;
+            }
+        };
+
+        let name = name.to_ascii_uppercase();
+
+        match name.as_str() {
+            "SUBSCRIBE" if !arguments.is_empty() => {
+                for channel in arguments {
+                    let count = subscription.subscribe(channel);
+                    reply(Connection::subscription_reply("subscribe", Some(channel), count));
+                }
+            }
+
+            "UNSUBSCRIBE" => {
+                let channels = if arguments.is_empty() { subscription.channels() } else { arguments.iter().map(|channel| channel.to_vec()).collect() };
+
+                if channels.is_empty() {
+                    reply(Connection::subscription_reply("unsubscribe", None, 0));
+                }
+
+                for channel in channels {
+                    let count = subscription.unsubscribe(&channel);
+                    reply(Connection::subscription_reply("unsubscribe", Some(&channel), count));
+                }
+            }
+
+            "PING" if subscription.count() > 0 => {
+                let message = arguments.first().map(|message| message.to_vec()).unwrap_or_default();
+                reply(Value::Array(vec![Value::BufBulk(b"pong".to_vec()), Value::BufBulk(message)]));
+            }
+
+            "QUIT" => {
+                reply(Value::String("OK".to_string()));
+                return true;
+            }
+
+            _ if subscription.count() > 0 => {
+                reply(Value::Error(format!("ERR Can't execute '{}': only SUBSCRIBE / UNSUBSCRIBE / PING / QUIT are allowed in this context", name.to_lowercase())));
+            }
+
+            _ => {
+                let (result, action) = self.handle_input(value, &name, arguments);
+
+                match action {
+                    commands::Action::Continue | commands::Action::HangUp | commands::Action::StartSubscribe => reply(result),
+
+                    // the writer thread now owns the connection's output, so it can't be handed over
+                    commands::Action::StartMonitor | commands::Action::StartSync => {
+                        reply(Value::Error("ERR MONITOR and SYNC can't be used on a connection that has subscribed".to_string()));
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    // Once HELLO has switched to RESP3, the replies that pair keys with values are framed as maps.  The same replies
+    // within EXEC's are still flat arrays.
+    fn replies_with_map(&self, name: &str) -> bool {
+        *self.protocol.lock().unwrap() == 3 && ["HGETALL", "HELLO"].contains(&name)
+    }
+
+    // switches the protocol replies are encoded with, if a version is given, and describes the server
+    fn hello(&self, arguments: &[&[u8]]) -> Value {
+        if arguments.len() > 1 {
+            return Value::Error("ERR syntax error".to_string());
+        }
+
+        let mut protocol = self.protocol.lock().unwrap();
+
+        if let Some(version) = arguments.first() {
+            match str::from_utf8(version).ok().and_then(|version| version.parse::<i64>().ok()) {
+                Some(version) if version == 2 || version == 3 => *protocol = version,
+                Some(_) => return Value::Error("NOPROTO unsupported protocol version".to_string()),
+                None    => return Value::Error("ERR Protocol version is not an integer or out of range".to_string())
+            }
+        }
+
+        let field = |name: &str| Value::BufBulk(name.as_bytes().to_vec());
+
+        Value::Array(vec![
+            field("server"),  field("blueis"),
+            field("version"), field(env!("CARGO_PKG_VERSION")),
+            field("proto"),   Value::Integer(*protocol),
+            field("mode"),    field("standalone"),
+            field("role"),    field("master"),
+            field("modules"), Value::Array(vec![]),
+        ])
+    }
+
+    // The password can be given alone, or after the only user there is, default, as redis 6 clients do.  A wrong one
+    // leaves the client as it was, authenticated or not.
+    fn auth(&self, arguments: &[&[u8]]) -> Value {
+        let password = match arguments.len() {
+            1                                    => arguments[0],
+            2 if arguments[0] == &b"default"[..] => arguments[1],
+            2                                    => return Value::Error("WRONGPASS invalid username-password pair or user is disabled.".to_string()),
+            _                                    => return Value::Error("ERR wrong number of arguments for 'auth' command".to_string())
+        };
+
+        match self.config.requirepass {
+            Some(ref requirepass) if requirepass.as_bytes() == password => {
+                *self.authenticated.lock().unwrap() = true;
+                Value::String("OK".to_string())
+            }
+
+            Some(_) => Value::Error("WRONGPASS invalid username-password pair or user is disabled.".to_string()),
+            None    => Value::Error("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".to_string())
+        }
+    }
+
+    fn subscription_reply(kind: &str, channel: Option<&[u8]>, count: usize) -> Value {
+        let channel = match channel {
+            Some(channel) => Value::BufBulk(channel.to_vec()),
+            None          => Value::Null
+        };
+
+        Value::Array(vec![Value::BufBulk(kind.as_bytes().to_vec()), channel, Value::Integer(count as i64)])
+    }
+
+    fn encode_command(command: Vec<Vec<u8>>) -> Vec<u8> {
+        Value::Array(command.into_iter().map(Value::BufBulk).collect()).encode()
+    }
+
+    // the name has already been uppercased
+    fn handle_input(&self, value: &Value, name: &str, arguments: Vec<&[u8]>) -> (Value, commands::Action) {
+        // with --requirepass, a client can only authenticate or leave until it has
+        if !*self.authenticated.lock().unwrap() && !["AUTH", "QUIT"].contains(&name) {
+            return (Value::Error("NOAUTH Authentication required.".to_string()), commands::Action::Continue);
+        }
+
+        // Nothing else can use the database until it's been set up.  PING and QUIT don't need it, so they still
+        // answer, and clients that understand LOADING retry the rest.
+        if self.stats.is_loading() && !["PING", "QUIT"].contains(&name) {
+            return (Value::Error("LOADING Redis is loading the dataset in memory".to_string()), commands::Action::Continue);
+        }
+
+        // QUIT is still allowed, so a client that's been refused can leave
+        if name != "QUIT" && !self.rate_limit.lock().unwrap().allow() {
+            return (Value::Error("ERR rate limit exceeded".to_string()), commands::Action::Continue);
+        }
+
+        let mut transaction = self.transaction.lock().unwrap();
+
+        let reply = match (name, transaction.is_some()) {
+            ("MULTI", false) => {
+                *transaction = Some(Transaction::default());
+                Value::String("OK".to_string())
+            }
+
+            ("MULTI", true)    => Value::Error("ERR MULTI calls can not be nested".to_string()),
+            ("EXEC", false)    => Value::Error("ERR EXEC without MULTI".to_string()),
+            ("DISCARD", false) => Value::Error("ERR DISCARD without MULTI".to_string()),
+
+            ("DISCARD", true) => {
+                *transaction = None;
+                Value::String("OK".to_string())
+            }
+
+            ("EXEC", true) => self.exec(transaction.take().unwrap()),
+
+            ("HELLO", false) => self.hello(&arguments),
+            ("AUTH", false)  => self.auth(&arguments),
+
+            // QUIT still hangs up straight away
+            (name, true) if name != "QUIT" => {
+                let transaction = transaction.as_mut().unwrap();
+
+                match commands::Command::check_queueable(name, arguments.len()) {
+                    Ok(()) => {
+                        transaction.commands.push((name.to_string(), value.clone()));
+                        Value::String("QUEUED".to_string())
+                    }
+
+                    Err(error) => {
+                        transaction.failed = true;
+                        error
+                    }
+                }
+            }
+
+            _ => return self.execute(name, arguments)
+        };
+
+        (reply, commands::Action::Continue)
+    }
+
+    // Each queued command runs in turn and its reply, error or not, takes its place in EXEC's reply, so one failing
+    // doesn't stop the rest, and what a command has written stays written whatever happens to the commands after it.
+    // The connection is reserved for this client until the last has run, so no other client's command runs between
+    // them.
+    fn exec(&self, transaction: Transaction) -> Value {
+        if transaction.failed {
+            return Value::Error("EXECABORT Transaction discarded because of previous errors.".to_string());
+        }
+
+        let _held = self.reservation.hold(&self.sqlite_connection_mutex);
+
+        Value::Array(transaction.commands.iter().map(|(name, value)| {
+            // every queued command was parsed successfully when it was queued
+            let (_, arguments) = parser::parse_command(value).unwrap();
+            self.execute(name, arguments).0
+        }).collect())
+    }
+
+    fn execute<'a>(&'a self, name: &'a str, arguments: Vec<&'a [u8]>) -> (Value, commands::Action) {
+        let mut command = commands::Command {
+            name:       name,
+            arguments:  arguments,
+            connection: self as &Connectionable,
+        };
+
+        command.execute()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Connection, Shared};
+    use super::{libc, rusqlite};
+    use super::resp::{Decoder, Value};
+    use checkout::Reservation;
+    use config::Config;
+    use monitor::Monitor;
+    use pubsub::PubSub;
+    use push_waiters::PushWaiters;
+    use read_cache::ReadCache;
+    use reply;
+    use stats::Stats;
+    use storage::SqliteStorage;
+    use set_up_tables;
+    use socket;
+    use thread_limit::ThreadLimit;
+    use std::io::{BufReader, Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream, SocketAddr};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn start_server(max_threads: usize) -> SocketAddr {
+        start_server_with(max_threads, Config::default(), Arc::new(Stats::default()))
+    }
+
+    fn start_server_with(max_threads: usize, config: Config, stats: Arc<Stats>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        set_up_tables(&connection);
+
+        let shared = Shared {
+            sqlite_connection_mutex: Arc::new(Mutex::new(connection)),
+            monitor:                 Monitor::new(100),
+            replication:             Monitor::new(100),
+            push_waiters:            Arc::new(PushWaiters::default()),
+            config:                  Arc::new(config),
+            read_cache:              Arc::new(Mutex::new(ReadCache::new(0))),
+            stats:                   stats,
+            pubsub:                  Arc::new(PubSub::default()),
+            storage:                 Arc::new(SqliteStorage),
+            reservation:             Arc::new(Reservation::default()),
+        };
+        let thread_limit = ThreadLimit::new(max_threads);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut connection = Connection::new(shared.clone());
+                let permit = thread_limit.acquire();
+
+                thread::spawn(move || {
+                    connection.run(stream.unwrap()).ok();
+                    drop(permit);
+                });
+            }
+        });
+
+        address
+    }
+
+    struct Client {
+        stream: TcpStream,
+        decoder: Decoder<TcpStream>,
+    }
+
+    impl Client {
+        fn connect(address: SocketAddr) -> Client {
+            let stream = TcpStream::connect(address).unwrap();
+            let decoder = Decoder::with_buf_bulk(BufReader::new(stream.try_clone().unwrap()));
+
+            Client { stream: stream, decoder: decoder }
+        }
+
+        fn send(&mut self, command: Vec<Value>) {
+            self.stream.write_all(&Value::Array(command).encode()).unwrap();
+        }
+
+        fn receive(&mut self) -> Value {
+            self.decoder.decode().unwrap()
+        }
+
+        fn call(&mut self, command: &[&str]) -> Value {
+            self.send(command.iter().map(|argument| Value::BufBulk(argument.as_bytes().to_vec())).collect());
+            self.receive()
+        }
+    }
+
+    #[test]
+    fn a_thread_limit_still_serves_many_short_lived_connections() {
+        let server = start_server(4);
+
+        let clients: Vec<_> = (0..40).map(|_| thread::spawn(move || {
+            let mut client = Client::connect(server);
+            client.call(&["RPUSH", "visits", "x"]);
+            assert_eq!(client.call(&["QUIT"]), Value::String("OK".to_string()));
+        })).collect();
+
+        for client in clients { client.join().unwrap(); }
+
+        assert_eq!(Client::connect(server).call(&["LLEN", "visits"]), Value::Integer(40));
+    }
+
+    #[test]
+    fn commands_are_found_whatever_their_case() {
+        let server = start_server(0);
+        let mut client = Client::connect(server);
+
+        assert_eq!(client.call(&["LpUsH", "queue", "a"]), Value::Integer(1));
+        assert_eq!(client.call(&["llen", "queue"]), Value::Integer(1));
+        assert_eq!(client.call(&["pInG"]), Value::String("PONG".to_string()));
+
+        assert_eq!(client.call(&["mUlTi"]), Value::String("OK".to_string()));
+        assert_eq!(client.call(&["rpush", "queue", "b"]), Value::String("QUEUED".to_string()));
+        assert_eq!(client.call(&["Exec"]), Value::Array(vec![Value::Integer(2)]));
+
+        // only ASCII letters are folded, so a lookalike such as a long s doesn't name SET
+        assert_eq!(client.call(&["\u{17f}et", "greeting", "hello"]), Value::Error("ERR unsupported".to_string()));
+
+        assert_eq!(client.call(&["Quit"]), Value::String("OK".to_string()));
+    }
+
+    fn info_field(client: &mut Client, field: &str) -> String {
+        let info = match client.call(&["INFO"]) {
+            Value::BufBulk(info) => String::from_utf8(info).unwrap(),
+            _                    => panic!("expected a bulk string")
+        };
+
+        let prefix = format!("{}:", field);
+        info.split("\r\n").find(|line| line.starts_with(&prefix)).unwrap()[prefix.len()..].to_string()
+    }
+
+    #[test]
+    fn info_counts_reads_and_error_replies() {
+        let server = start_server(0);
+        let mut client = Client::connect(server);
+
+        assert_eq!(info_field(&mut client, "total_reads_processed"), "1");
+        assert_eq!(info_field(&mut client, "total_error_replies"), "0");
+
+        client.send(vec![Value::Integer(1)]);
+        assert_eq!(client.receive(), Value::Error("ERR Protocol error: expected '$', got ':'".to_string()));
+        client.call(&["NOSUCHCOMMAND"]);
+
+        assert_eq!(info_field(&mut client, "total_reads_processed"), "5");
+        assert_eq!(info_field(&mut client, "total_error_replies"), "2");
+    }
+
+    #[test]
+    fn replies_to_a_protocol_error_and_counts_it_before_hanging_up() {
+        let server = start_server(0);
+        let mut client = Client::connect(server);
+        let mut other = Client::connect(server);
+
+        client.stream.write_all(b"*1\r\n$-2\r\n").unwrap();
+        assert_eq!(client.receive(), Value::Error("ERR Protocol error: invalid bulk length: -2".to_string()));
+        assert!(client.decoder.decode().is_err());
+
+        assert_eq!(info_field(&mut other, "total_error_replies"), "1");
+    }
+
+    #[test]
+    fn info_reports_the_peak_of_connected_clients_until_reset() {
+        let server = start_server(0);
+        let mut client = Client::connect(server);
+
+        let others: Vec<_> = (0..2).map(|_| {
+            let mut other = Client::connect(server);
+            other.call(&["PING"]);
+            other
+        }).collect();
+
+        client.call(&["NOSUCHCOMMAND"]);
+        assert_eq!(info_field(&mut client, "connected_clients"), "3");
+        assert_eq!(info_field(&mut client, "peak_connected_clients"), "3");
+        assert_eq!(info_field(&mut client, "maxclients"), "0");
+
+        drop(others);
+
+        // the other clients' threads notice they've gone in their own time
+        while info_field(&mut client, "connected_clients") != "1" {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(info_field(&mut client, "peak_connected_clients"), "3");
+
+        assert_eq!(client.call(&["CONFIG", "RESETSTAT"]), Value::String("OK".to_string()));
+        assert_eq!(info_field(&mut client, "peak_connected_clients"), "1");
+        assert_eq!(info_field(&mut client, "total_reads_processed"), "2");
+        assert_eq!(info_field(&mut client, "total_error_replies"), "0");
+    }
+
+    #[test]
+    fn commands_before_auth_are_refused_with_the_exact_noauth_reply() {
+        let config = Config { requirepass: Some("secret".to_string()), ..Config::default() };
+        let server = start_server_with(0, config, Arc::new(Stats::default()));
+        let mut client = Client::connect(server);
+
+        // client libraries match these bytes to know to authenticate
+        client.stream.write_all(b"*2\r\n$4\r\nLLEN\r\n$4\r\nlist\r\n").unwrap();
+        let mut reply = [0; 34];
+        client.stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply[..], &b"-NOAUTH Authentication required.\r\n"[..]);
+
+        assert_eq!(client.call(&["AUTH", "wrong"]), Value::Error("WRONGPASS invalid username-password pair or user is disabled.".to_string()));
+        assert_eq!(client.call(&["LLEN", "list"]), Value::Error("NOAUTH Authentication required.".to_string()));
+
+        assert_eq!(client.call(&["AUTH", "default", "secret"]), ok());
+        assert_eq!(client.call(&["LLEN", "list"]), Value::Integer(0));
+
+        // and without a password, there's nothing to authenticate
+        let mut client = Client::connect(start_server(0));
+        assert_eq!(client.call(&["LLEN", "list"]), Value::Integer(0));
+        assert_eq!(client.call(&["AUTH", "secret"]), Value::Error("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".to_string()));
+    }
+
+    #[test]
+    fn an_empty_command_is_an_error_rather_than_a_hang_up() {
+        let server = start_server(0);
+        let mut client = Client::connect(server);
+
+        client.stream.write_all(b"*0\r\n").unwrap();
+        assert_eq!(client.receive(), Value::Error("ERR empty command".to_string()));
+
+        client.call(&["MULTI"]);
+        client.stream.write_all(b"*0\r\n").unwrap();
+        assert_eq!(client.receive(), Value::Error("ERR empty command".to_string()));
+        client.call(&["DISCARD"]);
+
+        assert_eq!(client.call(&["PING"]), Value::String("PONG".to_string()));
+    }
+
+    #[test]
+    fn a_client_that_hangs_up_mid_command_is_disconnected_cleanly() {
+        let server = start_server(1);
+        let mut client = Client::connect(server);
+
+        client.stream.write_all(b"*2\r\n$5\r\nLPUSH\r\n").unwrap();
+        client.stream.shutdown(Shutdown::Write).unwrap();
+
+        // the server closes its end without replying, rather than the connection being reset
+        client.stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reply = vec![];
+        assert_eq!(client.stream.read_to_end(&mut reply).unwrap(), 0);
+
+        // and its thread has finished, as the one permit is free for another client, which finds nothing was pushed
+        assert_eq!(Client::connect(server).call(&["LLEN", "LPUSH"]), Value::Integer(0));
+    }
+
+    #[test]
+    fn only_ping_and_quit_are_answered_while_loading() {
+        let stats = Arc::new(Stats::default());
+        let server = start_server_with(0, Config::default(), stats.clone());
+        let mut client = Client::connect(server);
+
+        stats.set_loading(true);
+        assert_eq!(client.call(&["LLEN", "list"]), Value::Error("LOADING Redis is loading the dataset in memory".to_string()));
+        assert_eq!(client.call(&["MULTI"]), Value::Error("LOADING Redis is loading the dataset in memory".to_string()));
+        assert_eq!(client.call(&["PING"]), Value::String("PONG".to_string()));
+
+        stats.set_loading(false);
+        assert_eq!(client.call(&["LLEN", "list"]), Value::Integer(0));
+        assert_eq!(client.call(&["QUIT"]), ok());
+    }
+
+    #[test]
+    fn a_client_not_reading_a_reply_over_the_output_buffer_limit_is_disconnected() {
+        let config = Config { client_output_buffer_limit: 1024 * 1024, ..Config::default() };
+
+        let server = start_server_with(0, config, Arc::new(Stats::default()));
+        let mut client = Client::connect(server);
+
+        // far more than the socket buffers between the two ends can hold, once the client's can't grow
+        socket::set_option(&client.stream, libc::SOL_SOCKET, libc::SO_RCVBUF, 64 * 1024).unwrap();
+        let value = "x".repeat(512 * 1024);
+        for _ in 0..64 { client.call(&["RPUSH", "big", &value]); }
+
+        client.send(vec![Value::BufBulk(b"LRANGE".to_vec()), Value::BufBulk(b"big".to_vec()), Value::BufBulk(b"0".to_vec()), Value::BufBulk(b"-1".to_vec())]);
+        thread::sleep(Duration::from_secs(4));
+
+        // what the server had written before it gave up is still there to read, but not the rest of the reply
+        client.stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut received = vec![];
+        client.stream.read_to_end(&mut received).ok();
+        assert!(received.len() < 64 * value.len(), "the whole reply was sent");
+
+        assert_eq!(Client::connect(server).call(&["LLEN", "big"]), Value::Integer(64));
+    }
+
+    #[test]
+    fn a_client_slowly_reading_a_reply_over_the_output_buffer_limit_is_not_disconnected() {
+        let config = Config { client_output_buffer_limit: 256 * 1024, ..Config::default() };
+
+        let server = start_server_with(0, config, Arc::new(Stats::default()));
+        let mut client = Client::connect(server);
+
+        let value = "x".repeat(512 * 1024);
+        for _ in 0..64 { client.call(&["RPUSH", "big", &value]); }
+
+        client.send(vec![Value::BufBulk(b"LRANGE".to_vec()), Value::BufBulk(b"big".to_vec()), Value::BufBulk(b"0".to_vec()), Value::BufBulk(b"-1".to_vec())]);
+
+        // far more than the socket buffers between the two ends can hold, read over several seconds but some of it
+        // well within every second
+        let reply = Value::Array(vec![Value::BufBulk(value.into_bytes()); 64]).encode();
+        let mut received = 0;
+        let mut chunk = vec![0; 512 * 1024];
+        while received < reply.len() {
+            thread::sleep(Duration::from_millis(100));
+            let count = (reply.len() - received).min(chunk.len());
+            client.stream.read_exact(&mut chunk[..count]).unwrap();
+            received += count;
+        }
+
+        assert_eq!(client.call(&["LLEN", "big"]), Value::Integer(64));
+    }
+
+    #[test]
+    fn hgetall_replies_with_a_map_once_hello_switches_to_resp3() {
+        let server = start_server(0);
+        let mut client = Client::connect(server);
 
-                command.execute()
+        client.call(&["HSET", "hash", "a", "1", "b", "2"]);
+
+        let flat = Value::Array(["a", "1", "b", "2"].iter().map(|item| Value::BufBulk(item.as_bytes().to_vec())).collect());
+        assert_eq!(client.call(&["HGETALL", "hash"]), flat);
+        assert_eq!(client.call(&["HELLO", "4"]), Value::Error("NOPROTO unsupported protocol version".to_string()));
+
+        // the decoder only understands RESP2, so what follows is read as it arrives
+        client.send(["HELLO", "3"].iter().map(|item| Value::BufBulk(item.as_bytes().to_vec())).collect());
+
+        let mut hello = vec![];
+        reply::encode_map(&Value::Array(vec![
+            Value::BufBulk(b"server".to_vec()),  Value::BufBulk(b"blueis".to_vec()),
+            Value::BufBulk(b"version".to_vec()), Value::BufBulk(env!("CARGO_PKG_VERSION").as_bytes().to_vec()),
+            Value::BufBulk(b"proto".to_vec()),   Value::Integer(3),
+            Value::BufBulk(b"mode".to_vec()),    Value::BufBulk(b"standalone".to_vec()),
+            Value::BufBulk(b"role".to_vec()),    Value::BufBulk(b"master".to_vec()),
+            Value::BufBulk(b"modules".to_vec()), Value::Array(vec![]),
+        ]), &mut hello);
+
+        let mut received = vec![0; hello.len()];
+        client.stream.read_exact(&mut received).unwrap();
+        assert_eq!(received, hello);
+
+        client.send(["HGETALL", "hash"].iter().map(|item| Value::BufBulk(item.as_bytes().to_vec())).collect());
+
+        let map = b"%2\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n";
+        let mut received = vec![0; map.len()];
+        client.stream.read_exact(&mut received).unwrap();
+        assert_eq!(&received[..], &map[..]);
+
+        // replies that aren't maps are unchanged
+        assert_eq!(client.call(&["HSET", "hash", "c", "3"]), Value::Integer(1));
+    }
+
+    #[test]
+    fn a_client_over_the_command_rate_is_refused_while_a_slower_one_is_not() {
+        let config = Config { client_command_rate: 10, ..Config::default() };
+
+        let server = start_server_with(0, config, Arc::new(Stats::default()));
+        let mut fast = Client::connect(server);
+        let mut slow = Client::connect(server);
+
+        let refused = (0..20).filter(|_| fast.call(&["PING"]) == Value::Error("ERR rate limit exceeded".to_string())).count();
+        assert!(refused >= 9, "only {} refused", refused);
+
+        for _ in 0..5 {
+            assert_eq!(slow.call(&["PING"]), Value::String("PONG".to_string()));
+            thread::sleep(Duration::from_millis(150));
+        }
+
+        assert_eq!(fast.call(&["QUIT"]), ok());
+    }
+
+    #[test]
+    fn commands_pipelined_behind_a_blocking_command_run_once_it_returns() {
+        let server = start_server(0);
+        let mut client = Client::connect(server);
+
+        let blpop = Value::Array(["BLPOP", "queue", "1"].iter().map(|item| Value::BufBulk(item.as_bytes().to_vec())).collect());
+        let ping = Value::Array(vec![Value::BufBulk(b"PING".to_vec())]);
+
+        // sent in one write, so both arrive in the same packet and the PING sits buffered while BLPOP waits
+        let mut pipeline = blpop.encode();
+        pipeline.extend(ping.encode());
+        pipeline.extend(blpop.encode());
+        pipeline.extend(ping.encode());
+        client.stream.write_all(&pipeline).unwrap();
+
+        assert_eq!(client.receive(), Value::NullArray);
+        assert_eq!(client.receive(), Value::String("PONG".to_string()));
+
+        // and the same once BLPOP is woken by a push rather than timing out
+        Client::connect(server).call(&["RPUSH", "queue", "a"]);
+        assert_eq!(client.receive(), Value::Array(vec![Value::BufBulk(b"queue".to_vec()), Value::BufBulk(b"a".to_vec())]));
+        assert_eq!(client.receive(), Value::String("PONG".to_string()));
+    }
+
+    fn ok() -> Value { Value::String("OK".to_string()) }
+    fn queued() -> Value { Value::String("QUEUED".to_string()) }
+
+    #[test]
+    fn exec_replies_with_each_commands_result_including_errors() {
+        let server = start_server(0);
+        let mut client = Client::connect(server);
+
+        client.call(&["RPUSH", "list", "a", "b"]);
+
+        assert_eq!(client.call(&["MULTI"]), ok());
+        assert_eq!(client.call(&["LSET", "list", "0", "x"]), queued());
+        assert_eq!(client.call(&["LSET", "list", "5", "y"]), queued());
+        assert_eq!(client.call(&["RPUSH", "list", "c"]), queued());
+
+        assert_eq!(client.call(&["EXEC"]), Value::Array(vec![
+            ok(),
+            Value::Error("ERR index out of range".to_string()),
+            Value::Integer(3),
+        ]));
+
+        assert_eq!(client.call(&["LRANGE", "list", "0", "-1"]), Value::Array(vec![Value::BufBulk(b"x".to_vec()), Value::BufBulk(b"b".to_vec()), Value::BufBulk(b"c".to_vec())]));
+    }
+
+    // queued commands are only parsed, so an expiry is measured from when EXEC runs it
+    #[test]
+    fn a_queued_expiry_counts_from_exec() {
+        let server = start_server(0);
+        let mut client = Client::connect(server);
+
+        client.call(&["RPUSH", "list", "a"]);
+
+        assert_eq!(client.call(&["MULTI"]), ok());
+        assert_eq!(client.call(&["EXPIRE", "list", "2"]), queued());
+        assert_eq!(client.call(&["SETEX", "string", "2", "value"]), queued());
+        thread::sleep(Duration::from_millis(1000));
+        assert_eq!(client.call(&["EXEC"]), Value::Array(vec![Value::Integer(1), ok()]));
+
+        for key in &["list", "string"] {
+            match client.call(&["PTTL", key]) {
+                Value::Integer(ttl) => assert!(ttl > 1500, "{} has {}ms left", key, ttl),
+                other               => panic!("unexpected reply {:?}", other)
+            }
+        }
+    }
+
+    // another client pushing throughout never lands between the DEL and the LRANGE
+    #[test]
+    fn no_other_clients_command_runs_between_those_exec_runs() {
+        let server = start_server(0);
+        let mut client = Client::connect(server);
+
+        let handle = thread::spawn(move || {
+            let mut pusher = Client::connect(server);
+
+            for _ in 0..500 {
+                pusher.call(&["RPUSH", "list", "b"]);
             }
+        });
 
-            Err(error) => (Value::Error(format!("ERR {}", error)), commands::Action::Continue)
+        for _ in 0..100 {
+            assert_eq!(client.call(&["MULTI"]), ok());
+            client.call(&["DEL", "list"]);
+            client.call(&["RPUSH", "list", "a"]);
+            client.call(&["LRANGE", "list", "0", "-1"]);
+
+            match client.call(&["EXEC"]) {
+                Value::Array(ref replies) => assert_eq!(replies[2], Value::Array(vec![Value::BufBulk(b"a".to_vec())])),
+                other                     => panic!("unexpected reply {:?}", other)
+            }
         }
+
+        handle.join().unwrap();
+    }
+
+    // nothing could push while EXEC runs, so a blocking pop with nothing to pop times out at once whatever its timeout
+    #[test]
+    fn blocking_commands_inside_exec_do_not_block() {
+        let server = start_server(0);
+        let mut client = Client::connect(server);
+
+        assert_eq!(client.call(&["MULTI"]), ok());
+        assert_eq!(client.call(&["BLPOP", "list", "0"]), queued());
+        assert_eq!(client.call(&["RPUSH", "list", "a", "b"]), queued());
+        assert_eq!(client.call(&["BRPOP", "list", "0"]), queued());
+        assert_eq!(client.call(&["BLMOVE", "missing", "list", "LEFT", "LEFT", "0"]), queued());
+
+        assert_eq!(client.call(&["EXEC"]), Value::Array(vec![
+            Value::NullArray,
+            Value::Integer(2),
+            Value::Array(vec![Value::BufBulk(b"list".to_vec()), Value::BufBulk(b"b".to_vec())]),
+            Value::Null,
+        ]));
+
+        assert_eq!(client.call(&["LLEN", "list"]), Value::Integer(1));
+    }
+
+    #[test]
+    fn a_command_that_cannot_be_queued_aborts_exec() {
+        let server = start_server(0);
+        let mut client = Client::connect(server);
+
+        assert_eq!(client.call(&["EXEC"]), Value::Error("ERR EXEC without MULTI".to_string()));
+        assert_eq!(client.call(&["MULTI"]), ok());
+        assert_eq!(client.call(&["MULTI"]), Value::Error("ERR MULTI calls can not be nested".to_string()));
+        assert_eq!(client.call(&["RPUSH", "list", "a"]), queued());
+        assert_eq!(client.call(&["LLEN"]), Value::Error("ERR wrong number of arguments".to_string()));
+        assert_eq!(client.call(&["EXEC"]), Value::Error("EXECABORT Transaction discarded because of previous errors.".to_string()));
+
+        assert_eq!(client.call(&["MULTI"]), ok());
+        assert_eq!(client.call(&["RPUSH", "list", "a"]), queued());
+        assert_eq!(client.call(&["DISCARD"]), ok());
+        assert_eq!(client.call(&["DISCARD"]), Value::Error("ERR DISCARD without MULTI".to_string()));
+
+        assert_eq!(client.call(&["LLEN", "list"]), Value::Integer(0));
+    }
+
+    fn subscription_reply(kind: &str, channel: Option<&str>, count: i64) -> Value {
+        let channel = channel.map(|channel| Value::BufBulk(channel.as_bytes().to_vec())).unwrap_or(Value::Null);
+        Value::Array(vec![Value::BufBulk(kind.as_bytes().to_vec()), channel, Value::Integer(count)])
+    }
+
+    #[test]
+    fn subscription_counts_are_reported_in_order() {
+        let server = start_server(0);
+        let mut subscriber = Client::connect(server);
+
+        assert_eq!(subscriber.call(&["SUBSCRIBE", "news", "weather"]), subscription_reply("subscribe", Some("news"), 1));
+        assert_eq!(subscriber.receive(), subscription_reply("subscribe", Some("weather"), 2));
+        assert_eq!(subscriber.call(&["UNSUBSCRIBE", "news"]), subscription_reply("unsubscribe", Some("news"), 1));
+        assert_eq!(subscriber.call(&["UNSUBSCRIBE"]), subscription_reply("unsubscribe", Some("weather"), 0));
+        assert_eq!(subscriber.call(&["UNSUBSCRIBE"]), subscription_reply("unsubscribe", None, 0));
+
+        // with no subscriptions left, other commands work again
+        assert_eq!(subscriber.call(&["LLEN", "nothing"]), Value::Integer(0));
+    }
+
+    #[test]
+    fn subscribers_receive_published_messages() {
+        let server = start_server(0);
+        let mut subscriber = Client::connect(server);
+        let mut publisher = Client::connect(server);
+
+        subscriber.call(&["SUBSCRIBE", "news"]);
+
+        assert_eq!(publisher.call(&["PUBLISH", "news", "hello"]), Value::Integer(1));
+        assert_eq!(publisher.call(&["PUBLISH", "weather", "sunny"]), Value::Integer(0));
+        assert_eq!(subscriber.receive(), Value::Array(vec![Value::BufBulk(b"message".to_vec()), Value::BufBulk(b"news".to_vec()), Value::BufBulk(b"hello".to_vec())]));
+
+        assert_eq!(subscriber.call(&["LLEN", "nothing"]), Value::Error("ERR Can't execute 'llen': only SUBSCRIBE / UNSUBSCRIBE / PING / QUIT are allowed in this context".to_string()));
+        assert_eq!(subscriber.call(&["PING"]), Value::Array(vec![Value::BufBulk(b"pong".to_vec()), Value::BufBulk(vec![])]));
+        assert_eq!(subscriber.call(&["QUIT"]), Value::String("OK".to_string()));
+
+        let start = Instant::now();
+
+        while publisher.call(&["PUBLISH", "news", "anyone?"]) != Value::Integer(0) {
+            assert!(start.elapsed() < Duration::from_secs(5), "subscriber was never removed");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn replicas_follow_the_sync_stream() {
+        let primary = start_server(0);
+        let replica = start_server(0);
+
+        let mut writer = Client::connect(primary);
+        writer.call(&["RPUSH", "before", "a", "b"]);
+        writer.call(&["RPUSH", "emptied", "x"]);
+
+        let mut sync = Client::connect(primary);
+        assert_eq!(sync.call(&["SYNC"]), Value::String("OK".to_string()));
+
+        thread::spawn(move || {
+            let mut replica_client = Client::connect(replica);
+
+            loop {
+                match sync.receive() {
+                    Value::Array(command) => {
+                        replica_client.send(command);
+                        replica_client.receive();
+                    }
+
+                    _ => panic!("expected a command")
+                }
+            }
+        });
+
+        writer.call(&["LPUSH", "before", "c"]);
+        writer.call(&["RPOP", "emptied"]);
+        writer.call(&["RPUSH", "after", "d", "e", "f"]);
+        writer.call(&["LSET", "after", "1", "g"]);
+        writer.call(&["BLPOP", "nothing", "after", "1"]);
+
+        let mut reader = Client::connect(replica);
+        let expected = [("before", writer.call(&["LRANGE", "before", "0", "-1"])), ("emptied", Value::Array(vec![])), ("after", writer.call(&["LRANGE", "after", "0", "-1"]))];
+        let start = Instant::now();
+
+        while expected.iter().any(|&(key, ref value)| reader.call(&["LRANGE", key, "0", "-1"]) != *value) {
+            assert!(start.elapsed() < Duration::from_secs(5), "replica did not catch up");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(reader.call(&["LRANGE", "after", "0", "-1"]), Value::Array(vec![Value::BufBulk(b"g".to_vec()), Value::BufBulk(b"f".to_vec())]));
     }
 }
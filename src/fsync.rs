@@ -0,0 +1,123 @@
+extern crate rusqlite;
+
+use config::AppendFsync;
+use stats::Stats;
+use std::fs::{self, File};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// `always` leaves sqlite syncing the log as each write commits, which is what it does by default.  NORMAL would
+// only sync it at checkpoints, so a write already replied to could be lost in a power cut.  Otherwise the commits
+// themselves are left to the OS or, for `everysec`, to sync_every, but NORMAL still syncs checkpoints so the database
+// can't be damaged in a crash.
+pub fn configure(connection: &rusqlite::Connection, policy: AppendFsync) {
+    let synchronous = match policy {
+        AppendFsync::Always                     => "FULL",
+        AppendFsync::EverySec | AppendFsync::No => "NORMAL"
+    };
+
+    connection.execute_batch(&format!("PRAGMA synchronous = {}", synchronous)).unwrap();
+}
+
+// Syncs the database file every `interval` until `stopping` is set, so however many writes commit in between, they
+// cost one sync, and an interval with none costs nothing.  Each sync is counted in the stats.
+pub fn sync_every(path: String, interval: Duration, stats: Arc<Stats>, stopping: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let mut synced = vec![];
+
+        while !stopping.load(Ordering::SeqCst) {
+            thread::sleep(interval);
+
+            let written = last_written(&path);
+
+            if written == synced {
+                continue;
+            }
+
+            // A sync through any descriptor for the file writes out everything written through the others.  In WAL
+            // mode commits land in the log, and only reach the database file when it's checkpointed, so both are
+            // synced.
+            File::open(format!("{}-wal", path)).and_then(|file| file.sync_data()).ok();
+
+            if File::open(&path).and_then(|file| file.sync_data()).is_ok() {
+                Stats::increment(&stats.database_syncs);
+                synced = written;
+            }
+        }
+    });
+}
+
+// the size and modification time of the database file and its log, which change whenever either is written to
+fn last_written(path: &str) -> Vec<Option<(u64, SystemTime)>> {
+    [path.to_string(), format!("{}-wal", path)].iter()
+        .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified().map(|modified| (metadata.len(), modified))).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{configure, sync_every, rusqlite};
+    use config::AppendFsync;
+    use stats::Stats;
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    fn synchronous(connection: &rusqlite::Connection) -> i64 {
+        connection.query_row("PRAGMA synchronous", &[], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn always_leaves_sqlite_syncing_every_commit() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+
+        configure(&connection, AppendFsync::Always);
+        assert_eq!(synchronous(&connection), 2);
+
+        configure(&connection, AppendFsync::EverySec);
+        assert_eq!(synchronous(&connection), 1);
+
+        configure(&connection, AppendFsync::No);
+        assert_eq!(synchronous(&connection), 1);
+    }
+
+    // sqlite gives no way to count the syncs it makes as `always` commits, so only those sync_every makes are counted
+    #[test]
+    fn everysec_syncs_many_writes_at_once_and_none_while_idle() {
+        let path = env::temp_dir().join("blueis-fsync-test.sqlite3").to_string_lossy().into_owned();
+        fs::remove_file(&path).ok();
+
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        configure(&connection, AppendFsync::EverySec);
+        connection.execute("CREATE TABLE items (value integer)", &[]).unwrap();
+
+        let stats = Arc::new(Stats::default());
+        let stopping = Arc::new(AtomicBool::new(false));
+        sync_every(path.clone(), Duration::from_millis(100), stats.clone(), stopping.clone());
+
+        // the table, then nothing more
+        thread::sleep(Duration::from_millis(350));
+        assert_eq!(Stats::get(&stats.database_syncs), 1);
+
+        let writes = 100;
+
+        for value in 0..writes {
+            connection.execute("INSERT INTO items (value) VALUES (?1)", &[&value]).unwrap();
+        }
+
+        thread::sleep(Duration::from_millis(350));
+        let syncs = Stats::get(&stats.database_syncs) - 1;
+        assert!((1..=2).contains(&syncs), "{} syncs for {} writes", syncs, writes);
+
+        thread::sleep(Duration::from_millis(350));
+        assert_eq!(Stats::get(&stats.database_syncs) - 1, syncs);
+
+        stopping.store(true, Ordering::SeqCst);
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,59 @@
+extern crate rusqlite;
+
+// Runs sqlite's own integrity check, returning the problems it reports.  A file too damaged to read at all is
+// reported through the error sqlite gives instead.
+pub fn check(connection: &rusqlite::Connection) -> Result<(), String> {
+    let mut statement = connection.prepare("PRAGMA integrity_check").map_err(|error| error.to_string())?;
+    let rows = statement.query_map(&[], |row| row.get(0)).map_err(|error| error.to_string())?;
+    let messages = rows.collect::<Result<Vec<String>, _>>().map_err(|error| error.to_string())?;
+
+    if messages == ["ok"] {
+        Ok(())
+    }
+    else {
+        Err(messages.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, rusqlite};
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+
+    #[test]
+    fn a_healthy_database_passes() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        connection.execute("CREATE TABLE items (id integer primary key, value blob)", &[]).unwrap();
+
+        assert_eq!(check(&connection), Ok(()));
+    }
+
+    #[test]
+    fn a_damaged_database_is_reported() {
+        let path = env::temp_dir().join("blueis-integrity-test.sqlite3");
+        fs::remove_file(&path).ok();
+
+        {
+            let connection = rusqlite::Connection::open(&path).unwrap();
+            connection.execute("CREATE TABLE items (id integer primary key, value blob)", &[]).unwrap();
+            connection.execute("CREATE INDEX items_value ON items(value)", &[]).unwrap();
+
+            for index in 0..500 {
+                connection.execute("INSERT INTO items (value) VALUES (?1)", &[&format!("value {}", index)]).unwrap();
+            }
+        }
+
+        // overwrite the second page onwards, leaving the header readable
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(4096)).unwrap();
+        file.write_all(&[0xA5; 8192]).unwrap();
+        drop(file);
+
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        assert!(check(&connection).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,59 @@
+extern crate rusqlite;
+
+pub mod checkout;
+pub mod config;
+pub mod connection;
+pub mod commands;
+pub mod fsync;
+pub mod integrity;
+pub mod logger;
+mod parser;
+pub mod pubsub;
+pub mod push_waiters;
+mod rate_limit;
+pub mod read_cache;
+pub mod reply;
+pub mod monitor;
+mod serialization;
+pub mod server;
+pub mod socket;
+pub mod stats;
+pub mod storage;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod thread_limit;
+pub mod warmup;
+
+const DATABASE_VERSION: &'static str = "1";
+
+pub fn set_up_tables(connection: &rusqlite::Connection) {
+    connection.execute("CREATE TABLE blueis (id integer primary key autoincrement, key string, value blob)", &[]).ok();
+    connection.execute("CREATE UNIQUE INDEX blueis_key_index ON blueis(key)", &[]).ok();
+
+    match connection.prepare("SELECT value FROM blueis WHERE key = 'version'").unwrap().query_row(&[], |row| row.get(0)) as Result<String, _> {
+        Ok(value) => {
+            if value.as_str() != DATABASE_VERSION {
+                panic!("the database supplied has been used on a later version of blueis, and therefore is incompatible with this version");
+            }
+        }
+
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            connection.execute("INSERT INTO blueis (key, value) VALUES ('version', '1')", &[]).unwrap();
+        }
+
+        Err(e) => panic!("{}", e)
+    }
+
+    connection.execute("CREATE TABLE list_items (id integer primary key autoincrement, key blob, value blob, position integer)", &[]).ok();
+    connection.execute("CREATE INDEX list_items_key ON list_items(key, position)", &[]).ok();
+
+    connection.execute("CREATE TABLE zset_items (id integer primary key autoincrement, key blob, member blob, score real)", &[]).ok();
+    connection.execute("CREATE UNIQUE INDEX zset_items_key_member ON zset_items(key, member)", &[]).ok();
+    connection.execute("CREATE INDEX zset_items_key_score ON zset_items(key, score, member)", &[]).ok();
+
+    connection.execute("CREATE TABLE hash_items (id integer primary key autoincrement, key blob, field blob, value blob)", &[]).ok();
+    connection.execute("CREATE UNIQUE INDEX hash_items_key_field ON hash_items(key, field)", &[]).ok();
+
+    connection.execute("CREATE TABLE key_meta (id integer primary key autoincrement, key blob, expires_at integer)", &[]).ok();
+    connection.execute("CREATE UNIQUE INDEX key_meta_key ON key_meta(key)", &[]).ok();
+}
@@ -0,0 +1,162 @@
+extern crate libc;
+extern crate time;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// set by the SIGHUP handler, which can't safely do anything more; the next log line reopens the file
+static REOPEN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub enum Level {
+    Notice,
+    Warning,
+}
+
+impl Level {
+    fn name(&self) -> &'static str {
+        match *self {
+            Level::Notice  => "notice",
+            Level::Warning => "warning"
+        }
+    }
+}
+
+// Writes log lines to a file, or to stderr when there isn't one.  After SIGHUP the file is reopened by name before
+// the next line is written, so logrotate can move the file away and signal blueis to start a fresh one.
+pub struct Logger {
+    path: Option<String>,
+    file: Mutex<Option<File>>,
+}
+
+impl Logger {
+    pub fn new(path: Option<String>) -> io::Result<Logger> {
+        let file = match path {
+            Some(ref path) => Some(Logger::open(path)?),
+            None           => None
+        };
+
+        Ok(Logger { path: path, file: Mutex::new(file) })
+    }
+
+    // a log file that can't be opened shouldn't stop blueis starting, so it logs to stderr instead
+    pub fn new_or_stderr(path: Option<String>) -> Logger {
+        Logger::new(path.clone()).unwrap_or_else(|error| {
+            let logger = Logger::new(None).unwrap();
+            logger.log(Level::Warning, None, &format!("unable to open log file {}, logging to stderr: {}", path.unwrap(), error));
+            logger
+        })
+    }
+
+    pub fn reopen_on_sighup() {
+        unsafe {
+            libc::signal(libc::SIGHUP, request_reopen as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+    }
+
+    pub fn log(&self, level: Level, connection_id: Option<usize>, message: &str) {
+        self.write_line(&Logger::format_line(&time::now_utc(), level, connection_id, message));
+    }
+
+    fn write_line(&self, line: &str) {
+        let mut file = self.file.lock().unwrap();
+
+        if REOPEN_REQUESTED.swap(false, Ordering::SeqCst) {
+            if let Some(ref path) = self.path {
+                // if the file can't be reopened, keep writing to the old one rather than losing lines
+                if let Ok(reopened) = Logger::open(path) {
+                    *file = Some(reopened);
+                }
+            }
+        }
+
+        // there's nowhere left to report a failure to write a log line, so it's dropped
+        match *file {
+            Some(ref mut file) => { writeln!(file, "{}", line).ok(); }
+            None               => { writeln!(io::stderr(), "{}", line).ok(); }
+        }
+    }
+
+    // a UTC timestamp to the millisecond, the level, then the connection the line is about if there is one
+    fn format_line(now: &time::Tm, level: Level, connection_id: Option<usize>, message: &str) -> String {
+        let connection = match connection_id {
+            Some(id) => format!(" connection={}", id),
+            None     => String::new()
+        };
+
+        format!("{}.{:03}Z {}{} {}", now.strftime("%Y-%m-%dT%H:%M:%S").unwrap(), now.tm_nsec / 1000000, level.name(), connection, message)
+    }
+
+    fn open(path: &str) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+}
+
+extern "C" fn request_reopen(_signal: libc::c_int) {
+    REOPEN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Logger, Level, libc, time};
+    use std::fs::{self, File};
+    use std::io::Read;
+
+    fn read(path: &str) -> String {
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn lines_have_a_timestamp_level_and_connection() {
+        let now = time::at_utc(time::Timespec::new(1500000000, 123456789));
+
+        assert_eq!(Logger::format_line(&now, Level::Notice, None, "hello"), "2017-07-14T02:40:00.123Z notice hello");
+        assert_eq!(Logger::format_line(&now, Level::Warning, Some(7), "hello"), "2017-07-14T02:40:00.123Z warning connection=7 hello");
+    }
+
+    #[test]
+    fn starting_with_a_log_file_writes_to_it() {
+        fs::remove_file("startup-test.log").ok();
+
+        let logger = Logger::new_or_stderr(Some("startup-test.log".to_string()));
+        logger.log(Level::Notice, None, "blueis listening at 127.0.0.1:6379");
+
+        assert!(read("startup-test.log").ends_with(" notice blueis listening at 127.0.0.1:6379\n"));
+
+        fs::remove_file("startup-test.log").unwrap();
+    }
+
+    #[test]
+    fn an_unopenable_log_file_falls_back_to_stderr() {
+        let logger = Logger::new_or_stderr(Some("no-such-directory/blueis.log".to_string()));
+
+        assert!(logger.file.lock().unwrap().is_none());
+        logger.log(Level::Notice, None, "still logged");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn sighup_reopens_the_log_file() {
+        fs::remove_file("sighup-test.log").ok();
+        fs::remove_file("sighup-test.log.1").ok();
+
+        let logger = Logger::new(Some("sighup-test.log".to_string())).unwrap();
+        Logger::reopen_on_sighup();
+
+        logger.log(Level::Notice, None, "before rotation");
+        fs::rename("sighup-test.log", "sighup-test.log.1").unwrap();
+
+        unsafe { libc::raise(libc::SIGHUP); }
+        logger.log(Level::Notice, None, "after rotation");
+
+        assert!(read("sighup-test.log.1").ends_with(" before rotation\n"));
+        assert!(read("sighup-test.log").ends_with(" after rotation\n"));
+        assert_eq!(read("sighup-test.log").lines().count(), 1);
+
+        fs::remove_file("sighup-test.log").unwrap();
+        fs::remove_file("sighup-test.log.1").unwrap();
+    }
+}
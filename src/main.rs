@@ -1,79 +1,53 @@
-extern crate rusqlite;
-
-mod connection;
-mod commands;
-mod parser;
-mod monitor;
+extern crate blueis;
 
+use blueis::{config, logger, server};
 use std::env;
-use std::io::{self, Write};
-use std::net::TcpListener;
 use std::thread;
-use std::sync::{Arc, Mutex, Condvar};
-
-const DATABASE_VERSION: &'static str = "1";
-const MAX_MONITOR_QUEUE_SIZE: usize = 100;
+use std::time::Duration;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let config = match config::Config::parse(&args, &|name| env::var(name).ok()) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            eprintln!("usage: blueis [options] [host:port] [database.sqlite3]");
+            std::process::exit(1);
+        }
+    };
 
-    if args.len() != 3 {
-        writeln!(io::stderr(), "usage: blueis host:port database.sqlite3").unwrap();
+    if let Err(error) = config.check_dir() {
+        eprintln!("{}", error);
         std::process::exit(1);
     }
 
-    let listener = TcpListener::bind(args[1].clone()).unwrap();
-
-    let connection = rusqlite::Connection::open(args[2].clone()).unwrap();
-
-    set_up_tables(&connection);
-
-    let connection_mutex = Arc::new(Mutex::new(connection));
-
-    let push_notification = Arc::new((Mutex::new(false), Condvar::new()));
+    logger::Logger::reopen_on_sighup();
+    server::stop_on_sigterm();
 
-    let monitor = monitor::Monitor::new(MAX_MONITOR_QUEUE_SIZE);
+    let address = config.address.clone();
+    let database_path = config.database_path.clone();
 
-    println!("blueis listening at {}", args[1]);
-
-    for stream in listener.incoming() {
-        match stream {
-            Err(_) => {}
-            Ok(stream) => {
-                let connection_mutex = connection_mutex.clone();
-                let local_push_notification = push_notification.clone();
-                let local_monitor = monitor.clone();
-
-                thread::spawn(move || {
-                    connection::Connection::new(
-                        connection_mutex,
-                        local_monitor,
-                        local_push_notification
-                    ).run(stream);
-                });
+    match server::serve(config) {
+        // shutting down checkpoints the database, so it's left as one file rather than the process just exiting
+        Ok(server) => {
+            while !server::stop_requested() && !server.set_up_failed() {
+                thread::sleep(Duration::from_millis(100));
             }
-        }
-    }
-}
 
-fn set_up_tables(connection: &rusqlite::Connection) {
-    connection.execute("CREATE TABLE blueis (id integer primary key autoincrement, key string, value blob)", &[]).ok();
-    connection.execute("CREATE UNIQUE INDEX blueis_key_index ON blueis(key)", &[]).ok();
+            // rather than leave clients waiting on a database that will never load
+            let failed = server.set_up_failed();
+            server.shutdown();
 
-    match connection.prepare("SELECT value FROM blueis WHERE key = 'version'").unwrap().query_row(&[], |row| row.get(0)) as Result<String, _> {
-        Ok(value) => {
-            if value.as_str() != DATABASE_VERSION {
-                panic!("the database supplied has been used on a later version of blueis, and therefore is incompatible with this version");
+            if failed {
+                eprintln!("unable to set up the database at {}", database_path);
+                std::process::exit(1);
             }
         }
 
-        Err(rusqlite::Error::QueryReturnedNoRows) => {
-            connection.execute("INSERT INTO blueis (key, value) VALUES ('version', '1')", &[]).unwrap();
+        Err(error) => {
+            eprintln!("unable to start blueis at {}: {}", address, error);
+            std::process::exit(1);
         }
-
-        Err(e) => panic!(e)
     }
-
-    connection.execute("CREATE TABLE list_items (id integer primary key autoincrement, key blob, value blob, position integer)", &[]).ok();
-    connection.execute("CREATE INDEX list_items_key ON list_items(key, position)", &[]).ok();
 }
@@ -4,21 +4,21 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::cell::Cell;
 
 #[derive(Clone)]
-pub struct Monitor {
-    pub queue: Arc<Mutex<VecDeque<String>>>,
+pub struct Monitor<T: Clone> {
+    pub queue: Arc<Mutex<VecDeque<T>>>,
     pub start: Arc<AtomicUsize>,
     pub stop: Arc<AtomicUsize>,
     pub cond: Arc<Condvar>,
     max_queue_size: usize
 }
 
-pub struct Listener<'a> {
-    monitor: &'a Monitor,
+pub struct Listener<'a, T: 'a + Clone> {
+    monitor: &'a Monitor<T>,
     position: Cell<usize>
 }
 
-impl Monitor {
-    pub fn new(max_queue_size: usize) -> Monitor {
+impl<T: Clone> Monitor<T> {
+    pub fn new(max_queue_size: usize) -> Monitor<T> {
         if max_queue_size < 1 { panic!("max_queue_size must be 1 or greater"); }
 
         Monitor {
@@ -30,7 +30,7 @@ impl Monitor {
         }
     }
 
-    pub fn send(&self, payload: String) {
+    pub fn send(&self, payload: T) {
         let mut locked_queue = self.queue.lock().unwrap();
 
         locked_queue.push_back(payload);
@@ -44,7 +44,7 @@ impl Monitor {
         self.cond.notify_all();
     }
 
-    pub fn listen(&self) -> Listener {
+    pub fn listen(&self) -> Listener<'_, T> {
         Listener {
             monitor: self,
             position: Cell::new(self.stop.load(Ordering::Acquire))
@@ -52,8 +52,17 @@ impl Monitor {
     }
 }
 
-impl<'a> Listener<'a> {
-    pub fn recv(&self) -> Option<String> {
+impl<'a, T: Clone> Listener<'a, T> {
+    pub fn recv(&self) -> Option<T> {
+        self.next(true)
+    }
+
+    // returns None rather than skipping ahead if payloads were dropped because the queue overflowed
+    pub fn recv_without_gaps(&self) -> Option<T> {
+        self.next(false)
+    }
+
+    fn next(&self, allow_gaps: bool) -> Option<T> {
         let mut locked_queue = self.monitor.queue.lock().unwrap();
 
         while self.position.get() == self.monitor.stop.load(Ordering::Acquire) {
@@ -63,7 +72,7 @@ impl<'a> Listener<'a> {
         let start = self.monitor.start.load(Ordering::Acquire);
 
         if self.position.get() < start {
-            // we missed some, notify caller?
+            if !allow_gaps { return None; }
             self.position.set(start);
         }
 
@@ -160,4 +169,19 @@ mod tests {
         assert_eq!(listener.recv().unwrap(), "C".to_string());
         assert_eq!(listener.recv().unwrap(), "D".to_string());
     }
+
+    #[test]
+    fn strict_listeners_stop_if_queue_overflows() {
+        let monitor = Monitor::new(2);
+        let listener = monitor.listen();
+
+        monitor.send("A".to_string());
+        assert_eq!(listener.recv_without_gaps().unwrap(), "A".to_string());
+
+        monitor.send("B".to_string());
+        monitor.send("C".to_string());
+        monitor.send("D".to_string());
+
+        assert_eq!(listener.recv_without_gaps(), None);
+    }
 }
@@ -4,37 +4,58 @@ extern crate rusqlite;
 use self::resp::{Value};
 use std::str;
 
-type ParserResult<'a> = Result<(&'a str, Vec<&'a [u8]>), &'a str>;
+type ParserResult<'a> = Result<(&'a str, Vec<&'a [u8]>), String>;
+
+// The same limit redis places on the number of elements in a multibulk request.  Unlike redis's, it's only checked
+// once the decoder has read the whole request, which it gives no way to look at the header of first.
+const MAX_ARGUMENTS: usize = 1024 * 1024;
 
 pub fn parse_command<'a>(value: &'a Value) -> ParserResult<'a> {
     if let &Value::Array(ref array) = value {
         parse_command_array(array)
     }
     else {
-        Err("expected array")
+        Err(protocol_error('*', value))
     }
 }
 
 fn parse_command_array<'a>(array: &'a Vec<Value>) -> ParserResult<'a> {
+    if array.len() > MAX_ARGUMENTS {
+        return Err("Protocol error: invalid multibulk length".to_string());
+    }
+
     let iter = array.iter().map(|value|
         match *value {
             Value::String(ref string) => Ok(string.as_bytes()),
+            Value::Bulk(ref string) => Ok(string.as_bytes()),
             Value::BufBulk(ref string) => Ok(string.as_slice()),
-            _ => Err("all arguments should be strings")
+            _ => Err(protocol_error('$', value))
         }
     );
 
-    let strings = iter.collect::<Result<Vec<&[u8]>, &'static str>>()?;
-    let (head, tail) = strings.split_at(1);
+    let strings = iter.collect::<Result<Vec<&[u8]>, String>>()?;
+    let (head, tail) = strings.split_first().ok_or("empty command".to_string())?;
 
-    str::from_utf8(head[0])
+    str::from_utf8(head)
         .map(|command| (command, tail.to_vec()))
-        .map_err(|_| "invalid command name")
+        .map_err(|_| "invalid command name".to_string())
+}
+
+fn protocol_error(expected: char, value: &Value) -> String {
+    let got = match *value {
+        Value::Null | Value::Bulk(_) | Value::BufBulk(_) => '$',
+        Value::NullArray | Value::Array(_)               => '*',
+        Value::String(_)                                 => '+',
+        Value::Error(_)                                  => '-',
+        Value::Integer(_)                                => ':'
+    };
+
+    format!("Protocol error: expected '{}', got '{}'", expected, got)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::parse_command;
+    use super::{parse_command, MAX_ARGUMENTS};
     use super::resp::Value;
 
     #[test]
@@ -55,6 +76,24 @@ mod tests {
     #[test]
     fn rejects_a_value_has_non_string_values_in_the_array() {
         let value = Value::Array(vec![Value::String("COMMAND".to_string()), Value::Integer(2)]);
-        assert!(parse_command(&value).is_err());
+        assert_eq!(parse_command(&value), Err("Protocol error: expected '$', got ':'".to_string()));
+    }
+
+    #[test]
+    fn rejects_a_value_that_is_not_an_array_with_the_redis_wording() {
+        let value = Value::Integer(2);
+        assert_eq!(parse_command(&value), Err("Protocol error: expected '*', got ':'".to_string()));
+    }
+
+    #[test]
+    fn rejects_an_empty_array() {
+        let value = Value::Array(vec![]);
+        assert_eq!(parse_command(&value), Err("empty command".to_string()));
+    }
+
+    #[test]
+    fn rejects_too_many_arguments_before_looking_at_their_types() {
+        let value = Value::Array(vec![Value::Integer(0); MAX_ARGUMENTS + 1]);
+        assert_eq!(parse_command(&value), Err("Protocol error: invalid multibulk length".to_string()));
     }
 }
@@ -0,0 +1,281 @@
+extern crate resp;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use self::resp::Value;
+
+// a channel's subscribers, by id
+type Subscribers = HashMap<usize, Arc<Outbox>>;
+
+// The channels clients are subscribed to, shared by every connection.  Each subscriber is known by an id and is sent
+// messages, ready to be written to its client, through its outbox.
+#[derive(Default)]
+pub struct PubSub {
+    channels: Mutex<HashMap<Vec<u8>, Subscribers>>,
+    next_id: AtomicUsize,
+}
+
+// what happens to a subscriber with more than its limit of replies and messages waiting to be written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    // the oldest waiting is dropped, as MONITOR skips what a slow client missed
+    DropOldest,
+    // the client is hung up on, as redis's client-output-buffer-limit does
+    Disconnect,
+}
+
+// Replies and messages waiting to be written to one subscribed client by a thread of its own.  Holding more than
+// `limit` of them (zero meaning no limit) means the client isn't keeping up.  A client that's stopped reading can
+// leave that thread stuck in a write, so hanging up is left to `hang_up`, which has to unblock it.
+pub struct Outbox {
+    state: Mutex<OutboxState>,
+    ready: Condvar,
+    limit: usize,
+    overflow: Overflow,
+    hang_up: Box<Fn() + Send + Sync>,
+}
+
+#[derive(Default)]
+struct OutboxState {
+    values: VecDeque<Value>,
+    closed: bool,
+}
+
+// One connection's subscriptions.  Its own set of channels is what the counts in SUBSCRIBE and UNSUBSCRIBE replies
+// come from, and it's only changed while the registry is locked, so the count always matches what the registry
+// holds for it.
+pub struct Subscription {
+    id: usize,
+    pubsub: Arc<PubSub>,
+    outbox: Arc<Outbox>,
+    channels: HashSet<Vec<u8>>,
+}
+
+impl PubSub {
+    // returns the number of subscribers the message was sent to, which doesn't include any hung up on for falling behind
+    pub fn publish(&self, channel: &[u8], message: &[u8]) -> usize {
+        let channels = self.channels.lock().unwrap();
+
+        match channels.get(channel) {
+            Some(subscribers) => {
+                let value = Value::Array(vec![
+                    Value::BufBulk(b"message".to_vec()),
+                    Value::BufBulk(channel.to_vec()),
+                    Value::BufBulk(message.to_vec()),
+                ]);
+
+                // a subscriber that has gone away unsubscribes itself as soon as its connection finishes
+                subscribers.values().filter(|outbox| outbox.push(value.clone())).count()
+            }
+
+            None => 0
+        }
+    }
+}
+
+impl Outbox {
+    pub fn new(limit: usize, overflow: Overflow, hang_up: Box<Fn() + Send + Sync>) -> Outbox {
+        Outbox { state: Mutex::new(OutboxState::default()), ready: Condvar::new(), limit: limit, overflow: overflow, hang_up: hang_up }
+    }
+
+    // returns false if the outbox is closed, whether already or because this value overflowed it
+    pub fn push(&self, value: Value) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed {
+            return false;
+        }
+
+        state.values.push_back(value);
+
+        if self.limit > 0 && state.values.len() > self.limit {
+            match self.overflow {
+                Overflow::DropOldest => { state.values.pop_front(); }
+
+                Overflow::Disconnect => {
+                    state.closed = true;
+                    state.values.clear();
+                    (self.hang_up)();
+                }
+            }
+        }
+
+        self.ready.notify_one();
+        !state.closed
+    }
+
+    // waits for the next value, or returns None once the outbox is closed and everything in it has been taken
+    pub fn pop(&self) -> Option<Value> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some(value) = state.values.pop_front() {
+                return Some(value);
+            }
+
+            if state.closed {
+                return None;
+            }
+
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    // nothing more is accepted, but what's already waiting is still taken
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+impl Subscription {
+    pub fn new(pubsub: Arc<PubSub>, outbox: Arc<Outbox>) -> Subscription {
+        let id = pubsub.next_id.fetch_add(1, Ordering::Relaxed);
+        Subscription { id: id, pubsub: pubsub, outbox: outbox, channels: HashSet::new() }
+    }
+
+    // returns how many channels this connection is subscribed to afterwards
+    pub fn subscribe(&mut self, channel: &[u8]) -> usize {
+        let mut channels = self.pubsub.channels.lock().unwrap();
+
+        channels.entry(channel.to_vec()).or_default().insert(self.id, self.outbox.clone());
+        self.channels.insert(channel.to_vec());
+
+        self.channels.len()
+    }
+
+    // returns how many channels this connection is subscribed to afterwards
+    pub fn unsubscribe(&mut self, channel: &[u8]) -> usize {
+        let mut channels = self.pubsub.channels.lock().unwrap();
+
+        let emptied = match channels.get_mut(channel) {
+            Some(subscribers) => {
+                subscribers.remove(&self.id);
+                subscribers.is_empty()
+            }
+
+            None => false
+        };
+
+        if emptied {
+            channels.remove(channel);
+        }
+
+        self.channels.remove(channel);
+
+        self.channels.len()
+    }
+
+    // sorted, so unsubscribing from everything replies in a predictable order
+    pub fn channels(&self) -> Vec<Vec<u8>> {
+        let mut channels: Vec<Vec<u8>> = self.channels.iter().cloned().collect();
+        channels.sort();
+        channels
+    }
+
+    pub fn count(&self) -> usize {
+        self.channels.len()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        for channel in self.channels() {
+            self.unsubscribe(&channel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Outbox, Overflow, PubSub, Subscription};
+    use super::resp::Value;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn unlimited_outbox() -> Arc<Outbox> {
+        Arc::new(Outbox::new(0, Overflow::Disconnect, Box::new(|| {})))
+    }
+
+    fn message(channel: &str, payload: &str) -> Value {
+        Value::Array(vec![Value::BufBulk(b"message".to_vec()), Value::BufBulk(channel.as_bytes().to_vec()), Value::BufBulk(payload.as_bytes().to_vec())])
+    }
+
+    #[test]
+    fn counts_follow_subscribes_and_unsubscribes() {
+        let pubsub = Arc::new(PubSub::default());
+        let mut subscription = Subscription::new(pubsub, unlimited_outbox());
+
+        assert_eq!(subscription.subscribe(b"news"), 1);
+        assert_eq!(subscription.subscribe(b"weather"), 2);
+        assert_eq!(subscription.subscribe(b"news"), 2);
+        assert_eq!(subscription.unsubscribe(b"news"), 1);
+        assert_eq!(subscription.unsubscribe(b"sport"), 1);
+        assert_eq!(subscription.unsubscribe(b"weather"), 0);
+    }
+
+    #[test]
+    fn messages_reach_each_subscriber() {
+        let pubsub = Arc::new(PubSub::default());
+        let (first_outbox, second_outbox) = (unlimited_outbox(), unlimited_outbox());
+        let mut first = Subscription::new(pubsub.clone(), first_outbox.clone());
+        let mut second = Subscription::new(pubsub.clone(), second_outbox.clone());
+
+        first.subscribe(b"news");
+        second.subscribe(b"news");
+        assert_eq!(pubsub.publish(b"news", b"hello"), 2);
+        assert_eq!(pubsub.publish(b"weather", b"sunny"), 0);
+
+        assert_eq!(first_outbox.pop(), Some(message("news", "hello")));
+        assert_eq!(second_outbox.pop(), Some(message("news", "hello")));
+
+        drop(second);
+        assert_eq!(pubsub.publish(b"news", b"again"), 1);
+    }
+
+    #[test]
+    fn a_subscriber_over_its_limit_is_hung_up_on_while_others_keep_receiving() {
+        let pubsub = Arc::new(PubSub::default());
+        let hung_up = Arc::new(AtomicBool::new(false));
+        let flag = hung_up.clone();
+
+        let stalled_outbox = Arc::new(Outbox::new(2, Overflow::Disconnect, Box::new(move || flag.store(true, Ordering::SeqCst))));
+        let healthy_outbox = unlimited_outbox();
+        let mut stalled = Subscription::new(pubsub.clone(), stalled_outbox.clone());
+        let mut healthy = Subscription::new(pubsub.clone(), healthy_outbox.clone());
+
+        stalled.subscribe(b"news");
+        healthy.subscribe(b"news");
+
+        assert_eq!(pubsub.publish(b"news", b"one"), 2);
+        assert_eq!(pubsub.publish(b"news", b"two"), 2);
+        assert!(!hung_up.load(Ordering::SeqCst));
+
+        assert_eq!(pubsub.publish(b"news", b"three"), 1);
+        assert!(hung_up.load(Ordering::SeqCst));
+        assert_eq!(stalled_outbox.pop(), None);
+
+        assert_eq!(pubsub.publish(b"news", b"four"), 1);
+
+        for payload in &["one", "two", "three", "four"] {
+            assert_eq!(healthy_outbox.pop(), Some(message("news", payload)));
+        }
+    }
+
+    #[test]
+    fn a_subscriber_that_drops_the_oldest_keeps_the_newest() {
+        let outbox = Outbox::new(2, Overflow::DropOldest, Box::new(|| panic!("should not hang up")));
+
+        for integer in 1..4 {
+            assert!(outbox.push(Value::Integer(integer)));
+        }
+
+        outbox.close();
+        assert!(!outbox.push(Value::Integer(4)));
+
+        assert_eq!(outbox.pop(), Some(Value::Integer(2)));
+        assert_eq!(outbox.pop(), Some(Value::Integer(3)));
+        assert_eq!(outbox.pop(), None);
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+type Waiting = HashMap<Vec<u8>, Vec<Arc<Condvar>>>;
+
+// Clients blocked until an element is pushed onto one of their keys.  Each waits on a condvar of its own, registered
+// under every key it's waiting on, so a push wakes only the clients waiting on the key it pushed onto.
+#[derive(Default)]
+pub struct PushWaiters {
+    waiting: Mutex<Waiting>,
+}
+
+impl PushWaiters {
+    // Taken by a waiter before it lets go of the database connection, as pushers notify while they still hold it, so
+    // a push can't land and notify between the waiter finding nothing and its wait starting.
+    pub fn lock(&self) -> MutexGuard<'_, Waiting> {
+        self.waiting.lock().unwrap()
+    }
+
+    // returns whether a push onto one of the keys woke it, rather than the timeout running out
+    pub fn wait(&self, mut guard: MutexGuard<Waiting>, keys: &[&[u8]], timeout: Duration) -> bool {
+        let condvar = Arc::new(Condvar::new());
+
+        for key in keys {
+            guard.entry(key.to_vec()).or_default().push(condvar.clone());
+        }
+
+        let (mut guard, result) = condvar.wait_timeout(guard, timeout).unwrap();
+
+        for key in keys {
+            let emptied = match guard.get_mut(*key) {
+                Some(condvars) => {
+                    condvars.retain(|other| !Arc::ptr_eq(other, &condvar));
+                    condvars.is_empty()
+                }
+
+                None => false
+            };
+
+            if emptied {
+                guard.remove(*key);
+            }
+        }
+
+        !result.timed_out()
+    }
+
+    pub fn notify(&self, key: &[u8]) {
+        if let Some(condvars) = self.lock().get(key) {
+            for condvar in condvars {
+                condvar.notify_one();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PushWaiters;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    // waits on the given keys on a thread of its own, once it's registered, returning whether it was woken
+    fn wait_on(waiters: &Arc<PushWaiters>, keys: &'static [&'static [u8]]) -> thread::JoinHandle<bool> {
+        let guard = waiters.lock();
+
+        let handle = {
+            let waiters = waiters.clone();
+            thread::spawn(move || {
+                let guard = waiters.lock();
+                waiters.wait(guard, keys, Duration::from_millis(500))
+            })
+        };
+
+        drop(guard);
+
+        while !keys.iter().all(|key| waiters.lock().contains_key(*key)) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        handle
+    }
+
+    #[test]
+    fn a_push_wakes_only_the_clients_waiting_on_its_key() {
+        let waiters = Arc::new(PushWaiters::default());
+
+        let destination = wait_on(&waiters, &[b"destination"]);
+        let either = wait_on(&waiters, &[b"unrelated", b"destination"]);
+        let unrelated = wait_on(&waiters, &[b"unrelated"]);
+
+        waiters.notify(b"destination");
+
+        assert!(destination.join().unwrap());
+        assert!(either.join().unwrap());
+        assert!(!unrelated.join().unwrap());
+
+        // and every waiter is gone once it's finished waiting
+        assert!(waiters.lock().is_empty());
+    }
+}
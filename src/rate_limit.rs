@@ -0,0 +1,68 @@
+use std::time::Instant;
+
+// Limits how many commands a second one client can run.  Up to a second's worth can be run in a burst, after which
+// they're allowed at the rate given, so a client sending commands no faster than that on average is never refused.
+pub struct RateLimit {
+    rate: f64,
+    allowance: f64,
+    updated_at: Instant,
+}
+
+impl RateLimit {
+    // a rate of zero means no limit
+    pub fn new(rate: usize) -> RateLimit {
+        RateLimit { rate: rate as f64, allowance: rate as f64, updated_at: Instant::now() }
+    }
+
+    // whether another command can run now, counting it if so
+    pub fn allow(&mut self) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+
+        self.allowance = (self.allowance + elapsed * self.rate).min(self.rate);
+        self.updated_at = now;
+
+        if self.allowance >= 1.0 {
+            self.allowance -= 1.0;
+            true
+        }
+        else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimit;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn allows_a_burst_of_a_seconds_worth_then_refuses() {
+        let mut limit = RateLimit::new(5);
+
+        let allowed = (0..10).filter(|_| limit.allow()).count();
+        assert_eq!(allowed, 5);
+    }
+
+    #[test]
+    fn allows_more_as_time_passes() {
+        let mut limit = RateLimit::new(50);
+        while limit.allow() {}
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(limit.allow());
+    }
+
+    #[test]
+    fn a_rate_of_zero_is_no_limit() {
+        let mut limit = RateLimit::new(0);
+        assert!((0..1000).all(|_| limit.allow()));
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::VecDeque;
+
+// A small least-recently-used cache of read results, keyed by the key read and the range requested.  Callers
+// must hold the sqlite lock while using it, so a result can't be cached after a write has invalidated its key.
+pub struct ReadCache<T: Clone> {
+    capacity: usize,
+    entries: VecDeque<(Vec<u8>, i64, i64, T)>,
+}
+
+impl<T: Clone> ReadCache<T> {
+    // a capacity of zero disables the cache
+    pub fn new(capacity: usize) -> ReadCache<T> {
+        ReadCache { capacity: capacity, entries: VecDeque::new() }
+    }
+
+    pub fn get(&mut self, key: &[u8], start: i64, stop: i64) -> Option<T> {
+        let index = self.entries.iter().position(|entry| entry.0 == key && entry.1 == start && entry.2 == stop)?;
+        let entry = self.entries.remove(index).unwrap();
+        let result = entry.3.clone();
+
+        self.entries.push_front(entry);
+        Some(result)
+    }
+
+    pub fn insert(&mut self, key: &[u8], start: i64, stop: i64, result: T) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.entries.len() == self.capacity {
+            self.entries.pop_back();
+        }
+
+        self.entries.push_front((key.to_vec(), start, stop, result));
+    }
+
+    pub fn invalidate(&mut self, key: &[u8]) {
+        self.entries.retain(|entry| entry.0 != key);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReadCache;
+
+    #[test]
+    fn results_are_cached_by_key_and_range() {
+        let mut cache = ReadCache::new(10);
+
+        cache.insert(b"a", 0, 9, "first");
+        cache.insert(b"a", 0, -1, "all");
+
+        assert_eq!(cache.get(b"a", 0, 9), Some("first"));
+        assert_eq!(cache.get(b"a", 0, -1), Some("all"));
+        assert_eq!(cache.get(b"a", 1, 9), None);
+        assert_eq!(cache.get(b"b", 0, 9), None);
+    }
+
+    #[test]
+    fn invalidating_a_key_removes_all_its_ranges() {
+        let mut cache = ReadCache::new(10);
+
+        cache.insert(b"a", 0, 9, "first");
+        cache.insert(b"a", 0, -1, "all");
+        cache.insert(b"b", 0, 9, "other");
+        cache.invalidate(b"a");
+
+        assert_eq!(cache.get(b"a", 0, 9), None);
+        assert_eq!(cache.get(b"a", 0, -1), None);
+        assert_eq!(cache.get(b"b", 0, 9), Some("other"));
+    }
+
+    #[test]
+    fn clearing_removes_everything() {
+        let mut cache = ReadCache::new(10);
+
+        cache.insert(b"a", 0, 9, "a");
+        cache.insert(b"b", 0, 9, "b");
+        cache.clear();
+
+        assert_eq!(cache.get(b"a", 0, 9), None);
+        assert_eq!(cache.get(b"b", 0, 9), None);
+    }
+
+    #[test]
+    fn the_least_recently_used_result_is_evicted() {
+        let mut cache = ReadCache::new(2);
+
+        cache.insert(b"a", 0, 9, "a");
+        cache.insert(b"b", 0, 9, "b");
+        cache.get(b"a", 0, 9);
+        cache.insert(b"c", 0, 9, "c");
+
+        assert_eq!(cache.get(b"a", 0, 9), Some("a"));
+        assert_eq!(cache.get(b"b", 0, 9), None);
+        assert_eq!(cache.get(b"c", 0, 9), Some("c"));
+    }
+
+    #[test]
+    fn a_zero_capacity_caches_nothing() {
+        let mut cache = ReadCache::new(0);
+
+        cache.insert(b"a", 0, 9, "a");
+        assert_eq!(cache.get(b"a", 0, 9), None);
+    }
+}
@@ -0,0 +1,144 @@
+extern crate resp;
+
+use self::resp::Value;
+
+// Appends a value's RESP encoding to `buffer`, byte for byte what `Value::encode` returns.  `Value::encode` builds a
+// fresh Vec for every reply, and a String for every number in it, whereas this writes the digits of numbers straight
+// into the buffer, so a connection can keep one buffer for all its replies and, once it's grown to fit them, not
+// allocate.
+pub fn encode(value: &Value, buffer: &mut Vec<u8>) {
+    match *value {
+        Value::Null                => buffer.extend_from_slice(b"$-1\r\n"),
+        Value::NullArray           => buffer.extend_from_slice(b"*-1\r\n"),
+        Value::String(ref string)  => encode_line(b'+', string.as_bytes(), buffer),
+        Value::Error(ref error)    => encode_line(b'-', error.as_bytes(), buffer),
+        Value::Integer(integer)    => encode_number(b':', integer, buffer),
+        Value::Bulk(ref string)    => encode_bulk(string.as_bytes(), buffer),
+        Value::BufBulk(ref bytes)  => encode_bulk(bytes, buffer),
+
+        Value::Array(ref values) => {
+            encode_number(b'*', values.len() as i64, buffer);
+
+            for value in values {
+                encode(value, buffer);
+            }
+        }
+    }
+}
+
+// RESP3 frames a reply that's a flat array of alternating keys and values, such as HGETALL's, as a map.  Anything else
+// is encoded as usual.
+pub fn encode_map(value: &Value, buffer: &mut Vec<u8>) {
+    match *value {
+        Value::Array(ref values) if values.len() % 2 == 0 => {
+            encode_number(b'%', (values.len() / 2) as i64, buffer);
+
+            for value in values {
+                encode(value, buffer);
+            }
+        }
+
+        _ => encode(value, buffer)
+    }
+}
+
+fn encode_line(prefix: u8, line: &[u8], buffer: &mut Vec<u8>) {
+    buffer.push(prefix);
+    buffer.extend_from_slice(line);
+    buffer.extend_from_slice(b"\r\n");
+}
+
+fn encode_number(prefix: u8, number: i64, buffer: &mut Vec<u8>) {
+    // the most digits an i64 has, with room for a minus sign
+    let mut digits = [0u8; 20];
+    let mut start = digits.len();
+
+    // worked with negatively, as i64::MIN has no positive counterpart
+    let mut remaining = if number < 0 { number } else { -number };
+
+    loop {
+        start -= 1;
+        digits[start] = b'0' + -(remaining % 10) as u8;
+        remaining /= 10;
+
+        if remaining == 0 { break; }
+    }
+
+    if number < 0 {
+        start -= 1;
+        digits[start] = b'-';
+    }
+
+    buffer.push(prefix);
+    buffer.extend_from_slice(&digits[start..]);
+    buffer.extend_from_slice(b"\r\n");
+}
+
+fn encode_bulk(bytes: &[u8], buffer: &mut Vec<u8>) {
+    encode_number(b'$', bytes.len() as i64, buffer);
+    buffer.extend_from_slice(bytes);
+    buffer.extend_from_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, encode_map, resp};
+    use self::resp::Value;
+
+    fn encoded(value: &Value) -> Vec<u8> {
+        let mut buffer = vec![];
+        encode(value, &mut buffer);
+        buffer
+    }
+
+    #[test]
+    fn encodes_exactly_as_resp_does() {
+        let values = vec![
+            Value::Null,
+            Value::NullArray,
+            Value::String("OK".to_string()),
+            Value::Error("ERR no such key".to_string()),
+            Value::Integer(-42),
+            Value::Integer(0),
+            Value::Integer(i64::MAX),
+            Value::Integer(i64::MIN),
+            Value::Bulk("hello".to_string()),
+            Value::BufBulk(vec![0, 255, 13, 10]),
+            Value::BufBulk(vec![]),
+            Value::Array(vec![]),
+            Value::Array(vec![Value::Integer(1), Value::Array(vec![Value::BufBulk(b"nested".to_vec()), Value::Null])]),
+        ];
+
+        for value in values {
+            assert_eq!(encoded(&value), value.encode());
+        }
+    }
+
+    #[test]
+    fn encodes_a_map_as_pairs() {
+        let pairs = Value::Array(vec![Value::BufBulk(b"a".to_vec()), Value::Integer(1)]);
+        let mut buffer = vec![];
+
+        encode_map(&pairs, &mut buffer);
+        assert_eq!(buffer, b"%1\r\n$1\r\na\r\n:1\r\n".to_vec());
+
+        buffer.clear();
+        encode_map(&Value::Error("ERR no".to_string()), &mut buffer);
+        assert_eq!(buffer, b"-ERR no\r\n".to_vec());
+    }
+
+    #[test]
+    fn a_reused_buffer_is_not_reallocated_for_a_reply_that_fits() {
+        let reply = Value::Array((0..100).map(|index| Value::BufBulk(index.to_string().into_bytes())).collect());
+        let mut buffer = vec![];
+
+        encode(&reply, &mut buffer);
+        let (pointer, capacity) = (buffer.as_ptr(), buffer.capacity());
+
+        buffer.clear();
+        encode(&reply, &mut buffer);
+
+        assert_eq!((buffer.as_ptr(), buffer.capacity()), (pointer, capacity));
+        assert_eq!(buffer, reply.encode());
+    }
+}
@@ -0,0 +1,110 @@
+// All integers are written little-endian regardless of the host, so serialized data can move between machines.
+
+type ReadResult<T> = Result<T, &'static str>;
+
+pub fn write_u64(output: &mut Vec<u8>, value: u64) {
+    for shift in 0..8 {
+        output.push((value >> (shift * 8)) as u8);
+    }
+}
+
+pub fn write_bytes(output: &mut Vec<u8>, bytes: &[u8]) {
+    write_u64(output, bytes.len() as u64);
+    output.extend_from_slice(bytes);
+}
+
+pub struct Reader<'a> {
+    input: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(input: &'a [u8]) -> Reader<'a> {
+        Reader { input: input, offset: 0 }
+    }
+
+    pub fn read_u8(&mut self) -> ReadResult<u8> {
+        let byte = self.take(1)?;
+        Ok(byte[0])
+    }
+
+    pub fn read_u64(&mut self) -> ReadResult<u64> {
+        let bytes = self.take(8)?;
+        Ok(bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u64))
+    }
+
+    pub fn read_bytes(&mut self) -> ReadResult<&'a [u8]> {
+        let length = self.read_u64()?;
+
+        if length > (self.input.len() - self.offset) as u64 {
+            return Err("truncated data");
+        }
+
+        self.take(length as usize)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.offset == self.input.len()
+    }
+
+    fn take(&mut self, length: usize) -> ReadResult<&'a [u8]> {
+        if self.input.len() - self.offset < length {
+            return Err("truncated data");
+        }
+
+        let slice = &self.input[self.offset..self.offset + length];
+        self.offset += length;
+        Ok(slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_u64, write_bytes, Reader};
+
+    #[test]
+    fn integers_are_written_little_endian() {
+        let mut output = vec![];
+        write_u64(&mut output, 0x0102030405060708);
+        assert_eq!(output, vec![8, 7, 6, 5, 4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn integers_round_trip() {
+        let mut output = vec![];
+        for value in &[0, 1, 255, 256, u64::MAX] { write_u64(&mut output, *value); }
+
+        let mut reader = Reader::new(&output);
+        for value in &[0, 1, 255, 256, u64::MAX] { assert_eq!(reader.read_u64().unwrap(), *value); }
+        assert!(reader.is_finished());
+    }
+
+    #[test]
+    fn byte_strings_round_trip() {
+        let mut output = vec![];
+        write_bytes(&mut output, b"");
+        write_bytes(&mut output, b"abc");
+        write_bytes(&mut output, &[0, 255, b'\n']);
+
+        let mut reader = Reader::new(&output);
+        assert_eq!(reader.read_bytes().unwrap(), b"");
+        assert_eq!(reader.read_bytes().unwrap(), b"abc");
+        assert_eq!(reader.read_bytes().unwrap(), &[0, 255, b'\n']);
+        assert!(reader.is_finished());
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let mut output = vec![];
+        write_bytes(&mut output, b"abc");
+        output.pop();
+
+        assert!(Reader::new(&output).read_bytes().is_err());
+        assert!(Reader::new(&[1, 2, 3]).read_u64().is_err());
+        assert!(Reader::new(&[]).read_u8().is_err());
+
+        let mut huge_length = vec![];
+        write_u64(&mut huge_length, u64::MAX);
+        assert!(Reader::new(&huge_length).read_bytes().is_err());
+    }
+}
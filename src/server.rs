@@ -0,0 +1,544 @@
+extern crate libc;
+extern crate rusqlite;
+
+use checkout::{self, Reservation};
+use config::{AppendFsync, Config};
+use commands::Command;
+use connection::{Connection, Shared};
+use fsync;
+use integrity;
+use logger::{Level, Logger};
+use monitor::Monitor;
+use pubsub::PubSub;
+use push_waiters::PushWaiters;
+use read_cache::ReadCache;
+use socket;
+use stats::Stats;
+use storage::SqliteStorage;
+use thread_limit::ThreadLimit;
+use warmup;
+use set_up_tables;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_MONITOR_QUEUE_SIZE: usize = 100;
+const MAX_REPLICATION_QUEUE_SIZE: usize = 10000;
+
+// how often the database is synced with --appendfsync everysec
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+// set by the SIGTERM and SIGINT handler, which can't safely do anything more; main shuts the server down once it's set
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// the connected clients, by connection id, so shutting down can hang up on them
+type Clients = Arc<Mutex<HashMap<usize, TcpStream>>>;
+
+// A server accepting connections on its own thread.  Shutting it down, or dropping the handle, stops it accepting
+// connections and hangs up on every client.
+pub struct ServerHandle {
+    address: SocketAddr,
+    stats: Arc<Stats>,
+    clients: Clients,
+    connection_mutex: Arc<Mutex<rusqlite::Connection>>,
+    stopping: Arc<AtomicBool>,
+    set_up_failed: Arc<AtomicBool>,
+    accept_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    // the address actually listened at, so a port of 0 in the config is resolved to the port chosen
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.stats.is_loading()
+    }
+
+    // Whether setting up the database failed, in which case it's loading for good and the server is no use.  What to
+    // do about it is left to whoever started it, so a process embedding blueis isn't brought down.
+    pub fn set_up_failed(&self) -> bool {
+        self.set_up_failed.load(Ordering::SeqCst)
+    }
+
+    // blocks for as long as the server is accepting connections
+    pub fn wait(mut self) {
+        if let Some(accept_thread) = self.accept_thread.take() {
+            accept_thread.join().unwrap();
+        }
+    }
+
+    // Returns once no more connections will be accepted, every client has been hung up on and the database has been
+    // checkpointed.  Commands already running finish, but their replies go nowhere.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        let accept_thread = match self.accept_thread.take() {
+            Some(accept_thread) => accept_thread,
+            None                => return
+        };
+
+        self.stopping.store(true, Ordering::SeqCst);
+
+        // At --max-threads the accept loop waits for a client's thread to finish before it can accept anything, so
+        // the clients are hung up on first.
+        self.hang_up();
+
+        // the accept loop only notices it's stopping once it accepts something
+        let mut address = self.address;
+
+        if address.ip().is_unspecified() {
+            address.set_ip(if address.is_ipv4() { IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)) } else { IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)) });
+        }
+
+        TcpStream::connect(address).ok();
+        accept_thread.join().unwrap();
+
+        // and on any it accepted in the meantime
+        self.hang_up();
+
+        // In WAL mode this folds the log back into the database file and empties it, so the database is left as one
+        // file.  Otherwise it does nothing.  Should it fail, sqlite replays the log when the database is next opened.
+        checkout::lock(&self.connection_mutex).query_row("PRAGMA wal_checkpoint(TRUNCATE)", &[], |_| ()).ok();
+    }
+
+    fn hang_up(&self) {
+        for (_, stream) in self.clients.lock().unwrap().drain() {
+            stream.shutdown(Shutdown::Both).ok();
+        }
+    }
+}
+
+pub fn stop_on_sigterm() {
+    unsafe {
+        libc::signal(libc::SIGTERM, request_stop as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGINT, request_stop as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Binds the listen address, opens the database and, with --check-integrity, checks it, then returns straight away.
+// Clients are accepted while the database is set up, and told it's loading until it's ready.
+pub fn serve(config: Config) -> io::Result<ServerHandle> {
+    Command::check_settings();
+
+    let config = Arc::new(config);
+
+    let logger = Arc::new(Logger::new_or_stderr(config.logfile.clone()));
+
+    let listener = TcpListener::bind(config.address.clone())?;
+    let address = listener.local_addr()?;
+
+    let connection = rusqlite::Connection::open(config.database_path.clone())
+        .map_err(|error| io::Error::other(format!("unable to open {}: {}", config.database_path, error)))?;
+    // In WAL mode a commit appends to the log rather than rewriting pages in place, which is much faster for the many
+    // small writes blueis makes.  sqlite waits up to the busy timeout for a lock held by another process, such as a
+    // backup, rather than failing the command.  An in-memory database stays in its own journal mode.
+    connection.query_row("PRAGMA journal_mode = WAL", &[], |_| ())
+        .and_then(|_| connection.query_row("PRAGMA busy_timeout = 5000", &[], |_| ()))
+        .map_err(|error| io::Error::other(format!("unable to configure {}: {}", config.database_path, error)))?;
+    fsync::configure(&connection, config.appendfsync);
+
+    if config.check_integrity {
+        if let Err(error) = integrity::check(&connection) {
+            logger.log(Level::Warning, None, &format!("database integrity check failed, refusing to start: {}", error));
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("database integrity check failed: {}", error)));
+        }
+
+        logger.log(Level::Notice, None, "database integrity check passed");
+    }
+
+    // Every client shares this one connection rather than a pool.  Commands that read and then write, the blocked
+    // clients' check-then-wait against PushWaiters, and discarding the read cache on a write all rely on holding it
+    // being exclusive, and an in-memory database, as the tests and TestServer use, can't be opened twice anyway.
+    let connection_mutex = Arc::new(Mutex::new(connection));
+
+    let stats = Arc::new(Stats::default());
+    stats.set_loading(true);
+
+    let stopping = Arc::new(AtomicBool::new(false));
+
+    if config.appendfsync == AppendFsync::EverySec {
+        fsync::sync_every(config.database_path.clone(), SYNC_INTERVAL, stats.clone(), stopping.clone());
+    }
+
+    let set_up_failed = Arc::new(AtomicBool::new(false));
+
+    {
+        let connection_mutex = connection_mutex.clone();
+        let config = config.clone();
+        let logger = logger.clone();
+        let stats = stats.clone();
+        let set_up_failed = set_up_failed.clone();
+        let set_up_logger = logger.clone();
+
+        let set_up = thread::spawn(move || {
+            let connection = checkout::lock(&connection_mutex);
+
+            set_up_tables(&connection);
+            Command::drop_abandoned_flushes(&connection);
+
+            if config.warmup {
+                let started_at = Instant::now();
+                let list_items = warmup::run(&connection);
+                let elapsed = started_at.elapsed();
+
+                set_up_logger.log(Level::Notice, None, &format!("warmed up the database, with {} list items, in {}ms", list_items, elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000));
+            }
+        });
+
+        // Were setting up to fail partway, clients would be told the database is loading forever, so the failure is
+        // reported through the handle.  The panic has already been reported on stderr.
+        thread::spawn(move || {
+            match set_up.join() {
+                Ok(()) => stats.set_loading(false),
+                Err(_) => {
+                    logger.log(Level::Warning, None, "unable to set up the database");
+                    set_up_failed.store(true, Ordering::SeqCst);
+                }
+            }
+        });
+    }
+
+    let clients = Arc::new(Mutex::new(HashMap::new()));
+
+    let accept_thread = {
+        let connection_mutex = connection_mutex.clone();
+        let stats = stats.clone();
+        let clients = clients.clone();
+        let stopping = stopping.clone();
+
+        thread::spawn(move || accept_connections(listener, config, logger, connection_mutex, stats, clients, stopping))
+    };
+
+    Ok(ServerHandle {
+        address:          address,
+        stats:            stats,
+        clients:          clients,
+        connection_mutex: connection_mutex,
+        stopping:         stopping,
+        set_up_failed:    set_up_failed,
+        accept_thread:    Some(accept_thread),
+    })
+}
+
+fn accept_connections(listener: TcpListener, config: Arc<Config>, logger: Arc<Logger>, connection_mutex: Arc<Mutex<rusqlite::Connection>>, stats: Arc<Stats>, clients: Clients, stopping: Arc<AtomicBool>) {
+    let shared = Shared {
+        sqlite_connection_mutex: connection_mutex,
+        monitor:                 Monitor::new(MAX_MONITOR_QUEUE_SIZE),
+        replication:             Monitor::new(MAX_REPLICATION_QUEUE_SIZE),
+        push_waiters:            Arc::new(PushWaiters::default()),
+        config:                  config.clone(),
+        read_cache:              Arc::new(Mutex::new(ReadCache::new(config.read_cache_size))),
+        stats:                   stats,
+        pubsub:                  Arc::new(PubSub::default()),
+        storage:                 Arc::new(SqliteStorage),
+        reservation:             Arc::new(Reservation::default()),
+    };
+
+    let thread_limit = ThreadLimit::new(config.max_threads);
+
+    logger.log(Level::Notice, None, &format!("blueis listening at {}", listener.local_addr().unwrap()));
+
+    let mut connection_id = 0;
+
+    for stream in listener.incoming() {
+        if stopping.load(Ordering::SeqCst) {
+            break;
+        }
+
+        match stream {
+            Err(_) => {}
+            Ok(stream) => {
+                connection_id += 1;
+
+                if let Err(error) = socket::set_keepalive(&stream, config.tcp_keepalive) {
+                    logger.log(Level::Warning, Some(connection_id), &format!("unable to set tcp keepalive: {}", error));
+                }
+
+                if let Err(error) = stream.set_nodelay(config.tcp_nodelay) {
+                    logger.log(Level::Warning, Some(connection_id), &format!("unable to set tcp nodelay: {}", error));
+                }
+
+                if let Ok(clone) = stream.try_clone() {
+                    clients.lock().unwrap().insert(connection_id, clone);
+                }
+
+                let shared = shared.clone();
+                let local_clients = clients.clone();
+                let local_logger = logger.clone();
+
+                let permit = thread_limit.acquire();
+
+                thread::spawn(move || {
+                    let result = Connection::new(shared).run(stream);
+
+                    if let Err(reason) = result {
+                        local_logger.log(Level::Warning, Some(connection_id), &format!("closed the connection: {}", reason));
+                    }
+
+                    local_clients.lock().unwrap().remove(&connection_id);
+                    drop(permit);
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve, ServerHandle, libc, rusqlite};
+    use config::Config;
+    use socket;
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::net::TcpStream;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn start(mut config: Config) -> ServerHandle {
+        config.address = "127.0.0.1:0".to_string();
+        config.database_path = ":memory:".to_string();
+
+        let server = serve(config).unwrap();
+
+        while server.is_loading() {
+            assert!(!server.set_up_failed());
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        server
+    }
+
+    fn ping(stream: &mut TcpStream) {
+        stream.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+
+        let mut reply = [0; 7];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+    }
+
+    // the server's end of the connection, as it was accepted
+    fn accepted_stream(server: &ServerHandle) -> TcpStream {
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        ping(&mut stream);
+
+        let clients = server.clients.lock().unwrap();
+        assert_eq!(clients.len(), 1);
+        let accepted = clients.values().next().unwrap().try_clone().unwrap();
+        accepted
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn accepted_connections_get_tcp_nodelay_as_configured() {
+        let server = start(Config::default());
+        assert!(socket::get_option(&accepted_stream(&server), libc::IPPROTO_TCP, libc::TCP_NODELAY).unwrap() != 0);
+
+        let server = start(Config { tcp_nodelay: false, ..Config::default() });
+        assert_eq!(socket::get_option(&accepted_stream(&server), libc::IPPROTO_TCP, libc::TCP_NODELAY).unwrap(), 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn accepted_connections_get_tcp_keepalive_as_configured() {
+        let server = start(Config::default());
+        assert_eq!(socket::get_option(&accepted_stream(&server), libc::SOL_SOCKET, libc::SO_KEEPALIVE).unwrap(), 0);
+
+        let server = start(Config { tcp_keepalive: 60, ..Config::default() });
+        let stream = accepted_stream(&server);
+        assert_eq!(socket::get_option(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE).unwrap(), 1);
+        assert_eq!(socket::get_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE).unwrap(), 60);
+    }
+
+    #[test]
+    fn serves_until_shut_down() {
+        let server = start(Config::default());
+        let address = server.address();
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        ping(&mut stream);
+
+        server.shutdown();
+
+        let mut reply = [0; 7];
+
+        // hung up on, and nothing listening any more
+        assert_eq!(stream.read(&mut reply).unwrap(), 0);
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn shuts_down_at_the_thread_limit_with_a_client_waiting() {
+        let server = start(Config { max_threads: 1, ..Config::default() });
+
+        // the only thread there can be, blocked until there's something to pop
+        let mut blocked = TcpStream::connect(server.address()).unwrap();
+        ping(&mut blocked);
+        blocked.write_all(b"*3\r\n$5\r\nBLPOP\r\n$5\r\nqueue\r\n$1\r\n0\r\n").unwrap();
+
+        // accepted, but waiting for that thread to finish
+        let mut waiting = TcpStream::connect(server.address()).unwrap();
+        waiting.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+
+        while server.clients.lock().unwrap().len() < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            server.shutdown();
+            sender.send(()).unwrap();
+        });
+
+        receiver.recv_timeout(Duration::from_secs(5)).expect("shutting down hung");
+
+        let mut reply = [0; 7];
+        assert_eq!(blocked.read(&mut reply).unwrap(), 0);
+        assert_eq!(waiting.read(&mut reply).unwrap_or(0), 0);
+    }
+
+    #[test]
+    fn the_database_is_opened_in_wal_mode_and_checkpointed_on_shutdown() {
+        let database_path = env::temp_dir().join("blueis-checkpoint-test.sqlite3").to_string_lossy().into_owned();
+        let wal_path = format!("{}-wal", database_path);
+        fs::remove_file(&database_path).ok();
+        fs::remove_file(&wal_path).ok();
+
+        let server = serve(Config { address: "127.0.0.1:0".to_string(), database_path: database_path.clone(), ..Config::default() }).unwrap();
+
+        while server.is_loading() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        stream.write_all(b"*3\r\n$5\r\nRPUSH\r\n$5\r\nqueue\r\n$1\r\na\r\n").unwrap();
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b":1\r\n");
+
+        assert!(fs::metadata(&wal_path).unwrap().len() > 0);
+
+        server.shutdown();
+
+        assert_eq!(fs::metadata(&wal_path).map(|metadata| metadata.len()).unwrap_or(0), 0);
+
+        // with the log empty, the element can only have come from the database file
+        let connection = rusqlite::Connection::open(&database_path).unwrap();
+        let count: i64 = connection.query_row("SELECT COUNT(*) FROM list_items", &[], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+
+        // and the database is still in WAL mode for the next start, as that's kept in the file
+        let journal_mode: String = connection.query_row("PRAGMA journal_mode", &[], |row| row.get(0)).unwrap();
+        assert_eq!(journal_mode, "wal");
+
+        drop(connection);
+        fs::remove_file(&database_path).ok();
+        fs::remove_file(&wal_path).ok();
+        fs::remove_file(format!("{}-shm", database_path)).ok();
+    }
+
+    #[test]
+    fn refuses_to_serve_a_damaged_database_with_check_integrity() {
+        let database_path = env::temp_dir().join("blueis-damaged-test.sqlite3").to_string_lossy().into_owned();
+        fs::remove_file(&database_path).ok();
+
+        {
+            let connection = rusqlite::Connection::open(&database_path).unwrap();
+            connection.execute("CREATE TABLE items (id integer primary key, value blob)", &[]).unwrap();
+            connection.execute("CREATE INDEX items_value ON items(value)", &[]).unwrap();
+
+            for index in 0..500 {
+                connection.execute("INSERT INTO items (value) VALUES (?1)", &[&format!("value {}", index)]).unwrap();
+            }
+        }
+
+        // overwrite the second page onwards, leaving the header readable
+        let mut file = fs::OpenOptions::new().write(true).open(&database_path).unwrap();
+        file.seek(SeekFrom::Start(4096)).unwrap();
+        file.write_all(&[0xA5; 8192]).unwrap();
+        drop(file);
+
+        let config = Config { address: "127.0.0.1:0".to_string(), database_path: database_path.clone(), check_integrity: true, ..Config::default() };
+
+        match serve(config) {
+            Err(error) => assert!(error.to_string().starts_with("database integrity check failed: "), "{}", error),
+            Ok(_)      => panic!("served a damaged database")
+        }
+
+        fs::remove_file(&database_path).ok();
+        fs::remove_file(format!("{}-wal", database_path)).ok();
+        fs::remove_file(format!("{}-shm", database_path)).ok();
+    }
+
+    #[test]
+    fn a_failure_to_set_up_is_reported_through_the_handle() {
+        let database_path = env::temp_dir().join("blueis-later-version-test.sqlite3").to_string_lossy().into_owned();
+        fs::remove_file(&database_path).ok();
+
+        {
+            let connection = rusqlite::Connection::open(&database_path).unwrap();
+            connection.execute("CREATE TABLE blueis (id integer primary key autoincrement, key string, value blob)", &[]).unwrap();
+            connection.execute("INSERT INTO blueis (key, value) VALUES ('version', '2')", &[]).unwrap();
+        }
+
+        let server = serve(Config { address: "127.0.0.1:0".to_string(), database_path: database_path.clone(), ..Config::default() }).unwrap();
+
+        for _ in 0..5000 {
+            if server.set_up_failed() {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert!(server.set_up_failed());
+        assert!(server.is_loading());
+
+        server.shutdown();
+
+        fs::remove_file(&database_path).ok();
+        fs::remove_file(format!("{}-wal", database_path)).ok();
+        fs::remove_file(format!("{}-shm", database_path)).ok();
+    }
+
+    #[test]
+    fn warms_up_before_serving() {
+        let logfile = env::temp_dir().join("blueis-warmup-test.log").to_string_lossy().into_owned();
+        fs::remove_file(&logfile).ok();
+
+        let config = Config { warmup: true, logfile: Some(logfile.clone()), ..Config::default() };
+        let server = start(config);
+
+        ping(&mut TcpStream::connect(server.address()).unwrap());
+
+        let mut log = String::new();
+        fs::File::open(&logfile).unwrap().read_to_string(&mut log).unwrap();
+        fs::remove_file(&logfile).ok();
+
+        assert!(log.contains("notice warmed up the database, with 0 list items, in "), "{}", log);
+    }
+}
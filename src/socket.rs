@@ -0,0 +1,89 @@
+extern crate libc;
+
+use std::io;
+use std::mem;
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+const KEEPALIVE_IDLE_OPTION: libc::c_int = libc::TCP_KEEPIDLE;
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const KEEPALIVE_IDLE_OPTION: libc::c_int = libc::TCP_KEEPALIVE;
+
+pub fn set_keepalive(stream: &TcpStream, seconds: u32) -> io::Result<()> {
+    if seconds == 0 {
+        return set_option(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 0);
+    }
+
+    set_option(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    set_option(stream, libc::IPPROTO_TCP, KEEPALIVE_IDLE_OPTION, seconds as libc::c_int)
+}
+
+pub fn set_option(stream: &TcpStream, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t
+        )
+    };
+
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(test)]
+pub fn get_option(stream: &TcpStream, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut length = mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut length
+        )
+    };
+
+    if result == 0 { Ok(value) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{set_keepalive, get_option, libc, KEEPALIVE_IDLE_OPTION};
+    use std::net::{TcpListener, TcpStream};
+
+    fn accept_connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        (client, server)
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn enables_keepalive_on_an_accepted_socket() {
+        let (_client, server) = accept_connection();
+
+        set_keepalive(&server, 60).unwrap();
+
+        assert_eq!(get_option(&server, libc::SOL_SOCKET, libc::SO_KEEPALIVE).unwrap(), 1);
+        assert_eq!(get_option(&server, libc::IPPROTO_TCP, KEEPALIVE_IDLE_OPTION).unwrap(), 60);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn zero_disables_keepalive() {
+        let (_client, server) = accept_connection();
+
+        set_keepalive(&server, 60).unwrap();
+        set_keepalive(&server, 0).unwrap();
+
+        assert_eq!(get_option(&server, libc::SOL_SOCKET, libc::SO_KEEPALIVE).unwrap(), 0);
+    }
+}
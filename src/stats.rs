@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Server-wide counters reported by INFO, shared by every connection, along with whether the database is still
+// being set up at startup.
+#[derive(Default)]
+pub struct Stats {
+    pub total_reads_processed: AtomicUsize,
+    pub total_error_replies: AtomicUsize,
+    pub database_syncs: AtomicUsize,
+    pub keyspace_hits: AtomicUsize,
+    pub keyspace_misses: AtomicUsize,
+    pub connected_clients: AtomicUsize,
+    pub peak_connected_clients: AtomicUsize,
+    loading: AtomicBool,
+}
+
+impl Stats {
+    pub fn increment(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(counter: &AtomicUsize) -> usize {
+        counter.load(Ordering::Relaxed)
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.loading.load(Ordering::SeqCst)
+    }
+
+    pub fn set_loading(&self, loading: bool) {
+        self.loading.store(loading, Ordering::SeqCst);
+    }
+
+    // the peak is only ever raised here, so it's the most clients connected at once since startup or the last reset
+    pub fn client_connected(&self) {
+        let connected = self.connected_clients.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut peak = self.peak_connected_clients.load(Ordering::Relaxed);
+
+        while connected > peak {
+            match self.peak_connected_clients.compare_exchange(peak, connected, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_)      => break,
+                Err(found) => peak = found
+            }
+        }
+    }
+
+    pub fn client_disconnected(&self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // For CONFIG RESETSTAT.  The peak starts again from the clients connected now.
+    pub fn reset(&self) {
+        self.total_reads_processed.store(0, Ordering::Relaxed);
+        self.total_error_replies.store(0, Ordering::Relaxed);
+        self.database_syncs.store(0, Ordering::Relaxed);
+        self.keyspace_hits.store(0, Ordering::Relaxed);
+        self.keyspace_misses.store(0, Ordering::Relaxed);
+        self.peak_connected_clients.store(self.connected_clients.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,232 @@
+extern crate rusqlite;
+
+use std::cmp;
+
+// pushed elements are spaced this far apart so LINSERT can usually find a free position without renumbering
+pub const POSITION_STRIDE: i64 = 1000;
+
+pub enum Direction {
+    Left,
+    Right
+}
+
+// Where list elements are kept.  Every operation is given the sqlite connection the command has checked out, which
+// may be a transaction, so the storage's changes commit or roll back with the rest of the command.
+//
+// Every list command, and TYPE, DEL, RESTORE and the expiry commands, find lists through here.  Commands that go
+// through every key at once, which are EXISTS, KEYS, SCAN, DBSIZE, RANDOMKEY, FLUSHDB, --max-keys eviction and
+// replication snapshots, still read the list_items table directly, as do DEBUG LISTPACK and DEBUG QUICKLIST, which
+// report how sqlite holds a list.  So a storage must keep its lists there too.
+pub trait Storage: Send + Sync {
+    fn count(&self, connection: &rusqlite::Connection, key: &[u8]) -> i64;
+    fn push(&self, connection: &rusqlite::Connection, key: &[u8], direction: &Direction, values: &[&[u8]]);
+    fn pop(&self, connection: &rusqlite::Connection, key: &[u8], direction: &Direction) -> Option<Vec<u8>>;
+
+    // start and stop are redis-style indexes, either of which can count from the end
+    fn range(&self, connection: &rusqlite::Connection, key: &[u8], start: i64, stop: i64) -> Vec<Vec<u8>>;
+    fn trim(&self, connection: &rusqlite::Connection, key: &[u8], start: i64, stop: i64);
+
+    // offset counts from the head, and must be within the list
+    fn set(&self, connection: &rusqlite::Connection, key: &[u8], offset: i64, value: &[u8]);
+
+    // inserts the value to the left or right of the first element equal to the pivot, or returns false if there's none
+    fn insert(&self, connection: &rusqlite::Connection, key: &[u8], pivot: &[u8], direction: &Direction, value: &[u8]) -> bool;
+
+    // removes up to limit elements equal to the value, or every one without a limit, finding them from the given end
+    fn remove(&self, connection: &rusqlite::Connection, key: &[u8], value: &[u8], direction: &Direction, limit: Option<i64>) -> i64;
+
+    fn delete(&self, connection: &rusqlite::Connection, key: &[u8]);
+}
+
+pub struct SqliteStorage;
+
+// converts redis-style start and stop indexes into an offset and limit, or None if the range is empty
+pub fn parse_range(length: i64, (start, stop): (i64, i64)) -> Option<(i64, i64)> {
+    let start = cmp::max(if start < 0 { start + length } else { start }, 0);
+    let stop = cmp::min(if stop < 0 { stop + length } else { stop }, length - 1);
+
+    if start > stop { None } else { Some((start, stop - start + 1)) }
+}
+
+impl Storage for SqliteStorage {
+    fn count(&self, connection: &rusqlite::Connection, key: &[u8]) -> i64 {
+        let mut statement = connection.prepare("SELECT COUNT(*) AS c FROM list_items WHERE key = ?1").unwrap();
+        statement.query_row(&[&key], |row| row.get(0)).unwrap()
+    }
+
+    fn push(&self, connection: &rusqlite::Connection, key: &[u8], direction: &Direction, values: &[&[u8]]) {
+        let boundary_sql = match *direction {
+            Direction::Left  => "SELECT MIN(position) FROM list_items WHERE key = ?1",
+            Direction::Right => "SELECT MAX(position) FROM list_items WHERE key = ?1"
+        };
+
+        let boundary: Option<i64> = connection.query_row(boundary_sql, &[&key], |row| row.get(0)).unwrap();
+        let positions = SqliteStorage::push_positions(boundary, direction, values.len());
+
+        for (value, position) in values.iter().zip(positions) {
+            connection.execute("INSERT INTO list_items (key, value, position) VALUES (?1, ?2, ?3)", &[&key, value, &position]).unwrap();
+        }
+    }
+
+    fn pop(&self, connection: &rusqlite::Connection, key: &[u8], direction: &Direction) -> Option<Vec<u8>> {
+        let order = match *direction { Direction::Left => "ASC", Direction::Right => "DESC" };
+        let mut statement = connection.prepare(&format!("SELECT id, value FROM list_items WHERE key = ?1 ORDER BY position {} LIMIT 1", order)).unwrap();
+
+        match statement.query_row(&[&key], |row| (row.get(0), row.get(1))) {
+            Ok(result) => {
+                let (id, value): (i64, Vec<u8>) = result;
+
+                connection.execute("DELETE FROM list_items WHERE id = ?1", &[&id]).unwrap();
+                Some(value)
+            }
+
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+
+            Err(error) => panic!("{}", error)
+        }
+    }
+
+    fn range(&self, connection: &rusqlite::Connection, key: &[u8], start: i64, stop: i64) -> Vec<Vec<u8>> {
+        let result: Result<Vec<Vec<u8>>, _> = match (start, stop) {
+            (0, -1) => {
+                let mut statement = connection.prepare("SELECT value FROM list_items WHERE key = ?1 ORDER BY position").unwrap();
+                let rows = statement.query_map(&[&key], |row| row.get(0)).unwrap();
+                rows.collect()
+            }
+
+            (0, s) if s >= 0 => {
+                let mut statement = connection.prepare("SELECT value FROM list_items WHERE key = ?1 ORDER BY position LIMIT ?2").unwrap();
+                let rows = statement.query_map(&[&key, &(stop + 1)], |row| row.get(0)).unwrap();
+                rows.collect()
+            }
+
+            _ => {
+                let length = self.count(connection, key);
+
+                let (offset, limit) = match parse_range(length, (start, stop)) {
+                    Some(range) => range,
+                    None        => return vec![]
+                };
+
+                let mut statement = connection.prepare("SELECT value FROM list_items WHERE key = ?1 ORDER BY position LIMIT ?2 OFFSET ?3").unwrap();
+                let rows = statement.query_map(&[&key, &limit, &offset], |row| row.get(0)).unwrap();
+                rows.collect()
+            }
+        };
+
+        result.unwrap()
+    }
+
+    fn trim(&self, connection: &rusqlite::Connection, key: &[u8], start: i64, stop: i64) {
+        let length = self.count(connection, key);
+
+        match parse_range(length, (start, stop)) {
+            Some((offset, limit)) => connection.execute("DELETE FROM list_items WHERE key = ?1 AND id NOT IN (SELECT id FROM list_items WHERE key = ?1 ORDER BY position LIMIT ?2 OFFSET ?3)", &[&key, &limit, &offset]),
+            None                  => connection.execute("DELETE FROM list_items WHERE key = ?1", &[&key])
+        }.unwrap();
+    }
+
+    fn set(&self, connection: &rusqlite::Connection, key: &[u8], offset: i64, value: &[u8]) {
+        connection.execute("UPDATE list_items SET value = ?1 WHERE id = (SELECT id FROM list_items WHERE key = ?2 ORDER BY position LIMIT 1 OFFSET ?3)", &[&value, &key, &offset]).unwrap();
+    }
+
+    fn insert(&self, connection: &rusqlite::Connection, key: &[u8], pivot: &[u8], direction: &Direction, value: &[u8]) -> bool {
+        let pivot_position = match SqliteStorage::find_value_position(connection, key, pivot) {
+            Some(position) => position,
+            None           => return false
+        };
+
+        let position = SqliteStorage::insert_position(connection, key, pivot_position, direction);
+        connection.execute("INSERT INTO list_items (key, value, position) VALUES (?1, ?2, ?3)", &[&key, &value, &position]).unwrap();
+
+        true
+    }
+
+    // values are blobs, so they're compared byte for byte
+    fn remove(&self, connection: &rusqlite::Connection, key: &[u8], value: &[u8], direction: &Direction, limit: Option<i64>) -> i64 {
+        let order = match *direction { Direction::Left => "ASC", Direction::Right => "DESC" };
+        let sql = format!("DELETE FROM list_items WHERE id IN (SELECT id FROM list_items WHERE key = ?1 AND value = ?2 ORDER BY position {} LIMIT ?3)", order);
+
+        connection.execute(&sql, &[&key, &value, &limit.unwrap_or(-1)]).unwrap() as i64
+    }
+
+    fn delete(&self, connection: &rusqlite::Connection, key: &[u8]) {
+        connection.execute("DELETE FROM list_items WHERE key = ?1", &[&key]).unwrap();
+    }
+}
+
+impl SqliteStorage {
+    // Values are pushed one at a time, each a stride beyond the previous, starting a stride beyond the list's current
+    // first (left) or last (right) position, or beyond zero for an empty list.  So LPUSH key a b c leaves the list as
+    // c b a, while RPUSH key a b c leaves it as a b c.
+    fn push_positions(boundary: Option<i64>, direction: &Direction, count: usize) -> Vec<i64> {
+        let step = match *direction {
+            Direction::Left  => -POSITION_STRIDE,
+            Direction::Right => POSITION_STRIDE
+        };
+
+        let start = boundary.unwrap_or(0);
+
+        (1..count as i64 + 1).map(|index| start + step * index).collect()
+    }
+
+    fn find_value_position(connection: &rusqlite::Connection, key: &[u8], value: &[u8]) -> Option<i64> {
+        let mut statement = connection.prepare("SELECT position FROM list_items WHERE key = ?1 AND value = ?2 ORDER BY position LIMIT 1").unwrap();
+
+        match statement.query_row(&[&key, &value], |row| row.get(0)) {
+            Ok(position)                              => Some(position),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(error)                                => panic!("{}", error)
+        }
+    }
+
+    // finds a free position next to the pivot, shifting that side of the list outwards when there's no gap left
+    fn insert_position(connection: &rusqlite::Connection, key: &[u8], pivot: i64, direction: &Direction) -> i64 {
+        let (neighbour_sql, shift_sql, step) = match *direction {
+            Direction::Left => (
+                "SELECT MAX(position) FROM list_items WHERE key = ?1 AND position < ?2",
+                "UPDATE list_items SET position = position - ?3 WHERE key = ?1 AND position < ?2",
+                -POSITION_STRIDE
+            ),
+
+            Direction::Right => (
+                "SELECT MIN(position) FROM list_items WHERE key = ?1 AND position > ?2",
+                "UPDATE list_items SET position = position + ?3 WHERE key = ?1 AND position > ?2",
+                POSITION_STRIDE
+            )
+        };
+
+        let neighbour: Option<i64> = connection.query_row(neighbour_sql, &[&key, &pivot], |row| row.get(0)).unwrap();
+
+        match neighbour {
+            None                                             => pivot + step,
+            Some(neighbour) if (neighbour - pivot).abs() > 1 => pivot + (neighbour - pivot) / 2,
+            Some(_)                                          => {
+                connection.execute(shift_sql, &[&key, &pivot, &POSITION_STRIDE]).unwrap();
+                pivot + step / 2
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Direction, SqliteStorage};
+
+    #[test]
+    fn push_positions_for_lpush() {
+        assert_eq!(SqliteStorage::push_positions(None, &Direction::Left, 1), vec![-1000]);
+        assert_eq!(SqliteStorage::push_positions(None, &Direction::Left, 2), vec![-1000, -2000]);
+        assert_eq!(SqliteStorage::push_positions(Some(-5), &Direction::Left, 5), vec![-1005, -2005, -3005, -4005, -5005]);
+        assert_eq!(SqliteStorage::push_positions(Some(3000), &Direction::Left, 2), vec![2000, 1000]);
+    }
+
+    #[test]
+    fn push_positions_for_rpush() {
+        assert_eq!(SqliteStorage::push_positions(None, &Direction::Right, 1), vec![1000]);
+        assert_eq!(SqliteStorage::push_positions(None, &Direction::Right, 2), vec![1000, 2000]);
+        assert_eq!(SqliteStorage::push_positions(Some(-4), &Direction::Right, 5), vec![996, 1996, 2996, 3996, 4996]);
+        assert_eq!(SqliteStorage::push_positions(Some(-3000), &Direction::Right, 2), vec![-2000, -1000]);
+        assert_eq!(SqliteStorage::push_positions(Some(0), &Direction::Right, 0), vec![] as Vec<i64>);
+    }
+}
@@ -0,0 +1,80 @@
+use config::Config;
+use server::{self, ServerHandle};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+// A server for tests to connect to, listening on a port of its own at 127.0.0.1 with an in-memory database that's
+// ready by the time `start` returns.  Dropping it shuts the server down.
+pub struct TestServer {
+    handle: ServerHandle,
+}
+
+impl TestServer {
+    pub fn start() -> TestServer {
+        TestServer::start_with(Config::default())
+    }
+
+    // the listen address and database path in `config` are replaced
+    pub fn start_with(mut config: Config) -> TestServer {
+        config.address = "127.0.0.1:0".to_string();
+        config.database_path = ":memory:".to_string();
+
+        let handle = server::serve(config).unwrap();
+
+        while handle.is_loading() {
+            assert!(!handle.set_up_failed(), "unable to set up the database");
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        TestServer { handle: handle }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.handle.address()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate resp;
+
+    use super::TestServer;
+    use self::resp::{Decoder, Value};
+    use std::io::{BufReader, Write};
+    use std::net::TcpStream;
+
+    fn command(arguments: &[&str]) -> Vec<u8> {
+        Value::Array(arguments.iter().map(|argument| Value::Bulk(argument.to_string())).collect()).encode()
+    }
+
+    #[test]
+    fn serves_a_push_and_a_range() {
+        let server = TestServer::start();
+
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        let mut decoder = Decoder::with_buf_bulk(BufReader::new(stream.try_clone().unwrap()));
+
+        stream.write_all(&command(&["LPUSH", "queue", "a", "b"])).unwrap();
+        assert_eq!(decoder.decode().unwrap(), Value::Integer(2));
+
+        stream.write_all(&command(&["LRANGE", "queue", "0", "-1"])).unwrap();
+        assert_eq!(decoder.decode().unwrap(), Value::Array(vec![Value::BufBulk(b"b".to_vec()), Value::BufBulk(b"a".to_vec())]));
+    }
+
+    #[test]
+    fn each_server_has_its_own_database() {
+        let first = TestServer::start();
+        let second = TestServer::start();
+
+        let mut stream = TcpStream::connect(first.address()).unwrap();
+        let mut decoder = Decoder::with_buf_bulk(BufReader::new(stream.try_clone().unwrap()));
+        stream.write_all(&command(&["RPUSH", "queue", "a"])).unwrap();
+        assert_eq!(decoder.decode().unwrap(), Value::Integer(1));
+
+        let mut stream = TcpStream::connect(second.address()).unwrap();
+        let mut decoder = Decoder::with_buf_bulk(BufReader::new(stream.try_clone().unwrap()));
+        stream.write_all(&command(&["LLEN", "queue"])).unwrap();
+        assert_eq!(decoder.decode().unwrap(), Value::Integer(0));
+    }
+}
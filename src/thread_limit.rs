@@ -0,0 +1,91 @@
+use std::sync::{Arc, Mutex, Condvar};
+
+// Caps how many connection threads run at once.  The accept loop takes a permit before spawning each connection's
+// thread and blocks while none are free, so further clients wait in the listen backlog instead.  A client blocked
+// in BLPOP keeps its permit, so a limit smaller than the number of blocked clients can starve the client that would
+// wake them up.
+#[derive(Clone)]
+pub struct ThreadLimit {
+    maximum: usize,
+    running: Arc<(Mutex<usize>, Condvar)>,
+}
+
+pub struct Permit {
+    running: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl ThreadLimit {
+    // a maximum of zero means no limit
+    pub fn new(maximum: usize) -> ThreadLimit {
+        ThreadLimit { maximum: maximum, running: Arc::new((Mutex::new(0), Condvar::new())) }
+    }
+
+    pub fn acquire(&self) -> Permit {
+        let (ref lock, ref condvar) = *self.running;
+        let mut running = lock.lock().unwrap();
+
+        while self.maximum > 0 && *running >= self.maximum {
+            running = condvar.wait(running).unwrap();
+        }
+
+        *running += 1;
+
+        Permit { running: self.running.clone() }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let (ref lock, ref condvar) = *self.running;
+        *lock.lock().unwrap() -= 1;
+        condvar.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ThreadLimit;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    fn run_threads(limit: ThreadLimit, count: usize) -> usize {
+        // (currently active, most ever active)
+        let activity = Arc::new(Mutex::new((0, 0)));
+
+        let threads: Vec<_> = (0..count).map(|_| {
+            let permit = limit.acquire();
+            let activity = activity.clone();
+
+            thread::spawn(move || {
+                {
+                    let mut activity = activity.lock().unwrap();
+                    activity.0 += 1;
+                    activity.1 = activity.1.max(activity.0);
+                }
+
+                thread::sleep(Duration::from_millis(5));
+                activity.lock().unwrap().0 -= 1;
+                drop(permit);
+            })
+        }).collect();
+
+        for thread in threads { thread.join().unwrap(); }
+
+        let most_active = activity.lock().unwrap().1;
+        most_active
+    }
+
+    #[test]
+    fn never_runs_more_threads_than_the_maximum() {
+        let most_active = run_threads(ThreadLimit::new(3), 30);
+        assert!(most_active <= 3 && most_active > 0);
+    }
+
+    #[test]
+    fn zero_means_unlimited() {
+        let limit = ThreadLimit::new(0);
+        let permits: Vec<_> = (0..50).map(|_| limit.acquire()).collect();
+        assert_eq!(permits.len(), 50);
+    }
+}
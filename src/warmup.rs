@@ -0,0 +1,48 @@
+extern crate rusqlite;
+
+// every table, with its indexes
+const TABLES: [(&'static str, &'static [&'static str]); 5] = [
+    ("blueis",     &["blueis_key_index"]),
+    ("list_items", &["list_items_key"]),
+    ("zset_items", &["zset_items_key_member", "zset_items_key_score"]),
+    ("hash_items", &["hash_items_key_field"]),
+    ("key_meta",   &["key_meta_key"]),
+];
+
+// Reads every table and index through once, so the pages the first clients' queries need are already in sqlite's
+// and the OS's caches rather than read from disk as they're asked for.  Values too big to fit in a table's own pages
+// aren't read.  Returns how many list items there are.
+pub fn run(connection: &rusqlite::Connection) -> i64 {
+    let mut list_items = 0;
+
+    for &(table, indexes) in TABLES.iter() {
+        let count: i64 = connection.query_row(&format!("SELECT COUNT(*) FROM {} NOT INDEXED", table), &[], |row| row.get(0)).unwrap();
+
+        for index in indexes {
+            let _: i64 = connection.query_row(&format!("SELECT COUNT(*) FROM {} INDEXED BY {}", table, index), &[], |row| row.get(0)).unwrap();
+        }
+
+        if table == "list_items" {
+            list_items = count;
+        }
+    }
+
+    list_items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, rusqlite};
+    use set_up_tables;
+
+    #[test]
+    fn reads_every_table_and_index() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        set_up_tables(&connection);
+
+        connection.execute("INSERT INTO list_items (key, value, position) VALUES (x'71', x'61', 0), (x'71', x'62', 1)", &[]).unwrap();
+
+        // an index that was missing or renamed would make its query fail
+        assert_eq!(run(&connection), 2);
+    }
+}